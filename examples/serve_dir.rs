@@ -9,7 +9,6 @@ extern crate tokio_io;
 #[macro_use] extern crate lazy_static;
 
 use std::time::Duration;
-use std::path::{Path};
 use std::sync::Arc;
 
 use futures::{Future, Stream, Async};
@@ -22,6 +21,7 @@ use tokio_core::reactor::Core;
 use tk_http::server;
 use tk_http::Status;
 use http_file_headers::{Input, Output, Config};
+use http_file_headers::path::resolve;
 
 const MAX_SIMULTANEOUS_CONNECTIONS: usize = 500;
 const TIME_TO_WAIT_ON_ERROR: u64 = 100;
@@ -108,6 +108,22 @@ impl<S: AsyncWrite + Send + 'static> server::Codec<S> for Codec {
                         Either::A(ok(e.done()))
                     }
                 }
+                Ok(Output::FileInMemory(head, data)) => {
+                    if head.is_partial() {
+                        e.status(Status::PartialContent);
+                    } else {
+                        e.status(Status::Ok);
+                    }
+                    e.add_length(head.content_length()).unwrap();
+                    common_headers(&mut e);
+                    for (name, val) in head.headers() {
+                        e.format_header(name, val).unwrap();
+                    }
+                    if e.done_headers().unwrap() {
+                        e.write_body(&data);
+                    }
+                    Either::A(ok(e.done()))
+                }
                 Ok(Output::FileHead(head)) | Ok(Output::NotModified(head)) => {
                     if head.is_not_modified() {
                         e.status(Status::NotModified);
@@ -125,6 +141,26 @@ impl<S: AsyncWrite + Send + 'static> server::Codec<S> for Codec {
                     assert_eq!(e.done_headers().unwrap(), false);
                     Either::A(ok(e.done()))
                 }
+                Ok(Output::RangeNotSatisfiable(head)) => {
+                    e.status(Status::RequestRangeNotSatisfiable);
+                    e.add_length(0).unwrap();
+                    common_headers(&mut e);
+                    for (name, val) in head.headers() {
+                        e.format_header(name, val).unwrap();
+                    }
+                    assert_eq!(e.done_headers().unwrap(), false);
+                    Either::A(ok(e.done()))
+                }
+                Ok(Output::PreconditionFailed(head)) => {
+                    e.status(Status::PreconditionFailed);
+                    e.add_length(0).unwrap();
+                    common_headers(&mut e);
+                    for (name, val) in head.headers() {
+                        e.format_header(name, val).unwrap();
+                    }
+                    assert_eq!(e.done_headers().unwrap(), false);
+                    Either::A(ok(e.done()))
+                }
                 Ok(Output::InvalidRange) => {
                     Either::A(respond_error(
                         Status::RequestRangeNotSatisfiable, e))
@@ -133,6 +169,31 @@ impl<S: AsyncWrite + Send + 'static> server::Codec<S> for Codec {
                     Either::A(respond_error(
                         Status::MethodNotAllowed, e))
                 }
+                Ok(Output::NotAcceptable) => {
+                    Either::A(respond_error(Status::NotAcceptable, e))
+                }
+                Ok(Output::Delegate(..)) => {
+                    // delegation is not enabled in config, and there is
+                    // no frontend server to send the file anyway
+                    Either::A(respond_error(Status::InternalServerError, e))
+                }
+                Ok(Output::Redirect(code, location)) => {
+                    e.status(match code {
+                        301 => Status::MovedPermanently,
+                        303 => Status::SeeOther,
+                        307 => Status::TemporaryRedirect,
+                        308 => Status::PermanentRedirect,
+                        _ => Status::Found,
+                    });
+                    e.add_length(0).unwrap();
+                    common_headers(&mut e);
+                    e.format_header("Location", &location).unwrap();
+                    assert_eq!(e.done_headers().unwrap(), false);
+                    Either::A(ok(e.done()))
+                }
+                Ok(Output::Forbidden) => {
+                    Either::A(respond_error(Status::Forbidden, e))
+                }
                 Ok(Output::NotFound) | Ok(Output::Directory) => {
                     Either::A(respond_error(Status::NotFound, e))
                 }
@@ -150,10 +211,10 @@ impl<S: AsyncWrite + Send + 'static> server::Dispatcher<S> for Dispatcher {
         -> Result<Self::Codec, server::Error>
     {
         let inp = Input::from_headers(&*CONFIG, head.method(), head.headers());
-        let path = Path::new("./public").join(head.path()
-            .expect("only static requests expected") // fails on OPTIONS *
-            .trim_left_matches(|x| x == '/'));
+        let path = resolve("./public", head.path()
+            .expect("only static requests expected")); // fails on OPTIONS *
         let fut = POOL.spawn_fn(move || {
+            let path = path.map_err(|_| Status::NotFound)?;
             inp.probe_file(&path).map_err(|e| {
                 error!("Error reading file {:?}: {}", path, e);
                 Status::InternalServerError
@@ -0,0 +1,246 @@
+use std::str::from_utf8;
+
+use accept_encoding::parse_q;
+
+/// Media ranges beyond this number are ignored (limits memory used per
+/// request)
+const MAX_RANGES: usize = 32;
+
+
+/// Parsed `Accept` header
+///
+/// Only used for content negotiation (see `Config::negotiate`).
+#[derive(Debug, Clone)]
+pub struct Accept {
+    /// Media ranges (lowercased) with quality, `None` if there was no header
+    ///
+    /// Boxed slice is used to keep `Input` small.
+    ranges: Option<Box<[(String, u16 /*0..1000*/)]>>,
+}
+
+/// Parser for accept header
+pub struct AcceptParser {
+    buf: Option<Vec<(String, u16)>>,
+}
+
+/// Parser for accept-charset header that checks a single charset
+pub struct AcceptCharsetParser<'a> {
+    charset: &'a str,
+    exact: Option<u16>,
+    any: Option<u16>,
+    seen: bool,
+}
+
+impl Accept {
+    /// Accept any media type (as if there is no header)
+    pub fn any() -> Accept {
+        Accept { ranges: None }
+    }
+    /// Quality of the media type, the most specific matching range is used
+    ///
+    /// Returns zero if media type is not acceptable
+    pub fn quality(&self, mime: &str) -> u16 {
+        let ranges = match self.ranges {
+            Some(ref ranges) => ranges,
+            None => return 1000,
+        };
+        let major = mime.split('/').next().unwrap_or("");
+        let mut best = None;
+        for &(ref range, q) in ranges.iter() {
+            let specificity = if range.eq_ignore_ascii_case(mime) {
+                2
+            } else if range.ends_with("/*") &&
+                range[..range.len()-2].eq_ignore_ascii_case(major)
+            {
+                1
+            } else if range == "*/*" {
+                0
+            } else {
+                continue;
+            };
+            match best {
+                Some((s, _)) if s >= specificity => {}
+                _ => best = Some((specificity, q)),
+            }
+        }
+        best.map(|(_, q)| q).unwrap_or(0)
+    }
+    /// Returns extensions of acceptable types in preferred order
+    ///
+    /// Types with the same quality are kept in the order of `types`
+    pub fn extensions<'x>(&self, types: &'x [(String, String)])
+        -> Vec<&'x str>
+    {
+        let mut buf = types.iter()
+            .map(|(mime, ext)| (self.quality(mime), &ext[..]))
+            .filter(|&(q, _)| q != 0)
+            .collect::<Vec<_>>();
+        // sort is stable
+        buf.sort_by_key(|&(q, _)| ::std::cmp::Reverse(q));
+        buf.into_iter().map(|(_, ext)| ext).collect()
+    }
+}
+
+impl AcceptParser {
+    pub fn new() -> AcceptParser {
+        AcceptParser {
+            buf: None,
+        }
+    }
+    fn add_chunk(&mut self, chunk: &[u8]) {
+        if self.buf.as_ref().map(|x| x.len() >= MAX_RANGES).unwrap_or(false) {
+            return;
+        }
+        let mut piter = chunk.split(|&x| x == b';');
+        let range = match piter.next().and_then(|x| from_utf8(x).ok()) {
+            Some(range) if !range.trim().is_empty() => {
+                range.trim().to_ascii_lowercase()
+            }
+            _ => return,
+        };
+        // other parameters are ignored
+        let q = piter.find(|x| from_utf8(x).ok()
+            .map(|x| x.trim_start().starts_with("q="))
+            .unwrap_or(false));
+        let q = if let Some(q) = parse_q(q) {
+            q
+        } else {
+            return;
+        };
+        self.buf.get_or_insert_with(Vec::new).push((range, q));
+    }
+    pub fn add_header(&mut self, header: &[u8]) {
+        for chunk in header.split(|&x| x == b',') {
+            self.add_chunk(chunk)
+        }
+    }
+    pub fn done(self) -> Accept {
+        Accept {
+            ranges: self.buf.map(Vec::into_boxed_slice),
+        }
+    }
+}
+
+impl<'a> AcceptCharsetParser<'a> {
+    pub fn new(charset: &'a str) -> AcceptCharsetParser<'a> {
+        AcceptCharsetParser {
+            charset,
+            exact: None,
+            any: None,
+            seen: false,
+        }
+    }
+    fn add_chunk(&mut self, chunk: &[u8]) {
+        let mut piter = chunk.split(|&x| x == b';');
+        let name = match piter.next().and_then(|x| from_utf8(x).ok()) {
+            Some(name) if !name.trim().is_empty() => name.trim(),
+            _ => return,
+        };
+        let q = if let Some(q) = parse_q(piter.next()) {
+            q
+        } else {
+            return;
+        };
+        self.seen = true;
+        if name == "*" {
+            self.any = Some(q);
+        } else if name.eq_ignore_ascii_case(self.charset) {
+            self.exact = Some(q);
+        }
+    }
+    pub fn add_header(&mut self, header: &[u8]) {
+        for chunk in header.split(|&x| x == b',') {
+            self.add_chunk(chunk)
+        }
+    }
+    /// Returns true if charset is acceptable
+    ///
+    /// Any charset is acceptable if there is no (valid) header
+    pub fn done(self) -> bool {
+        if !self.seen {
+            return true;
+        }
+        self.exact.or(self.any).map(|q| q > 0).unwrap_or(false)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn parse(header: &str) -> Accept {
+        let mut parser = AcceptParser::new();
+        parser.add_header(header.as_bytes());
+        parser.done()
+    }
+
+    fn types(list: &[(&str, &str)]) -> Vec<(String, String)> {
+        list.iter().map(|&(m, e)| (m.into(), e.into())).collect()
+    }
+
+    #[test]
+    fn quality() {
+        let acc = parse("text/html, application/*;q=0.5, */*;q=0.1");
+        assert_eq!(acc.quality("text/html"), 1000);
+        assert_eq!(acc.quality("application/json"), 500);
+        assert_eq!(acc.quality("image/png"), 100);
+        let acc = parse("text/*, text/plain;q=0");
+        assert_eq!(acc.quality("text/plain"), 0);
+        assert_eq!(acc.quality("text/html"), 1000);
+        assert_eq!(acc.quality("application/json"), 0);
+        assert_eq!(Accept::any().quality("application/json"), 1000);
+    }
+
+    #[test]
+    fn params() {
+        let acc = parse("text/html;level=1;q=0.3, Application/JSON ; q=0.7");
+        assert_eq!(acc.quality("text/html"), 300);
+        assert_eq!(acc.quality("application/json"), 700);
+    }
+
+    fn charset(header: &str) -> bool {
+        let mut parser = AcceptCharsetParser::new("utf-8");
+        parser.add_header(header.as_bytes());
+        parser.done()
+    }
+
+    #[test]
+    fn accept_charset() {
+        assert!(charset(""));
+        assert!(charset("UTF-8"));
+        assert!(charset("iso-8859-1, *;q=0.1"));
+        assert!(charset("*, iso-8859-1"));
+        assert!(!charset("iso-8859-1"));
+        assert!(!charset("utf-8;q=0, *"));
+        assert!(!charset("*;q=0"));
+    }
+
+    #[test]
+    fn extensions() {
+        let list = types(&[
+            ("text/html", "html"),
+            ("application/json", "json"),
+            ("application/xml", "xml"),
+        ]);
+        assert_eq!(Accept::any().extensions(&list),
+                   vec!["html", "json", "xml"]);
+        assert_eq!(parse("application/*, text/html;q=0.9").extensions(&list),
+                   vec!["json", "xml", "html"]);
+        assert_eq!(parse("application/xml").extensions(&list),
+                   vec!["xml"]);
+        assert_eq!(parse("image/*").extensions(&list), Vec::<&str>::new());
+        // empty header is the same as no header
+        assert_eq!(parse("").extensions(&list), vec!["html", "json", "xml"]);
+    }
+
+    #[test]
+    fn max_ranges() {
+        let header = vec!["text/plain;q=0.5"; 100].join(", ");
+        let mut parser = AcceptParser::new();
+        parser.add_header(header.as_bytes());
+        parser.add_header(b"text/html");
+        let accept = parser.done();
+        assert_eq!(accept.ranges.as_ref().unwrap().len(), MAX_RANGES);
+        assert_eq!(accept.quality("text/html"), 0);
+    }
+}
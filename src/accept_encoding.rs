@@ -1,7 +1,11 @@
 use std::fmt;
+use std::path::{Path, PathBuf};
 use std::str::from_utf8;
 use std::slice;
 
+/// Entries beyond this number are ignored (limits memory used per request)
+const MAX_ENTRIES: usize = 16;
+
 /// Single encoding that might be accepted by user agent
 ///
 /// Note: We only support fixed set of encodings, the most useful ones. We
@@ -20,6 +24,22 @@ pub enum Encoding {
     __Nonexhaustive,
 }
 
+/// Derives paths of the encoded variants of the file
+///
+/// Set it using `Config::encoded_path` for layouts that can't be expressed
+/// by `Config::encoding_suffix`, e.g. variants stored in a sibling
+/// directory (`gz/file.html`).
+pub trait EncodedPath: fmt::Debug + Send + Sync {
+    /// Returns the path of the file encoded with `encoding`
+    ///
+    /// This is never called for `Encoding::Identity`.
+    fn encoded_path(&self, path: &Path, encoding: Encoding) -> PathBuf;
+}
+
+/// Encodings accepted by the user agent in preferred order
+///
+/// Only supported encodings are kept. Identity is implied to be accepted
+/// after all the listed ones. Made by `AcceptEncodingParser`.
 #[derive(Debug, Clone)]
 pub struct AcceptEncoding {
     ordered: [Encoding; 3],
@@ -27,11 +47,25 @@ pub struct AcceptEncoding {
 
 /// Parser for accept encoding header
 ///
-/// It drops unaccepted encodings and returns only supported ones
+/// It drops unaccepted encodings and returns only supported ones. This
+/// can be used standalone, e.g. to choose compression for a dynamic
+/// response:
+///
+/// ```rust
+/// # use http_file_headers::{AcceptEncodingParser, Encoding};
+/// let mut parser = AcceptEncodingParser::new();
+/// parser.add_header(b"gzip, br;q=0.5");
+/// let accepted = parser.done();
+/// assert_eq!(accepted.iter().collect::<Vec<_>>(),
+///            [Encoding::Gzip, Encoding::Brotli, Encoding::Identity]);
+/// ```
+#[derive(Debug)]
 pub struct AcceptEncodingParser {
     buf: Vec<(Encoding, u16 /*0..1000*/)>,
-    /// TODO(tailhook) it's unclear what to do with `allow_any`
-    allow_any: bool,
+    /// Encodings having `q=0` (they are never accepted)
+    excluded: Vec<Encoding>,
+    /// Quality of the `*` entry (if any)
+    wildcard: Option<u16>,
 }
 
 /// Iterator over encodings in preferred order
@@ -55,20 +89,78 @@ impl Encoding {
             __Nonexhaustive => unimplemented!(),
         }
     }
+    /// Returns the name of the encoding as used in HTTP headers
+    /// (`br`, `gzip` or `identity`)
+    pub fn token(&self) -> &'static str {
+        use self::Encoding::*;
+        match *self {
+            Identity => "identity",
+            Gzip => "gzip",
+            Brotli => "br",
+            __Nonexhaustive => unreachable!(),
+        }
+    }
+    /// Find encoding by the name used in HTTP headers
+    ///
+    /// Names are case-insensitive, `x-gzip` is recognized as gzip too.
+    pub fn from_token(token: &str) -> Option<Encoding> {
+        use self::Encoding::*;
+        [Brotli, Gzip, Identity].iter().cloned()
+            .find(|enc| enc.token().eq_ignore_ascii_case(token))
+            .or_else(|| {
+                if token.eq_ignore_ascii_case("x-gzip") {
+                    Some(Gzip)
+                } else {
+                    None
+                }
+            })
+    }
+    /// Find encoding by the default filename suffix (`.br` or `.gz`)
+    ///
+    /// Empty suffix is identity. Note: suffixes set by
+    /// `Config::encoding_suffix` are not recognized.
+    pub fn from_suffix(suffix: &str) -> Option<Encoding> {
+        use self::Encoding::*;
+        [Brotli, Gzip, Identity].iter().cloned()
+            .find(|enc| enc.suffix() == suffix)
+    }
 }
 
 impl AcceptEncoding {
-    pub fn iter(&self) -> Iter {
+    /// Iterate over accepted encodings in preferred order
+    ///
+    /// Identity is always yielded (last, unless it's explicitly preferred
+    /// over some encodings).
+    pub fn iter(&self) -> Iter<'_> {
         Iter {
             slice: self.ordered.iter(),
             identity: false,
         }
     }
+    /// Returns true if the encoding is acceptable
+    pub fn accepts(&self, encoding: Encoding) -> bool {
+        self.iter().any(|x| x == encoding)
+    }
+    /// Returns the most preferred encoding
+    pub fn preferred(&self) -> Encoding {
+        self.iter().next().unwrap_or(Encoding::Identity)
+    }
+    /// Accept only identity (i.e. no `Accept-Encoding` header)
     pub fn identity() -> AcceptEncoding {
         AcceptEncoding {
             ordered: [Encoding::Identity; 3],
         }
     }
+    /// Accept encodings in the order of preference
+    ///
+    /// Only the first three are used.
+    pub fn from_list(list: &[Encoding]) -> AcceptEncoding {
+        let mut result = AcceptEncoding::identity();
+        for (i, &e) in list.iter().take(3).enumerate() {
+            result.ordered[i] = e;
+        }
+        result
+    }
 }
 
 impl<'a> Iterator for Iter<'a> {
@@ -89,108 +181,172 @@ impl<'a> Iterator for Iter<'a> {
     }
 }
 
-fn parse_q(val: Option<&[u8]>) -> Option<u16> {
+pub(crate) fn parse_q(val: Option<&[u8]>) -> Option<u16> {
     if let Some(qbytes) = val {
         if let Ok(qstr) = from_utf8(qbytes) {
             let qstr = qstr.trim();
-            if qstr.starts_with("q=") && qstr.len() <= 7 {
-                if qstr.as_bytes()[2] == b'1' {
-                    if qstr.len() == 3 || qstr.as_bytes()[3] == b'.' &&
-                        qstr.as_bytes()[4..].iter().all(|&x| x == b'0')
-                    {
-                        return Some(1000);
-                    } else {
-                        return None;
-                    }
-                } else if qstr.as_bytes()[2] == b'0' {
-                    if qstr.len() == 3 {
-                        return Some(0)
-                    } else if qstr.as_bytes()[3] != b'.' {
-                        return None;
-                    } else {
-                        let mut val = 0;
-                        for i in 0..qstr.len()-4 {
-                            match qstr.as_bytes()[i+4] {
-                                x@b'0'...b'9' => {
-                                    val += (x - b'0') as u16 * 10u16.pow((2-i) as u32);
-                                }
-                                _ => return None,
-                            }
-                        }
-                        return Some(val);
-                    }
-                } else {
-                    return None;
-                }
-            } else {
-                return None;
+            if let Some(value) = qstr.strip_prefix("q=") {
+                return parse_qvalue(value);
             }
-        } else {
-            return None;
         }
+        None
     } else {
         return Some(1000)
     }
 }
 
+/// Finds the `q` parameter among parameters of the entry
+///
+/// Other parameters are skipped, names are case-insensitive and whitespace
+/// around the `=` is allowed. Returns `Some(1000)` if there is no `q`
+/// parameter and `None` if its value is invalid.
+fn find_q<'a, I>(params: I) -> Option<u16>
+    where I: Iterator<Item=&'a [u8]>
+{
+    for param in params {
+        let param = match from_utf8(param) {
+            Ok(param) => param,
+            Err(_) => continue,
+        };
+        if let Some(eq) = param.find('=') {
+            if param[..eq].trim().eq_ignore_ascii_case("q") {
+                return parse_qvalue(param[eq+1..].trim());
+            }
+        }
+    }
+    Some(1000)
+}
+
+/// Parses qvalue (`0.xxx` or `1.000`) into thousandths
+fn parse_qvalue(qstr: &str) -> Option<u16> {
+    let bytes = qstr.as_bytes();
+    if bytes.is_empty() || bytes.len() > 5 {
+        return None;
+    }
+    if bytes.len() > 1 && bytes[1] != b'.' {
+        return None;
+    }
+    let frac = if bytes.len() > 2 { &bytes[2..] } else { &[][..] };
+    match bytes[0] {
+        b'1' if frac.iter().all(|&x| x == b'0') => Some(1000),
+        b'0' => {
+            let mut val = 0;
+            for (i, &x) in frac.iter().enumerate() {
+                match x {
+                    b'0'..=b'9' => {
+                        val += (x - b'0') as u16 * 10u16.pow(2 - i as u32);
+                    }
+                    _ => return None,
+                }
+            }
+            Some(val)
+        }
+        _ => None,
+    }
+}
+
 impl AcceptEncodingParser {
+    /// Create a parser (with no headers added)
     pub fn new() -> AcceptEncodingParser {
         AcceptEncodingParser {
             buf: Vec::new(),
-            allow_any: true,
+            excluded: Vec::new(),
+            wildcard: None,
         }
     }
     fn add_chunk(&mut self, chunk: &[u8]) {
-        use self::Encoding::*;
         let mut piter = chunk.split(|&x| x == b';');
         let enc = piter.next().and_then(|x| from_utf8(x).ok()).map(str::trim);
         let enc = match enc {
-            Some("identity") => Some(Identity),
-            Some("br") => Some(Brotli),
-            Some("gzip") => Some(Gzip),
             Some("*") => None,
-            _ => return,
+            Some(token) => match Encoding::from_token(token) {
+                Some(enc) => Some(enc),
+                None => return,
+            },
+            None => return,
         };
-        let q = if let Some(q) = parse_q(piter.next()) {
+        let q = if let Some(q) = find_q(piter) {
             q
         } else {
             return;
         };
         match (enc, q) {
-            (None, 0) => self.allow_any = false,
-            (None, _) => {}, // useless?
-            (Some(x), _) => self.buf.push((x, q)),
+            (None, _) => {
+                self.wildcard = Some(self.wildcard.map_or(q, |w| w.max(q)));
+            }
+            (Some(x), 0) => {
+                if !self.excluded.contains(&x) {
+                    self.excluded.push(x);
+                }
+            }
+            (Some(x), _) if self.buf.len() < MAX_ENTRIES => {
+                self.buf.push((x, q))
+            }
+            (Some(_), _) => {}
         }
     }
+    /// Add the value of the `Accept-Encoding` header
+    ///
+    /// Call it for every header if there are multiple ones. Unknown
+    /// encodings and invalid entries are skipped.
     pub fn add_header(&mut self, header: &[u8]) {
         for chunk in header.split(|&x| x == b',') {
             self.add_chunk(chunk)
         }
     }
+    /// Returns accepted encodings sorted by quality
+    ///
+    /// With equal quality brotli is preferred over gzip. Encodings with
+    /// `q=0` are excluded, even if they are also listed with non-zero
+    /// quality. The `*` entry adds encodings that aren't listed explicitly
+    /// with its quality, but after explicit ones of equal quality. Identity
+    /// is still implied last (the file itself is the only thing to send
+    /// anyway).
     pub fn done(mut self) -> AcceptEncoding {
-        self.buf.sort_by(|&(a, qa), &(b, qb)|
-            qb.cmp(&qa).then(a.cmp(&b)));
+        let explicit = self.buf.len();
+        if let Some(q) = self.wildcard.filter(|&q| q > 0) {
+            for &enc in &[Encoding::Brotli, Encoding::Gzip] {
+                if !self.buf[..explicit].iter().any(|&(e, _)| e == enc) {
+                    self.buf.push((enc, q));
+                }
+            }
+        }
+        let mut order: Vec<_> = (0..self.buf.len()).collect();
+        order.sort_by(|&a, &b| {
+            let (ea, qa) = self.buf[a];
+            let (eb, qb) = self.buf[b];
+            qb.cmp(&qa)
+                .then((a >= explicit).cmp(&(b >= explicit)))
+                .then(ea.cmp(&eb))
+        });
         let mut result = AcceptEncoding {
             ordered: [Encoding::Identity; 3],
         };
-        // TODO(tailhook) process disabled (q=0) encodings
-        let it = self.buf.iter().filter(|&&(_, q)| q != 0).take(3).enumerate();
-        for (i, &(e, _)) in it {
-            result.ordered[i] = e;
+        let mut len = 0;
+        for &(e, _) in order.iter().map(|&i| &self.buf[i]) {
+            if self.excluded.contains(&e) || result.ordered[..len].contains(&e)
+            {
+                continue;
+            }
+            result.ordered[len] = e;
+            len += 1;
+            if len == result.ordered.len() {
+                break;
+            }
         }
         return result;
     }
 }
 
+impl Default for AcceptEncodingParser {
+    fn default() -> AcceptEncodingParser {
+        AcceptEncodingParser::new()
+    }
+}
+
 impl fmt::Display for Encoding {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        use self::Encoding::*;
-        match *self {
-            Brotli => f.write_str("br"),
-            Gzip => f.write_str("gzip"),
-            Identity => f.write_str("identity"),
-            __Nonexhaustive => unreachable!(),
-        }
+        f.write_str(self.token())
     }
 }
 
@@ -284,4 +440,65 @@ mod test {
         assert_eq!(to_ext("identity, br"), vec![".br", ""]);
         assert_eq!(to_ext("identity, br;q=0.5"), vec!["", ".br"]);
     }
+    #[test]
+    fn accepts() {
+        let mut parser = AcceptEncodingParser::new();
+        parser.add_header(b"gzip;q=0.5, br;q=0.9");
+        let ae = parser.done();
+        assert!(ae.accepts(Encoding::Gzip));
+        assert!(ae.accepts(Encoding::Identity));
+        assert_eq!(ae.preferred(), Encoding::Brotli);
+        let ae = AcceptEncoding::identity();
+        assert!(!ae.accepts(Encoding::Brotli));
+        assert_eq!(ae.preferred(), Encoding::Identity);
+    }
+    #[test]
+    fn tokens() {
+        use super::Encoding::*;
+        for &enc in &[Brotli, Gzip, Identity] {
+            assert_eq!(Encoding::from_token(enc.token()), Some(enc));
+            assert_eq!(Encoding::from_suffix(enc.suffix()), Some(enc));
+        }
+        assert_eq!(Encoding::from_token("GZip"), Some(Gzip));
+        assert_eq!(Encoding::from_token("x-gzip"), Some(Gzip));
+        assert_eq!(Encoding::from_token("deflate"), None);
+        assert_eq!(Encoding::from_suffix(".gzip"), None);
+        assert_eq!(to_ext("BR"), vec![".br", ""]);
+    }
+    #[test]
+    fn test_disabled() {
+        assert_eq!(to_ext("gzip;q=0, br"), vec![".br", ""]);
+        assert_eq!(to_ext("br, gzip, br;q=0"), vec![".gz", ""]);
+        assert_eq!(to_ext("gzip;q=0.5, gzip;q=0"), vec![""]);
+        assert_eq!(to_ext("gzip;q=0, *"), vec![".br", ""]);
+        assert_eq!(to_ext("identity;q=0, gzip"), vec![".gz", ""]);
+        assert_eq!(to_ext("gzip, gzip;q=0.5, br"), vec![".br", ".gz", ""]);
+    }
+    #[test]
+    fn test_max_entries() {
+        let mut parser = AcceptEncodingParser::new();
+        for _ in 0..100 {
+            parser.add_header(b"identity, identity");
+        }
+        parser.add_header(b"br");
+        assert_eq!(parser.buf.len(), MAX_ENTRIES);
+    }
+    #[test]
+    fn test_wildcard() {
+        assert_eq!(to_ext("*"), vec![".br", ".gz", ""]);
+        assert_eq!(to_ext("gzip, *"), vec![".gz", ".br", ""]);
+        assert_eq!(to_ext("*;q=0.5, gzip;q=0.4"), vec![".br", ".gz", ""]);
+        assert_eq!(to_ext("*;q=0, gzip"), vec![".gz", ""]);
+        assert_eq!(to_ext("*, *;q=0"), vec![".br", ".gz", ""]);
+        assert_eq!(to_ext("identity, *;q=0.5"), vec!["", ".br", ".gz"]);
+    }
+    #[test]
+    fn test_lenient() {
+        assert_eq!(to_ext("gzip;level=5;q=0.5, BR"), vec![".br", ".gz", ""]);
+        assert_eq!(to_ext(" \tx-gzip ; Q = 0.5 ,\tbr;q=0.4"),
+                   vec![".gz", ".br", ""]);
+        assert_eq!(to_ext("gzip;foo, br;q=0.5"), vec![".gz", ".br", ""]);
+        assert_eq!(to_ext("gzip;q=2, br"), vec![".br", ""]);
+        assert_eq!(to_ext(",, gzip ,"), vec![".gz", ""]);
+    }
 }
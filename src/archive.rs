@@ -0,0 +1,523 @@
+use std::cmp::min;
+use std::collections::HashSet;
+use std::fmt;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use accept_encoding::Encoding;
+use etag::Etag;
+use input::{Input, Mode, content_disposition, is_hidden};
+use output::{Output, FileInfo, Head, Mime, sensible_date};
+use vfs::{FileSystem, ReadAt, StdFs};
+
+const BLOCK: u64 = 512;
+/// Two zero blocks mark the end of the archive
+const TRAILER: u64 = 2*BLOCK;
+/// Largest size that fits 11 octal digits, larger ones are base-256
+const MAX_OCTAL: u64 = 0o77777777777;
+const NAME_LEN: usize = 100;
+
+
+/// A directory streamed as a tar archive (see `Input::probe_archive`)
+///
+/// The archive is not stored anywhere, headers are made when the
+/// directory is probed, and the files are opened while the body is read.
+/// So the size of the archive is known upfront, and range requests work
+/// as for regular files. Only tar is supported: zip needs checksums of
+/// the files in the headers, so the files would have to be read before
+/// the response is started.
+pub struct TarArchive<FS: FileSystem=StdFs> {
+    fs: FS,
+    entries: Vec<Entry>,
+    size: u64,
+    /// File that is being read (index of entry and the file)
+    current: Mutex<Option<(usize, FS::File)>>,
+}
+
+/// State of the walk over the directory tree
+#[derive(Debug, Default)]
+struct Walk {
+    modified: Option<SystemTime>,
+    depth: usize,
+    /// Real paths of the directories archived, so symlink loops are not
+    /// followed
+    visited: HashSet<PathBuf>,
+}
+
+#[derive(Debug)]
+struct Entry {
+    /// Offset of the header in the archive
+    offset: u64,
+    /// Header blocks (incl. GNU long name, if any)
+    header: Arc<[u8]>,
+    /// Path to read data from (`None` for directories)
+    path: Option<Box<Path>>,
+    size: u64,
+}
+
+impl<FS: FileSystem> TarArchive<FS> {
+    /// Returns `None` if `Config::archive_limits` are exceeded
+    fn build(fs: FS, dir: &Path, inp: &Input)
+        -> io::Result<Option<(TarArchive<FS>, Option<SystemTime>)>>
+    {
+        let mut archive = TarArchive {
+            fs,
+            entries: Vec::new(),
+            size: 0,
+            current: Mutex::new(None),
+        };
+        let mut walk = Walk::default();
+        if let Some(real) = archive.fs.canonicalize(dir)? {
+            walk.visited.insert(real);
+        }
+        if !archive.walk(dir, "", inp, &mut walk)? {
+            return Ok(None);
+        }
+        archive.size += TRAILER;
+        Ok(Some((archive, walk.modified)))
+    }
+    /// Returns false if `Config::archive_limits` are exceeded
+    fn walk(&mut self, dir: &Path, prefix: &str, inp: &Input,
+        walk: &mut Walk)
+        -> io::Result<bool>
+    {
+        let (max_depth, max_entries, max_size) = inp.config.archive_limits;
+        let mut names = self.fs.read_dir(dir)?;
+        names.sort();
+        for name in names {
+            let path = dir.join(&name);
+            let name = match name.to_str() {
+                Some(name) => format!("{}{}", prefix, name),
+                None => {
+                    debug!(path = ?path, "non-utf8 name is not archived");
+                    continue;
+                }
+            };
+            if inp.config.deny_hidden && is_hidden(Path::new(&name)) ||
+                inp.is_denied(&path)
+            {
+                continue;
+            }
+            // removed while listing, or a dangling symlink
+            let skip = |e: io::Error| if e.kind() == io::ErrorKind::NotFound {
+                Ok(())
+            } else {
+                Err(e)
+            };
+            match inp.escapes_root(&self.fs, &path) {
+                Ok(false) => {}
+                Ok(true) => continue,
+                Err(e) => { skip(e)?; continue; }
+            }
+            let meta = match self.fs.metadata(&path) {
+                Ok(meta) => meta,
+                Err(e) => { skip(e)?; continue; }
+            };
+            if meta.is_dir() {
+                // metadata follows symlinks, so a link may point to the
+                // directory that is being archived (or was already)
+                match self.fs.canonicalize(&path) {
+                    Ok(Some(real)) => {
+                        if !walk.visited.insert(real) {
+                            debug!(path = ?path, "directory is archived");
+                            continue;
+                        }
+                    }
+                    Ok(None) => {}
+                    Err(e) => { skip(e)?; continue; }
+                }
+            }
+            let mtime = meta.modified().and_then(sensible_date);
+            if mtime > walk.modified {
+                walk.modified = mtime;
+            }
+            if meta.is_dir() {
+                let name = format!("{}/", name);
+                self.push(&name, b'5', 0, mtime, None);
+                if walk.depth >= max_depth {
+                    debug!(path = ?path, "archive is too deep");
+                    return Ok(false);
+                }
+                walk.depth += 1;
+                if !self.walk(&path, &name, inp, walk)? {
+                    return Ok(false);
+                }
+                walk.depth -= 1;
+            } else {
+                self.push(&name, b'0', meta.len(), mtime, Some(&path));
+            }
+            if self.entries.len() > max_entries ||
+                self.size + TRAILER > max_size
+            {
+                debug!(entries = self.entries.len(), size = self.size,
+                       "archive is too large");
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+    fn push(&mut self, name: &str, kind: u8, size: u64,
+        mtime: Option<SystemTime>, path: Option<&Path>)
+    {
+        let mut header = Vec::with_capacity(BLOCK as usize);
+        if name.len() > NAME_LEN {
+            // GNU extension: the name is stored in a pseudo-entry
+            let len = name.len() as u64 + 1;
+            header.extend(&block("././@LongLink", b'L', len, None)[..]);
+            header.extend(name.as_bytes());
+            let padded = padded(len) as usize;
+            header.resize(BLOCK as usize + padded, 0);
+        }
+        header.extend(&block(name, kind, size, mtime)[..]);
+        self.entries.push(Entry {
+            offset: self.size,
+            path: path.map(|p| p.to_path_buf().into_boxed_path()),
+            size,
+            header: header.into(),
+        });
+        let last = &self.entries[self.entries.len()-1];
+        self.size += last.header.len() as u64 + padded(size);
+    }
+    /// Weak etag of the archive made of all the headers
+    ///
+    /// Headers contain names, sizes and modification times of the files.
+    fn etag(&self) -> Etag {
+        let mut data = Vec::new();
+        for entry in &self.entries {
+            data.extend(&entry.header[..]);
+        }
+        Etag::weak(Etag::from_data(&data).value)
+    }
+    /// Number of bytes of the archive
+    fn len(&self) -> u64 {
+        self.size
+    }
+    /// Fill the buffer with zeroes up to the `end` offset
+    fn zeroes(&self, buf: &mut [u8], offset: u64, end: u64) -> usize {
+        let n = min(end.saturating_sub(offset), buf.len() as u64) as usize;
+        for b in &mut buf[..n] {
+            *b = 0;
+        }
+        n
+    }
+    /// Read part of the file of `entry` (the file is opened if needed)
+    fn read_file(&self, idx: usize, buf: &mut [u8], offset: u64)
+        -> io::Result<usize>
+    {
+        let entry = &self.entries[idx];
+        let path = entry.path.as_ref().expect("only files have data");
+        let mut current = self.current.lock()
+            .expect("archive file is not poisoned");
+        match *current {
+            Some((cur, _)) if cur == idx => {}
+            _ => {
+                let (file, meta) = self.fs.open(path)?;
+                if meta.len() < entry.size {
+                    return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                        "file is truncated while archiving"));
+                }
+                *current = Some((idx, file));
+            }
+        }
+        let file = &current.as_ref().expect("file is open").1;
+        match file.read_at(buf, offset)? {
+            0 => Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                "file is truncated while archiving")),
+            n => Ok(n),
+        }
+    }
+}
+
+impl<FS: FileSystem> ReadAt for TarArchive<FS> {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        let found = self.entries.binary_search_by_key(&offset, |e| e.offset);
+        let idx = match found {
+            Ok(idx) => idx,
+            Err(0) => return Ok(self.zeroes(buf, offset, self.size)),
+            Err(idx) => idx - 1,
+        };
+        let entry = &self.entries[idx];
+        let header = entry.header.len() as u64;
+        let pos = offset - entry.offset;
+        if pos < header {
+            let chunk = &entry.header[pos as usize..];
+            let n = min(chunk.len(), buf.len());
+            buf[..n].copy_from_slice(&chunk[..n]);
+            return Ok(n);
+        }
+        let pos = pos - header;
+        if pos < entry.size {
+            let max = min(entry.size - pos, buf.len() as u64) as usize;
+            return self.read_file(idx, &mut buf[..max], pos);
+        }
+        // padding of the file, or the trailer after the last entry
+        let end = if idx + 1 < self.entries.len() {
+            self.entries[idx + 1].offset
+        } else {
+            self.size
+        };
+        Ok(self.zeroes(buf, offset, end))
+    }
+}
+
+impl<FS: FileSystem> fmt::Debug for TarArchive<FS> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("TarArchive")
+            .field("entries", &self.entries)
+            .field("size", &self.size)
+            .finish()
+    }
+}
+
+/// Size of the data rounded up to the whole blocks
+fn padded(size: u64) -> u64 {
+    size.div_ceil(BLOCK) * BLOCK
+}
+
+/// Write `value` as zero-terminated octal number, or base-256 if too large
+fn numeric(field: &mut [u8], value: u64) {
+    let digits = field.len() - 1;
+    if digits == 11 && value > MAX_OCTAL {
+        field[0] = 0x80;
+        for i in 1..field.len() {
+            let shift = 8*(field.len() - 1 - i);
+            field[i] = if shift < 64 { (value >> shift) as u8 } else { 0 };
+        }
+        return;
+    }
+    write!(&mut field[..], "{:0width$o}\0", value, width=digits)
+        .expect("value fits the field");
+}
+
+/// Make a header block (GNU format, so every tar can read long names)
+fn block(name: &str, kind: u8, size: u64, mtime: Option<SystemTime>)
+    -> [u8; 512]
+{
+    let mut block = [0u8; 512];
+    let name = name.as_bytes();
+    let len = min(name.len(), NAME_LEN);
+    block[..len].copy_from_slice(&name[..len]);
+    numeric(&mut block[100..108], if kind == b'5' { 0o755 } else { 0o644 });
+    numeric(&mut block[108..116], 0);
+    numeric(&mut block[116..124], 0);
+    numeric(&mut block[124..136], size);
+    let mtime = mtime.and_then(|x| x.duration_since(UNIX_EPOCH).ok())
+        .map(|x| x.as_secs()).unwrap_or(0);
+    numeric(&mut block[136..148], mtime);
+    block[156] = kind;
+    block[257..265].copy_from_slice(b"ustar  \0");
+    for b in &mut block[148..156] {
+        *b = b' ';
+    }
+    let sum = block.iter().map(|&x| x as u32).sum::<u32>();
+    write!(&mut block[148..155], "{:06o}\0", sum)
+        .expect("checksum fits the field");
+    block
+}
+
+impl Input {
+    /// Stream the whole directory as a tar archive
+    ///
+    /// This is the "download all" feature, it's never used by `probe_file`,
+    /// so call it only when the application decides to (e.g. on
+    /// `?download` query). The files are not compressed, and hidden
+    /// files (if `Config::deny_hidden_files` is set), denied extensions and
+    /// symlinks out of the `Config::restrict_to_root` are skipped.
+    /// Symlinks to the directories that are already in the archive (e.g.
+    /// loops) are skipped too. If the directory exceeds
+    /// `Config::archive_limits`, `Output::Forbidden` is returned.
+    /// Response has `application/x-tar` type and `Content-Disposition`
+    /// with the name of the directory (unless `force_download` is used).
+    /// Returns `NotFound` if the path is not a directory.
+    ///
+    /// The etag is weak and is made of the names, sizes and modification
+    /// times of the files, so conditional requests work. If file is
+    /// changed while archive is being sent, reading fails.
+    ///
+    /// **Must be run in disk thread**
+    pub fn probe_archive<P: AsRef<Path>>(&self, path: P)
+        -> Result<Output<TarArchive>, io::Error>
+    {
+        let fs = StdFs::with_cache(self.config.fd_cache.clone());
+        self.probe_archive_in(fs, path)
+    }
+    /// Same as `probe_archive` but for the specified filesystem
+    ///
+    /// The filesystem is moved into the archive as files are opened
+    /// while it's read.
+    pub fn probe_archive_in<FS, P>(&self, fs: FS, path: P)
+        -> Result<Output<TarArchive<FS>>, io::Error>
+        where FS: FileSystem, P: AsRef<Path>,
+    {
+        let path = path.as_ref();
+        enter_span!("probe_archive", path = ?path, mode = ?self.mode);
+        self.explain.reset();
+        self.report(self.archive(fs, path))
+    }
+    fn archive<FS: FileSystem>(&self, fs: FS, path: &Path)
+        -> Result<Output<TarArchive<FS>>, io::Error>
+    {
+        match self.mode {
+            Mode::Head | Mode::Get => {}
+            Mode::InvalidMethod => return Ok(Output::InvalidMethod),
+            Mode::InvalidRange => return Ok(Output::InvalidRange),
+        }
+        match fs.metadata(path) {
+            Ok(ref m) if m.is_dir() => {}
+            Ok(_) => return Ok(Output::NotFound),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                return Ok(Output::NotFound);
+            }
+            Err(e) => return Err(e),
+        }
+        if self.escapes_root(&fs, path)? {
+            return Ok(Output::Forbidden);
+        }
+        let (archive, modified) = match TarArchive::build(fs, path, self)? {
+            Some(result) => result,
+            None => return Ok(Output::Forbidden),
+        };
+        let info = FileInfo {
+            size: archive.len(),
+            modified: if self.config.last_modified { modified } else { None },
+            etag: if self.config.etag { Some(archive.etag()) } else { None },
+            charset: None,
+        };
+        let mime = Mime::Static("application/x-tar");
+        let mut head = match Head::from_meta(self, Encoding::Identity,
+                                             &info, &mime)
+        {
+            Err(output) => return Ok(output),
+            Ok(head) => head,
+        };
+        let has_disposition = head.data.extra_headers.iter()
+            .any(|(name, _)| {
+                name.eq_ignore_ascii_case("Content-Disposition")
+            });
+        if !has_disposition {
+            let name = path.file_name()
+                .map(|x| x.to_string_lossy().into_owned())
+                .unwrap_or_else(|| String::from("archive"));
+            let value = content_disposition(&format!("{}.tar", name));
+            head.data.extra_headers.push(
+                (Arc::from("Content-Disposition"), Arc::from(value)));
+        }
+        self.respond(head, archive)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Read;
+    use std::str::from_utf8;
+    use config::Config;
+    use input::Input;
+    use output::Output;
+    use test_util::TempDir;
+    use super::{block, numeric, padded};
+
+    #[test]
+    fn header() {
+        let block = block("dir/a.txt", b'0', 5, None);
+        assert_eq!(&block[..10], b"dir/a.txt\0");
+        assert_eq!(&block[124..136], b"00000000005\0");
+        assert_eq!(&block[148..156], b"007437\0 ");
+        let mut field = [0u8; 12];
+        numeric(&mut field, 1 << 40);
+        assert_eq!(field, [0x80, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0]);
+        assert_eq!(padded(0), 0);
+        assert_eq!(padded(1), 512);
+        assert_eq!(padded(512), 512);
+    }
+
+    #[test]
+    fn archive() {
+        let tmp = TempDir::new("archive");
+        let root = tmp.join("site");
+        tmp.write("site/a.txt", "hello");
+        tmp.write("site/.hidden", "secret");
+        let long = "x".repeat(120);
+        tmp.write(format!("site/sub/{}", long), "world!");
+        let cfg = Config::new().deny_hidden_files(true).done();
+        let inp = Input::from_headers(&cfg, "GET", vec![].into_iter());
+        let mut wrapper = match inp.probe_archive(&root).unwrap() {
+            Output::File(wrapper) => wrapper,
+            _ => unreachable!(),
+        };
+        let mut data = Vec::new();
+        wrapper.read_to_end(&mut data).unwrap();
+        // a.txt, sub/, long name (2 blocks), its header, trailer
+        assert_eq!(data.len(), 512*2 + 512 + 512*2 + 512*2 + 1024);
+        assert_eq!(&data[..6], b"a.txt\0");
+        assert_eq!(&data[512..517], b"hello");
+        assert_eq!(&data[1024..1029], b"sub/\0");
+        assert_eq!(&data[1536..1550], b"././@LongLink\0");
+        assert_eq!(from_utf8(&data[2048..2048+124]).unwrap(),
+                   format!("sub/{}", long));
+        assert_eq!(&data[3072..3078], b"world!");
+        assert!(data[3078..].iter().all(|&x| x == 0));
+        let head = wrapper.into_head();
+        assert!(head.headers().any(|(name, value)| {
+            name == "Content-Disposition" &&
+                value.to_string() ==
+                "attachment; filename=\"site.tar\""
+        }));
+
+        let inp = Input::from_headers(&cfg, "GET",
+            vec![("Range", &b"bytes=512-516"[..])].into_iter());
+        let mut wrapper = match inp.probe_archive(&root).unwrap() {
+            Output::File(wrapper) => wrapper,
+            _ => unreachable!(),
+        };
+        let mut data = Vec::new();
+        wrapper.read_to_end(&mut data).unwrap();
+        assert_eq!(data, b"hello");
+
+        let inp = Input::from_headers(&cfg, "GET", vec![].into_iter());
+        let output = inp.probe_archive(root.join("a.txt")).unwrap();
+        assert_eq!(output.suggested_status(), 404);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlink_loop() {
+        use std::os::unix::fs::symlink;
+        let tmp = TempDir::new("archive-loop");
+        let root = tmp.join("site");
+        tmp.write("site/sub/a.txt", "hello");
+        symlink(".", root.join("loop")).unwrap();
+        symlink("..", root.join("sub/parent")).unwrap();
+        symlink("missing", root.join("dangling")).unwrap();
+        let cfg = Config::new().done();
+        let inp = Input::from_headers(&cfg, "GET", vec![].into_iter());
+        let mut wrapper = match inp.probe_archive(&root).unwrap() {
+            Output::File(wrapper) => wrapper,
+            _ => unreachable!(),
+        };
+        let mut data = Vec::new();
+        wrapper.read_to_end(&mut data).unwrap();
+        // sub/, sub/a.txt with data, trailer
+        assert_eq!(data.len(), 512 + 512*2 + 1024);
+        assert_eq!(&data[..5], b"sub/\0");
+        assert_eq!(&data[512..522], b"sub/a.txt\0");
+    }
+
+    #[test]
+    fn limits() {
+        let tmp = TempDir::new("archive-limits");
+        let root = tmp.join("site");
+        tmp.write("site/a/b/c.txt", "hello");
+        let probe = |depth, entries, bytes| {
+            let cfg = Config::new().archive_limits(depth, entries, bytes)
+                .done();
+            let inp = Input::from_headers(&cfg, "HEAD", vec![].into_iter());
+            inp.probe_archive(&root).unwrap().suggested_status()
+        };
+        assert_eq!(probe(2, 3, 3072), 200);
+        assert_eq!(probe(1, 3, 3072), 403);
+        assert_eq!(probe(2, 2, 3072), 403);
+        assert_eq!(probe(2, 3, 3071), 403);
+    }
+}
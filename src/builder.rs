@@ -0,0 +1,574 @@
+use std::error::Error;
+use std::fmt;
+use std::ops;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use accept::Accept;
+use accept_encoding::{AcceptEncoding, Encoding, EncodedPath};
+use config::{Config, EncodingSupport};
+use etag::{Etag, EtagProvider};
+use explain::Recorder;
+use input::{Input, Mode};
+use metrics::Metrics;
+use range::{Range, RangeError, Slice};
+use rules::{HeaderRules, RedirectRules};
+
+
+/// Consuming builder of the `Config` that validates options
+///
+/// Methods are the same as of `Config`, but take and return the builder
+/// by value. Unlike `Config::done`, the `build` method checks that
+/// options make sense together and returns an error for combinations
+/// where some option would be silently ignored, e.g. `inline_threshold`
+/// along with `delegate`:
+///
+/// ```rust
+/// # use http_file_headers::Config;
+/// let config = Config::builder()
+///     .add_index_file("index.html")
+///     .max_ranges(4)
+///     .reject_excess_ranges(true)
+///     .build()
+///     .unwrap();
+/// ```
+#[derive(Debug, Clone)]
+pub struct ConfigBuilder {
+    config: Config,
+    /// Option that got a value `Config` would panic on
+    invalid: Option<&'static str>,
+}
+
+/// Error returned by `ConfigBuilder::build`
+///
+/// Values are names of the options (methods of the `Config`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigError {
+    /// The first option is ignored because of the second one
+    Conflict(&'static str, &'static str),
+    /// The first option is ignored unless the second one is set
+    Requires(&'static str, &'static str),
+    /// The value of the option is zero
+    Zero(&'static str),
+    /// The value of the option is invalid (e.g. directory doesn't exist)
+    Invalid(&'static str),
+}
+
+/// Builder of the `Input` for requests that don't come as HTTP headers
+///
+/// This is useful for proxies and internal callers that want the same
+/// handling of conditions and ranges as for HTTP requests:
+///
+/// ```rust
+/// # use http_file_headers::{Config, Input, Method};
+/// let config = Config::new().done();
+/// let input = Input::builder(&config)
+///     .method(Method::Get)
+///     .range(100..200)
+///     .build();
+/// ```
+///
+/// Options disabled in config (e.g. `Config::etag`) are ignored the
+/// same way as corresponding headers.
+#[derive(Debug, Clone)]
+pub struct InputBuilder {
+    config: Arc<Config>,
+    mode: Mode,
+    accept_encoding: AcceptEncoding,
+    range: Option<Result<Range, RangeError>>,
+    if_match: Option<Vec<Etag>>,
+    if_none: Vec<Etag>,
+    if_modified: Option<SystemTime>,
+    if_unmodified: Option<SystemTime>,
+}
+
+/// Method of the request made by `InputBuilder`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Method {
+    /// Get the file body
+    Get,
+    /// Get the headers only
+    Head,
+}
+
+macro_rules! forward {
+    ($($(#[$attr:meta])* fn $name:ident($($arg:ident: $ty:ty),*);)*) => {
+        $(
+            $(#[$attr])*
+            #[doc = concat!("See `Config::", stringify!($name), "`")]
+            pub fn $name(mut self, $($arg: $ty),*) -> Self {
+                self.config.$name($($arg),*);
+                self
+            }
+        )*
+    };
+}
+
+impl Config {
+    /// Start building config with default values (see `ConfigBuilder`)
+    pub fn builder() -> ConfigBuilder {
+        ConfigBuilder { config: Config::new(), invalid: None }
+    }
+}
+
+impl Input {
+    /// Start building input without parsing headers (see `InputBuilder`)
+    ///
+    /// By default the method is `GET` without any conditions, and only
+    /// identity encoding is accepted.
+    pub fn builder(cfg: &Arc<Config>) -> InputBuilder {
+        InputBuilder {
+            config: cfg.clone(),
+            mode: Mode::Get,
+            accept_encoding: AcceptEncoding::identity(),
+            range: None,
+            if_match: None,
+            if_none: Vec::new(),
+            if_modified: None,
+            if_unmodified: None,
+        }
+    }
+}
+
+impl From<Config> for ConfigBuilder {
+    fn from(config: Config) -> ConfigBuilder {
+        ConfigBuilder { config, invalid: None }
+    }
+}
+
+impl ConfigBuilder {
+    forward! {
+        fn text_charset(charset: &str);
+        fn no_text_charset();
+        fn sniff_charset(value: bool);
+        fn charset_on_types(patterns: &[&str]);
+        fn add_charset_type(pattern: &str);
+        fn drop_unacceptable_charset();
+        fn reject_unacceptable_charset();
+        fn add_index_file(name: &str);
+        fn negotiate_index(value: bool);
+        fn negotiate(media_type: &str, extension: &str);
+        fn allow_method(method: &str);
+        fn no_encodings();
+        fn encodings_on_text_files();
+        fn encodings_on_types(patterns: &[&str]);
+        fn add_compressible_type(pattern: &str);
+        fn encodings_on_all_files();
+        fn brotli_min_size(bytes: u64);
+        fn no_encodings_for_extension(ext: &str);
+        fn no_encodings_for_path(pattern: &str);
+        fn encoded_inner_type(value: bool);
+        fn extended_text_types(value: bool);
+        fn content_type(value: bool);
+        fn content_length_header(value: bool);
+        fn content_location(value: bool);
+        fn date_header(value: bool);
+        fn etag(value: bool);
+        fn etag_content_only();
+        fn etag_salt(salt: &[u8]);
+        fn etag_encoding_suffix(value: bool);
+        fn strong_etag(capacity: usize);
+        fn strong_if_range(value: bool);
+        #[cfg(feature="xattr")]
+        fn etag_xattr(value: bool);
+        fn last_modified(value: bool);
+        fn deny_hidden_files(value: bool);
+        fn deny_extension(ext: &str);
+        fn mime_override(ext: &str, mime: &str);
+        fn max_path_length(bytes: usize);
+        fn max_path_components(num: usize);
+        fn archive_limits(depth: usize, entries: usize, bytes: u64);
+        fn explain(value: bool);
+        fn tail_mode(value: bool);
+        fn delegate(header: &str);
+        fn inline_threshold(bytes: u64);
+        fn ranges(value: bool);
+        fn range_merge_gap(bytes: u64);
+        fn max_ranges(num: usize);
+        fn reject_excess_ranges(value: bool);
+        fn ignore_invalid_range(value: bool);
+        fn full_range_as_ok(value: bool);
+        fn fd_cache(capacity: usize);
+        fn metadata_cache(ttl: Duration, capacity: usize);
+        fn not_found_cache(ttl: Duration, capacity: usize);
+        fn redirect_query(value: bool);
+        /// **Crashes the process** if a mapped file is truncated
+        /// while served.
+        #[cfg(feature="memmap")]
+        fn mmap_threshold(bytes: u64);
+    }
+
+    /// See `Config::read_buffer_size` (zero is an error of `build`)
+    pub fn read_buffer_size(mut self, bytes: usize) -> Self {
+        self.config.read_buffer_size = bytes;
+        self
+    }
+    /// See `Config::rate_limit` (zero is an error of `build`)
+    pub fn rate_limit(mut self, bytes_per_second: u64) -> Self {
+        self.config.rate_limit = Some(bytes_per_second);
+        self
+    }
+    /// See `Config::encoding_suffix` (`Identity` is an error of `build`)
+    pub fn encoding_suffix(mut self, encoding: Encoding, suffix: &str)
+        -> Self
+    {
+        match encoding {
+            Encoding::Brotli | Encoding::Gzip => {
+                self.config.encoding_suffix(encoding, suffix);
+            }
+            _ => self.invalid = Some("encoding_suffix"),
+        }
+        self
+    }
+    /// See `Config::redirect_status` (unsupported status is an error of
+    /// `build`)
+    pub fn redirect_status(mut self, status: u16) -> Self {
+        self.config.redirect_status = status;
+        self
+    }
+    /// See `Config::encoded_path`
+    pub fn encoded_path<P>(mut self, provider: P) -> Self
+        where P: EncodedPath + 'static
+    {
+        self.config.encoded_path(provider);
+        self
+    }
+    /// See `Config::etag_provider`
+    pub fn etag_provider<P>(mut self, provider: P) -> Self
+        where P: EtagProvider + 'static
+    {
+        self.config.etag_provider(provider);
+        self
+    }
+    /// See `Config::metrics`
+    pub fn metrics<M>(mut self, metrics: M) -> Self
+        where M: Metrics + 'static
+    {
+        self.config.metrics(metrics);
+        self
+    }
+    /// See `Config::directory_overrides`
+    #[cfg(feature="serde")]
+    pub fn directory_overrides<P: AsRef<Path>>(mut self, root: P,
+        file_name: &str)
+        -> Self
+    {
+        self.config.directory_overrides(root, file_name);
+        self
+    }
+    /// See `Config::header_rules`
+    pub fn header_rules<P: AsRef<Path>>(mut self, root: P,
+        rules: HeaderRules)
+        -> Self
+    {
+        self.config.header_rules(root, rules);
+        self
+    }
+    /// See `Config::redirect_rules`
+    pub fn redirect_rules<P: AsRef<Path>>(mut self, root: P,
+        rules: RedirectRules)
+        -> Self
+    {
+        self.config.redirect_rules(root, rules);
+        self
+    }
+    /// See `Config::restrict_to_root`
+    pub fn restrict_to_root<P: AsRef<Path>>(mut self, root: P) -> Self {
+        self.config.restrict_to_root(root);
+        self
+    }
+
+    /// Validate options and wrap config into an Arc
+    ///
+    /// The first problem found is returned.
+    pub fn build(self) -> Result<Arc<Config>, ConfigError> {
+        use self::ConfigError::*;
+        let cfg = &self.config;
+        if let Some(name) = self.invalid {
+            return Err(Invalid(name));
+        }
+        match cfg.redirect_status {
+            301 | 302 | 307 | 308 => {}
+            _ => return Err(Invalid("redirect_status")),
+        }
+        if cfg.read_buffer_size == 0 {
+            return Err(Zero("read_buffer_size"));
+        }
+        if cfg.rate_limit == Some(0) {
+            return Err(Zero("rate_limit"));
+        }
+        if let Some(Err(_)) = cfg.root {
+            return Err(Invalid("restrict_to_root"));
+        }
+        if cfg.encoding_support == EncodingSupport::Never {
+            if cfg.encoded_path.is_some() {
+                return Err(Conflict("encoded_path", "no_encodings"));
+            }
+            if cfg.brotli_suffix != ".br" || cfg.gzip_suffix != ".gz" {
+                return Err(Conflict("encoding_suffix", "no_encodings"));
+            }
+            if cfg.brotli_min_size > 0 {
+                return Err(Conflict("brotli_min_size", "no_encodings"));
+            }
+            if !cfg.plain_extensions.is_empty() {
+                return Err(Conflict("no_encodings_for_extension",
+                                    "no_encodings"));
+            }
+            if !cfg.plain_paths.is_empty() {
+                return Err(Conflict("no_encodings_for_path", "no_encodings"));
+            }
+        }
+        if !cfg.etag {
+            if cfg.strong_etag.is_some() {
+                return Err(Conflict("strong_etag", "etag"));
+            }
+            if !cfg.etag_salt.is_empty() {
+                return Err(Conflict("etag_salt", "etag"));
+            }
+            if cfg.etag_encoding_suffix {
+                return Err(Conflict("etag_encoding_suffix", "etag"));
+            }
+            if cfg.strong_if_range {
+                return Err(Conflict("strong_if_range", "etag"));
+            }
+        }
+        #[cfg(feature="xattr")]
+        {
+            if cfg.etag_xattr && cfg.strong_etag.is_none() {
+                return Err(Requires("etag_xattr", "strong_etag"));
+            }
+        }
+        if !cfg.ranges {
+            if cfg.max_ranges.is_some() {
+                return Err(Conflict("max_ranges", "ranges"));
+            }
+            if cfg.range_merge_gap > 0 {
+                return Err(Conflict("range_merge_gap", "ranges"));
+            }
+            if cfg.ignore_invalid_range {
+                return Err(Conflict("ignore_invalid_range", "ranges"));
+            }
+            if cfg.full_range_as_ok {
+                return Err(Conflict("full_range_as_ok", "ranges"));
+            }
+        }
+        if cfg.redirect_rules.is_none() {
+            if cfg.redirect_status != 301 {
+                return Err(Requires("redirect_status", "redirect_rules"));
+            }
+            if cfg.redirect_query {
+                return Err(Requires("redirect_query", "redirect_rules"));
+            }
+        }
+        if cfg.reject_excess_ranges && cfg.max_ranges.is_none() {
+            return Err(Requires("reject_excess_ranges", "max_ranges"));
+        }
+        if cfg.delegate_header.is_some() {
+            if cfg.inline_threshold.is_some() {
+                return Err(Conflict("inline_threshold", "delegate"));
+            }
+            if cfg.rate_limit.is_some() {
+                return Err(Conflict("rate_limit", "delegate"));
+            }
+            #[cfg(feature="memmap")]
+            {
+                if cfg.mmap_threshold.is_some() {
+                    return Err(Conflict("mmap_threshold", "delegate"));
+                }
+            }
+        }
+        Ok(Arc::new(self.config))
+    }
+}
+
+impl InputBuilder {
+    /// Set method of the request (`GET` by default)
+    pub fn method(mut self, method: Method) -> Self {
+        self.mode = match method {
+            Method::Get => Mode::Get,
+            Method::Head => Mode::Head,
+        };
+        self
+    }
+    /// Accept these encodings in the order of preference
+    ///
+    /// Identity is implied after the listed ones. Like `Accept-Encoding`.
+    pub fn encodings(mut self, list: &[Encoding]) -> Self {
+        self.accept_encoding = AcceptEncoding::from_list(list);
+        self
+    }
+    /// Request bytes from `start` to `end` (exclusive), like `Range`
+    ///
+    /// Empty range is invalid (as `RangeError::Reversed`).
+    pub fn range(mut self, range: ops::Range<u64>) -> Self {
+        self.range = Some(if range.start < range.end {
+            let slice = Slice::FromTo(range.start, range.end - 1);
+            Ok(Range::SingleRangeOfBytes(slice))
+        } else {
+            Err(RangeError::Reversed)
+        });
+        self
+    }
+    /// Respond with 412 unless etag matches, like `If-Match`
+    ///
+    /// Can be called multiple times to match any of the etags.
+    pub fn if_match(mut self, etag: Etag) -> Self {
+        self.if_match.get_or_insert_with(Vec::new).push(etag);
+        self
+    }
+    /// Respond with 304 if etag matches, like `If-None-Match`
+    ///
+    /// Can be called multiple times to match any of the etags.
+    pub fn if_none_match(mut self, etag: Etag) -> Self {
+        self.if_none.push(etag);
+        self
+    }
+    /// Respond with 304 unless modified after `time`, like
+    /// `If-Modified-Since`
+    pub fn if_modified_since(mut self, time: SystemTime) -> Self {
+        self.if_modified = Some(time);
+        self
+    }
+    /// Respond with 412 if modified after `time`, like
+    /// `If-Unmodified-Since`
+    pub fn if_unmodified_since(mut self, time: SystemTime) -> Self {
+        self.if_unmodified = Some(time);
+        self
+    }
+    /// Make the input
+    pub fn build(self) -> Input {
+        let cfg = self.config;
+        let (mode, range, range_error) = match self.range {
+            Some(_) if !cfg.ranges => (self.mode, None, None),
+            Some(Ok(range)) => (self.mode, Some(range), None),
+            Some(Err(e)) if cfg.ignore_invalid_range => {
+                (self.mode, None, Some(e))
+            }
+            Some(Err(e)) => (Mode::InvalidRange, None, Some(e)),
+            None => (self.mode, None, None),
+        };
+        Input {
+            mode,
+            accept_encoding: if cfg.encoding_support != EncodingSupport::Never {
+                self.accept_encoding
+            } else {
+                AcceptEncoding::identity()
+            },
+            accept: Accept::any(),
+            charset_acceptable: true,
+            range,
+            range_error,
+            if_match: if cfg.etag { self.if_match } else { None },
+            if_none: if cfg.etag { self.if_none } else { Vec::new() },
+            if_modified: if cfg.last_modified { self.if_modified } else {
+                None
+            },
+            if_unmodified: if cfg.last_modified { self.if_unmodified } else {
+                None
+            },
+            explain: Recorder::new(cfg.explain),
+            overrides: None,
+            config: cfg,
+        }
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ConfigError::Conflict(option, other) => {
+                write!(f, "option `{}` is ignored because of `{}`",
+                       option, other)
+            }
+            ConfigError::Requires(option, other) => {
+                write!(f, "option `{}` requires `{}`", option, other)
+            }
+            ConfigError::Zero(option) => {
+                write!(f, "option `{}` must be non-zero", option)
+            }
+            ConfigError::Invalid(option) => {
+                write!(f, "value of option `{}` is invalid", option)
+            }
+        }
+    }
+}
+
+impl Error for ConfigError {}
+
+#[cfg(test)]
+mod test {
+    use config::Config;
+    use input::Input;
+    use output::Output;
+    use super::ConfigError::*;
+    use super::Method;
+
+    #[test]
+    fn valid() {
+        let cfg = Config::builder()
+            .add_index_file("index.html")
+            .ranges(true)
+            .max_ranges(4)
+            .reject_excess_ranges(true)
+            .build()
+            .unwrap();
+        assert_eq!(cfg.index_files, ["index.html"]);
+        assert_eq!(cfg.max_ranges, Some(4));
+    }
+
+    #[test]
+    fn errors() {
+        let b = Config::builder;
+        assert_eq!(b().read_buffer_size(0).build().unwrap_err(),
+                   Zero("read_buffer_size"));
+        assert_eq!(b().restrict_to_root("public/nonexistent").build()
+                   .unwrap_err(),
+                   Invalid("restrict_to_root"));
+        assert_eq!(b().delegate("X-Accel-Redirect").inline_threshold(1024)
+                   .build().unwrap_err(),
+                   Conflict("inline_threshold", "delegate"));
+        assert_eq!(b().ranges(false).max_ranges(2).build().unwrap_err(),
+                   Conflict("max_ranges", "ranges"));
+        assert_eq!(b().reject_excess_ranges(true).build().unwrap_err(),
+                   Requires("reject_excess_ranges", "max_ranges"));
+        assert_eq!(b().no_encodings().encoding_suffix(::Encoding::Gzip, "z")
+                   .build().unwrap_err().to_string(),
+                   "option `encoding_suffix` is ignored because of \
+                    `no_encodings`");
+        assert_eq!(b().redirect_status(303).build().unwrap_err(),
+                   Invalid("redirect_status"));
+        assert_eq!(b().encoding_suffix(::Encoding::Identity, ".id").build()
+                   .unwrap_err(),
+                   Invalid("encoding_suffix"));
+        // order of the calls doesn't matter
+        assert_eq!(b().strong_etag(10).etag(false).build().unwrap_err(),
+                   Conflict("strong_etag", "etag"));
+    }
+
+    #[test]
+    fn input() {
+        let cfg = Config::new().done();
+        let inp = Input::builder(&cfg).range(0..10).build();
+        let etag = match inp.probe_file("public/index.html").unwrap() {
+            Output::File(wrapper) => {
+                assert_eq!(wrapper.head().byte_range(), Some((0, 9, 126)));
+                wrapper.head().etag().unwrap().clone()
+            }
+            _ => unreachable!(),
+        };
+        let inp = Input::builder(&cfg)
+            .method(Method::Head)
+            .if_none_match(etag.clone())
+            .build();
+        assert_eq!(inp.probe_file("public/index.html").unwrap()
+                   .suggested_status(), 304);
+        // weak etags never match `If-Match`
+        let inp = Input::builder(&cfg).if_match(etag).build();
+        assert_eq!(inp.probe_file("public/index.html").unwrap()
+                   .suggested_status(), 412);
+        let inp = Input::builder(&cfg).range(5..5).build();
+        assert_eq!(inp.probe_file("public/index.html").unwrap()
+                   .suggested_status(), 416);
+    }
+}
@@ -0,0 +1,67 @@
+use std::cmp::min;
+use std::io;
+
+use bytes::{Bytes, BytesMut};
+
+use output::FileWrapper;
+use vfs::ReadAt;
+
+
+impl<F: ReadAt> FileWrapper<F> {
+    /// Read next chunk of the body of at most `max` bytes
+    ///
+    /// Empty chunk means the whole body is read.
+    ///
+    /// **Must be run in disk thread**
+    ///
+    /// Only available with the `bytes` feature.
+    pub fn read_bytes(&mut self, max: usize) -> io::Result<Bytes> {
+        let mut buf = BytesMut::new();
+        self.read_buf(&mut buf, max)?;
+        Ok(buf.freeze())
+    }
+    /// Append next chunk of the body of at most `max` bytes to the buffer
+    ///
+    /// This allows to reuse the buffer. Returns number of bytes read,
+    /// zero means the whole body is read.
+    ///
+    /// **Must be run in disk thread**
+    ///
+    /// Only available with the `bytes` feature.
+    pub fn read_buf(&mut self, buf: &mut BytesMut, max: usize)
+        -> io::Result<usize>
+    {
+        let max = min(max as u64, self.bytes_left()) as usize;
+        let start = buf.len();
+        buf.resize(start + max, 0);
+        let result = self.read_into(&mut buf[start..]);
+        let bytes = *result.as_ref().unwrap_or(&0);
+        buf.truncate(start + bytes);
+        result
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use config::Config;
+    use input::Input;
+    use output::Output;
+    use super::*;
+
+    #[test]
+    fn read_bytes() {
+        let cfg = Arc::new(Config::new());
+        let inp = Input::from_headers(&cfg, "GET",
+            vec![("Range", &b"bytes=2-8"[..])].into_iter());
+        let mut wrapper = match inp.probe_file("public/index.html").unwrap() {
+            Output::File(wrapper) => wrapper,
+            _ => unreachable!(),
+        };
+        assert_eq!(&wrapper.read_bytes(4).unwrap()[..], b"DOCT");
+        let mut buf = BytesMut::from(&b"<!"[..]);
+        assert_eq!(wrapper.read_buf(&mut buf, 100).unwrap(), 3);
+        assert_eq!(&buf[..], b"<!YPE");
+        assert_eq!(wrapper.read_bytes(4).unwrap().len(), 0);
+    }
+}
@@ -0,0 +1,75 @@
+use std::io;
+use std::str::from_utf8;
+
+use vfs::ReadAt;
+
+/// Number of bytes at the start of the file used to detect charset
+const SNIFF_BYTES: usize = 4096;
+
+
+/// Detect charset of the text by byte order mark or by content
+///
+/// Returns `None` if data is ASCII-only, so any ASCII-compatible charset
+/// configured by user is fine. Data that is not valid UTF-8 is considered
+/// latin-1 (the most common legacy charset). If `truncated` is true, the
+/// data is the start of the file, so it may end in the middle of a
+/// character.
+pub fn detect(data: &[u8], truncated: bool) -> Option<&'static str> {
+    if data.starts_with(b"\xEF\xBB\xBF") {
+        return Some("utf-8");
+    }
+    if data.starts_with(b"\xFF\xFE") {
+        return Some("utf-16le");
+    }
+    if data.starts_with(b"\xFE\xFF") {
+        return Some("utf-16be");
+    }
+    if data.iter().all(|x| x.is_ascii()) {
+        return None;
+    }
+    match from_utf8(data) {
+        Ok(_) => Some("utf-8"),
+        // multi-byte character is cut at the end of the buffer
+        Err(ref e) if truncated && e.error_len().is_none() => Some("utf-8"),
+        Err(_) => Some("iso-8859-1"),
+    }
+}
+
+/// Read the start of the file and detect its charset
+///
+/// **Must be run in disk thread**
+pub fn sniff<F: ReadAt>(file: &F) -> io::Result<Option<&'static str>> {
+    let mut buf = [0u8; SNIFF_BYTES];
+    let mut len = 0;
+    while len < buf.len() {
+        match file.read_at(&mut buf[len..], len as u64)? {
+            0 => break,
+            bytes => len += bytes,
+        }
+    }
+    Ok(detect(&buf[..len], len == buf.len()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn bom() {
+        assert_eq!(detect(b"\xEF\xBB\xBFhello", false), Some("utf-8"));
+        assert_eq!(detect(b"\xFF\xFEh\x00i\x00", false), Some("utf-16le"));
+        assert_eq!(detect(b"\xFE\xFF\x00h\x00i", false), Some("utf-16be"));
+    }
+
+    #[test]
+    fn content() {
+        assert_eq!(detect(b"", false), None);
+        assert_eq!(detect(b"<h1>hello</h1>", false), None);
+        assert_eq!(detect("caf\u{e9}".as_bytes(), false), Some("utf-8"));
+        assert_eq!(detect(&"caf\u{e9}".as_bytes()[..4], true), Some("utf-8"));
+        assert_eq!(detect(&"caf\u{e9}".as_bytes()[..4], false),
+                   Some("iso-8859-1"));
+        assert_eq!(detect(b"caf\xE9", true), Some("utf-8"));
+        assert_eq!(detect(b"caf\xE9 au lait", true), Some("iso-8859-1"));
+    }
+}
@@ -4,6 +4,9 @@ use std::time::SystemTime;
 use httpdate;
 use etag::Etag;
 
+/// Etags beyond this number are ignored (limits memory used per request)
+const MAX_ETAGS: usize = 32;
+
 
 pub struct ModifiedParser {
     result: Result<Option<SystemTime>, ()>,
@@ -13,6 +16,16 @@ pub struct NoneMatchParser {
     etags: Vec<Etag>,
 }
 
+pub struct IfRangeParser {
+    result: Result<Option<Result<SystemTime, Etag>>, ()>,
+}
+
+pub struct MatchParser {
+    tags: NoneMatchParser,
+    seen: bool,
+    any: bool,
+}
+
 
 impl ModifiedParser {
     pub fn new() -> ModifiedParser {
@@ -44,33 +57,43 @@ impl ModifiedParser {
     }
 }
 
+/// Parses a single entity tag, returns `None` if it's invalid or not ours
+fn parse_etag(mut chunk: &[u8]) -> Option<Etag> {
+    while !chunk.is_empty() && chunk[0] == b' ' {
+        chunk = &chunk[1..];
+    }
+    let strong = !chunk.starts_with(b"W/");
+    if !strong {
+        chunk = &chunk[2..];
+    }
+    if chunk.len() < 2 || chunk[0] != b'"' {
+        // Is not a tag
+        return None;
+    }
+    let end = match chunk[1..].iter().position(|&x| x == b'"') {
+        Some(end) => end + 1,
+        None => return None,
+    };
+    if !chunk[end+1..].iter().all(|&x| x == b' ') {
+        // invalid trailing bytes
+        return None;
+    }
+    Etag::decode_base64(&chunk[1..end], strong).ok()
+}
+
 impl NoneMatchParser {
     pub fn new() -> NoneMatchParser {
         NoneMatchParser {
             etags: Vec::new(),
         }
     }
-    fn add_chunk(&mut self, mut chunk: &[u8]) {
-        while chunk.len() > 0 && chunk[0] == b' ' {
-            chunk = &chunk[1..];
-        }
-        if chunk.len() < 4 + 16 {  // the 'W/"xx"' and 16 bytes of base64
-            // Is not our etag
+    fn add_chunk(&mut self, chunk: &[u8]) {
+        if self.etags.len() >= MAX_ETAGS {
             return;
         }
-        if chunk[0] != b'W' || chunk[1] != b'/' || chunk[2] != b'"' ||
-            chunk[16+3] != b'"'
-        {
-            // Is not a weak tag (or wrong length)
-            return;
-        }
-        if !chunk[16+4..].iter().all(|&x| x == b' ') {
-            // invalid trailing bytes
-            return;
-        }
-        match Etag::decode_base64(&chunk[3..16+3]) {
-            Ok(etag) => self.etags.push(etag),
-            Err(()) => return, // skip invalid tags
+        // skip invalid tags (or not our tags)
+        if let Some(etag) = parse_etag(chunk) {
+            self.etags.push(etag);
         }
     }
     pub fn add_header(&mut self, header: &[u8]) {
@@ -83,18 +106,107 @@ impl NoneMatchParser {
     }
 }
 
+impl IfRangeParser {
+    pub fn new() -> IfRangeParser {
+        IfRangeParser {
+            result: Ok(None),
+        }
+    }
+    pub fn add_header(&mut self, header: &[u8]) {
+        match self.result {
+            Err(()) => {}
+            ref mut r @ Ok(Some(_)) => {
+                // Duplicate if-range header
+                *r = Err(());
+            }
+            ref mut r @ Ok(None) => {
+                let trimmed = from_utf8(header).ok().map(|s| s.trim());
+                let res = match trimmed {
+                    Some(s) if s.starts_with('"') || s.starts_with("W/") => {
+                        parse_etag(s.as_bytes()).map(Err)
+                    }
+                    Some(s) => httpdate::parse_http_date(s).ok().map(Ok),
+                    None => None,
+                };
+                match res {
+                    Some(x) => *r = Ok(Some(x)),
+                    None => *r = Err(()),
+                }
+            }
+        }
+    }
+    /// Returns the date or the etag to compare
+    ///
+    /// Returns `Err(())` for invalid or duplicate header and for etags that
+    /// are not ours, so they can't match anyway.
+    pub fn done(self) -> Result<Option<Result<SystemTime, Etag>>, ()> {
+        self.result
+    }
+}
+
+impl MatchParser {
+    pub fn new() -> MatchParser {
+        MatchParser {
+            tags: NoneMatchParser::new(),
+            seen: false,
+            any: false,
+        }
+    }
+    pub fn add_header(&mut self, header: &[u8]) {
+        for chunk in header.split(|&x| x == b',') {
+            if chunk.iter().all(|&x| x == b' ') {
+                continue;
+            }
+            self.seen = true;
+            if chunk.iter().filter(|&&x| x != b' ').eq(b"*".iter()) {
+                self.any = true;
+            } else {
+                self.tags.add_chunk(chunk);
+            }
+        }
+    }
+    /// Returns `None` if there is no header or it is `*`
+    ///
+    /// Otherwise returns the list of tags, which is empty if none of them
+    /// are ours (so nothing can match).
+    pub fn done(self) -> Option<Vec<Etag>> {
+        if !self.seen || self.any {
+            None
+        } else {
+            Some(self.tags.done())
+        }
+    }
+}
+
 #[cfg(test)]
 mod test {
     use std::time::{SystemTime, Duration, UNIX_EPOCH};
     use etag::Etag;
     use super::*;
 
+    const VALUE: [u8; 12] = [181, 130, 83, 244, 162, 84, 35, 66,
+                             151, 216, 142, 106];
+
     fn parse_etag(val: &str) -> Vec<Etag> {
         let mut parser = NoneMatchParser::new();
         parser.add_header(val.as_bytes());
         parser.done()
     }
 
+    fn parse_match(val: &str) -> Option<Vec<Etag>> {
+        let mut parser = MatchParser::new();
+        parser.add_header(val.as_bytes());
+        parser.done()
+    }
+
+    fn parse_if_range(val: &str)
+        -> Result<Option<Result<SystemTime, Etag>>, ()>
+    {
+        let mut parser = IfRangeParser::new();
+        parser.add_header(val.as_bytes());
+        parser.done()
+    }
+
     fn parse_mod(val: &str) -> Option<SystemTime> {
         let mut parser = ModifiedParser::new();
         parser.add_header(val.as_bytes());
@@ -104,10 +216,10 @@ mod test {
     #[test]
     fn single_etag() {
         assert_eq!(parse_etag(r#"W/"tYJT9KJUI0KX2I5q""#), vec![
-            Etag([181, 130, 83, 244, 162, 84, 35, 66, 151, 216, 142, 106])
+            Etag::weak(VALUE)
         ]);
         assert_eq!(parse_etag(r#"    W/"tYJT9KJUI0KX2I5q"  "#), vec![
-            Etag([181, 130, 83, 244, 162, 84, 35, 66, 151, 216, 142, 106])
+            Etag::weak(VALUE)
         ]);
     }
 
@@ -115,9 +227,27 @@ mod test {
     fn two_tags() {
         assert_eq!(parse_etag(r#"W/"tYJT9KJUI0KX2I5q", W/"tYJT9KJUI0KX2I5q""#),
         vec![
-            Etag([181, 130, 83, 244, 162, 84, 35, 66, 151, 216, 142, 106]),
-            Etag([181, 130, 83, 244, 162, 84, 35, 66, 151, 216, 142, 106]),
+            Etag::weak(VALUE),
+            Etag::weak(VALUE),
+        ]);
+    }
+
+    #[test]
+    fn strong_tag() {
+        assert_eq!(parse_etag(r#""tYJT9KJUI0KX2I5q", W/"tYJT9KJUI0KX2I5q""#),
+        vec![Etag::strong(VALUE), Etag::weak(VALUE)]);
+    }
+
+    #[test]
+    fn encoding_suffix() {
+        use accept_encoding::Encoding;
+        assert_eq!(parse_etag(
+            r#"W/"tYJT9KJUI0KX2I5q-br", "tYJT9KJUI0KX2I5q-gz""#),
+        vec![
+            Etag::weak(VALUE).with_encoding(Encoding::Brotli),
+            Etag::strong(VALUE).with_encoding(Encoding::Gzip),
         ]);
+        assert_eq!(parse_etag(r#"W/"tYJT9KJUI0KX2I5q-xz""#), vec![]);
     }
 
     #[test]
@@ -126,12 +256,42 @@ mod test {
             Some(UNIX_EPOCH + Duration::new(1503434833, 0)));
     }
 
+    #[test]
+    fn if_match() {
+        assert_eq!(parse_match(""), None);
+        assert_eq!(parse_match(" * "), None);
+        assert_eq!(parse_match(r#""tYJT9KJUI0KX2I5q""#),
+                   Some(vec![Etag::strong(VALUE)]));
+        assert_eq!(parse_match(r#""tYJT9KJUI0KX2I5q", "foreign""#),
+                   Some(vec![Etag::strong(VALUE)]));
+        assert_eq!(parse_match(r#""foreign""#), Some(vec![]));
+    }
+
+    #[test]
+    fn if_range() {
+        assert_eq!(parse_if_range(r#"W/"tYJT9KJUI0KX2I5q""#),
+                   Ok(Some(Err(Etag::weak(VALUE)))));
+        assert_eq!(parse_if_range(r#" "tYJT9KJUI0KX2I5q" "#),
+                   Ok(Some(Err(Etag::strong(VALUE)))));
+        assert_eq!(parse_if_range("Tue, 22 Aug 2017 20:47:13 GMT"),
+            Ok(Some(Ok(UNIX_EPOCH + Duration::new(1503434833, 0)))));
+        assert_eq!(parse_if_range(r#""foreign""#), Err(()));
+        assert_eq!(parse_if_range("yesterday"), Err(()));
+    }
+
+    #[test]
+    fn max_etags() {
+        let header = vec![r#"W/"tYJT9KJUI0KX2I5q""#; 100].join(", ");
+        assert_eq!(parse_etag(&header).len(), MAX_ETAGS);
+    }
+
     #[test]
     fn bad_etags() {
         assert_eq!(parse_etag(r#"W/"tYJT9KJ^^UI0KX2I5q""#), vec![]);
-        assert_eq!(parse_etag(r#""tYJT9KJUI0KX2I5q""#), vec![]);
         assert_eq!(parse_etag(r#""tYJT9KJUI  0KX2I5q""#), vec![]);
         assert_eq!(parse_etag(r#""tYJT9KJUI0KX2I5q"+1"#), vec![]);
         assert_eq!(parse_etag(r#"X/"tYJT9KJUI0KX2I5q""#), vec![]);
+        assert_eq!(parse_etag(r#"W/tYJT9KJUI0KX2I5q"#), vec![]);
+        assert_eq!(parse_etag(r#"W/"tYJT9KJUI0KX2I5q"#), vec![]);
     }
 }
@@ -1,22 +1,112 @@
+use std::ffi::OsString;
+use std::io;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::Duration;
+
+use accept_encoding::{Encoding, EncodedPath};
+#[cfg(feature="serde")] use dir_config::DirOverrides;
+use etag::{ContentHashes, EtagProvider, DefaultEtag, ContentOnlyEtag};
+use metrics::Metrics;
+use fd_cache::FdCache;
+use head_cache::{HeadCache, TtlCache};
+use input::type_matches;
+use rules::{self, HeaderRules, RedirectRules};
+
+/// Types besides `text/*` that are text (so have charset) by default
+const EXTENDED_TEXT_TYPES: &[&str] = &[
+    "application/json",
+    "application/manifest+json",
+    "application/xml",
+    "image/svg+xml",
+];
+/// Binary types that are compressible by default
+const EXTENDED_BINARY_TYPES: &[&str] = &[
+    "application/wasm",
+];
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub(crate) enum EncodingSupport {
     Never,
-    TextFiles,
+    Types,
     AllFiles,
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum CharsetPolicy {
+    Ignore,
+    Drop,
+    NotAcceptable,
+}
+
 
 /// A configuration with the builder interface
 #[derive(Clone, Debug)]
 pub struct Config {
     pub(crate) text_charset: Option<String>,
+    pub(crate) charset_types: Vec<String>,
+    pub(crate) charset_policy: CharsetPolicy,
+    pub(crate) sniff_charset: bool,
     pub(crate) index_files: Vec<String>,
+    pub(crate) negotiate_index: bool,
+    pub(crate) negotiate_types: Vec<(String, String)>,
+    pub(crate) extra_methods: Vec<String>,
     pub(crate) encoding_support: EncodingSupport,
+    pub(crate) compressible_types: Vec<String>,
+    pub(crate) brotli_suffix: String,
+    pub(crate) gzip_suffix: String,
+    pub(crate) brotli_min_size: u64,
+    pub(crate) plain_extensions: Vec<String>,
+    pub(crate) plain_paths: Vec<String>,
+    pub(crate) encoded_path: Option<Arc<dyn EncodedPath>>,
+    pub(crate) encoded_inner_type: bool,
+    pub(crate) extended_text_types: bool,
     pub(crate) content_type: bool,
+    pub(crate) content_length_header: bool,
+    pub(crate) content_location: bool,
+    pub(crate) date_header: bool,
     pub(crate) etag: bool,
     pub(crate) last_modified: bool,
+    pub(crate) etag_provider: Arc<dyn EtagProvider>,
+    pub(crate) etag_encoding_suffix: bool,
+    pub(crate) etag_salt: Vec<u8>,
+    pub(crate) strong_etag: Option<Arc<ContentHashes>>,
+    pub(crate) strong_if_range: bool,
+    #[cfg(feature="xattr")]
+    pub(crate) etag_xattr: bool,
+    pub(crate) read_buffer_size: usize,
+    pub(crate) tail_mode: bool,
+    pub(crate) rate_limit: Option<u64>,
+    pub(crate) metrics: Option<Arc<dyn Metrics>>,
+    pub(crate) explain: bool,
+    pub(crate) delegate_header: Option<String>,
+    pub(crate) inline_threshold: Option<u64>,
+    pub(crate) ranges: bool,
+    pub(crate) range_merge_gap: u64,
+    pub(crate) max_ranges: Option<usize>,
+    pub(crate) reject_excess_ranges: bool,
+    pub(crate) ignore_invalid_range: bool,
+    pub(crate) full_range_as_ok: bool,
+    pub(crate) fd_cache: Option<Arc<FdCache>>,
+    pub(crate) head_cache: Option<Arc<HeadCache>>,
+    pub(crate) not_found_cache: Option<Arc<TtlCache<()>>>,
+    /// Canonical root, or the error of resolving it
+    pub(crate) root: Option<Result<PathBuf, io::ErrorKind>>,
+    pub(crate) header_rules: Option<(PathBuf, Arc<HeaderRules>)>,
+    pub(crate) redirect_rules: Option<(PathBuf, Arc<RedirectRules>)>,
+    pub(crate) redirect_status: u16,
+    pub(crate) redirect_query: bool,
+    #[cfg(feature="serde")]
+    pub(crate) dir_overrides: Option<Arc<DirOverrides>>,
+    pub(crate) deny_hidden: bool,
+    pub(crate) denied_extensions: Vec<String>,
+    pub(crate) mime_overrides: Vec<(String, Arc<str>)>,
+    pub(crate) max_path_length: Option<usize>,
+    pub(crate) max_path_components: Option<usize>,
+    /// Depth, number of entries and size of `Input::probe_archive`
+    pub(crate) archive_limits: (usize, usize, u64),
+    #[cfg(feature="memmap")]
+    pub(crate) mmap_threshold: Option<u64>,
 }
 
 impl Config {
@@ -27,16 +117,81 @@ impl Config {
     /// * `text_charset("utf-8")`
     /// * no index files
     /// * `encodings_on_text_files()`
+    /// * `extended_text_types(true)`
     /// * etags, last-modified and conditions based on them are enabled
     /// * content-type is enabled
+    /// * only `GET` and `HEAD` methods are allowed
+    /// * `read_buffer_size(65536)`
     pub fn new() -> Config {
         Config {
             text_charset: Some(String::from("utf-8")),
+            charset_types: vec![
+                String::from("text/*"),
+                String::from("application/javascript"),
+            ],
+            charset_policy: CharsetPolicy::Ignore,
+            sniff_charset: false,
             index_files: Vec::new(),
-            encoding_support: EncodingSupport::TextFiles,
+            negotiate_index: false,
+            negotiate_types: Vec::new(),
+            extra_methods: Vec::new(),
+            encoding_support: EncodingSupport::Types,
+            compressible_types: vec![
+                String::from("text/*"),
+                String::from("application/javascript"),
+            ],
+            extended_text_types: true,
+            brotli_suffix: String::from(".br"),
+            gzip_suffix: String::from(".gz"),
+            brotli_min_size: 0,
+            plain_extensions: Vec::new(),
+            plain_paths: Vec::new(),
+            encoded_path: None,
+            encoded_inner_type: false,
             content_type: true,
+            content_length_header: false,
+            content_location: false,
+            date_header: false,
             etag: true,
             last_modified: true,
+            etag_provider: Arc::new(DefaultEtag),
+            etag_encoding_suffix: false,
+            etag_salt: Vec::new(),
+            strong_etag: None,
+            strong_if_range: false,
+            #[cfg(feature="xattr")]
+            etag_xattr: false,
+            read_buffer_size: 65536,
+            tail_mode: false,
+            rate_limit: None,
+            metrics: None,
+            explain: false,
+            delegate_header: None,
+            inline_threshold: None,
+            ranges: true,
+            range_merge_gap: 0,
+            max_ranges: None,
+            reject_excess_ranges: false,
+            ignore_invalid_range: false,
+            full_range_as_ok: false,
+            fd_cache: None,
+            head_cache: None,
+            not_found_cache: None,
+            root: None,
+            header_rules: None,
+            redirect_rules: None,
+            redirect_status: 301,
+            redirect_query: false,
+            #[cfg(feature="serde")]
+            dir_overrides: None,
+            deny_hidden: false,
+            denied_extensions: Vec::new(),
+            mime_overrides: Vec::new(),
+            max_path_length: None,
+            max_path_components: None,
+            archive_limits: (32, 10000, 4 << 30),
+            #[cfg(feature="memmap")]
+            mmap_threshold: None,
         }
     }
 
@@ -55,6 +210,60 @@ impl Config {
         self
     }
 
+    /// Detect charset of text files instead of always using `text_charset`
+    ///
+    /// The first 4 KiB of the file are checked for the byte order mark
+    /// (UTF-8 or UTF-16), then the content is checked to be valid UTF-8.
+    /// Non-UTF-8 files are reported as `iso-8859-1`, and the configured
+    /// charset is used for ASCII-only files. For encoded variants the
+    /// uncompressed file is checked (and if there is none, the configured
+    /// charset is used). The result is cached with `metadata_cache`.
+    ///
+    /// Disabled by default, as it requires an additional read.
+    pub fn sniff_charset(&mut self, value: bool) -> &mut Self {
+        self.sniff_charset = value;
+        self
+    }
+
+    /// Add charset value only to these mime types
+    ///
+    /// This replaces the default list (`text/*` and
+    /// `application/javascript`), e.g. to exclude `text/csv`. See
+    /// `add_compressible_type` for the syntax of patterns. Note: extended
+    /// text types still get charset unless disabled by
+    /// `extended_text_types(false)`.
+    pub fn charset_on_types(&mut self, patterns: &[&str]) -> &mut Self {
+        self.charset_types = patterns.iter()
+            .map(|x| x.to_ascii_lowercase())
+            .collect();
+        self
+    }
+
+    /// Also add charset value to this mime type
+    pub fn add_charset_type(&mut self, pattern: &str) -> &mut Self {
+        self.charset_types.push(pattern.to_ascii_lowercase());
+        self
+    }
+
+    /// Omit charset of text files if it's unacceptable by `Accept-Charset`
+    ///
+    /// By default `Accept-Charset` is ignored (as most servers do) and
+    /// `text_charset` is always sent.
+    pub fn drop_unacceptable_charset(&mut self) -> &mut Self {
+        self.charset_policy = CharsetPolicy::Drop;
+        self
+    }
+
+    /// Return `Output::NotAcceptable` for text files if charset is
+    /// unacceptable by `Accept-Charset`
+    ///
+    /// This is for strict legacy clients, see also
+    /// `drop_unacceptable_charset`.
+    pub fn reject_unacceptable_charset(&mut self) -> &mut Self {
+        self.charset_policy = CharsetPolicy::NotAcceptable;
+        self
+    }
+
     /// Add a name of the file used as the directory index, like `index.html`
     ///
     /// Multiple names can be added. They are probed in the order in which
@@ -65,6 +274,48 @@ impl Config {
         self
     }
 
+    /// Choose the index file by the `Accept` header
+    ///
+    /// If there are multiple index files (e.g. `index.html` and
+    /// `index.json`), they are probed in the order of quality of their
+    /// media types in `Accept`, files of equal quality are probed in the
+    /// order of `add_index_file`. Response has `Vary: Accept` then. By
+    /// default the order of `add_index_file` is used.
+    pub fn negotiate_index(&mut self, value: bool) -> &mut Self {
+        self.negotiate_index = value;
+        self
+    }
+
+    /// Add a media type for negotiation of extensionless paths
+    ///
+    /// If the path passed to `probe_file` has no extension and doesn't
+    /// exist, files with extensions of media types acceptable by the
+    /// `Accept` header are tried in client preference order, e.g.
+    /// `negotiate("text/html", "html")` and
+    /// `negotiate("application/json", "json")` make `report.json` served
+    /// for `/report`. Types with the same quality are tried in the order
+    /// they are added here. Responses have `Content-Location` (the name
+    /// of the file) and `Vary: Accept` headers.
+    pub fn negotiate(&mut self, media_type: &str, extension: &str)
+        -> &mut Self
+    {
+        let ext = extension.trim_start_matches('.');
+        self.negotiate_types.push((media_type.to_ascii_lowercase(),
+                                   String::from(ext)));
+        self
+    }
+
+    /// Allow serving files on requests with this method (besides `GET`
+    /// and `HEAD`)
+    ///
+    /// The method is treated exactly like `GET`, i.e. the file body is
+    /// returned. This is useful for things like analytics beacons which
+    /// are sent using `POST`. Method names are case-sensitive.
+    pub fn allow_method(&mut self, method: &str) -> &mut Self {
+        self.extra_methods.push(String::from(method));
+        self
+    }
+
     /// Do not search for `.br` and `.gz` files
     pub fn no_encodings(&mut self) -> &mut Self {
         self.encoding_support = EncodingSupport::Never;
@@ -73,10 +324,34 @@ impl Config {
 
     /// Search for `.br` and `.gz` files for text files
     ///
-    /// Text files re those having `text/*` mime type
-    /// or `application/javascript`
+    /// Text files are those matching compressible types, by default
+    /// `text/*` and `application/javascript` (see `add_compressible_type`)
     pub fn encodings_on_text_files(&mut self) -> &mut Self {
-        self.encoding_support = EncodingSupport::TextFiles;
+        self.encoding_support = EncodingSupport::Types;
+        self
+    }
+
+    /// Search for `.br` and `.gz` files only for these mime types
+    ///
+    /// This replaces the default list of compressible types. See
+    /// `add_compressible_type` for the syntax of patterns. Note: extended
+    /// text types are still compressible unless disabled by
+    /// `extended_text_types(false)`.
+    pub fn encodings_on_types(&mut self, patterns: &[&str]) -> &mut Self {
+        self.encoding_support = EncodingSupport::Types;
+        self.compressible_types = patterns.iter()
+            .map(|x| x.to_ascii_lowercase())
+            .collect();
+        self
+    }
+
+    /// Also search for `.br` and `.gz` files for this mime type
+    ///
+    /// Pattern is either an exact type (`application/json`), a type with
+    /// any subtype (`font/*`), a structured syntax suffix (`*+xml`,
+    /// matching `image/svg+xml`), or `*/*`.
+    pub fn add_compressible_type(&mut self, pattern: &str) -> &mut Self {
+        self.compressible_types.push(pattern.to_ascii_lowercase());
         self
     }
 
@@ -85,6 +360,105 @@ impl Config {
         self.encoding_support = EncodingSupport::AllFiles;
         self
     }
+    /// Use `suffix` instead of `.br` or `.gz` for files with this encoding
+    ///
+    /// For example, `encoding_suffix(Encoding::Gzip, ".gzip")` makes
+    /// `file.html.gzip` served for `file.html`. The suffix is appended to
+    /// the whole file name as is.
+    ///
+    /// # Panics
+    ///
+    /// When encoding is `Identity`
+    pub fn encoding_suffix(&mut self, encoding: Encoding, suffix: &str)
+        -> &mut Self
+    {
+        match encoding {
+            Encoding::Brotli => self.brotli_suffix = suffix.into(),
+            Encoding::Gzip => self.gzip_suffix = suffix.into(),
+            _ => panic!("no suffix can be set for {:?}", encoding),
+        }
+        self
+    }
+
+    /// Don't look for brotli variants of files smaller than `bytes`
+    ///
+    /// Brotli gains little over gzip on small files, so for them gzip (or
+    /// the file itself) is served without probing the `.br` file at all.
+    /// The size of the original (not encoded) file is checked. Default is
+    /// `0`, i.e. brotli is preferred for all sizes.
+    pub fn brotli_min_size(&mut self, bytes: u64) -> &mut Self {
+        self.brotli_min_size = bytes;
+        self
+    }
+
+    /// Do not search for `.br` and `.gz` files for this extension
+    ///
+    /// This is useful for already compressed formats (e.g. `png`, `woff2`
+    /// or `mp4`) if their mime type is compressible by the rules above, or
+    /// is unknown when encodings are enabled on all files. Extensions are
+    /// compared case-insensitively, leading dot is optional.
+    pub fn no_encodings_for_extension(&mut self, ext: &str) -> &mut Self {
+        let ext = ext.trim_start_matches('.').to_ascii_lowercase();
+        self.plain_extensions.push(ext);
+        self
+    }
+
+    /// Do not search for `.br` and `.gz` files for paths matching `pattern`
+    ///
+    /// Pattern is matched against the whole path passed to `probe_file`
+    /// (or the index file found), `*` matches any sequence of characters
+    /// including slashes, e.g. `*/media/*` or `*.min.js`. Path components
+    /// are always joined with `/` before matching, so the same pattern
+    /// works with Windows paths.
+    pub fn no_encodings_for_path(&mut self, pattern: &str) -> &mut Self {
+        self.plain_paths.push(pattern.to_string());
+        self
+    }
+
+    /// Derive paths of encoded files using `provider`
+    ///
+    /// This overrides `encoding_suffix`. Paths are derived from the path
+    /// passed to `probe_file` (after index file is found), so they are
+    /// subject to the same checks (e.g. `restrict_to_root`).
+    pub fn encoded_path<P>(&mut self, provider: P) -> &mut Self
+        where P: EncodedPath + 'static
+    {
+        self.encoded_path = Some(Arc::new(provider));
+        self
+    }
+
+    /// Serve direct requests for encoded files with type of the inner file
+    ///
+    /// When enabled, a request for `/bundle.js.gz` is served with
+    /// `Content-Type: application/javascript` and `Content-Encoding: gzip`,
+    /// if the user agent accepts gzip (otherwise it's served as is, i.e.
+    /// as `application/gzip`). Files are recognized by the suffixes set
+    /// with `encoding_suffix`. Such requests bypass `metadata_cache`.
+    ///
+    /// This only applies if the inner file is of compressible type (see
+    /// `encodings_on_types`), so downloads like `backup.tar.gz` or
+    /// `dump.sql.gz` are still served as is.
+    ///
+    /// Either way, no encoded variants of the encoded files (like
+    /// `bundle.js.gz.br`) are looked up. By default it's disabled.
+    pub fn encoded_inner_type(&mut self, value: bool) -> &mut Self {
+        self.encoded_inner_type = value;
+        self
+    }
+
+    /// Toggles treating JSON, XML, SVG, web manifest and wasm as text
+    ///
+    /// When enabled (default), `.br` and `.gz` files are searched for
+    /// `application/json`, `application/manifest+json`, `application/xml`,
+    /// `image/svg+xml` and `application/wasm` (if encodings are enabled for
+    /// text files), and all of them except wasm (which is binary) get the
+    /// `text_charset`. Disable to only treat `text/*` and
+    /// `application/javascript` this way.
+    pub fn extended_text_types(&mut self, value: bool) -> &mut Self {
+        self.extended_text_types = value;
+        self
+    }
+
     /// Togggles generation of Content-Type header (so user can override)
     ///
     /// By default it's enabled
@@ -92,6 +466,38 @@ impl Config {
         self.content_type = value;
         self
     }
+    /// Toggles yielding `Content-Length` by `Head::headers()`
+    ///
+    /// This is useful for frameworks that only have a generic header sink.
+    /// The header is never yielded for `304 Not Modified` responses.
+    ///
+    /// By default it's disabled, use `Head::content_length()`.
+    pub fn content_length_header(&mut self, value: bool) -> &mut Self {
+        self.content_length_header = value;
+        self
+    }
+    /// Toggles `Content-Location` header pointing to the served file
+    ///
+    /// The value is the name of the file that is actually served, e.g.
+    /// `style.css.br` for `style.css`, or `index.html` for a directory
+    /// (so directories are expected to be requested with trailing slash).
+    /// This helps caches and debugging of negotiation.
+    ///
+    /// By default it's disabled (but it's always sent when a file is chosen
+    /// by `negotiate`).
+    pub fn content_location(&mut self, value: bool) -> &mut Self {
+        self.content_location = value;
+        self
+    }
+    /// Toggles generation of the `Date` header
+    ///
+    /// The date is the time when response metadata is made (i.e. in
+    /// `probe_file`). By default it's disabled, as most HTTP servers add
+    /// the header themselves.
+    pub fn date_header(&mut self, value: bool) -> &mut Self {
+        self.date_header = value;
+        self
+    }
     /// Toggles generation of Etag generation (and so `If-None-Match` too)
     ///
     /// By default it's enabled
@@ -100,6 +506,94 @@ impl Config {
         self
     }
 
+    /// Use custom algorithm of making etags (see `EtagProvider`)
+    ///
+    /// By default it's `DefaultEtag`.
+    pub fn etag_provider<P>(&mut self, provider: P) -> &mut Self
+        where P: EtagProvider + 'static
+    {
+        self.etag_provider = Arc::new(provider);
+        self
+    }
+
+    /// Make etags only from size and modification time of the file
+    ///
+    /// By default inode number and creation time are used too, so the
+    /// same file copied to multiple servers has different etags, which
+    /// breaks shared caches. With this option etags are the same as long as
+    /// modification time is preserved (e.g. `rsync --times`). Use it along
+    /// with `strong_etag` to make etags purely from file contents.
+    ///
+    /// This is a shortcut for `etag_provider(ContentOnlyEtag)`.
+    pub fn etag_content_only(&mut self) -> &mut Self {
+        self.etag_provider(ContentOnlyEtag)
+    }
+
+    /// Mix `salt` into every etag
+    ///
+    /// Changing the salt (e.g. to the build number on each deploy)
+    /// invalidates all the etags cached by clients, without touching
+    /// the files. Applies to strong etags too. By default salt is empty.
+    pub fn etag_salt(&mut self, salt: &[u8]) -> &mut Self {
+        self.etag_salt = salt.to_vec();
+        self
+    }
+
+    /// Append encoding to the etag of compressed files, like
+    /// `W/"...-br"` or `W/"...-gz"`
+    ///
+    /// This makes sure that caches never confuse compressed and identity
+    /// representations of the same URL, even if they are served from the
+    /// same file. By default it's disabled.
+    pub fn etag_encoding_suffix(&mut self, value: bool) -> &mut Self {
+        self.etag_encoding_suffix = value;
+        self
+    }
+
+    /// Use strong etag made of the hash of the file contents
+    ///
+    /// File is hashed on the first request, then the hash is kept in memory
+    /// while inode, size and modification time of the file are unchanged.
+    /// Up to `capacity` hashes are kept, the cache is shared between all
+    /// clones of this config.
+    ///
+    /// Strong etags are needed for `If-Range` and resumable downloads to
+    /// work correctly (see `strong_if_range`). Note: large files are read
+    /// twice on the first request (even for `HEAD`). By default weak etags
+    /// made of file metadata are used.
+    pub fn strong_etag(&mut self, capacity: usize) -> &mut Self {
+        self.strong_etag = Some(Arc::new(ContentHashes::new(capacity)));
+        self
+    }
+
+    /// Only honor `Range` if the etag in `If-Range` is strong
+    ///
+    /// RFC 7233 requires strong comparison for `If-Range`, but etags are
+    /// weak unless `strong_etag` is enabled, so this would make range
+    /// requests with an etag always return the whole file. By default weak
+    /// comparison is used, and weak etags are trusted to change with the
+    /// file. A date in `If-Range` must be equal to `Last-Modified` anyway.
+    pub fn strong_if_range(&mut self, value: bool) -> &mut Self {
+        self.strong_if_range = value;
+        self
+    }
+
+    /// Persist hashes for `strong_etag` in the `user.http_etag` extended
+    /// attribute of the file
+    ///
+    /// Hash is only used while size and modification time of the file are
+    /// unchanged, so it survives restarts and is shared by multiple
+    /// processes. Errors of writing the attribute (e.g. on read-only
+    /// filesystems) are ignored.
+    ///
+    /// By default attributes are not used. Only available with the
+    /// `xattr` feature on unix.
+    #[cfg(feature="xattr")]
+    pub fn etag_xattr(&mut self, value: bool) -> &mut Self {
+        self.etag_xattr = value;
+        self
+    }
+
     /// Toggles generation of Last-Modified (and so `If-Modified-Since` too)
     ///
     /// Note: Last-Modified date is never sent if date is earlier than
@@ -109,8 +603,478 @@ impl Config {
         self
     }
 
+    /// Return `Output::NotFound` for paths having components starting with
+    /// a dot (like `.git` or `.env`)
+    ///
+    /// Note: the whole path passed to `probe_file` is checked, so the
+    /// root directory itself must not be hidden. By default hidden files
+    /// are served.
+    pub fn deny_hidden_files(&mut self, value: bool) -> &mut Self {
+        self.deny_hidden = value;
+        self
+    }
+
+    /// Never serve files with this extension (e.g. `php`, `bak` or `sql`)
+    ///
+    /// Such files are reported as `Output::Forbidden`. Extensions are
+    /// compared case-insensitively, leading dot is optional. Multiple
+    /// extensions can be added. Every extension of the file name is
+    /// checked, so `dump.sql.gz` and `index.php.bak` are denied too.
+    pub fn deny_extension(&mut self, ext: &str) -> &mut Self {
+        let ext = ext.trim_start_matches('.').to_ascii_lowercase();
+        self.denied_extensions.push(ext);
+        self
+    }
+
+    /// Use `mime` as the content type of files with extension `ext`
+    ///
+    /// This is consulted before the built-in table, so can be used both to
+    /// fix and to extend type detection (e.g. `mime_override("wasm",
+    /// "application/wasm")`). Extensions are compared case-insensitively,
+    /// leading dot is optional. Later overrides take precedence.
+    ///
+    /// Extension may consist of multiple parts, like `tar.gz` or `js.map`,
+    /// the longest matching suffix of the file name is used.
+    pub fn mime_override(&mut self, ext: &str, mime: &str) -> &mut Self {
+        let ext = ext.trim_start_matches('.').to_ascii_lowercase();
+        self.mime_overrides.insert(0, (ext, mime.into()));
+        self
+    }
+
+    /// Return `Output::NotFound` for paths longer than `bytes`
+    ///
+    /// The whole path passed to `probe_file` is checked (including the
+    /// root directory). Without the limit too long paths fail with an
+    /// OS error. By default there is no limit.
+    pub fn max_path_length(&mut self, bytes: usize) -> &mut Self {
+        self.max_path_length = Some(bytes);
+        self
+    }
+
+    /// Return `Output::NotFound` for paths having more than `num`
+    /// components
+    ///
+    /// Components of the root directory are counted too. By default there
+    /// is no limit.
+    pub fn max_path_components(&mut self, num: usize) -> &mut Self {
+        self.max_path_components = Some(num);
+        self
+    }
+
+    /// Limits of the archives made by `Input::probe_archive`
+    ///
+    /// `depth` is the number of nested directories, `entries` is the
+    /// number of files and directories and `bytes` is the size of the
+    /// archive. The tree is walked when the archive is probed, so this
+    /// also limits the work done in disk thread. Directories exceeding any
+    /// of the limits are reported as `Output::Forbidden`. By default
+    /// it's 32 levels, 10000 entries and 4 GiB.
+    pub fn archive_limits(&mut self, depth: usize, entries: usize,
+        bytes: u64)
+        -> &mut Self
+    {
+        self.archive_limits = (depth, entries, bytes);
+        self
+    }
+
+    /// Maximum number of bytes read by a single `FileWrapper::read_chunk`
+    ///
+    /// Buffers up to 64 KiB (the default) are allocated on stack, larger
+    /// ones are allocated on heap for each chunk.
+    ///
+    /// # Panics
+    ///
+    /// When `bytes` is zero
+    pub fn read_buffer_size(&mut self, bytes: usize) -> &mut Self {
+        assert!(bytes > 0, "read buffer size must be non-zero");
+        self.read_buffer_size = bytes;
+        self
+    }
+
+    /// Limit bandwidth of each response to `bytes_per_second`
+    ///
+    /// `FileWrapper::read_chunk` and `read_into` return error of kind
+    /// `WouldBlock` when the limit is exceeded, use
+    /// `FileWrapper::throttle_delay` to find out when to retry. Up to
+    /// `read_buffer_size` bytes are sent at once. The limit can be changed
+    /// for individual responses using `FileWrapper::set_rate_limit`.
+    ///
+    /// Note: `mapped_chunk` and `sendfile(2)` are not limited (but bytes
+    /// marked as sent count towards the limit), nor are files read
+    /// because of `inline_threshold`. By default bandwidth is not limited.
+    ///
+    /// # Panics
+    ///
+    /// When limit is zero
+    pub fn rate_limit(&mut self, bytes_per_second: u64) -> &mut Self {
+        assert!(bytes_per_second > 0, "rate limit must be non-zero");
+        self.rate_limit = Some(bytes_per_second);
+        self
+    }
+
+    /// Report served files and bytes to the metrics collector
+    ///
+    /// See `Metrics` for the details. By default nothing is reported.
+    pub fn metrics<M>(&mut self, metrics: M) -> &mut Self
+        where M: Metrics + 'static
+    {
+        self.metrics = Some(Arc::new(metrics));
+        self
+    }
+
+    /// Collect `Explanation` of the decisions made while probing files
+    ///
+    /// It's retrieved by `Input::explanation` or `Output::explanation` and
+    /// is meant for debug logging. This is disabled by default, because
+    /// it allocates for each probed path.
+    pub fn explain(&mut self, value: bool) -> &mut Self {
+        self.explain = value;
+        self
+    }
+
+    /// Return error of kind `WouldBlock` when reading a file that ends
+    /// before the response body is complete
+    ///
+    /// This is for files that are still being written, e.g. if the head
+    /// is made by `Head::synthetic` with the final size of the file. The
+    /// read should be retried later, when the file grows (nothing is
+    /// consumed by the failed read). By default the body is considered
+    /// complete at the end of file.
+    pub fn tail_mode(&mut self, value: bool) -> &mut Self {
+        self.tail_mode = value;
+        self
+    }
+
+    /// Let the frontend server (nginx, apache) send the file body
+    ///
+    /// With this option `Output::Delegate` is returned instead of
+    /// `Output::File` and `header` is the name of the header that contains
+    /// the path of the file, like `X-Accel-Redirect` (nginx) or
+    /// `X-Sendfile` (apache). Conditional requests are still handled by
+    /// this library, and response headers for the chosen encoding are
+    /// provided, so only sending bytes is delegated.
+    ///
+    /// Note: nginx expects an URI in `X-Accel-Redirect`, so paths passed
+    /// to `probe_file` should be relative to an `internal` location. Only
+    /// useful with `probe_file` (not virtual filesystems).
+    pub fn delegate(&mut self, header: &str) -> &mut Self {
+        self.delegate_header = Some(header.into());
+        self
+    }
+
+    /// Read files (or ranges) up to `bytes` size immediately
+    ///
+    /// Such files are returned as `Output::FileInMemory`, so there is no
+    /// need to schedule reading in the disk thread again. Something like
+    /// 16 KiB is a good value. Such files are not limited by `rate_limit`.
+    /// Files are never inlined with `tail_mode` enabled.
+    ///
+    /// By default files are never read in `probe_file`.
+    pub fn inline_threshold(&mut self, bytes: u64) -> &mut Self {
+        self.inline_threshold = Some(bytes);
+        self
+    }
+
+    /// Toggles support of `Range` requests
+    ///
+    /// When disabled, `Range` and `If-Range` headers are ignored (so the
+    /// whole file is always sent) and `Accept-Ranges: none` is sent. This
+    /// is useful for files that are rewritten often, where partial
+    /// responses of different versions can be mixed up. Enabled by
+    /// default.
+    pub fn ranges(&mut self, value: bool) -> &mut Self {
+        self.ranges = value;
+        self
+    }
+
+    /// Merge ranges in `Range` header having up to `bytes` between them
+    ///
+    /// Only a single range can be sent, so ranges that can't be merged
+    /// make the header invalid. With this option the bytes between ranges
+    /// are sent too, e.g. `bytes=0-1,100-200` is served as `0-200` with
+    /// the gap of 100 or more. By default only overlapping and adjacent
+    /// ranges are merged.
+    pub fn range_merge_gap(&mut self, bytes: u64) -> &mut Self {
+        self.range_merge_gap = bytes;
+        self
+    }
+
+    /// Limit the number of ranges in the `Range` header
+    ///
+    /// Requests having more ranges are served as if there is no `Range`
+    /// header (i.e. the whole file is sent with 200), unless
+    /// `reject_excess_ranges` is enabled. By default number of ranges is
+    /// not limited.
+    pub fn max_ranges(&mut self, num: usize) -> &mut Self {
+        self.max_ranges = Some(num);
+        self
+    }
+
+    /// Return `Output::InvalidRange` (416) if there are more ranges than
+    /// `max_ranges` instead of sending the whole file
+    pub fn reject_excess_ranges(&mut self, value: bool) -> &mut Self {
+        self.reject_excess_ranges = value;
+        self
+    }
+
+    /// Serve the whole file if `Range` header is invalid
+    ///
+    /// By default `Output::InvalidRange` (416) is returned. Use
+    /// `Input::range_error` to log the reason either way.
+    pub fn ignore_invalid_range(&mut self, value: bool) -> &mut Self {
+        self.ignore_invalid_range = value;
+        self
+    }
+
+    /// Respond with 200 if requested range covers the whole file
+    ///
+    /// I.e. `bytes=0-` is served as a normal response without
+    /// `Content-Range`, which is cached better by some CDNs. By default
+    /// such requests are responded with 206.
+    pub fn full_range_as_ok(&mut self, value: bool) -> &mut Self {
+        self.full_range_as_ok = value;
+        self
+    }
+
+    /// Keep up to `capacity` files open to serve hot files faster
+    ///
+    /// Cached files are revalidated by their size, modification time and
+    /// inode on each request, so this only saves `open` and `fstat` calls.
+    /// The cache is shared between all clones of this config.
+    ///
+    /// By default there is no cache.
+    pub fn fd_cache(&mut self, capacity: usize) -> &mut Self {
+        self.fd_cache = Some(Arc::new(FdCache::new(capacity)));
+        self
+    }
+
+    /// Cache resolved paths and file metadata for `ttl`
+    ///
+    /// This includes looking up index files and encoded variants of the
+    /// file, so responses for `HEAD` and conditional requests on cache
+    /// hits require no system calls at all. For `GET` requests the file is
+    /// still opened and checked to be unchanged. Up to `capacity` paths
+    /// are cached, the cache is shared between all clones of this config.
+    ///
+    /// Note: files changed within `ttl` may be served with stale headers
+    /// on `HEAD` requests.
+    ///
+    /// By default there is no cache.
+    pub fn metadata_cache(&mut self, ttl: Duration, capacity: usize)
+        -> &mut Self
+    {
+        self.head_cache = Some(Arc::new(HeadCache::new(ttl, capacity)));
+        self
+    }
+
+    /// Remember paths that were not found for `ttl`
+    ///
+    /// This is useful for paths that are requested frequently but don't
+    /// exist (`favicon.ico`, vulnerability scanners). Keep `ttl` short,
+    /// because new files are not visible until it expires. Up to
+    /// `capacity` paths are cached, the cache is shared between all clones
+    /// of this config.
+    ///
+    /// By default there is no cache.
+    pub fn not_found_cache(&mut self, ttl: Duration, capacity: usize)
+        -> &mut Self
+    {
+        self.not_found_cache = Some(Arc::new(TtlCache::new(ttl, capacity)));
+        self
+    }
+
+    /// Serve files (or ranges) of `bytes` size or larger using mmap
+    ///
+    /// This avoids copying file contents into a buffer on each
+    /// `read_chunk`, and allows to get the data using
+    /// `FileWrapper::mapped_chunk`. Only the requested range of the file
+    /// is mapped.
+    ///
+    /// # Crashes on truncated files
+    ///
+    /// If a mapped file is truncated (by this or any other process) while
+    /// it's being served, the **whole process is killed by `SIGBUS`**.
+    /// There is no `UnexpectedEof` error in this case, unlike for files
+    /// read into a buffer. Only enable this if every served file is
+    /// replaced atomically (by renaming a new file in place) and never
+    /// written in place, e.g. for a directory of build artifacts. Don't
+    /// use it for user uploads, logs or files shared with other tools.
+    ///
+    /// By default mmap is not used. Only available with the `memmap`
+    /// feature.
+    #[cfg(feature="memmap")]
+    pub fn mmap_threshold(&mut self, bytes: u64) -> &mut Self {
+        self.mmap_threshold = Some(bytes);
+        self
+    }
+
+    /// Merge settings from `file_name` in directories of probed paths
+    ///
+    /// Files are looked up in every directory from `root` to the probed
+    /// path (and in the path itself if it's a directory), and are applied
+    /// on top of this config, inner directories last. The format is TOML,
+    /// but only a few keys of `Config::from_toml_str` are allowed, since
+    /// anyone who can write files into the served tree could otherwise
+    /// disable access checks:
+    ///
+    /// * `index-files` -- replaces the list
+    /// * `etag`, `last-modified` -- validators sent
+    /// * `deny-extensions` -- added to the denied ones
+    /// * `deny-hidden-files` -- can only be enabled (`true`)
+    ///
+    /// Paths are compared to the root textually, so use the same form of
+    /// the path (e.g. both relative) as passed to `probe_file`.
+    ///
+    /// Merged configs are cached and revalidated by the size and
+    /// modification time of override files (on every request, so this
+    /// costs a `stat` per directory). Override files themselves are never
+    /// served. Invalid file is reported as error of kind `InvalidData`.
+    ///
+    /// Only available with the `serde` feature.
+    #[cfg(feature="serde")]
+    pub fn directory_overrides<P: AsRef<Path>>(&mut self, root: P,
+        file_name: &str)
+        -> &mut Self
+    {
+        self.dir_overrides = Some(Arc::new(
+            DirOverrides::new(root.as_ref(), file_name)));
+        self
+    }
+
+    /// Add response headers from the `_headers` file (see `rules` module)
+    ///
+    /// Patterns are matched against the path passed to `probe_file`
+    /// relative to `root` (compared textually, like for
+    /// `directory_overrides`). Headers are added to all outputs having a
+    /// head, including `NotModified`, and are yielded after the ones
+    /// generated by this library (which are never replaced).
+    pub fn header_rules<P: AsRef<Path>>(&mut self, root: P,
+        rules: HeaderRules)
+        -> &mut Self
+    {
+        self.header_rules = Some((root.as_ref().to_path_buf(),
+                                  Arc::new(rules)));
+        self
+    }
+
+    /// Apply redirects and rewrites from the `_redirects` file (see `rules`
+    /// module)
+    ///
+    /// Redirects are checked before the filesystem lookup and return
+    /// `Output::Redirect`. Rewrites are applied only when the file is not
+    /// found (unless forced), the target is resolved relative to `root`.
+    /// Only `GET` and `HEAD` requests are affected.
+    pub fn redirect_rules<P: AsRef<Path>>(&mut self, root: P,
+        rules: RedirectRules)
+        -> &mut Self
+    {
+        self.redirect_rules = Some((root.as_ref().to_path_buf(),
+                                    Arc::new(rules)));
+        self
+    }
+
+    /// Status of redirects that don't specify one in `redirect_rules`
+    ///
+    /// Default is `301`. Permanent redirects are cached by browsers, so
+    /// `302` or `307` are better for redirects that might change. `307`
+    /// and `308` also make clients repeat the method and body.
+    ///
+    /// # Panics
+    ///
+    /// When status is not one of `301`, `302`, `307` or `308`
+    pub fn redirect_status(&mut self, status: u16) -> &mut Self {
+        match status {
+            301 | 302 | 307 | 308 => self.redirect_status = status,
+            _ => panic!("unsupported redirect status {}", status),
+        }
+        self
+    }
+
+    /// Append query string of the request to redirect targets
+    ///
+    /// The query is set by `Input::query` (`Input::from_request_parts`
+    /// does it automatically if this is enabled). If the target has a
+    /// query of its own, the request query is appended after `&`. By
+    /// default the query is dropped.
+    pub fn redirect_query(&mut self, value: bool) -> &mut Self {
+        self.redirect_query = value;
+        self
+    }
+
+    /// Only serve files that are located within `root` directory
+    ///
+    /// Real path of every file is checked after resolving symlinks, and
+    /// `Output::Forbidden` is returned if symlink points outside of the
+    /// root. Note: this costs few more system calls per request and is
+    /// prone to races if symlinks are changed concurrently.
+    ///
+    /// Only applies to filesystems with symlinks (i.e. `probe_file`).
+    ///
+    /// The root itself is resolved here. If that fails (e.g. directory
+    /// doesn't exist), `ConfigBuilder::build` returns an error, and so
+    /// does probing any file with this config.
+    pub fn restrict_to_root<P: AsRef<Path>>(&mut self, root: P) -> &mut Self {
+        self.root = Some(root.as_ref().canonicalize().map_err(|e| e.kind()));
+        self
+    }
+
     /// Finalize configuration and wrap into an Arc
     pub fn done(&self) -> Arc<Config> {
         Arc::new(self.clone())
     }
+
+    /// Returns true if `text_charset` should be added to this mime type
+    pub(crate) fn has_charset(&self, mime: &str) -> bool {
+        self.charset_types.iter().any(|x| type_matches(x, mime)) ||
+            self.extended_text_types && EXTENDED_TEXT_TYPES.contains(&mime)
+    }
+
+    /// Returns the path of the file variant with the specified encoding
+    pub(crate) fn variant_path(&self, path: &Path, encoding: Encoding)
+        -> PathBuf
+    {
+        if encoding == Encoding::Identity {
+            return path.to_path_buf();
+        }
+        if let Some(ref provider) = self.encoded_path {
+            return provider.encoded_path(path, encoding);
+        }
+        let suffix = match encoding {
+            Encoding::Brotli => &self.brotli_suffix,
+            Encoding::Gzip => &self.gzip_suffix,
+            Encoding::Identity | Encoding::__Nonexhaustive => unreachable!(),
+        };
+        let mut buf = OsString::with_capacity(
+            path.as_os_str().len() + suffix.len());
+        buf.push(path);
+        buf.push(suffix);
+        buf.into()
+    }
+
+    /// Returns true if encodings are disabled for the path
+    pub(crate) fn is_plain(&self, path: &Path) -> bool {
+        if let Some(ext) = path.extension().and_then(|x| x.to_str()) {
+            if self.plain_extensions.iter()
+                .any(|x| x.eq_ignore_ascii_case(ext))
+            {
+                return true;
+            }
+        }
+        if self.plain_paths.is_empty() {
+            return false;
+        }
+        match rules::slash_path(path) {
+            Some(path) => self.plain_paths.iter()
+                .any(|pattern| rules::matches(pattern, &path)),
+            None => false,
+        }
+    }
+
+    /// Returns true if `.br` and `.gz` files should be searched for
+    pub(crate) fn is_compressible(&self, mime: &str) -> bool {
+        self.compressible_types.iter().any(|x| type_matches(x, mime)) ||
+            self.extended_text_types &&
+            (EXTENDED_TEXT_TYPES.contains(&mime) ||
+             EXTENDED_BINARY_TYPES.contains(&mime))
+    }
 }
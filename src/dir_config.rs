@@ -0,0 +1,227 @@
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use serde::Deserialize;
+use toml;
+
+use config::Config;
+use vfs::{FileSystem, ReadAt};
+
+/// Override files larger than this are rejected
+const MAX_FILE_SIZE: u64 = 65536;
+
+
+/// Per-directory overrides (see `Config::directory_overrides`)
+#[derive(Debug)]
+pub(crate) struct DirOverrides {
+    root: PathBuf,
+    file_name: String,
+    cache: Mutex<HashMap<PathBuf, Merged>>,
+}
+
+/// Config merged from override files, which are valid while `stamps`
+/// match
+#[derive(Debug)]
+struct Merged {
+    stamps: Vec<Stamp>,
+    config: Arc<Config>,
+}
+
+/// Path, size and modification time of an override file
+type Stamp = (PathBuf, u64, Option<SystemTime>);
+
+/// Options allowed in override files
+///
+/// This is deliberately a small subset of `Settings`: anyone who can put
+/// a file into the served tree can write an override file, so it must
+/// not be able to relax access checks (`restrict-to-root`, `delegate`,
+/// etc. are rejected as unknown fields). Denies only add to the ones
+/// of the parent config.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all="kebab-case")]
+struct DirSettings {
+    /// Replace index files
+    index_files: Option<Vec<String>>,
+    etag: Option<bool>,
+    last_modified: Option<bool>,
+    /// Added to the denied extensions of the parent
+    deny_extensions: Vec<String>,
+    /// Can only be enabled
+    deny_hidden_files: Option<bool>,
+}
+
+impl DirOverrides {
+    pub fn new(root: &Path, file_name: &str) -> DirOverrides {
+        DirOverrides {
+            root: root.to_path_buf(),
+            file_name: file_name.to_string(),
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+    /// Returns true if path refers to an override file (never served)
+    pub fn is_override_file(&self, path: &Path) -> bool {
+        path.file_name().map(|x| x == &self.file_name[..]).unwrap_or(false)
+    }
+    /// Returns config for the path if there are any override files in the
+    /// directories from the root to the path (inclusive)
+    pub fn config_for<FS: FileSystem>(&self, fs: &FS, base: &Arc<Config>,
+        path: &Path)
+        -> io::Result<Option<Arc<Config>>>
+    {
+        let rel = match path.strip_prefix(&self.root) {
+            Ok(rel) => rel,
+            Err(_) => return Ok(None),
+        };
+        let mut dirs = vec![self.root.clone()];
+        for cmp in rel.parent().into_iter().flat_map(|x| x.components()) {
+            let next = dirs[dirs.len()-1].join(cmp);
+            dirs.push(next);
+        }
+        if rel.file_name().is_some() {
+            match fs.metadata(path) {
+                Ok(ref m) if m.is_dir() => dirs.push(path.to_path_buf()),
+                _ => {}
+            }
+        }
+        let mut stamps = Vec::new();
+        for dir in dirs {
+            let file = dir.join(&self.file_name);
+            match fs.metadata(&file) {
+                Ok(ref m) if m.is_file() => {
+                    stamps.push((file, m.len(), m.modified()));
+                }
+                Ok(_) => {}
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+        }
+        let key = match stamps.last() {
+            Some((path, _, _)) => path.clone(),
+            None => return Ok(None),
+        };
+        let mut cache = self.cache.lock().expect("cache is not poisoned");
+        if let Some(merged) = cache.get(&key) {
+            if merged.stamps == stamps {
+                return Ok(Some(merged.config.clone()));
+            }
+        }
+        let mut config = (**base).clone();
+        for (file, _, _) in &stamps {
+            read_settings(fs, file)?.apply(&mut config);
+        }
+        let config = Arc::new(config);
+        cache.insert(key, Merged { stamps, config: config.clone() });
+        Ok(Some(config))
+    }
+}
+
+impl DirSettings {
+    fn apply(&self, cfg: &mut Config) {
+        if let Some(ref x) = self.index_files {
+            cfg.index_files.clear();
+            for name in x {
+                cfg.add_index_file(name);
+            }
+        }
+        if let Some(x) = self.etag { cfg.etag(x); }
+        if let Some(x) = self.last_modified { cfg.last_modified(x); }
+        for ext in &self.deny_extensions {
+            cfg.deny_extension(ext);
+        }
+        if let Some(true) = self.deny_hidden_files {
+            cfg.deny_hidden_files(true);
+        }
+    }
+}
+
+fn read_settings<FS: FileSystem>(fs: &FS, path: &Path)
+    -> io::Result<DirSettings>
+{
+    let (file, meta) = fs.open(path)?;
+    if meta.len() > MAX_FILE_SIZE {
+        return Err(io::Error::new(io::ErrorKind::InvalidData,
+            "directory override file is too large"));
+    }
+    let mut buf = vec![0u8; meta.len() as usize];
+    let mut pos = 0;
+    while pos < buf.len() {
+        match file.read_at(&mut buf[pos..], pos as u64)? {
+            0 => break,
+            n => pos += n,
+        }
+    }
+    buf.truncate(pos);
+    let text = String::from_utf8(buf)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidData,
+        format!("invalid {}: {}", path.display(), msg));
+    let settings: DirSettings = toml::from_str(&text)
+        .map_err(|e| invalid(e.to_string()))?;
+    if settings.deny_hidden_files == Some(false) {
+        return Err(invalid("`deny-hidden-files` can't be disabled".into()));
+    }
+    Ok(settings)
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::write;
+    use config::Config;
+    use input::Input;
+    use output::Output;
+    use test_util::TempDir;
+
+    #[test]
+    fn overrides() {
+        let root = TempDir::new("dir-overrides");
+        let sub = root.join("sub");
+        root.write("a.txt", "hello");
+        root.write("sub/b.txt", "hello");
+        root.write("sub/home.html", "<p>hello</p>");
+        root.write(".headers.toml", "index-files = [\"home.html\"]");
+        root.write("sub/.headers.toml", "deny-extensions = [\"txt\"]");
+        let cfg = Config::new()
+            .directory_overrides(root.path(), ".headers.toml")
+            .done();
+        let inp = Input::from_headers(&cfg, "HEAD", vec![].into_iter());
+        let probe = |path| inp.probe_file(path).unwrap().suggested_status();
+        assert_eq!(probe(root.join("a.txt")), 200);
+        assert_eq!(probe(sub.join("b.txt")), 403);
+        assert_eq!(probe(sub.join(".headers.toml")), 404);
+        // index file is inherited from the parent directory
+        match inp.probe_file(&sub).unwrap() {
+            Output::FileHead(head) => assert_eq!(head.content_length(), 12),
+            _ => unreachable!(),
+        }
+
+        write(sub.join(".headers.toml"), "deny-extensions = []").unwrap();
+        assert_eq!(probe(sub.join("b.txt")), 200);
+
+        write(sub.join(".headers.toml"), "deny_extensions = 1").unwrap();
+        assert!(inp.probe_file(sub.join("b.txt")).is_err());
+
+        // override files can't relax checks of the parent config
+        let cfg = Config::new().deny_extension("txt")
+            .directory_overrides(root.path(), ".headers.toml")
+            .done();
+        let inp = Input::from_headers(&cfg, "HEAD", vec![].into_iter());
+        let probe = |path| inp.probe_file(path).unwrap().suggested_status();
+        write(sub.join(".headers.toml"), "deny-extensions = []").unwrap();
+        assert_eq!(probe(sub.join("b.txt")), 403);
+        for text in &["restrict-to-root = \"/\"", "delegate = \"X-Sendfile\"",
+                      "deny-hidden-files = false", "rate-limit = 1"]
+        {
+            write(sub.join(".headers.toml"), text).unwrap();
+            assert!(inp.probe_file(sub.join("home.html")).is_err());
+        }
+
+        // unaffected by override files
+        let cfg = Config::new().done();
+        let inp = Input::from_headers(&cfg, "HEAD", vec![].into_iter());
+        assert_eq!(inp.probe_file(sub.join(".headers.toml")).unwrap()
+                   .suggested_status(), 200);
+    }
+}
@@ -0,0 +1,203 @@
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::panic::{catch_unwind, resume_unwind, AssertUnwindSafe};
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Sender, Receiver};
+use std::task::{Context, Poll, Waker};
+use std::thread;
+
+use input::Input;
+use output::{Output, FileWrapper};
+use vfs::ReadAt;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+
+/// A small thread pool for doing disk operations
+///
+/// Probing files and reading chunks should not be done in the event
+/// loop thread. This pool is the simplest way to offload them. Threads
+/// exit when the pool is dropped and all pending jobs are finished.
+#[derive(Debug)]
+pub struct DiskPool {
+    tx: Mutex<Sender<Job>>,
+}
+
+/// Result of the operation scheduled on `DiskPool`
+///
+/// If operation panics, the panic is propagated when future is polled.
+pub struct DiskFuture<T> {
+    shared: Arc<Mutex<Shared<T>>>,
+}
+
+struct Shared<T> {
+    result: Option<thread::Result<T>>,
+    waker: Option<Waker>,
+}
+
+impl DiskPool {
+    /// Create a pool with specified number of threads
+    ///
+    /// # Panics
+    ///
+    /// When `threads` is zero (jobs would never finish)
+    pub fn new(threads: usize) -> Arc<DiskPool> {
+        assert!(threads > 0, "disk pool needs at least one thread");
+        let (tx, rx) = channel::<Job>();
+        let rx = Arc::new(Mutex::new(rx));
+        for idx in 0..threads {
+            let rx = rx.clone();
+            thread::Builder::new()
+                .name(format!("disk-pool-{}", idx))
+                .spawn(move || worker(&rx))
+                .expect("can spawn disk thread");
+        }
+        Arc::new(DiskPool {
+            tx: Mutex::new(tx),
+        })
+    }
+    /// Run arbitrary function in the pool
+    pub fn spawn<F, T>(&self, f: F) -> DiskFuture<T>
+        where F: FnOnce() -> T + Send + 'static,
+              T: Send + 'static,
+    {
+        let shared = Arc::new(Mutex::new(Shared {
+            result: None,
+            waker: None,
+        }));
+        let job_shared = shared.clone();
+        let job: Job = Box::new(move || {
+            let result = catch_unwind(AssertUnwindSafe(f));
+            let mut shared = job_shared.lock().expect("disk future lock");
+            shared.result = Some(result);
+            if let Some(waker) = shared.waker.take() {
+                waker.wake();
+            }
+        });
+        self.tx.lock().expect("disk pool lock")
+            .send(job).expect("disk threads are running");
+        DiskFuture { shared }
+    }
+    /// Run `Input::probe_file` in the pool
+    pub fn probe<P>(&self, input: Input, path: P)
+        -> DiskFuture<io::Result<Output>>
+        where P: AsRef<Path> + Send + 'static,
+    {
+        self.spawn(move || input.probe_file(path))
+    }
+    /// Read next chunk of the file in the pool
+    ///
+    /// Returns the chunk and the wrapper to read the next chunk from.
+    /// Empty chunk means the whole file is read.
+    pub fn read_chunk<F>(&self, mut wrapper: FileWrapper<F>)
+        -> DiskFuture<io::Result<(Vec<u8>, FileWrapper<F>)>>
+        where F: ReadAt + Send + 'static,
+    {
+        self.spawn(move || {
+            let mut buf = Vec::new();
+            wrapper.read_chunk(&mut buf)?;
+            Ok((buf, wrapper))
+        })
+    }
+}
+
+fn worker(rx: &Mutex<Receiver<Job>>) {
+    loop {
+        let job = match rx.lock() {
+            Ok(rx) => match rx.recv() {
+                Ok(job) => job,
+                Err(_) => return,  // pool is dropped
+            },
+            Err(_) => return,
+        };
+        job();
+    }
+}
+
+impl<T> Future for DiskFuture<T> {
+    type Output = T;
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<T> {
+        let mut shared = self.shared.lock().expect("disk future lock");
+        match shared.result.take() {
+            Some(Ok(value)) => Poll::Ready(value),
+            Some(Err(panic)) => resume_unwind(panic),
+            None => {
+                shared.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl<T> fmt::Debug for DiskFuture<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("DiskFuture").finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::task::Wake;
+    use std::thread::{self, Thread};
+    use config::Config;
+    use super::*;
+
+    struct Unpark(Thread);
+
+    impl Wake for Unpark {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    fn block_on<F: Future>(mut fut: F) -> F::Output {
+        let waker = Waker::from(Arc::new(Unpark(thread::current())));
+        let mut cx = Context::from_waker(&waker);
+        let mut fut = unsafe { Pin::new_unchecked(&mut fut) };
+        loop {
+            match fut.as_mut().poll(&mut cx) {
+                Poll::Ready(x) => return x,
+                Poll::Pending => thread::park(),
+            }
+        }
+    }
+
+    #[test]
+    fn probe_and_read() {
+        let pool = DiskPool::new(2);
+        let cfg = Config::new().done();
+        let inp = Input::from_headers(&cfg, "GET", vec![].into_iter());
+        let mut wrapper = match block_on(pool.probe(inp, "public/index.html"))
+        {
+            Ok(Output::File(wrapper)) => wrapper,
+            _ => unreachable!(),
+        };
+        let mut body = Vec::new();
+        loop {
+            let (chunk, w) = block_on(pool.read_chunk(wrapper)).unwrap();
+            if chunk.is_empty() {
+                break;
+            }
+            body.extend(chunk);
+            wrapper = w;
+        }
+        assert_eq!(body.len(), 126);
+    }
+
+    #[test]
+    #[should_panic(expected="job failed")]
+    fn panic() {
+        let pool = DiskPool::new(1);
+        block_on(pool.spawn(|| panic!("job failed")));
+    }
+
+    #[test]
+    #[should_panic(expected="at least one thread")]
+    fn no_threads() {
+        DiskPool::new(0);
+    }
+}
@@ -0,0 +1,179 @@
+use std::cmp::min;
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsString;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use vfs::{FileSystem, Metadata, ReadAt, normalize, list_dir};
+
+
+/// Filesystem of files embedded into the binary
+///
+/// Files are usually embedded with `include_bytes!` or by a generated
+/// module. Precompressed variants are just files with `.gz` or `.br`
+/// suffix, the same as on disk. Directories are implied by file paths.
+///
+/// Leading slash and `.` components are ignored in paths, so both
+/// `"/css/main.css"` and `"css/main.css"` refer to the same file.
+///
+/// Etags are calculated from the content of the files, so they are stable
+/// between builds if file is unchanged.
+#[derive(Debug)]
+pub struct EmbeddedFs {
+    files: HashMap<PathBuf, (&'static [u8], u64)>,
+    dirs: HashSet<PathBuf>,
+    modified: Option<SystemTime>,
+}
+
+/// A file opened from `EmbeddedFs`
+#[derive(Debug, Clone, Copy)]
+pub struct EmbeddedFile(&'static [u8]);
+
+impl EmbeddedFs {
+    /// Create filesystem from a list of `(path, data)` pairs
+    pub fn new(files: &[(&'static str, &'static [u8])]) -> EmbeddedFs {
+        let mut fs = EmbeddedFs {
+            files: HashMap::new(),
+            dirs: HashSet::new(),
+            modified: None,
+        };
+        fs.dirs.insert(PathBuf::new());
+        for &(path, data) in files {
+            let path = match normalize(Path::new(path)) {
+                Some(path) => path,
+                None => continue,
+            };
+            let mut dir = path.clone();
+            while dir.pop() {
+                fs.dirs.insert(dir.clone());
+            }
+            fs.files.insert(path, (data, checksum(data)));
+        }
+        fs
+    }
+    /// Set modification time reported for all the files
+    ///
+    /// By default there is no modification time, so `Last-Modified` header
+    /// is not sent. Usually you want to put a build time here.
+    pub fn modified(&mut self, time: SystemTime) -> &mut Self {
+        self.modified = Some(time);
+        self
+    }
+}
+
+/// FNV-1a hash of the data, used as a version of the file for etags
+fn checksum(data: &[u8]) -> u64 {
+    let mut hash = 0xcbf29ce484222325u64;
+    for &b in data {
+        hash ^= b as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+fn not_found() -> io::Error {
+    io::ErrorKind::NotFound.into()
+}
+
+impl FileSystem for EmbeddedFs {
+    type File = EmbeddedFile;
+    fn metadata(&self, path: &Path) -> io::Result<Metadata> {
+        let path = normalize(path).ok_or_else(not_found)?;
+        if let Some(&(data, version)) = self.files.get(&path) {
+            Ok(Metadata::file(data.len() as u64, self.modified)
+                .with_version(version))
+        } else if self.dirs.contains(&path) {
+            Ok(Metadata::directory())
+        } else {
+            Err(not_found())
+        }
+    }
+    fn open(&self, path: &Path) -> io::Result<(EmbeddedFile, Metadata)> {
+        let meta = self.metadata(path)?;
+        let norm = normalize(path).ok_or_else(not_found)?;
+        match self.files.get(&norm) {
+            Some(&(data, _)) => Ok((EmbeddedFile(data), meta)),
+            None => Err(io::ErrorKind::PermissionDenied.into()),
+        }
+    }
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<OsString>> {
+        let path = normalize(path).ok_or_else(not_found)?;
+        if !self.dirs.contains(&path) {
+            return Err(not_found());
+        }
+        Ok(list_dir(&path, self.files.keys().chain(self.dirs.iter())))
+    }
+}
+
+impl EmbeddedFile {
+    /// Returns the whole content of the file
+    pub fn data(&self) -> &'static [u8] {
+        self.0
+    }
+}
+
+impl ReadAt for EmbeddedFile {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        let start = min(offset, self.0.len() as u64) as usize;
+        let data = &self.0[start..];
+        let n = min(data.len(), buf.len());
+        buf[..n].copy_from_slice(&data[..n]);
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use config::Config;
+    use input::Input;
+    use output::Output;
+    use super::*;
+
+    static FILES: &[(&str, &[u8])] = &[
+        ("/index.html", b"<html></html>"),
+        ("/index.html.gz", b"fake gzip"),
+        ("/css/main.css", b"body {}"),
+    ];
+
+    fn get(cfg: &Arc<Config>, path: &str) -> Output<EmbeddedFile> {
+        let inp = Input::from_headers(cfg, "GET",
+            vec![("Accept-Encoding", &b"gzip"[..])].into_iter());
+        inp.probe_file_in(&EmbeddedFs::new(FILES), path).unwrap()
+    }
+
+    #[test]
+    fn serve() {
+        let cfg = Config::new()
+            .add_index_file("index.html")
+            .encodings_on_text_files()
+            .done();
+        match get(&cfg, "/") {
+            Output::File(mut wrapper) => {
+                assert_eq!(wrapper.read_all(100).unwrap(), b"fake gzip");
+                assert!(wrapper.headers()
+                    .any(|(name, _)| name == "Content-Encoding"));
+                assert!(wrapper.headers().any(|(name, _)| name == "ETag"));
+            }
+            _ => unreachable!(),
+        }
+        match get(&cfg, "css") {
+            Output::Directory => {}
+            _ => unreachable!(),
+        }
+        match get(&cfg, "/css/../index.html") {
+            Output::NotFound => {}
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn read_dir() {
+        let fs = EmbeddedFs::new(FILES);
+        assert_eq!(fs.read_dir(Path::new("/")).unwrap(),
+            vec!["css", "index.html", "index.html.gz"]);
+        assert_eq!(fs.read_dir(Path::new("/css")).unwrap(),
+            vec!["main.css"]);
+    }
+}
@@ -1,63 +1,334 @@
-use std::io::Write;
-use std::fs::Metadata;
+use std::collections::HashMap;
+use std::ffi::OsStr;
 use std::fmt;
+use std::io::{self, Write};
 use std::time::{Duration, UNIX_EPOCH};
 use std::str::from_utf8_unchecked;
+use std::sync::Mutex;
 
 use blake2::{Blake2b, Digest, digest::VariableOutput};
 use digest_writer::Writer;
 use byteorder::{WriteBytesExt, BigEndian};
 
+use accept_encoding::Encoding;
+use vfs::{Metadata, ReadAt};
 
+/// Size of the buffer used for hashing file contents
+const HASH_BUFFER: usize = 65536;
+
+/// Extended attribute used to persist content hashes
+#[cfg(all(unix, feature="xattr"))]
+const XATTR_NAME: &str = "user.http_etag";
+
+
+/// Entity tag of the file
+///
+/// Use `to_string()` to get the value for the `ETag` header.
 #[derive(Clone, PartialEq, Eq)]
-pub struct Etag(pub(crate) [u8; 12]);
+pub struct Etag {
+    pub(crate) value: [u8; 12],
+    pub(crate) strong: bool,
+    /// Encoding suffix of the tag (identity means no suffix)
+    pub(crate) encoding: Encoding,
+}
+
+/// Algorithm of making etags out of file metadata
+///
+/// Set it using `Config::etag_provider`. Note: etags made by the provider
+/// are weak, `Config::strong_etag` hashes file contents independently.
+pub trait EtagProvider: fmt::Debug + Send + Sync {
+    /// Returns the value of the etag for the file
+    ///
+    /// The value must change whenever the file changes, and it should not
+    /// be the same for different files. Returned bytes are formatted
+    /// using base64.
+    fn etag(&self, metadata: &Metadata) -> [u8; 12];
+}
+
+/// Etag provider that hashes size, modification and creation time, and
+/// inode number (on unix) of the file using blake2b
+///
+/// This is the default one.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DefaultEtag;
+
+/// Etag provider that hashes only size and modification time of the file
+///
+/// Unlike `DefaultEtag` it doesn't use inode numbers and creation time, so
+/// the same file deployed to multiple servers (with modification time
+/// preserved) has the same etag everywhere.
+///
+/// See `Config::etag_content_only`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ContentOnlyEtag;
+
+/// Hashes of file contents keyed by the metadata-derived etag
+///
+/// Keys are made by `DefaultEtag`, which includes inode, size and
+/// modification time, so entries never need to be invalidated, only
+/// evicted.
+#[derive(Debug)]
+pub struct ContentHashes {
+    capacity: usize,
+    entries: Mutex<HashMap<[u8; 12], [u8; 12]>>,
+}
 
 
 impl Etag {
-    pub fn from_metadata(metadata: &Metadata) -> Etag {
-        let mut wr = Writer::new(<Blake2b as VariableOutput>::new(12)
-            .expect("blake2b supports 12 bytes"));
-        wr.write_u64::<BigEndian>(metadata.len()).unwrap();
-        let fmod = metadata.modified().ok()
-            .and_then(|x| x.duration_since(UNIX_EPOCH).ok())
-            .unwrap_or(Duration::new(0, 0));
-        wr.write_u64::<BigEndian>(fmod.as_secs()).unwrap();
-        wr.write_u32::<BigEndian>(fmod.subsec_nanos()).unwrap();
-        let fcreated = metadata.created().ok()
-            .and_then(|x| x.duration_since(UNIX_EPOCH).ok())
-            .unwrap_or(Duration::new(0, 0));
-        wr.write_u64::<BigEndian>(fcreated.as_secs()).unwrap();
-        wr.write_u32::<BigEndian>(fcreated.subsec_nanos()).unwrap();
-        extra(&mut wr, metadata);
-        let digest = wr.into_inner();
-        let mut value = [0u8; 12];
-        digest.variable_result(&mut value[..]);
-        return Etag(value);
+    /// Make a weak etag out of the raw value
+    pub fn weak(value: [u8; 12]) -> Etag {
+        Etag { value, strong: false, encoding: Encoding::Identity }
+    }
+    /// Make a strong etag out of the raw value
+    pub fn strong(value: [u8; 12]) -> Etag {
+        Etag { value, strong: true, encoding: Encoding::Identity }
+    }
+    /// Make a strong etag by hashing the data in memory
+    ///
+    /// This is useful for generated content (see `Head::synthetic`).
+    pub fn from_data(data: &[u8]) -> Etag {
+        Etag::strong(digest12(|wr| {
+            wr.write_u64::<BigEndian>(data.len() as u64)?;
+            wr.write_all(data)
+        }).expect("hashing data in memory"))
+    }
+    /// Makes weak etag of the directory listing
+    ///
+    /// Hashes metadata of the directory (as `DefaultEtag` does) and names
+    /// of the entries, regardless of their order.
+    pub(crate) fn from_listing(metadata: &Metadata, entries: &[&OsStr])
+        -> Etag
+    {
+        let mut names = entries.iter()
+            .map(|x| x.to_string_lossy())
+            .collect::<Vec<_>>();
+        names.sort();
+        Etag::weak(digest12(|wr| {
+            wr.write_all(&DefaultEtag.etag(metadata))?;
+            for name in &names {
+                wr.write_u64::<BigEndian>(name.len() as u64)?;
+                wr.write_all(name.as_bytes())?;
+            }
+            Ok(())
+        }).expect("hashing data in memory"))
+    }
+    /// Returns true if this is a weak etag (`W/"..."`)
+    pub fn is_weak(&self) -> bool {
+        !self.strong
     }
-    pub(crate) fn decode_base64(slice: &[u8]) -> Result<Etag, ()> {
-        debug_assert!(slice.len() == 16);
+    /// Mixes `salt` into the value of the etag (if salt is not empty)
+    pub(crate) fn salted(self, salt: &[u8]) -> Etag {
+        if salt.is_empty() {
+            return self;
+        }
+        let value = digest12(|wr| {
+            wr.write_all(&self.value)?;
+            wr.write_all(salt)
+        }).expect("hashing data in memory");
+        Etag { value, .. self }
+    }
+    /// Returns the same etag with the suffix of the encoding
+    pub(crate) fn with_encoding(self, encoding: Encoding) -> Etag {
+        Etag { encoding, .. self }
+    }
+    /// Weak comparison (RFC 7232), used for `If-None-Match`
+    pub(crate) fn weak_eq(&self, other: &Etag) -> bool {
+        self.value == other.value && self.encoding == other.encoding
+    }
+    /// Strong comparison (RFC 7232), used for `If-Match`
+    pub(crate) fn strong_eq(&self, other: &Etag) -> bool {
+        self.strong && other.strong && self.weak_eq(other)
+    }
+    /// Makes strong etag by hashing the whole file (up to `len` bytes)
+    pub(crate) fn from_content<F: ReadAt>(file: &F, len: u64)
+        -> io::Result<Etag>
+    {
+        digest12(|wr| {
+            wr.write_u64::<BigEndian>(len)?;
+            let mut buf = vec![0u8; HASH_BUFFER];
+            let mut offset = 0;
+            while offset < len {
+                let max = ::std::cmp::min(len - offset, buf.len() as u64);
+                match file.read_at(&mut buf[..max as usize], offset)? {
+                    0 => return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "file is truncated while hashing")),
+                    bytes => {
+                        wr.write_all(&buf[..bytes])?;
+                        offset += bytes as u64;
+                    }
+                }
+            }
+            Ok(())
+        }).map(Etag::strong)
+    }
+    /// Decodes the tag (without quotes), optionally having encoding suffix
+    pub(crate) fn decode_base64(slice: &[u8], strong: bool)
+        -> Result<Etag, ()>
+    {
+        let encoding = match slice.len() {
+            16 => Encoding::Identity,
+            19 if &slice[16..] == b"-br" => Encoding::Brotli,
+            19 if &slice[16..] == b"-gz" => Encoding::Gzip,
+            _ => return Err(()),
+        };
         let mut value = [0u8; 12];
         decode4(&slice[..4], &mut value[..3])?;
         decode4(&slice[4..8], &mut value[3..6])?;
         decode4(&slice[8..12], &mut value[6..9])?;
-        decode4(&slice[12..], &mut value[9..])?;
-        Ok(Etag(value))
+        decode4(&slice[12..16], &mut value[9..])?;
+        Ok(Etag { value, strong, encoding })
+    }
+}
+
+impl EtagProvider for DefaultEtag {
+    fn etag(&self, metadata: &Metadata) -> [u8; 12] {
+        digest12(|wr| {
+            wr.write_u64::<BigEndian>(metadata.len())?;
+            let fmod = metadata.modified()
+                .and_then(|x| x.duration_since(UNIX_EPOCH).ok())
+                .unwrap_or(Duration::new(0, 0));
+            wr.write_u64::<BigEndian>(fmod.as_secs())?;
+            wr.write_u32::<BigEndian>(fmod.subsec_nanos())?;
+            let fcreated = metadata.created()
+                .and_then(|x| x.duration_since(UNIX_EPOCH).ok())
+                .unwrap_or(Duration::new(0, 0));
+            wr.write_u64::<BigEndian>(fcreated.as_secs())?;
+            wr.write_u32::<BigEndian>(fcreated.subsec_nanos())?;
+            if let Some(extra) = metadata.extra() {
+                for &x in extra {
+                    wr.write_u64::<BigEndian>(x)?;
+                }
+            }
+            Ok(())
+        }).expect("hashing data in memory")
     }
 }
 
-#[cfg(unix)]
-fn extra<W: Write>(wr: &mut W, metadata: &Metadata) {
-    use std::os::unix::fs::MetadataExt;
-    // sometimes last_modified date is not reliable
-    // so we use inode number and `ctime` date on unix systems too
-    wr.write_u64::<BigEndian>(metadata.dev()).unwrap();
-    wr.write_u64::<BigEndian>(metadata.ino()).unwrap();
-    wr.write_i64::<BigEndian>(metadata.ctime()).unwrap();
-    wr.write_i64::<BigEndian>(metadata.ctime_nsec()).unwrap();
+impl EtagProvider for ContentOnlyEtag {
+    fn etag(&self, metadata: &Metadata) -> [u8; 12] {
+        digest12(|wr| {
+            wr.write_u64::<BigEndian>(metadata.len())?;
+            let fmod = metadata.modified()
+                .and_then(|x| x.duration_since(UNIX_EPOCH).ok())
+                .unwrap_or(Duration::new(0, 0));
+            wr.write_u64::<BigEndian>(fmod.as_secs())?;
+            wr.write_u32::<BigEndian>(fmod.subsec_nanos())
+        }).expect("hashing data in memory")
+    }
+}
+
+/// Hashes everything written by `f` into 12 bytes using blake2b
+fn digest12<F>(f: F) -> io::Result<[u8; 12]>
+    where F: FnOnce(&mut Writer<Blake2b>) -> io::Result<()>
+{
+    let mut wr = Writer::new(<Blake2b as VariableOutput>::new(12)
+        .expect("blake2b supports 12 bytes"));
+    f(&mut wr)?;
+    let mut value = [0u8; 12];
+    wr.into_inner().variable_result(&mut value[..])
+        .expect("buffer is 12 bytes");
+    Ok(value)
+}
+
+impl ContentHashes {
+    pub fn new(capacity: usize) -> ContentHashes {
+        ContentHashes {
+            capacity,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+    /// Returns strong etag for the file, hashing it if not cached yet
+    ///
+    /// If `persist` is true, hash is also looked up and stored in an
+    /// extended attribute.
+    pub(crate) fn get<F: ReadAt>(&self, file: &F, metadata: &Metadata,
+        persist: bool)
+        -> io::Result<Etag>
+    {
+        let key = DefaultEtag.etag(metadata);
+        if let Some(value) = self.entries.lock().expect("cache lock")
+            .get(&key)
+        {
+            return Ok(Etag::strong(*value));
+        }
+        // hash without the lock, so other files are served meanwhile
+        let etag = match load_xattr(file, metadata, persist) {
+            Some(etag) => etag,
+            None => {
+                let etag = Etag::from_content(file, metadata.len())?;
+                store_xattr(file, metadata, persist, &etag);
+                etag
+            }
+        };
+        let mut entries = self.entries.lock().expect("cache lock");
+        if entries.len() >= self.capacity {
+            let key = entries.keys().next().cloned();
+            if let Some(key) = key {
+                entries.remove(&key);
+            }
+        }
+        if self.capacity > 0 {
+            entries.insert(key, etag.value);
+        }
+        Ok(etag)
+    }
 }
 
-#[cfg(not(unix))]
-fn extra<W: Write>(_: &mut W, _: &Metadata) {
+/// Returns the part of the xattr value that must match current file
+///
+/// Note: ctime (and so metadata etag) is changed by setting the
+/// attribute, so only size and modification time are checked.
+#[cfg(all(unix, feature="xattr"))]
+fn xattr_prefix(metadata: &Metadata) -> Option<String> {
+    let fmod = metadata.modified()?.duration_since(UNIX_EPOCH).ok()?;
+    Some(format!("{}.{:09} {} ",
+        fmod.as_secs(), fmod.subsec_nanos(), metadata.len()))
+}
+
+#[cfg(all(unix, feature="xattr"))]
+fn load_xattr<F: ReadAt>(file: &F, metadata: &Metadata, persist: bool)
+    -> Option<Etag>
+{
+    use xattr::FileExt;
+    if !persist {
+        return None;
+    }
+    let prefix = xattr_prefix(metadata)?;
+    let value = file.as_file()?.get_xattr(XATTR_NAME).ok()??;
+    if !value.starts_with(prefix.as_bytes()) {
+        return None;
+    }
+    let tag = &value[prefix.len()..];
+    if tag.len() != 16+2 || tag[0] != b'"' || tag[16+1] != b'"' {
+        return None;
+    }
+    Etag::decode_base64(&tag[1..16+1], true).ok()
+}
+
+#[cfg(all(unix, feature="xattr"))]
+fn store_xattr<F: ReadAt>(file: &F, metadata: &Metadata, persist: bool,
+    etag: &Etag)
+{
+    use xattr::FileExt;
+    if !persist {
+        return;
+    }
+    if let (Some(file), Some(prefix)) = (file.as_file(), xattr_prefix(metadata))
+    {
+        let value = format!("{}{}", prefix, etag);
+        // errors are ignored, e.g. filesystem may be read-only
+        file.set_xattr(XATTR_NAME, value.as_bytes()).ok();
+    }
+}
+
+#[cfg(not(all(unix, feature="xattr")))]
+fn load_xattr<F: ReadAt>(_: &F, _: &Metadata, _: bool) -> Option<Etag> {
+    None
+}
+
+#[cfg(not(all(unix, feature="xattr")))]
+fn store_xattr<F: ReadAt>(_: &F, _: &Metadata, _: bool, _: &Etag) {
 }
 
 #[inline(always)]
@@ -110,11 +381,21 @@ fn decode4(src: &[u8], dest: &mut [u8]) -> Result<(), ()> {
 impl fmt::Display for Etag {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         let mut result = [0u8; 16];
-        base64triple(&self.0[..3], &mut result[..4]);
-        base64triple(&self.0[3..6], &mut result[4..8]);
-        base64triple(&self.0[6..9], &mut result[8..12]);
-        base64triple(&self.0[9..], &mut result[12..]);
-        write!(f, r#"W/"{}""#, unsafe { from_utf8_unchecked(&result[..]) })
+        base64triple(&self.value[..3], &mut result[..4]);
+        base64triple(&self.value[3..6], &mut result[4..8]);
+        base64triple(&self.value[6..9], &mut result[8..12]);
+        base64triple(&self.value[9..], &mut result[12..]);
+        let value = unsafe { from_utf8_unchecked(&result[..]) };
+        let suffix = match self.encoding {
+            Encoding::Brotli => "-br",
+            Encoding::Gzip => "-gz",
+            _ => "",
+        };
+        if self.strong {
+            write!(f, r#""{}{}""#, value, suffix)
+        } else {
+            write!(f, r#"W/"{}{}""#, value, suffix)
+        }
     }
 }
 
@@ -128,10 +409,89 @@ impl fmt::Debug for Etag {
 mod test {
     use super::*;
 
+    const VALUE: [u8; 12] = [181, 130, 83, 244, 162, 84, 35, 66,
+                             151, 216, 142, 106];
+
     #[test]
     fn format() {
-        assert_eq!(format!("{}",
-            Etag([181, 130, 83, 244, 162, 84, 35, 66, 151, 216, 142, 106])),
+        assert_eq!(format!("{}", Etag::weak(VALUE)),
             String::from(r#"W/"tYJT9KJUI0KX2I5q""#));
+        assert_eq!(format!("{}", Etag::strong(VALUE)),
+            String::from(r#""tYJT9KJUI0KX2I5q""#));
+        assert_eq!(format!("{}",
+            Etag::weak(VALUE).with_encoding(Encoding::Brotli)),
+            String::from(r#"W/"tYJT9KJUI0KX2I5q-br""#));
+        assert_eq!(format!("{}",
+            Etag::strong(VALUE).with_encoding(Encoding::Gzip)),
+            String::from(r#""tYJT9KJUI0KX2I5q-gz""#));
+    }
+
+    #[test]
+    fn salt() {
+        assert_eq!(Etag::weak(VALUE).salted(b""), Etag::weak(VALUE));
+        let salted = Etag::strong(VALUE).salted(b"v2");
+        assert!(salted.strong);
+        assert!(salted != Etag::strong(VALUE));
+        assert!(salted != Etag::strong(VALUE).salted(b"v3"));
+    }
+
+    #[test]
+    fn content_only() {
+        use std::time::SystemTime;
+        let time = UNIX_EPOCH + Duration::new(1503434833, 0);
+        let a = Metadata::file(10, Some(time));
+        let b = Metadata::file(10, Some(time))
+            .with_created(SystemTime::now()).with_version(1);
+        assert_eq!(ContentOnlyEtag.etag(&a), ContentOnlyEtag.etag(&b));
+        assert!(DefaultEtag.etag(&a) != DefaultEtag.etag(&b));
+        assert!(ContentOnlyEtag.etag(&a) !=
+                ContentOnlyEtag.etag(&Metadata::file(11, Some(time))));
+    }
+
+    #[test]
+    fn content() {
+        use std::fs::{File, write};
+        use test_util::TempDir;
+        let dir = TempDir::new("etag");
+        let path = dir.write("a.txt", "hello world");
+        let file = File::open(&path).unwrap();
+        let etag = Etag::from_content(&file, 11).unwrap();
+        assert!(etag.strong);
+        assert_eq!(Etag::from_content(&file, 11).unwrap(), etag);
+        assert!(Etag::from_content(&file, 5).unwrap() != etag);
+        assert_eq!(Etag::from_content(&file, 12).unwrap_err().kind(),
+                   io::ErrorKind::UnexpectedEof);
+        let cache = ContentHashes::new(1);
+        let meta = Metadata::file(11, None);
+        assert_eq!(cache.get(&file, &meta, false).unwrap(), etag);
+        // cached by the metadata, so contents are not read again
+        write(&path, "").unwrap();
+        assert_eq!(cache.get(&file, &meta, false).unwrap(), etag);
+    }
+
+    #[cfg(all(unix, feature="xattr"))]
+    #[test]
+    fn xattr() {
+        use std::fs::File;
+        use xattr::FileExt;
+        use test_util::TempDir;
+        let dir = TempDir::new("xattr");
+        let path = dir.write("a.txt", "hello world");
+        let file = File::open(&path).unwrap();
+        let meta = Metadata::from(&file.metadata().unwrap());
+        let etag = Etag::from_content(&file, 11).unwrap();
+        store_xattr(&file, &meta, true, &etag);
+        match file.get_xattr(XATTR_NAME) {
+            Ok(Some(_)) => {}
+            // filesystem doesn't support user attributes
+            _ => return,
+        }
+        assert_eq!(load_xattr(&file, &meta, true), Some(etag.clone()));
+        assert_eq!(load_xattr(&file, &meta, false), None);
+        let changed = Metadata::file(12, meta.modified());
+        assert_eq!(load_xattr(&file, &changed, true), None);
+        // hash is taken from the attribute, even if memory cache is empty
+        let cache = ContentHashes::new(1);
+        assert_eq!(cache.get(&file, &meta, true).unwrap(), etag);
     }
 }
@@ -0,0 +1,157 @@
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use accept_encoding::Encoding;
+
+
+/// Report of the decisions made while probing a file
+///
+/// It's collected only if `Config::explain` is enabled, and is useful for
+/// debug logging, e.g. to find out why client got 200 instead of 304.
+/// The `Display` implementation prints everything on a single line.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Explanation {
+    paths: Vec<PathBuf>,
+    selected: Option<(PathBuf, Encoding)>,
+    condition: Option<&'static str>,
+    range: Option<(u64, u64)>,
+}
+
+/// Collects explanation for `Input` (if enabled)
+///
+/// Locked only from the thread that probes the file, so lock is never
+/// contended.
+#[derive(Debug)]
+pub(crate) struct Recorder(Option<Box<Mutex<Explanation>>>);
+
+impl Explanation {
+    /// Paths that are checked on the filesystem (in order)
+    ///
+    /// Includes index files and variants with encoding suffixes. Paths
+    /// found in the `not_found_cache` or `head_cache` are not included.
+    pub fn paths(&self) -> &[PathBuf] {
+        &self.paths
+    }
+    /// Path of the file (or the encoded variant of it) that is chosen
+    pub fn selected_path(&self) -> Option<&Path> {
+        self.selected.as_ref().map(|(path, _)| path.as_path())
+    }
+    /// Encoding of the chosen file
+    pub fn encoding(&self) -> Option<Encoding> {
+        self.selected.as_ref().map(|&(_, enc)| enc)
+    }
+    /// Name of the conditional header that determined the output
+    ///
+    /// This is `If-Match` or `If-Unmodified-Since` for 412, `If-None-Match`
+    /// or `If-Modified-Since` for 304, and `If-Range` if range is ignored
+    /// because the validator doesn't match. `None` means conditional
+    /// headers are absent or haven't matched.
+    pub fn condition(&self) -> Option<&'static str> {
+        self.condition
+    }
+    /// First and last byte of the range that is served (both inclusive)
+    pub fn range(&self) -> Option<(u64, u64)> {
+        self.range
+    }
+}
+
+impl fmt::Display for Explanation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "probed [")?;
+        for (idx, path) in self.paths.iter().enumerate() {
+            if idx > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{}", path.display())?;
+        }
+        write!(f, "]")?;
+        if let Some((ref path, enc)) = self.selected {
+            write!(f, ", selected {} ({:?})", path.display(), enc)?;
+        }
+        if let Some(header) = self.condition {
+            write!(f, ", matched {}", header)?;
+        }
+        if let Some((start, end)) = self.range {
+            write!(f, ", range {}-{}", start, end)?;
+        }
+        Ok(())
+    }
+}
+
+impl Recorder {
+    pub fn new(enabled: bool) -> Recorder {
+        if enabled {
+            Recorder(Some(Box::new(Mutex::new(Explanation::default()))))
+        } else {
+            Recorder(None)
+        }
+    }
+    fn update<F: FnOnce(&mut Explanation)>(&self, f: F) {
+        if let Some(ref cell) = self.0 {
+            f(&mut cell.lock().expect("explanation is not poisoned"));
+        }
+    }
+    pub fn reset(&self) {
+        self.update(|e| *e = Explanation::default());
+    }
+    pub fn probed(&self, path: &Path) {
+        self.update(|e| e.paths.push(path.to_path_buf()));
+    }
+    pub fn selected(&self, path: &Path, encoding: Encoding) {
+        self.update(|e| e.selected = Some((path.to_path_buf(), encoding)));
+    }
+    pub fn condition(&self, header: &'static str) {
+        self.update(|e| e.condition = Some(header));
+    }
+    pub fn range(&self, start: u64, end: u64) {
+        self.update(|e| e.range = Some((start, end)));
+    }
+    /// Copy explanation collected by another recorder
+    #[cfg(feature="serde")]
+    pub fn replace(&self, other: &Recorder) {
+        if let Some(expl) = other.get() {
+            self.update(|e| *e = expl);
+        }
+    }
+    pub fn get(&self) -> Option<Explanation> {
+        self.0.as_ref()
+            .map(|cell| cell.lock().expect("explanation is not poisoned"))
+            .map(|e| e.clone())
+    }
+}
+
+impl Clone for Recorder {
+    fn clone(&self) -> Recorder {
+        Recorder(self.get().map(|e| Box::new(Mutex::new(e))))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+    use accept_encoding::Encoding;
+    use super::Recorder;
+
+    #[test]
+    fn disabled() {
+        let rec = Recorder::new(false);
+        rec.probed(Path::new("a"));
+        assert_eq!(rec.get(), None);
+    }
+
+    #[test]
+    fn display() {
+        let rec = Recorder::new(true);
+        rec.probed(Path::new("a.br"));
+        rec.probed(Path::new("a.gz"));
+        rec.selected(Path::new("a.gz"), Encoding::Gzip);
+        rec.range(0, 9);
+        assert_eq!(rec.get().unwrap().to_string(),
+            "probed [a.br, a.gz], selected a.gz (Gzip), range 0-9");
+        let copy = rec.clone();
+        rec.reset();
+        assert_eq!(rec.get().unwrap().to_string(), "probed []");
+        assert_eq!(copy.get().unwrap().encoding(), Some(Encoding::Gzip));
+    }
+}
@@ -0,0 +1,161 @@
+use std::collections::HashMap;
+use std::fs::{self, File, Metadata};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+
+/// Cache of open file descriptors
+///
+/// Entries are revalidated on each access by checking metadata of the
+/// path, so this saves `open` and `fstat` calls but not `stat`.
+#[derive(Debug)]
+pub struct FdCache {
+    capacity: usize,
+    inner: Mutex<Inner>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    tick: u64,
+    entries: HashMap<PathBuf, Entry>,
+}
+
+#[derive(Debug)]
+struct Entry {
+    file: File,
+    stamp: Stamp,
+    last_used: u64,
+}
+
+/// The data used to check whether file is changed
+#[derive(Debug, PartialEq, Eq)]
+struct Stamp {
+    len: u64,
+    modified: Option<SystemTime>,
+    inode: (u64, u64),
+}
+
+impl Stamp {
+    fn new(meta: &Metadata) -> Stamp {
+        Stamp {
+            len: meta.len(),
+            modified: meta.modified().ok(),
+            inode: inode(meta),
+        }
+    }
+}
+
+#[cfg(unix)]
+fn inode(meta: &Metadata) -> (u64, u64) {
+    use std::os::unix::fs::MetadataExt;
+    (meta.dev(), meta.ino())
+}
+
+#[cfg(not(unix))]
+fn inode(_: &Metadata) -> (u64, u64) {
+    (0, 0)
+}
+
+impl FdCache {
+    pub fn new(capacity: usize) -> FdCache {
+        FdCache {
+            capacity,
+            inner: Mutex::new(Inner {
+                tick: 0,
+                entries: HashMap::new(),
+            }),
+        }
+    }
+    /// Open file or get it from cache
+    ///
+    /// Returned file shares file position with the cached one, so it
+    /// must only be read with positional reads.
+    pub fn open(&self, path: &Path) -> io::Result<(File, Metadata)> {
+        let meta = fs::metadata(path)?;
+        if !meta.is_file() {
+            return Ok((File::open(path)?, meta));
+        }
+        let stamp = Stamp::new(&meta);
+        let key = path.to_path_buf();
+        {
+            let mut inner = self.inner.lock().expect("fd cache lock");
+            inner.tick += 1;
+            let tick = inner.tick;
+            if let Some(entry) = inner.entries.get_mut(&key) {
+                if entry.stamp == stamp {
+                    entry.last_used = tick;
+                    return Ok((entry.file.try_clone()?, meta));
+                }
+            }
+        }
+        let file = File::open(path)?;
+        let meta = file.metadata()?;
+        if meta.is_file() && self.capacity > 0 {
+            let entry = Entry {
+                file: file.try_clone()?,
+                stamp: Stamp::new(&meta),
+                last_used: 0,
+            };
+            self.insert(key, entry);
+        }
+        Ok((file, meta))
+    }
+    fn insert(&self, key: PathBuf, mut entry: Entry) {
+        let mut inner = self.inner.lock().expect("fd cache lock");
+        if !inner.entries.contains_key(&key) &&
+            inner.entries.len() >= self.capacity
+        {
+            let oldest = inner.entries.iter()
+                .min_by_key(|&(_, e)| e.last_used)
+                .map(|(k, _)| k.clone());
+            if let Some(oldest) = oldest {
+                inner.entries.remove(&oldest);
+            }
+        }
+        entry.last_used = inner.tick;
+        inner.entries.insert(key, entry);
+    }
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.inner.lock().unwrap().entries.len()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::{write, remove_file};
+    use std::io::Read;
+    use test_util::TempDir;
+    use super::*;
+
+    fn read(cache: &FdCache, path: &Path) -> String {
+        let mut buf = String::new();
+        cache.open(path).unwrap().0
+            .read_to_string(&mut buf).unwrap();
+        buf
+    }
+
+    #[test]
+    fn revalidate() {
+        let dir = TempDir::new("fd-cache");
+        let path = dir.write("a.txt", "hello");
+        let cache = FdCache::new(10);
+        assert_eq!(read(&cache, &path), "hello");
+        assert_eq!(cache.len(), 1);
+        write(&path, "hello world").unwrap();
+        assert_eq!(read(&cache, &path), "hello world");
+        remove_file(&path).unwrap();
+        assert_eq!(cache.open(&path).unwrap_err().kind(),
+                   io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn evict() {
+        let cache = FdCache::new(1);
+        cache.open(Path::new("public/index.html")).unwrap();
+        cache.open(Path::new("public/index.html.gz")).unwrap();
+        assert_eq!(cache.len(), 1);
+    }
+}
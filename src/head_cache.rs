@@ -0,0 +1,157 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use accept_encoding::Encoding;
+use output::{FileInfo, Mime};
+
+
+/// Cache of resolved paths and metadata of the files
+///
+/// Entries are not revalidated until `ttl` expires, except when a file
+/// is opened for reading the body.
+pub type HeadCache = TtlCache<Arc<Resolved>>;
+
+/// Bounded map from paths to values that expire after `ttl`
+#[derive(Debug)]
+pub struct TtlCache<V> {
+    ttl: Duration,
+    capacity: usize,
+    entries: Mutex<HashMap<PathBuf, (Instant, V)>>,
+}
+
+/// A file (after looking up index file) with all of its encoded variants
+#[derive(Debug)]
+pub struct Resolved {
+    pub ctype: Mime,
+    pub variants: Vec<Variant>,
+}
+
+#[derive(Debug)]
+pub struct Variant {
+    pub encoding: Encoding,
+    pub path: PathBuf,
+    pub info: FileInfo,
+}
+
+impl<V: Clone> TtlCache<V> {
+    pub fn new(ttl: Duration, capacity: usize) -> TtlCache<V> {
+        TtlCache {
+            ttl,
+            capacity,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+    pub fn get(&self, path: &Path) -> Option<V> {
+        let mut entries = self.entries.lock().expect("cache lock");
+        let expired = match entries.get(path) {
+            Some(&(expires, ref value)) if expires > Instant::now() => {
+                return Some(value.clone());
+            }
+            Some(_) => true,
+            None => false,
+        };
+        if expired {
+            entries.remove(path);
+        }
+        None
+    }
+    pub fn insert(&self, path: &Path, value: V) {
+        if self.capacity == 0 {
+            return;
+        }
+        let now = Instant::now();
+        let mut entries = self.entries.lock().expect("cache lock");
+        if entries.len() >= self.capacity && !entries.contains_key(path) {
+            entries.retain(|_, &mut (expires, _)| expires > now);
+            if entries.len() >= self.capacity {
+                let oldest = entries.iter()
+                    .min_by_key(|&(_, &(expires, _))| expires)
+                    .map(|(k, _)| k.clone());
+                if let Some(oldest) = oldest {
+                    entries.remove(&oldest);
+                }
+            }
+        }
+        entries.insert(path.to_path_buf(), (now + self.ttl, value));
+    }
+    pub fn remove(&self, path: &Path) {
+        self.entries.lock().expect("cache lock").remove(path);
+    }
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.entries.lock().unwrap().len()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use config::Config;
+    use input::Input;
+    use output::Output;
+    use super::*;
+
+    fn probe(cfg: &Arc<Config>, method: &str, path: &str) -> Output {
+        let inp = Input::from_headers(cfg, method,
+            vec![("Accept-Encoding", &b"gzip"[..])].into_iter());
+        inp.probe_file(path).unwrap()
+    }
+
+    #[test]
+    fn cached() {
+        let cfg = Config::new()
+            .add_index_file("index.html")
+            .metadata_cache(Duration::new(60, 0), 10)
+            .done();
+        let cache = cfg.head_cache.as_ref().unwrap();
+        for _ in 0..2 {
+            match probe(&cfg, "HEAD", "public") {
+                Output::FileHead(head) => {
+                    assert_eq!(head.content_length(), 109);
+                }
+                _ => unreachable!(),
+            }
+            assert_eq!(cache.len(), 1);
+        }
+        match probe(&cfg, "GET", "public") {
+            Output::File(wrapper) => {
+                assert_eq!(wrapper.content_length(), 109);
+            }
+            _ => unreachable!(),
+        }
+        match probe(&cfg, "GET", "public/nonexistent") {
+            Output::NotFound => {}
+            _ => unreachable!(),
+        }
+        assert_eq!(cache.len(), 1);
+    }
+
+    #[test]
+    fn expire() {
+        let cache = TtlCache::new(Duration::new(0, 0), 1);
+        cache.insert(Path::new("/a"), ());
+        assert!(cache.get(Path::new("/a")).is_none());
+        cache.insert(Path::new("/b"), ());
+        assert_eq!(cache.len(), 1);
+    }
+
+    #[test]
+    fn not_found() {
+        let cfg = Config::new()
+            .not_found_cache(Duration::new(60, 0), 10)
+            .done();
+        let cache = cfg.not_found_cache.as_ref().unwrap();
+        match probe(&cfg, "GET", "public/nonexistent") {
+            Output::NotFound => {}
+            _ => unreachable!(),
+        }
+        assert!(cache.get(Path::new("public/nonexistent")).is_some());
+        match probe(&cfg, "GET", "public/index.html") {
+            Output::File(_) => {}
+            _ => unreachable!(),
+        }
+        assert_eq!(cache.len(), 1);
+    }
+}
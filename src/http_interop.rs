@@ -0,0 +1,232 @@
+use std::sync::Arc;
+
+use http::{HeaderMap, Method, StatusCode};
+use http::header::{HeaderName, HeaderValue, CONTENT_LENGTH};
+use http::request::Parts;
+use http::response::Builder;
+
+use config::Config;
+use input::Input;
+use output::{Head, FileWrapper};
+use vfs::ReadAt;
+
+
+impl Input {
+    /// A constructor for `Input` object from `http::Request` parts
+    ///
+    /// This is equivalent to `Input::from_headers` but avoids adapting
+    /// headers and method manually.
+    ///
+    /// Only available with the `http` feature.
+    pub fn from_request_parts(cfg: &Arc<Config>, parts: &Parts) -> Input {
+        let mut inp = Input::from_header_map(cfg, &parts.method,
+                                             &parts.headers);
+        if let Some(query) = parts.uri.query() {
+            inp.query(query);
+        }
+        inp
+    }
+    /// A constructor for `Input` object from method and headers of the
+    /// `http` crate
+    ///
+    /// Every value of the repeated headers is processed, the same way as
+    /// multiple headers of the same name are passed to `from_headers`.
+    /// Values are passed as raw bytes, so a value having non-UTF-8 bytes
+    /// (`obs-text`) is still parsed rather than dropped.
+    ///
+    /// Only available with the `http` feature.
+    pub fn from_header_map(cfg: &Arc<Config>, method: &Method,
+        headers: &HeaderMap)
+        -> Input
+    {
+        Input::from_headers(cfg, method.as_str(),
+            headers.iter().map(|(k, v)| (k.as_str(), v.as_bytes())))
+    }
+}
+
+impl Head {
+    /// Returns the status code that should be sent
+    ///
+    /// Only available with the `http` feature.
+    pub fn status_code(&self) -> StatusCode {
+        StatusCode::from_u16(self.suggested_status())
+            .expect("suggested status is always valid")
+    }
+    /// Put all the response headers into the header map
+    ///
+    /// Unlike `headers()` this includes `Content-Length` (unless response
+    /// is `304 Not Modified`). Headers that already exist in the map are
+    /// overwritten. Extra headers of the same name (e.g. several `Link`
+    /// headers from `_headers` rules) are all kept.
+    ///
+    /// Only available with the `http` feature.
+    pub fn apply_to(&self, map: &mut HeaderMap) {
+        if !self.is_not_modified() {
+            map.insert(CONTENT_LENGTH, self.content_length().into());
+        }
+        for (name, value) in self.typed_headers() {
+            let name = HeaderName::from_bytes(name.as_bytes());
+            // mime type may be set by user, so it's validated anyway
+            let value = match value.as_static() {
+                Some(value) => HeaderValue::from_str(value),
+                None => HeaderValue::from_str(&value.to_string()),
+            };
+            match (name, value) {
+                (Ok(name), Ok(value)) => {
+                    map.insert(name, value);
+                }
+                // can only happen on misconfiguration, e.g. if charset
+                // contains non-printable characters
+                _ => continue,
+            }
+        }
+        let mut added = Vec::new();
+        for (name, value) in self.extra_headers() {
+            let name = HeaderName::from_bytes(name.as_bytes());
+            match (name, HeaderValue::from_str(value)) {
+                (Ok(ref name), Ok(value)) if added.contains(name) => {
+                    map.append(name, value);
+                }
+                (Ok(name), Ok(value)) => {
+                    map.insert(&name, value);
+                    added.push(name);
+                }
+                // values are checked when rules are parsed
+                _ => continue,
+            }
+        }
+    }
+    /// Create a response builder with status code and headers filled in
+    ///
+    /// Only available with the `http` feature.
+    pub fn response_builder(&self) -> Builder {
+        let mut builder = Builder::new().status(self.status_code());
+        if let Some(map) = builder.headers_mut() {
+            self.apply_to(map);
+        }
+        builder
+    }
+}
+
+impl<F: ReadAt> FileWrapper<F> {
+    /// Returns the status code that should be sent
+    ///
+    /// Only available with the `http` feature.
+    pub fn status_code(&self) -> StatusCode {
+        self.head().status_code()
+    }
+    /// Put all the response headers into the header map
+    ///
+    /// See `Head::apply_to` for details.
+    ///
+    /// Only available with the `http` feature.
+    pub fn apply_to(&self, map: &mut HeaderMap) {
+        self.head().apply_to(map)
+    }
+    /// Create a response builder with status code and headers filled in
+    ///
+    /// Only available with the `http` feature.
+    pub fn response_builder(&self) -> Builder {
+        self.head().response_builder()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use http::Request;
+    use http::header::{CONTENT_TYPE, CONTENT_RANGE, ACCEPT_RANGES};
+    use accept_encoding::Encoding;
+    use input::Mode;
+    use output::Output;
+    use super::*;
+
+    fn parts(method: &str, range: &str) -> Parts {
+        Request::builder()
+            .method(method)
+            .header("Range", range)
+            .body(()).unwrap()
+            .into_parts().0
+    }
+
+    #[test]
+    fn from_parts() {
+        let cfg = Config::new().done();
+        let inp = Input::from_request_parts(&cfg, &parts("GET", "bytes=0-1"));
+        assert_eq!(inp.mode, Mode::Get);
+        assert!(inp.range.is_some());
+        let inp = Input::from_request_parts(&cfg, &parts("PUT", "bytes=0-1"));
+        assert_eq!(inp.mode, Mode::InvalidMethod);
+    }
+
+    #[test]
+    fn header_map() {
+        let cfg = Config::new().done();
+        let inp = Input::from_headers(&cfg, "HEAD", vec![].into_iter());
+        let etag = match inp.probe_file("public/index.html").unwrap() {
+            Output::FileHead(head) => head.etag().unwrap().to_string(),
+            _ => unreachable!(),
+        };
+        let mut map = HeaderMap::new();
+        map.append("If-None-Match", HeaderValue::from_static("W/\"x\""));
+        map.append("If-None-Match", HeaderValue::from_str(&etag).unwrap());
+        let inp = Input::from_header_map(&cfg, &Method::HEAD, &map);
+        assert_eq!(inp.probe_file("public/index.html").unwrap()
+                   .suggested_status(), 304);
+        let inp = Input::from_header_map(&cfg, &Method::POST, &map);
+        assert_eq!(inp.mode, Mode::InvalidMethod);
+
+        let mut map = HeaderMap::new();
+        map.insert("Accept-Encoding",
+            HeaderValue::from_bytes(b"gzip, x-\xe9t\xe9").unwrap());
+        assert!(map["Accept-Encoding"].to_str().is_err());
+        let inp = Input::from_header_map(&cfg, &Method::HEAD, &map);
+        assert!(inp.accepts(Encoding::Gzip));
+    }
+
+    #[test]
+    fn apply_head() {
+        let cfg = Config::new().done();
+        let mut inp = Input::from_request_parts(&cfg,
+                                                &parts("HEAD", "bytes=0-9"));
+        inp.force_download("a.html");
+        let head = match inp.probe_file("public/index.html").unwrap() {
+            Output::FileHead(head) => head,
+            _ => unreachable!(),
+        };
+        let mut map = HeaderMap::new();
+        head.apply_to(&mut map);
+        assert_eq!(head.status_code(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(map[CONTENT_LENGTH], "10");
+        assert_eq!(map[ACCEPT_RANGES], "bytes");
+        assert_eq!(map[CONTENT_TYPE], "text/html; charset=utf-8");
+        assert!(map[CONTENT_RANGE].to_str().unwrap().starts_with("bytes 0-9/"));
+        assert_eq!(map["Content-Disposition"],
+                   "attachment; filename=\"a.html\"");
+    }
+
+    #[test]
+    fn apply_repeated_extra_headers() {
+        use rules::HeaderRules;
+
+        let rules = HeaderRules::parse("\
+            /*\n  \
+              Link: </style.css>; rel=preload\n\
+            /index.html\n  \
+              Link: </app.js>; rel=preload\n\
+        ").unwrap();
+        let cfg = Config::new().header_rules("public", rules).done();
+        let inp = Input::from_headers(&cfg, "HEAD", vec![].into_iter());
+        let head = match inp.probe_file("public/index.html").unwrap() {
+            Output::FileHead(head) => head,
+            _ => unreachable!(),
+        };
+        let mut map = HeaderMap::new();
+        map.insert("Link", HeaderValue::from_static("</old.css>"));
+        head.apply_to(&mut map);
+        let links = map.get_all("Link").iter()
+            .map(|x| x.to_str().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(links, ["</style.css>; rel=preload",
+                           "</app.js>; rel=preload"]);
+    }
+}
@@ -0,0 +1,145 @@
+//! Integration with [hyper](https://hyper.rs)
+//!
+//! Only available with the `hyper` feature.
+//!
+//! Example of a handler:
+//!
+//! ```rust,ignore
+//! let inp = Input::from_request_parts(&config, &parts);
+//! let output = handle.spawn_blocking(move || inp.probe_file(&path)).await??;
+//! Ok(hyper_compat::into_response(output, &handle))
+//! ```
+use http::StatusCode;
+use http::header::{HeaderName, HeaderValue, CONTENT_LENGTH, LOCATION};
+use hyper::{Body, Response};
+use tokio::runtime::Handle;
+
+use output::{Output, FileWrapper};
+use vfs::ReadAt;
+
+
+/// Convert the output into a response with body streamed from the file
+///
+/// File chunks are read using `spawn_blocking` on the supplied runtime.
+/// Error responses contain a short plain-text body.
+pub fn into_response<F>(output: Output<F>, pool: &Handle) -> Response<Body>
+    where F: ReadAt + Send + 'static,
+{
+    match output {
+        Output::File(wrapper) | Output::FileRange(wrapper) => {
+            wrapper.response_builder()
+                .body(stream_body(wrapper, pool))
+                .expect("headers are valid")
+        }
+        Output::FileInMemory(head, data) => {
+            head.response_builder()
+                .body(Body::from(data))
+                .expect("headers are valid")
+        }
+        Output::FileHead(head) | Output::NotModified(head) |
+        Output::RangeNotSatisfiable(head) |
+        Output::PreconditionFailed(head) => {
+            head.response_builder()
+                .body(Body::empty())
+                .expect("headers are valid")
+        }
+        Output::Delegate(head, path) => {
+            let mut response = head.response_builder()
+                .body(Body::empty())
+                .expect("headers are valid");
+            // the body is sent by the frontend server
+            response.headers_mut().remove(CONTENT_LENGTH);
+            let name = head.data.config.delegate_header.as_ref()
+                .and_then(|x| HeaderName::from_bytes(x.as_bytes()).ok());
+            let value = HeaderValue::from_str(&path.to_string_lossy());
+            match (name, value) {
+                (Some(name), Ok(value)) => {
+                    response.headers_mut().insert(name, value);
+                }
+                _ => return error_response(500),
+            }
+            response
+        }
+        Output::Redirect(status, location) => {
+            let mut response = error_response(status);
+            match HeaderValue::from_str(&location) {
+                Ok(value) => {
+                    response.headers_mut().insert(LOCATION, value);
+                }
+                Err(_) => return error_response(500),
+            }
+            response
+        }
+        output => error_response(output.suggested_status()),
+    }
+}
+
+/// Returns the body which reads file in the disk thread
+///
+/// See `FileWrapper::into_stream` for details.
+pub fn stream_body<F>(wrapper: FileWrapper<F>, pool: &Handle) -> Body
+    where F: ReadAt + Send + 'static,
+{
+    Body::wrap_stream(wrapper.into_stream(pool))
+}
+
+pub(crate) fn error_response(status: u16) -> Response<Body> {
+    let status = StatusCode::from_u16(status)
+        .expect("suggested status is always valid");
+    let body = format!("{} {}", status.as_u16(),
+        status.canonical_reason().unwrap_or(""));
+    Response::builder()
+        .status(status)
+        .header("Content-Type", "text/plain")
+        .header("Content-Length", body.len())
+        .body(Body::from(body))
+        .expect("headers are valid")
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use hyper::body::to_bytes;
+    use tokio::runtime::Builder;
+    use config::Config;
+    use input::Input;
+    use super::*;
+
+    fn probe(method: &str, path: &str) -> Output {
+        let cfg = Arc::new(Config::new());
+        let inp = Input::from_headers(&cfg, method, vec![].into_iter());
+        inp.probe_file(path).unwrap()
+    }
+
+    #[test]
+    fn file() {
+        let rt = Builder::new_current_thread().build().unwrap();
+        let resp = into_response(probe("GET", "public/index.html"),
+                                 rt.handle());
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = rt.block_on(to_bytes(resp.into_body())).unwrap();
+        assert!(body.starts_with(b"<!DOCTYPE html>"));
+    }
+
+    #[test]
+    fn delegate() {
+        let rt = Builder::new_current_thread().build().unwrap();
+        let cfg = Config::new().delegate("X-Accel-Redirect").done();
+        let inp = Input::from_headers(&cfg, "GET", vec![].into_iter());
+        let resp = into_response(inp.probe_file("public/index.html").unwrap(),
+                                 rt.handle());
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()["X-Accel-Redirect"], "public/index.html");
+        assert!(resp.headers().get(CONTENT_LENGTH).is_none());
+    }
+
+    #[test]
+    fn not_found() {
+        let rt = Builder::new_current_thread().build().unwrap();
+        let resp = into_response(probe("GET", "public/nonexistent"),
+                                 rt.handle());
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let body = rt.block_on(to_bytes(resp.into_body())).unwrap();
+        assert_eq!(&body[..], b"404 Not Found");
+    }
+}
@@ -1,20 +1,41 @@
 use std::io;
+use std::fs::File;
 use std::time::SystemTime;
-use std::fs::{File};
-use std::path::Path;
+use std::path::{Path, PathBuf, Component};
 use std::ffi::OsString;
 use std::sync::Arc;
 
+use accept::{Accept, AcceptParser, AcceptCharsetParser};
 use accept_encoding::{AcceptEncoding, AcceptEncodingParser};
+use charset;
 use accept_encoding::{Iter as EncodingIter, Encoding};
-use config::{Config, EncodingSupport};
-use conditionals::{ModifiedParser, NoneMatchParser};
+use config::{Config, EncodingSupport, CharsetPolicy};
+use conditionals::{ModifiedParser, NoneMatchParser, MatchParser};
+use conditionals::IfRangeParser;
 use etag::Etag;
-use output::{Head, FileWrapper};
-use range::{Range, RangeParser};
+use explain::{Explanation, Recorder};
+use head_cache::{HeadCache, Resolved, Variant};
+use output::{Head, DirInfo, FileWrapper, FileInfo, Mime};
+use range::{Range, RangeParser, RangeError};
+use path;
+use rules::{self, Redirect, ExtraHeader};
 use mime_guess::get_mime_type_str;
+use vfs::{FileSystem, Metadata, ReadAt, StdFs};
 use {Output};
 
+/// Mime types for extensions that consist of multiple parts
+///
+/// These are checked before the last extension (which is looked up in
+/// `mime_guess`).
+const COMPOUND_TYPES: &[(&str, &str)] = &[
+    ("tar.gz", "application/x-gtar"),
+    ("js.map", "application/json"),
+    ("css.map", "application/json"),
+];
+
+/// Header values longer than this are ignored by `Input::from_headers`
+const MAX_HEADER_LENGTH: usize = 8192;
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Mode {
     Head,
@@ -23,8 +44,86 @@ pub enum Mode {
     InvalidRange,
 }
 
-pub fn is_text_file(val: &str) -> bool {
-    return val.starts_with("text/") || val == "application/javascript"
+/// Match mime type against pattern like `text/*` or `*+json`
+///
+/// Pattern must be lowercase.
+pub(crate) fn type_matches(pattern: &str, mime: &str) -> bool {
+    if pattern == "*/*" {
+        true
+    } else if pattern.starts_with("*+") {
+        let suffix = &pattern[1..];
+        mime.len() > suffix.len() &&
+            mime.is_char_boundary(mime.len() - suffix.len()) &&
+            mime[mime.len() - suffix.len()..].eq_ignore_ascii_case(suffix)
+    } else if pattern.ends_with("/*") {
+        let major = &pattern[..pattern.len()-1];
+        mime.len() > major.len() && mime.is_char_boundary(major.len()) &&
+            mime[..major.len()].eq_ignore_ascii_case(major)
+    } else {
+        pattern.eq_ignore_ascii_case(mime)
+    }
+}
+
+/// Returns true for names that refer to devices or alternate data streams
+/// on windows, or that windows silently strips (trailing dots and spaces)
+fn is_unsafe_windows_name(name: &str) -> bool {
+    const DEVICES: &[&str] = &["CON", "PRN", "AUX", "NUL", "CONIN$", "CONOUT$"];
+    if name.ends_with('.') || name.ends_with(' ') || name.contains(':') {
+        return true;
+    }
+    // device names are reserved with any extension too
+    let stem = name.split('.').next().unwrap_or("").trim_end_matches(' ');
+    if DEVICES.iter().any(|d| d.eq_ignore_ascii_case(stem)) {
+        return true;
+    }
+    let bytes = stem.as_bytes();
+    bytes.len() == 4 &&
+        (bytes[..3].eq_ignore_ascii_case(b"COM") ||
+         bytes[..3].eq_ignore_ascii_case(b"LPT")) &&
+        bytes[3] >= b'0' && bytes[3] <= b'9'
+}
+
+fn is_unsafe_on_windows(path: &Path) -> bool {
+    path.components().any(|c| match c {
+        Component::Normal(name) => {
+            is_unsafe_windows_name(&name.to_string_lossy())
+        }
+        _ => false,
+    })
+}
+
+pub(crate) fn is_hidden(path: &Path) -> bool {
+    path.components().any(|c| match c {
+        Component::Normal(name) => name.to_string_lossy().starts_with('.'),
+        _ => false,
+    })
+}
+
+/// Value of `Content-Disposition` for downloading file as `name`
+///
+/// Characters that can't be put into the quoted string are replaced by
+/// underscores there, and the exact name is sent in `filename*` (RFC 6266).
+pub(crate) fn content_disposition(name: &str) -> String {
+    let ascii = name.chars()
+        .map(|c| match c {
+            ' '..='~' if c != '"' && c != '\\' => c,
+            _ => '_',
+        })
+        .collect::<String>();
+    if ascii == name {
+        return format!("attachment; filename=\"{}\"", name);
+    }
+    let mut result = format!("attachment; filename=\"{}\"; \
+        filename*=UTF-8''", ascii);
+    for &b in name.as_bytes() {
+        match b {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' |
+            b'!' | b'#' | b'$' | b'&' | b'+' | b'-' | b'.' |
+            b'^' | b'_' | b'`' | b'|' | b'~' => result.push(b as char),
+            _ => result.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    result
 }
 
 /// The structure represents parsed input headers
@@ -37,16 +136,41 @@ pub struct Input {
     pub(crate) config: Arc<Config>,
     pub(crate) mode: Mode,
     pub(crate) accept_encoding: AcceptEncoding,
+    pub(crate) accept: Accept,
+    pub(crate) charset_acceptable: bool,
     pub(crate) range: Option<Range>,
-    pub(crate) if_range: Option<Result<SystemTime, Etag>>,
-    pub(crate) if_match: Vec<Etag>,
+    pub(crate) range_error: Option<RangeError>,
+    pub(crate) if_match: Option<Vec<Etag>>,
     pub(crate) if_none: Vec<Etag>,
     pub(crate) if_unmodified: Option<SystemTime>,
     pub(crate) if_modified: Option<SystemTime>,
+    pub(crate) explain: Recorder,
+    pub(crate) overrides: Option<Box<Overrides>>,
+}
+
+/// Per-request overrides of the config (see `Input::override_mime`)
+///
+/// Rarely used request state is kept here too, so `Input` stays small.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Overrides {
+    pub mime: Option<&'static str>,
+    pub no_etag: bool,
+    pub disposition: Option<ExtraHeader>,
+    /// Query string appended to redirects (see `Config::redirect_query`)
+    pub query: Option<String>,
+    /// Precondition header that was too long to parse (it always fails)
+    pub oversized: Option<&'static str>,
+    pub if_range: Option<Result<SystemTime, Etag>>,
 }
 
 impl Input {
     /// A constructor for `Input` object
+    ///
+    /// Header values longer than 8 KiB are ignored, as are excess etags
+    /// and media ranges in long lists, so memory used is bounded however
+    /// many headers the client sends. The exception is `If-Match` and
+    /// `If-Unmodified-Since`: a precondition that is too long to check
+    /// fails (`Output::PreconditionFailed`) rather than passes.
     pub fn from_headers<'x, I>(cfg: &Arc<Config>, method: &str, headers: I)
         -> Input
         where I: Iterator<Item=(&'x str, &'x[u8])>
@@ -54,29 +178,68 @@ impl Input {
         let mode = match method {
             "HEAD" => Mode::Head,
             "GET" => Mode::Get,
+            _ if cfg.extra_methods.iter().any(|m| m == method) => Mode::Get,
             _ => return Input {
                 config: cfg.clone(),
                 mode: Mode::InvalidMethod,
                 accept_encoding: AcceptEncoding::identity(),
+                accept: Accept::any(),
+                charset_acceptable: true,
                 range: None,
-                if_range: None,
-                if_match: Vec::new(),
+                range_error: None,
+                if_match: None,
                 if_none: Vec::new(),
                 if_unmodified: None,
                 if_modified: None,
+                explain: Recorder::new(cfg.explain),
+                overrides: None,
             },
         };
         let mut ae_parser = AcceptEncodingParser::new();
-        let mut range_parser = RangeParser::new();
+        let mut accept_parser = AcceptParser::new();
+        let mut charset_parser = match cfg.text_charset {
+            Some(ref charset) if cfg.charset_policy != CharsetPolicy::Ignore
+            => Some(AcceptCharsetParser::new(charset)),
+            _ => None,
+        };
+        let mut range_parser = RangeParser::new(cfg.range_merge_gap,
+                                                cfg.max_ranges);
         let mut modified_parser = ModifiedParser::new();
         let mut none_match_parser = NoneMatchParser::new();
+        let mut unmodified_parser = ModifiedParser::new();
+        let mut match_parser = MatchParser::new();
+        let mut if_range_parser = IfRangeParser::new();
+        let mut oversized = None;
         for (key, val) in headers {
+            if val.len() > MAX_HEADER_LENGTH {
+                debug!(header = key, length = val.len(), "header is too long");
+                if cfg.etag && key.eq_ignore_ascii_case("if-match") {
+                    oversized = Some("If-Match");
+                } else if cfg.last_modified &&
+                          key.eq_ignore_ascii_case("if-unmodified-since") &&
+                          oversized.is_none()
+                {
+                    oversized = Some("If-Unmodified-Since");
+                }
+                continue;
+            }
             if cfg.encoding_support != EncodingSupport::Never &&
                key.eq_ignore_ascii_case("accept-encoding")
             {
                 ae_parser.add_header(val);
-            } else if key.eq_ignore_ascii_case("range") {
+            } else if (!cfg.negotiate_types.is_empty() ||
+                       cfg.negotiate_index) &&
+                      key.eq_ignore_ascii_case("accept")
+            {
+                accept_parser.add_header(val);
+            } else if key.eq_ignore_ascii_case("accept-charset") {
+                if let Some(ref mut parser) = charset_parser {
+                    parser.add_header(val);
+                }
+            } else if cfg.ranges && key.eq_ignore_ascii_case("range") {
                 range_parser.add_header(val);
+            } else if cfg.ranges && key.eq_ignore_ascii_case("if-range") {
+                if_range_parser.add_header(val);
             } else if cfg.last_modified &&
                       key.eq_ignore_ascii_case("if-modified-since")
             {
@@ -85,122 +248,856 @@ impl Input {
                       key.eq_ignore_ascii_case("if-none-match")
             {
                 none_match_parser.add_header(val);
+            } else if cfg.last_modified &&
+                      key.eq_ignore_ascii_case("if-unmodified-since")
+            {
+                unmodified_parser.add_header(val);
+            } else if cfg.etag &&
+                      key.eq_ignore_ascii_case("if-match")
+            {
+                match_parser.add_header(val);
             }
         }
-        let range = match range_parser.done() {
-            Ok(range) => range,
-            Err(()) => return Input {
+        let (range, range_error) = match range_parser.done() {
+            Ok(range) => (range, None),
+            Err(e @ RangeError::TooManyRanges)
+            if !cfg.reject_excess_ranges => (None, Some(e)),
+            Err(e) if cfg.ignore_invalid_range &&
+                      e != RangeError::TooManyRanges => (None, Some(e)),
+            Err(e) => return Input {
                 config: cfg.clone(),
                 mode: Mode::InvalidRange,
                 accept_encoding: AcceptEncoding::identity(),
+                accept: Accept::any(),
+                charset_acceptable: true,
                 range: None,
-                if_range: None,
-                if_match: Vec::new(),
+                range_error: Some(e),
+                if_match: None,
                 if_none: Vec::new(),
                 if_unmodified: None,
                 if_modified: None,
+                explain: Recorder::new(cfg.explain),
+                overrides: None,
             },
         };
-        Input {
+        let (range, if_range) = match if_range_parser.done() {
+            Ok(if_range) => (range, if_range),
+            // can't match, so the whole file is sent
+            Err(()) => (None, None),
+        };
+        let mut input = Input {
             config: cfg.clone(),
             mode: mode,
             accept_encoding: ae_parser.done(),
+            accept: accept_parser.done(),
+            charset_acceptable: charset_parser.map(|p| p.done())
+                .unwrap_or(true),
             range: range,
-            if_range: None,
-            if_match: Vec::new(),
+            range_error,
+            if_match: match_parser.done(),
             if_none: none_match_parser.done(),
-            if_unmodified: None,
+            if_unmodified: unmodified_parser.done(),
             if_modified: modified_parser.done(),
+            explain: Recorder::new(cfg.explain),
+            overrides: None,
+        };
+        if oversized.is_some() {
+            input.overrides_mut().oversized = oversized;
+        }
+        if if_range.is_some() {
+            input.overrides_mut().if_range = if_range;
+        }
+        input
+    }
+    /// Returns the reason why `Range` header is invalid
+    ///
+    /// This is returned both if request is responded with
+    /// `Output::InvalidRange` and if the header is ignored (see
+    /// `Config::ignore_invalid_range` and `Config::max_ranges`).
+    pub fn range_error(&self) -> Option<RangeError> {
+        self.range_error
+    }
+    /// Returns explanation of the output of the last `probe_*` call
+    ///
+    /// This is `None` unless `Config::explain` is enabled. Unlike
+    /// `Output::explanation` it's available for outputs without a head
+    /// too (e.g. `NotFound`).
+    pub fn explanation(&self) -> Option<Explanation> {
+        self.explain.get()
+    }
+    /// Send files with this `Content-Type` (for this request only)
+    ///
+    /// Charset is added the same way as for files of this type. Note:
+    /// encoded variants of the file are still looked up according to the
+    /// type guessed from the file name.
+    pub fn override_mime(&mut self, mime: &'static str) -> &mut Self {
+        self.overrides_mut().mime = Some(mime);
+        self
+    }
+    /// Don't send `ETag` (for this request only)
+    ///
+    /// `If-Match` and `If-None-Match` headers are ignored, as if etags are
+    /// disabled in config.
+    pub fn disable_etag(&mut self) -> &mut Self {
+        {
+            let overrides = self.overrides_mut();
+            overrides.no_etag = true;
+            if overrides.oversized == Some("If-Match") {
+                overrides.oversized = None;
+            }
         }
+        self.if_match = None;
+        self.if_none.clear();
+        self
+    }
+    /// Make browser save the file as `name` instead of displaying it
+    ///
+    /// This adds `Content-Disposition: attachment` header (for this request
+    /// only), which replaces the one from `Config::header_rules` if any.
+    /// Non-ASCII names are sent in the `filename*` parameter.
+    pub fn force_download(&mut self, name: &str) -> &mut Self {
+        let value = content_disposition(name);
+        self.overrides_mut().disposition =
+            Some((Arc::from("Content-Disposition"), Arc::from(value)));
+        self
+    }
+    /// Serve the variant with this encoding regardless of
+    /// `Accept-Encoding`
+    ///
+    /// If there is no such variant of the file, the file itself is served
+    /// (as if only `encoding` and identity are accepted). This is useful
+    /// when the client is known to support the encoding.
+    ///
+    /// Does nothing if encodings are disabled by `Config::no_encodings`.
+    pub fn force_encoding(&mut self, encoding: Encoding) -> &mut Self {
+        if self.config.encoding_support != EncodingSupport::Never {
+            self.accept_encoding = AcceptEncoding::from_list(&[encoding]);
+        }
+        self
+    }
+    /// Never serve encoded variants of the file (for this request only)
+    ///
+    /// This is useful for routes where bytes of the file itself must be
+    /// served, e.g. videos requested by ranges.
+    pub fn identity_only(&mut self) -> &mut Self {
+        self.accept_encoding = AcceptEncoding::identity();
+        self
+    }
+    /// Set query string of the request (without `?`)
+    ///
+    /// It's only used to preserve the query in redirects if
+    /// `Config::redirect_query` is enabled, and is ignored otherwise.
+    pub fn query(&mut self, query: &str) -> &mut Self {
+        if self.config.redirect_query && !query.is_empty() {
+            self.overrides_mut().query = Some(query.to_string());
+        }
+        self
+    }
+    fn overrides_mut(&mut self) -> &mut Overrides {
+        self.overrides.get_or_insert_with(Default::default)
     }
     /// Iterate over encodings accepted by user-agent in preferred order
-    pub fn encodings(&self) -> EncodingIter {
+    pub fn encodings(&self) -> EncodingIter<'_> {
         self.accept_encoding.iter()
     }
+    /// Returns true if user-agent accepts the encoding
+    ///
+    /// This is useful to compress dynamic responses. Note: only identity
+    /// is accepted if `Config::no_encodings` is set, because the header is
+    /// not parsed then.
+    pub fn accepts(&self, encoding: Encoding) -> bool {
+        self.accept_encoding.accepts(encoding)
+    }
+    /// Returns the encoding most preferred by user-agent
+    ///
+    /// See `accepts` for the caveats.
+    pub fn preferred_encoding(&self) -> Encoding {
+        self.accept_encoding.preferred()
+    }
     /// Open files from filesystem
     ///
     /// **Must be run in disk thread**
     pub fn probe_file<P: AsRef<Path>>(&self, base_path: P)
         -> Result<Output, io::Error>
+    {
+        let fs = StdFs::with_cache(self.config.fd_cache.clone());
+        self.probe_file_in(&fs, base_path)
+    }
+    /// Same as `probe_file` but also returns the directory found
+    ///
+    /// The `DirInfo` is `Some` only for `Output::Directory`, and has the
+    /// path and metadata of the directory for making a listing or a
+    /// redirect without probing it again.
+    ///
+    /// **Must be run in disk thread**
+    pub fn probe_file_dir<P: AsRef<Path>>(&self, base_path: P)
+        -> Result<(Output, Option<DirInfo>), io::Error>
+    {
+        let fs = StdFs::with_cache(self.config.fd_cache.clone());
+        self.probe_file_dir_in(&fs, base_path)
+    }
+    /// Open files from the specified (virtual) filesystem
+    ///
+    /// This is the same as `probe_file` but works with any implementation
+    /// of `FileSystem`. Note: `Config::fd_cache` is only used by
+    /// `probe_file`, but other caches are keyed by path, so don't share
+    /// a config with caches enabled between different filesystems.
+    ///
+    /// **Must be run in disk thread** (unless filesystem is in memory)
+    pub fn probe_file_in<FS, P>(&self, fs: &FS, base_path: P)
+        -> Result<Output<FS::File>, io::Error>
+        where FS: FileSystem, P: AsRef<Path>,
+    {
+        self.probe_file_dir_in(fs, base_path).map(|(output, _)| output)
+    }
+    /// Same as `probe_file_dir` but for the specified filesystem
+    pub fn probe_file_dir_in<FS, P>(&self, fs: &FS, base_path: P)
+        -> Result<(Output<FS::File>, Option<DirInfo>), io::Error>
+        where FS: FileSystem, P: AsRef<Path>,
+    {
+        enter_span!("probe_file", path = ?base_path.as_ref(),
+                    mode = ?self.mode);
+        self.explain.reset();
+        let mut dir = None;
+        let output = self.report(
+            self.probe_base(fs, base_path.as_ref(), &mut dir))?;
+        match output {
+            Output::Directory => Ok((output, dir)),
+            _ => Ok((output, None)),
+        }
+    }
+    fn probe_base<FS: FileSystem>(&self, fs: &FS, base_path: &Path,
+        dir: &mut Option<DirInfo>)
+        -> Result<Output<FS::File>, io::Error>
+    {
+        let mut output = self.probe_redirected(fs, base_path, dir)?;
+        if let Some((ref root, ref rules)) = self.config.header_rules {
+            if let Some(head) = output.head_mut() {
+                if let Some(url) = rules::url_path(root, base_path) {
+                    let mut headers = rules.matching(&url);
+                    let overridden = &head.data.extra_headers;
+                    headers.retain(|(name, _)| {
+                        !overridden.iter()
+                            .any(|(x, _)| x.eq_ignore_ascii_case(name))
+                    });
+                    headers.extend(overridden.iter().cloned());
+                    head.data.extra_headers = headers;
+                }
+            }
+        }
+        Ok(output)
+    }
+    fn probe_redirected<FS: FileSystem>(&self, fs: &FS, base_path: &Path,
+        dir: &mut Option<DirInfo>)
+        -> Result<Output<FS::File>, io::Error>
+    {
+        let found = match self.config.redirect_rules {
+            Some((ref root, ref rules))
+            if self.mode == Mode::Get || self.mode == Mode::Head
+            => {
+                let status = self.config.redirect_status;
+                rules::url_path(root, base_path)
+                    .and_then(|url| rules.find(&url, status))
+                    .map(|found| (root, found))
+            }
+            _ => None,
+        };
+        match found {
+            None => self.probe_overridden(fs, base_path, dir),
+            Some((_, Redirect::Redirect(status, mut location))) => {
+                let query = self.overrides.as_ref()
+                    .and_then(|x| x.query.as_ref());
+                if let Some(query) = query {
+                    let sep = if location.contains('?') { '&' } else { '?' };
+                    location.push(sep);
+                    location.push_str(query);
+                }
+                debug!(status, location = &location[..], "redirect");
+                Ok(Output::Redirect(status, location))
+            }
+            Some((root, Redirect::Rewrite(target, force))) => {
+                if !force {
+                    match self.probe_overridden(fs, base_path, dir)? {
+                        Output::NotFound => {}
+                        output => return Ok(output),
+                    }
+                }
+                debug!(target = &target[..], "rewrite");
+                match path::resolve(root, &target) {
+                    Ok(path) => self.probe_overridden(fs, &path, dir),
+                    Err(_) => Ok(Output::NotFound),
+                }
+            }
+        }
+    }
+    #[cfg(feature="serde")]
+    fn probe_overridden<FS: FileSystem>(&self, fs: &FS, base_path: &Path,
+        dir: &mut Option<DirInfo>)
+        -> Result<Output<FS::File>, io::Error>
+    {
+        let overrides = match self.config.dir_overrides {
+            Some(ref overrides) => overrides,
+            None => return self.probe_checked(fs, base_path, dir),
+        };
+        if overrides.is_override_file(base_path) {
+            return Ok(Output::NotFound);
+        }
+        match overrides.config_for(fs, &self.config, base_path)? {
+            Some(config) => {
+                let inp = Input { config, ..self.clone() };
+                let result = inp.probe_checked(fs, base_path, dir);
+                self.explain.replace(&inp.explain);
+                result
+            }
+            None => self.probe_checked(fs, base_path, dir),
+        }
+    }
+    #[cfg(not(feature="serde"))]
+    fn probe_overridden<FS: FileSystem>(&self, fs: &FS, base_path: &Path,
+        dir: &mut Option<DirInfo>)
+        -> Result<Output<FS::File>, io::Error>
+    {
+        self.probe_checked(fs, base_path, dir)
+    }
+    fn probe_checked<FS: FileSystem>(&self, fs: &FS, base_path: &Path,
+        dir: &mut Option<DirInfo>)
+        -> Result<Output<FS::File>, io::Error>
     {
         match self.mode {
             Mode::Head | Mode::Get => {}
             Mode::InvalidMethod => return Ok(Output::InvalidMethod),
             Mode::InvalidRange => return Ok(Output::InvalidRange),
         }
-        let base_path = base_path.as_ref();
-        match base_path.metadata() {
-            Ok(ref m) if m.is_dir() => self.try_dir(base_path),
-            Ok(_) => self.try_file(base_path),
+        if self.config.deny_hidden && is_hidden(base_path) {
+            debug!("hidden path is denied");
+            return Ok(Output::NotFound);
+        }
+        if cfg!(windows) && is_unsafe_on_windows(base_path) {
+            debug!("path is unsafe on windows");
+            return Ok(Output::NotFound);
+        }
+        if self.exceeds_limits(base_path) {
+            debug!("path exceeds limits");
+            return Ok(Output::NotFound);
+        }
+        if self.negotiates(base_path) {
+            return self.probe_negotiated(fs, base_path, dir);
+        }
+        self.probe_path(fs, base_path, dir)
+    }
+    /// Make output for a file that is already opened by the caller
+    ///
+    /// This is useful when files are opened with `openat` for sandboxing,
+    /// or passed from another process. The file is served as is, with the
+    /// specified content type, no index files or encodings are looked up.
+    ///
+    /// **Must be run in disk thread**
+    pub fn probe_open_file(&self, file: File, content_type: &str)
+        -> Result<Output, io::Error>
+    {
+        enter_span!("probe_open_file", content_type = content_type,
+                    mode = ?self.mode);
+        self.explain.reset();
+        self.report(self.probe_opened(file, content_type))
+    }
+    fn probe_opened(&self, file: File, content_type: &str)
+        -> Result<Output, io::Error>
+    {
+        match self.mode {
+            Mode::Head | Mode::Get => {}
+            Mode::InvalidMethod => return Ok(Output::InvalidMethod),
+            Mode::InvalidRange => return Ok(Output::InvalidRange),
+        }
+        let meta = file.metadata()?;
+        if !meta.is_file() {
+            return Err(io::ErrorKind::PermissionDenied.into());
+        }
+        let mut info = FileInfo::with_content(&self.config,
+                                              &(&meta).into(), &file)?;
+        if self.sniffs(content_type) {
+            info.charset = charset::sniff(&file)?;
+        }
+        let mime = Mime::Shared(content_type.into());
+        let head = match Head::from_meta(self, Encoding::Identity,
+                                         &info, &mime)
+        {
+            Err(output) => return Ok(output),
+            Ok(head) => head,
+        };
+        self.respond(head, file)
+    }
+    /// Try several base directories in order and return the first hit
+    ///
+    /// This is useful for layouts like "theme overrides base assets".
+    /// Directories (without index files) are not a hit, so a file from the
+    /// later path is preferred to a directory in the earlier one.
+    ///
+    /// **Must be run in disk thread**
+    pub fn probe_file_multi<P: AsRef<Path>>(&self, base_paths: &[P])
+        -> Result<Output, io::Error>
+    {
+        let fs = StdFs::with_cache(self.config.fd_cache.clone());
+        self.probe_file_multi_in(&fs, base_paths)
+    }
+    /// Same as `probe_file_multi` but for the specified filesystem
+    pub fn probe_file_multi_in<FS, P>(&self, fs: &FS, base_paths: &[P])
+        -> Result<Output<FS::File>, io::Error>
+        where FS: FileSystem, P: AsRef<Path>,
+    {
+        enter_span!("probe_file_multi", mode = ?self.mode);
+        self.explain.reset();
+        let mut result = Output::NotFound;
+        for path in base_paths {
+            enter_span!("base_path", path = ?path.as_ref());
+            match self.probe_base(fs, path.as_ref(), &mut None)? {
+                Output::NotFound => {}
+                Output::Directory => result = Output::Directory,
+                output => return self.report(Ok(output)),
+            }
+        }
+        self.report(Ok(result))
+    }
+    /// Pass the result to `Config::metrics` (and tracing), and attach
+    /// explanation to it
+    pub(crate) fn report<F: ReadAt>(&self,
+        mut result: Result<Output<F>, io::Error>)
+        -> Result<Output<F>, io::Error>
+    {
+        if let Ok(ref mut output) = result {
+            if let Some(head) = output.head_mut() {
+                head.data.explanation = self.explain.get();
+            }
+        }
+        #[cfg(feature="tracing")]
+        match result {
+            Ok(ref output) => {
+                debug!(variant = output.variant_name(),
+                       status = output.suggested_status(),
+                       encoding = ?output.encoding(), "probed");
+            }
+            Err(ref e) => {
+                debug!(error = %e, "probe failed");
+            }
+        }
+        if let (Some(ref metrics), Ok(output)) =
+            (&self.config.metrics, &result)
+        {
+            metrics.probed(output.variant_name(), output.suggested_status(),
+                           output.encoding());
+        }
+        result
+    }
+    fn probe_path<FS: FileSystem>(&self, fs: &FS, path: &Path,
+        dir: &mut Option<DirInfo>)
+        -> Result<Output<FS::File>, io::Error>
+    {
+        if let Some(ref cache) = self.config.not_found_cache {
+            if cache.get(path).is_some() {
+                debug!(path = ?path, "not found (cached)");
+                return Ok(Output::NotFound);
+            }
+        }
+        let result = self.probe_any(fs, path, dir);
+        if let Some(ref cache) = self.config.not_found_cache {
+            if let Ok(Output::NotFound) = result {
+                cache.insert(path, ());
+            }
+        }
+        result
+    }
+    fn probe_any<FS: FileSystem>(&self, fs: &FS, path: &Path,
+        dir: &mut Option<DirInfo>)
+        -> Result<Output<FS::File>, io::Error>
+    {
+        // result depends on accept-encoding but is not cached as a variant
+        if let Some((_, inner)) = self.encoded_file(path) {
+            if self.serves_inner(&self.content_type(&inner)) {
+                return self.probe_uncached(fs, path, dir);
+            }
+        }
+        match self.config.head_cache {
+            Some(ref cache) => self.probe_cached(fs, cache, path, dir),
+            None => self.probe_uncached(fs, path, dir),
+        }
+    }
+    /// Returns encoding and path of the inner file if the file at `path`
+    /// is an encoded variant (by suffix)
+    fn encoded_file(&self, path: &Path) -> Option<(Encoding, PathBuf)> {
+        let name = path.file_name()?.to_str()?;
+        let suffixes = [
+            (Encoding::Brotli, &self.config.brotli_suffix),
+            (Encoding::Gzip, &self.config.gzip_suffix),
+        ];
+        for &(enc, suffix) in &suffixes {
+            if !suffix.is_empty() && name.len() > suffix.len() &&
+                name.ends_with(&suffix[..])
+            {
+                let inner = &name[..name.len() - suffix.len()];
+                return Some((enc, path.with_file_name(inner)));
+            }
+        }
+        None
+    }
+    /// Returns true if encoded file with the inner file of `ctype` is
+    /// served with `Config::encoded_inner_type`
+    ///
+    /// Only compressible types are, as other files (e.g. `.tar.gz`) are
+    /// compressed to be downloaded as is.
+    fn serves_inner(&self, ctype: &str) -> bool {
+        self.config.encoded_inner_type && self.config.is_compressible(ctype)
+    }
+    fn negotiates_index(&self) -> bool {
+        self.config.negotiate_index && self.config.index_files.len() > 1
+    }
+    /// Index file names in the order they should be probed
+    fn index_files(&self) -> Vec<&str> {
+        let mut names = self.config.index_files.iter()
+            .map(|x| &x[..])
+            .collect::<Vec<_>>();
+        if self.negotiates_index() {
+            // sort is stable, so config order is kept for equal quality
+            names.sort_by_key(|name| {
+                1000 - self.accept.quality(&self.content_type(Path::new(name)))
+            });
+        }
+        names
+    }
+    fn negotiates(&self, path: &Path) -> bool {
+        !self.config.negotiate_types.is_empty() &&
+            path.file_name().is_some() && path.extension().is_none()
+    }
+    /// Probe the path itself, then path with extensions of acceptable
+    /// media types
+    ///
+    /// The path itself is not put into the `not_found_cache` because
+    /// result depends on the `Accept` header.
+    fn probe_negotiated<FS: FileSystem>(&self, fs: &FS, base_path: &Path,
+        dir: &mut Option<DirInfo>)
+        -> Result<Output<FS::File>, io::Error>
+    {
+        match self.probe_any(fs, base_path, dir)? {
+            Output::NotFound => {}
+            output => return Ok(output),
+        }
+        let base = base_path.as_os_str();
+        let mut buf = OsString::with_capacity(base.len() + 8);
+        for ext in self.accept.extensions(&self.config.negotiate_types) {
+            buf.clear();
+            buf.push(base);
+            buf.push(".");
+            buf.push(ext);
+            let path = Path::new(&buf);
+            match self.probe_path(fs, path, dir)? {
+                Output::NotFound => continue,
+                output => {
+                    let location = path.file_name()
+                        .expect("path has a file name")
+                        .to_string_lossy().into_owned();
+                    return Ok(output.negotiated(location));
+                }
+            }
+        }
+        Ok(Output::NotFound)
+    }
+    fn probe_uncached<FS: FileSystem>(&self, fs: &FS, base_path: &Path,
+        dir: &mut Option<DirInfo>)
+        -> Result<Output<FS::File>, io::Error>
+    {
+        match fs.metadata(base_path) {
+            Ok(m) if m.is_dir() => self.try_dir(fs, base_path, m, dir),
+            Ok(ref m) => self.try_file(fs, base_path, Some(m.len())),
             Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                self.explain.probed(base_path);
                 return Ok(Output::NotFound);
             }
             Err(e) => return Err(e),
         }
     }
-    fn try_dir(&self, base_path: &Path) -> Result<Output, io::Error> {
+    fn find_index<FS: FileSystem>(&self, fs: &FS, base_path: &Path)
+        -> Option<PathBuf>
+    {
         let mut buf = base_path.to_path_buf();
-        for name in &self.config.index_files {
+        for name in self.index_files() {
             buf.push(name);
-            if buf.exists() {
-                return self.try_file(&buf);
+            self.explain.probed(&buf);
+            if fs.metadata(&buf).is_ok() {
+                return Some(buf);
             }
             buf.pop();
         }
-        Ok(Output::Directory)
+        None
+    }
+    fn directory<F: ReadAt>(&self, path: &Path, meta: Metadata,
+        dir: &mut Option<DirInfo>)
+        -> Output<F>
+    {
+        *dir = Some(DirInfo::new(path, meta));
+        Output::Directory
     }
-    fn try_file(&self, base_path: &Path) -> Result<Output, io::Error> {
+    fn try_dir<FS: FileSystem>(&self, fs: &FS, base_path: &Path,
+        meta: Metadata, dir: &mut Option<DirInfo>)
+        -> Result<Output<FS::File>, io::Error>
+    {
+        match self.find_index(fs, base_path) {
+            Some(path) if self.negotiates_index() => {
+                Ok(self.try_file(fs, &path, None)?.vary_accept())
+            }
+            Some(path) => self.try_file(fs, &path, None),
+            None => Ok(self.directory(base_path, meta, dir)),
+        }
+    }
+    /// Finds mime type by the longest matching suffix of the file name
+    ///
+    /// E.g. for `app.min.js.map` it tries `min.js.map`, `js.map`, then
+    /// `map`. Overrides from config take precedence for the same suffix.
+    fn content_type(&self, path: &Path) -> Mime {
+        const DEFAULT: Mime = Mime::Static("application/octed-stream");
+        let name = match path.file_name().and_then(|x| x.to_str()) {
+            Some(name) => name,
+            None => return DEFAULT,
+        };
+        let dots = name.char_indices().filter(|&(i, c)| c == '.' && i > 0);
+        for (idx, _) in dots {
+            let ext = &name[idx+1..];
+            let found = self.config.mime_overrides.iter()
+                .find(|&(x, _)| x.eq_ignore_ascii_case(ext))
+                .map(|(_, mime)| mime);
+            if let Some(mime) = found {
+                return Mime::Shared(mime.clone());
+            }
+            if ext.contains('.') {
+                let found = COMPOUND_TYPES.iter()
+                    .find(|&&(x, _)| x.eq_ignore_ascii_case(ext));
+                if let Some(&(_, mime)) = found {
+                    return Mime::Static(mime);
+                }
+            } else if ext.eq_ignore_ascii_case("gz") {
+                // registered type (RFC 6713) rather than `x-gzip`
+                return Mime::Static("application/gzip");
+            } else {
+                return get_mime_type_str(ext).map(Mime::Static)
+                    .unwrap_or(DEFAULT);
+            }
+        }
+        DEFAULT
+    }
+    fn encodings_enabled(&self, ctype: &str) -> bool {
         use config::EncodingSupport as E;
-        let ctype = base_path.extension()
-            .and_then(|x| x.to_str())
-            .and_then(|x| get_mime_type_str(x))
-            .unwrap_or("application/octed-stream");
-        let encodings = match self.config.encoding_support {
+        match self.config.encoding_support {
             E::Never => false,
-            E::TextFiles => is_text_file(ctype),
+            E::Types => self.config.is_compressible(ctype),
             E::AllFiles => true,
+        }
+    }
+    /// Returns true if any of the extensions of the file is denied
+    pub(crate) fn is_denied(&self, path: &Path) -> bool {
+        let denied = &self.config.denied_extensions;
+        if denied.is_empty() {
+            return false;
+        }
+        let name = match path.file_name().and_then(|x| x.to_str()) {
+            Some(name) => name,
+            None => return false,
         };
-        if encodings {
-            return self.try_encodings(base_path, ctype);
+        name.split('.').skip(1)
+            .any(|ext| denied.iter().any(|x| x.eq_ignore_ascii_case(ext)))
+    }
+    fn exceeds_limits(&self, path: &Path) -> bool {
+        if let Some(max) = self.config.max_path_length {
+            if path.as_os_str().len() > max {
+                return true;
+            }
+        }
+        if let Some(max) = self.config.max_path_components {
+            if path.components().count() > max {
+                return true;
+            }
+        }
+        false
+    }
+    fn try_file<FS: FileSystem>(&self, fs: &FS, base_path: &Path,
+        size: Option<u64>)
+        -> Result<Output<FS::File>, io::Error>
+    {
+        if self.is_denied(base_path) {
+            debug!(path = ?base_path, "extension is denied");
+            return Ok(Output::Forbidden);
+        }
+        if let Some((enc, inner)) = self.encoded_file(base_path) {
+            let ctype = self.content_type(&inner);
+            if self.serves_inner(&ctype) && self.accepts(enc) {
+                return self.try_path(fs, &inner, base_path, enc, &ctype);
+            }
+            let ctype = self.content_type(base_path);
+            return self.try_path(fs, base_path, base_path,
+                                 Encoding::Identity, &ctype);
+        }
+        let ctype = self.content_type(base_path);
+        if self.encodings_enabled(&ctype) && !self.config.is_plain(base_path)
+        {
+            self.try_encodings(fs, base_path, size, &ctype)
         } else {
-            return self.try_path(base_path, Encoding::Identity, ctype);
+            self.try_path(fs, base_path, base_path,
+                          Encoding::Identity, &ctype)
         }
     }
 
-    fn try_path(&self, path: &Path, enc: Encoding, ctype: &'static str)
-        -> Result<Output, io::Error>
+    /// Returns true if file is outside of `Config::restrict_to_root`
+    pub(crate) fn escapes_root<FS: FileSystem>(&self, fs: &FS, path: &Path)
+        -> Result<bool, io::Error>
+    {
+        let root = match self.config.root {
+            Some(Ok(ref root)) => root,
+            // not the original kind, so it's not mistaken for a missing file
+            Some(Err(kind)) => {
+                return Err(io::Error::other(format!(
+                    "can't resolve root of `restrict_to_root`: {:?}", kind)));
+            }
+            None => return Ok(false),
+        };
+        match fs.canonicalize(path)? {
+            Some(real) => Ok(!real.starts_with(root)),
+            None => Ok(false),
+        }
+    }
+
+    /// Returns the value of `Content-Location` if enabled
+    fn location(&self, path: &Path) -> Option<String> {
+        if !self.config.content_location {
+            return None;
+        }
+        path.file_name().map(|x| x.to_string_lossy().into_owned())
+    }
+
+    fn sniffs(&self, ctype: &str) -> bool {
+        self.config.sniff_charset && self.config.text_charset.is_some() &&
+            self.config.has_charset(ctype)
+    }
+
+    /// Detect charset of the (unencoded) file at path if enabled
+    fn sniff_path<FS: FileSystem>(&self, fs: &FS, path: &Path,
+        ctype: &str)
+        -> Result<Option<&'static str>, io::Error>
+    {
+        if !self.sniffs(ctype) {
+            return Ok(None);
+        }
+        match self.escapes_root(fs, path) {
+            Ok(false) => {}
+            Ok(true) => return Ok(None),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                return Ok(None);
+            }
+            Err(e) => return Err(e),
+        }
+        match fs.open(path) {
+            Ok((f, _)) => charset::sniff(&f),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Try the variant at `path` of the file at `base_path`
+    fn try_path<FS: FileSystem>(&self, fs: &FS, base_path: &Path,
+        path: &Path, enc: Encoding, ctype: &Mime)
+        -> Result<Output<FS::File>, io::Error>
+    {
+        let output = self.open_path(fs, base_path, path, enc, ctype)?;
+        Ok(output.with_location(self.location(path)))
+    }
+
+    fn open_path<FS: FileSystem>(&self, fs: &FS, base_path: &Path,
+        path: &Path, enc: Encoding, ctype: &Mime)
+        -> Result<Output<FS::File>, io::Error>
     {
-        let f = File::open(path)?;
-        let meta = f.metadata()?;
+        self.explain.probed(path);
+        // checked before opening, as opening a FIFO or a device outside
+        // of the root may have side effects or block
+        if self.escapes_root(fs, path)? {
+            debug!(path = ?path, "file is outside of the root");
+            return Ok(Output::Forbidden);
+        }
+        let (f, meta) = fs.open(path)?;
+        debug!(path = ?path, encoding = ?enc, content_type = &**ctype,
+               "variant selected");
+        self.explain.selected(path, enc);
         if !meta.is_file() {
             return Err(io::ErrorKind::PermissionDenied.into());
         }
-        let head = match Head::from_meta(self, enc, &meta, ctype) {
+        let mut info = FileInfo::with_content(&self.config, &meta, &f)?;
+        if enc == Encoding::Identity && self.sniffs(ctype) {
+            info.charset = charset::sniff(&f)?;
+        } else {
+            info.charset = self.sniff_path(fs, base_path, ctype)?;
+        }
+        let head = match Head::from_meta(self, enc, &info, ctype) {
             Err(output) => return Ok(output),
             Ok(head) => head,
         };
+        if self.delegates() {
+            return Ok(Output::Delegate(head, path.to_path_buf()));
+        }
+        self.respond(head, f)
+    }
+
+    /// Returns true if brotli variant isn't used for a file of this size
+    fn skips_brotli(&self, size: u64) -> bool {
+        size < self.config.brotli_min_size
+    }
+
+    fn delegates(&self) -> bool {
+        self.mode == Mode::Get && self.config.delegate_header.is_some()
+    }
+
+    pub(crate) fn respond<F: ReadAt>(&self, head: Head, f: F)
+        -> Result<Output<F>, io::Error>
+    {
         match self.mode {
             Mode::InvalidMethod => unreachable!(),
             Mode::InvalidRange => unreachable!(),
             Mode::Head => Ok(Output::FileHead(head)),
-            Mode::Get => Ok(Output::File(FileWrapper::new(head, f)?)),
+            Mode::Get => {
+                let mut wrapper = FileWrapper::new(head, f)?;
+                match self.config.inline_threshold {
+                    // in tail mode the file may be not written yet, and
+                    // the wrapper knows how to wait for the rest
+                    Some(limit) if wrapper.bytes_left() <= limit &&
+                                   !self.config.tail_mode
+                    => {
+                        // sent at once by the caller, so not throttled
+                        wrapper.set_rate_limit(None);
+                        let data = wrapper.read_all(limit)?;
+                        Ok(Output::FileInMemory(wrapper.into_head(), data))
+                    }
+                    _ => Ok(Output::File(wrapper)),
+                }
+            }
         }
     }
 
-    fn try_encodings(&self, base_path: &Path, ctype: &'static str)
-        -> Result<Output, io::Error>
+    fn try_encodings<FS: FileSystem>(&self, fs: &FS, base_path: &Path,
+        size: Option<u64>, ctype: &Mime)
+        -> Result<Output<FS::File>, io::Error>
     {
-        let path = base_path.as_os_str();
-        let mut buf = OsString::with_capacity(path.len() + 3);
         for enc in self.encodings() {
-            buf.clear();
-            buf.push(path);
-            buf.push(enc.suffix());
-            let path = Path::new(&buf);
-            match self.try_path(&path, enc, ctype) {
+            if enc == Encoding::Brotli && self.config.brotli_min_size > 0 {
+                let size = match size {
+                    Some(size) => size,
+                    None => fs.metadata(base_path)?.len(),
+                };
+                if self.skips_brotli(size) {
+                    trace!(size = size, "file is too small for brotli");
+                    continue;
+                }
+            }
+            let path = self.config.variant_path(base_path, enc);
+            match self.try_path(fs, base_path, &path, enc, ctype) {
                 Ok(x) => return Ok(x),
                 Err(ref e) if e.kind() == io::ErrorKind::NotFound
-                => continue,
+                => {
+                    trace!(path = ?path, encoding = ?enc, "no variant");
+                    continue;
+                }
                 Err(e) => return Err(e),
             }
         }
@@ -208,12 +1105,164 @@ impl Input {
         // we are looking for encodings
         Ok(Output::NotFound)
     }
+
+    /// Find the file and all encoded variants of it
+    ///
+    /// Returns `Err(output)` for things that are not cached
+    fn resolve<FS: FileSystem>(&self, fs: &FS, base_path: &Path,
+        dir: &mut Option<DirInfo>)
+        -> Result<Result<Resolved, Output<FS::File>>, io::Error>
+    {
+        use accept_encoding::Encoding::*;
+        let path = match fs.metadata(base_path) {
+            // choice depends on `Accept`, so only the index file is cached
+            Ok(m) if m.is_dir() && self.negotiates_index() => {
+                let output = match self.find_index(fs, base_path) {
+                    Some(path) => {
+                        self.probe_path(fs, &path, dir)?.vary_accept()
+                    }
+                    None => self.directory(base_path, m, dir),
+                };
+                return Ok(Err(output));
+            }
+            Ok(m) if m.is_dir() => match self.find_index(fs, base_path) {
+                Some(path) => path,
+                None => return Ok(Err(self.directory(base_path, m, dir))),
+            },
+            Ok(_) => base_path.to_path_buf(),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                self.explain.probed(base_path);
+                return Ok(Err(Output::NotFound));
+            }
+            Err(e) => return Err(e),
+        };
+        if self.is_denied(&path) {
+            return Ok(Err(Output::Forbidden));
+        }
+        let ctype = self.content_type(&path);
+        let encodings: &[Encoding] = if self.encoded_file(&path).is_some() {
+            &[Identity]
+        } else if self.encodings_enabled(&ctype) &&
+            !self.config.is_plain(&path)
+        {
+            &[Brotli, Gzip, Identity]
+        } else {
+            &[Identity]
+        };
+        let mut variants = Vec::new();
+        for &enc in encodings {
+            let vpath = self.config.variant_path(&path, enc);
+            self.explain.probed(&vpath);
+            match fs.metadata(&vpath) {
+                Ok(ref m) if m.is_file() => {
+                    if self.escapes_root(fs, &vpath)? {
+                        return Ok(Err(Output::Forbidden));
+                    }
+                    let info = if self.config.strong_etag.is_some() {
+                        let (f, ref meta) = fs.open(&vpath)?;
+                        FileInfo::with_content(&self.config, meta, &f)?
+                    } else {
+                        FileInfo::new(&self.config, m)
+                    };
+                    variants.push(Variant {
+                        encoding: enc,
+                        info,
+                        path: vpath,
+                    });
+                }
+                Ok(_) => {}
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+        }
+        let charset = self.sniff_path(fs, &path, &ctype)?;
+        for var in &mut variants {
+            var.info.charset = charset;
+        }
+        Ok(Ok(Resolved { ctype, variants }))
+    }
+
+    fn probe_cached<FS: FileSystem>(&self, fs: &FS, cache: &HeadCache,
+        base_path: &Path, dir: &mut Option<DirInfo>)
+        -> Result<Output<FS::File>, io::Error>
+    {
+        let entry = match cache.get(base_path) {
+            Some(entry) => entry,
+            None => match self.resolve(fs, base_path, dir)? {
+                Ok(resolved) => {
+                    let resolved = Arc::new(resolved);
+                    cache.insert(base_path, resolved.clone());
+                    resolved
+                }
+                Err(output) => return Ok(output),
+            },
+        };
+        let identity = entry.variants.iter()
+            .find(|v| v.encoding == Encoding::Identity);
+        for enc in self.encodings() {
+            let var = match entry.variants.iter().find(|v| v.encoding == enc) {
+                Some(var) => var,
+                None => {
+                    trace!(encoding = ?enc, "no variant (cached)");
+                    continue;
+                }
+            };
+            match identity {
+                Some(orig) if enc == Encoding::Brotli &&
+                    self.skips_brotli(orig.info.size)
+                => {
+                    trace!(size = orig.info.size,
+                           "file is too small for brotli (cached)");
+                    continue;
+                }
+                _ => {}
+            }
+            debug!(path = ?var.path, encoding = ?enc,
+                   content_type = &*entry.ctype, "variant selected (cached)");
+            self.explain.selected(&var.path, enc);
+            let location = self.location(&var.path);
+            let mut head =
+                match Head::from_meta(self, enc, &var.info, &entry.ctype)
+            {
+                Err(output) => return Ok(output.with_location(location)),
+                Ok(head) => head,
+            };
+            head.data.location = location;
+            if self.mode == Mode::Head {
+                return Ok(Output::FileHead(head));
+            }
+            if self.delegates() {
+                return Ok(Output::Delegate(head, var.path.clone()));
+            }
+            let f = match fs.open(&var.path) {
+                Ok((f, ref meta))
+                if FileInfo::with_content(&self.config, meta, &f)?
+                    .same_file(&var.info)
+                => f,
+                // file is changed since it's cached
+                Ok(_) => {
+                    debug!(path = ?var.path, "cached head is stale");
+                    cache.remove(base_path);
+                    return self.probe_uncached(fs, base_path, dir);
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                    debug!(path = ?var.path, "cached file is removed");
+                    cache.remove(base_path);
+                    return self.probe_uncached(fs, base_path, dir);
+                }
+                Err(e) => return Err(e),
+            };
+            return self.respond(head, f);
+        }
+        Ok(Output::NotFound)
+    }
 }
 
 #[cfg(test)]
 mod test {
     use std::mem::size_of;
-    use accept_encoding::{AcceptEncodingParser};
+    use accept_encoding::{AcceptEncodingParser, EncodedPath};
+    use metrics::Metrics;
     use super::*;
 
     fn send<T: Send>(_: &T) {}
@@ -225,17 +1274,1053 @@ mod test {
             config: Config::new().done(),
             mode: Mode::Get,
             accept_encoding: AcceptEncodingParser::new().done(),
+            accept: Accept::any(),
+            charset_acceptable: true,
             range: None,
-            if_range: None,
-            if_match: Vec::new(),
+            range_error: None,
+            if_match: None,
             if_none: Vec::new(),
             if_unmodified: None,
             if_modified: None,
+            explain: Recorder::new(true),
+            overrides: None,
         };
         send(&v);
         self_contained(&v);
     }
 
+    fn mode(cfg: &Arc<Config>, method: &str) -> Mode {
+        Input::from_headers(cfg, method, vec![].into_iter()).mode
+    }
+
+    #[test]
+    fn methods() {
+        let cfg = Config::new().done();
+        assert_eq!(mode(&cfg, "GET"), Mode::Get);
+        assert_eq!(mode(&cfg, "HEAD"), Mode::Head);
+        assert_eq!(mode(&cfg, "POST"), Mode::InvalidMethod);
+        let cfg = Config::new().allow_method("POST").done();
+        assert_eq!(mode(&cfg, "POST"), Mode::Get);
+        assert_eq!(mode(&cfg, "PUT"), Mode::InvalidMethod);
+    }
+
+    #[test]
+    fn multi() {
+        let cfg = Config::new().done();
+        let inp = Input::from_headers(&cfg, "HEAD", vec![].into_iter());
+        match inp.probe_file_multi(&["public", "public/index.html"]).unwrap() {
+            Output::FileHead(head) => assert_eq!(head.content_length(), 126),
+            _ => unreachable!(),
+        }
+        match inp.probe_file_multi(&["public/a", "public", "public/b"])
+            .unwrap()
+        {
+            Output::Directory => {}
+            _ => unreachable!(),
+        }
+        match inp.probe_file_multi(&["public/a", "public/b"]).unwrap() {
+            Output::NotFound => {}
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn probe_dir() {
+        let cfg = Config::new().done();
+        let inp = Input::from_headers(&cfg, "GET", vec![].into_iter());
+        match inp.probe_file_dir("public").unwrap() {
+            (Output::Directory, Some(dir)) => {
+                assert_eq!(dir.path(), Path::new("public"));
+                assert!(dir.metadata().is_dir());
+            }
+            _ => unreachable!(),
+        }
+        match inp.probe_file_dir("public/index.html").unwrap() {
+            (Output::File(_), None) => {}
+            _ => unreachable!(),
+        }
+        match inp.probe_file_dir("public/nonexistent").unwrap() {
+            (Output::NotFound, None) => {}
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn open_file() {
+        let cfg = Config::new().done();
+        let inp = Input::from_headers(&cfg, "GET",
+            vec![("Range", &b"bytes=0-1"[..])].into_iter());
+        let file = File::open("public/index.html").unwrap();
+        let mime = String::from("text/html");
+        match inp.probe_open_file(file, &mime).unwrap() {
+            Output::File(wrapper) => {
+                assert_eq!(wrapper.content_length(), 2);
+                assert_eq!(wrapper.head().mime(), "text/html");
+                assert!(wrapper.headers().any(|(name, _)| name == "ETag"));
+            }
+            _ => unreachable!(),
+        }
+        let dir = File::open("public").unwrap();
+        assert_eq!(inp.probe_open_file(dir, "text/html").unwrap_err().kind(),
+                   io::ErrorKind::PermissionDenied);
+    }
+
+    #[test]
+    fn hidden() {
+        assert!(is_hidden(Path::new("./public/.git/config")));
+        assert!(is_hidden(Path::new(".env")));
+        assert!(!is_hidden(Path::new("./public/../index.html")));
+        let cfg = Config::new().deny_hidden_files(true).done();
+        let inp = Input::from_headers(&cfg, "HEAD", vec![].into_iter());
+        match inp.probe_file("./public/.hidden").unwrap() {
+            Output::NotFound => {}
+            _ => unreachable!(),
+        }
+        match inp.probe_file("./public/index.html").unwrap() {
+            Output::FileHead(_) => {}
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn strong_etag() {
+        let cfg = Config::new().strong_etag(10).done();
+        let inp = Input::from_headers(&cfg, "HEAD", vec![].into_iter());
+        let etag = match inp.probe_file("./public/index.html").unwrap() {
+            Output::FileHead(head) => {
+                let (_, etag) = head.headers()
+                    .find(|&(name, _)| name == "ETag").unwrap();
+                etag.to_string()
+            }
+            _ => unreachable!(),
+        };
+        assert!(etag.starts_with('"'));
+        let inp = Input::from_headers(&cfg, "GET",
+            vec![("If-None-Match", etag.as_bytes())].into_iter());
+        match inp.probe_file("./public/index.html").unwrap() {
+            Output::NotModified(_) => {}
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn etag_provider() {
+        use etag::EtagProvider;
+        use vfs::Metadata;
+        #[derive(Debug)]
+        struct Size;
+        impl EtagProvider for Size {
+            fn etag(&self, metadata: &Metadata) -> [u8; 12] {
+                let mut value = [0u8; 12];
+                value[11] = metadata.len() as u8;
+                value
+            }
+        }
+        let cfg = Config::new().etag_provider(Size).done();
+        let inp = Input::from_headers(&cfg, "HEAD", vec![].into_iter());
+        match inp.probe_file("./public/index.html").unwrap() {
+            Output::FileHead(head) => {
+                let (_, etag) = head.headers()
+                    .find(|&(name, _)| name == "ETag").unwrap();
+                // 126 bytes is 0x7E
+                assert_eq!(etag.to_string(), r#"W/"AAAAAAAAAAAAAAB-""#);
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn if_match() {
+        let cfg = Config::new().strong_etag(10).done();
+        let inp = Input::from_headers(&cfg, "HEAD", vec![].into_iter());
+        let etag = match inp.probe_file("./public/index.html").unwrap() {
+            Output::FileHead(head) => head.etag().unwrap().to_string(),
+            _ => unreachable!(),
+        };
+        let inp = Input::from_headers(&cfg, "GET",
+            vec![("If-Match", etag.as_bytes())].into_iter());
+        match inp.probe_file("./public/index.html").unwrap() {
+            Output::File(_) => {}
+            _ => unreachable!(),
+        }
+        let inp = Input::from_headers(&cfg, "GET",
+            vec![("If-Match", &br#""AAAAAAAAAAAAAAAA""#[..])].into_iter());
+        match inp.probe_file("./public/index.html").unwrap() {
+            Output::PreconditionFailed(head) => {
+                assert_eq!(head.suggested_status(), 412);
+                assert_eq!(head.etag().unwrap().to_string(), etag);
+                let names = head.headers().map(|(name, _)| name)
+                    .collect::<Vec<_>>();
+                assert_eq!(names, vec!["Last-Modified", "ETag"]);
+            }
+            _ => unreachable!(),
+        }
+        // weak etags never match `If-Match`
+        let cfg = Config::new().done();
+        let inp = Input::from_headers(&cfg, "HEAD", vec![].into_iter());
+        let etag = match inp.probe_file("./public/index.html").unwrap() {
+            Output::FileHead(head) => head.etag().unwrap().to_string(),
+            _ => unreachable!(),
+        };
+        let inp = Input::from_headers(&cfg, "GET",
+            vec![("If-Match", etag.as_bytes())].into_iter());
+        match inp.probe_file("./public/index.html").unwrap() {
+            Output::PreconditionFailed(_) => {}
+            _ => unreachable!(),
+        }
+    }
+
+    fn if_range(cfg: &Arc<Config>, validator: &str) -> u16 {
+        let inp = Input::from_headers(cfg, "GET", vec![
+            ("Range", &b"bytes=2-8"[..]),
+            ("If-Range", validator.as_bytes()),
+        ].into_iter());
+        inp.probe_file("./public/index.html").unwrap().suggested_status()
+    }
+
+    #[test]
+    fn if_range_etag() {
+        let cfg = Config::new().done();
+        let inp = Input::from_headers(&cfg, "HEAD", vec![].into_iter());
+        let (etag, modified) = match inp.probe_file("./public/index.html") {
+            Ok(Output::FileHead(head)) => {
+                let modified = head.headers()
+                    .find(|&(name, _)| name == "Last-Modified").unwrap().1
+                    .to_string();
+                (head.etag().unwrap().to_string(), modified)
+            }
+            _ => unreachable!(),
+        };
+        assert_eq!(if_range(&cfg, &etag), 206);
+        assert_eq!(if_range(&cfg, &modified), 206);
+        assert_eq!(if_range(&cfg, r#"W/"AAAAAAAAAAAAAAAA""#), 200);
+        assert_eq!(if_range(&cfg, "Tue, 22 Aug 2017 20:47:13 GMT"), 200);
+        assert_eq!(if_range(&cfg, "garbage"), 200);
+        let cfg = Config::new().strong_if_range(true).done();
+        assert_eq!(if_range(&cfg, &etag), 200);
+        assert_eq!(if_range(&cfg, &modified), 206);
+    }
+
+    #[test]
+    fn strong_if_range() {
+        let cfg = Config::new().strong_etag(10).strong_if_range(true).done();
+        let inp = Input::from_headers(&cfg, "HEAD", vec![].into_iter());
+        let etag = match inp.probe_file("./public/index.html").unwrap() {
+            Output::FileHead(head) => head.etag().unwrap().to_string(),
+            _ => unreachable!(),
+        };
+        assert_eq!(if_range(&cfg, &etag), 206);
+    }
+
+    fn head_range(cfg: &Arc<Config>, headers: Vec<(&str, &[u8])>) -> Head {
+        let inp = Input::from_headers(cfg, "HEAD", headers.into_iter());
+        match inp.probe_file("./public/index.html").unwrap() {
+            Output::FileHead(head) => head,
+            _ => unreachable!(),
+        }
+    }
+
+    fn content_range(head: &Head) -> Option<String> {
+        head.headers().find(|&(name, _)| name == "Content-Range")
+            .map(|(_, value)| value.to_string())
+    }
+
+    #[test]
+    fn head_with_range() {
+        let plain = Config::new().done();
+        let cached = Config::new()
+            .metadata_cache(::std::time::Duration::new(60, 0), 10).done();
+        for cfg in &[plain, cached] {
+            // second iteration is a cache hit
+            for _ in 0..2 {
+                let head = head_range(cfg,
+                    vec![("Range", &b"bytes=2-8"[..])]);
+                assert!(head.is_partial());
+                assert_eq!(head.suggested_status(), 206);
+                assert_eq!(head.content_length(), 7);
+                assert_eq!(head.byte_range(), Some((2, 8, 126)));
+                assert_eq!(content_range(&head).unwrap(), "bytes 2-8/126");
+
+                let head = head_range(cfg,
+                    vec![("Range", &b"bytes=-26"[..])]);
+                assert!(head.is_partial());
+                assert_eq!(head.content_length(), 26);
+                assert_eq!(content_range(&head).unwrap(),
+                           "bytes 100-125/126");
+
+                let head = head_range(cfg, vec![
+                    ("Range", &b"bytes=0-9"[..]),
+                    ("Accept-Encoding", &b"gzip"[..]),
+                ]);
+                assert!(head.is_partial());
+                assert_eq!(head.content_length(), 10);
+                assert_eq!(content_range(&head).unwrap(), "bytes 0-9/109");
+
+                let head = head_range(cfg, vec![]);
+                assert!(!head.is_partial());
+                assert_eq!(head.suggested_status(), 200);
+                assert_eq!(head.content_length(), 126);
+                assert_eq!(content_range(&head), None);
+            }
+        }
+    }
+
+    #[test]
+    fn range_error() {
+        let cfg = Config::new().done();
+        let inp = Input::from_headers(&cfg, "GET",
+            vec![("Range", &b"bytes=10-1"[..])].into_iter());
+        assert_eq!(inp.range_error(), Some(RangeError::Reversed));
+        match inp.probe_file("./public/index.html").unwrap() {
+            Output::InvalidRange => {}
+            _ => unreachable!(),
+        }
+        let cfg = Config::new().ignore_invalid_range(true).done();
+        let inp = Input::from_headers(&cfg, "GET",
+            vec![("Range", &b"lines=1-10"[..])].into_iter());
+        assert_eq!(inp.range_error(), Some(RangeError::BadUnit));
+        match inp.probe_file("./public/index.html").unwrap() {
+            Output::File(wrapper) => assert!(!wrapper.head().is_partial()),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn max_ranges() {
+        let range = &b"bytes=0-1,2-3,4-5"[..];
+        let cfg = Config::new().max_ranges(2).done();
+        let inp = Input::from_headers(&cfg, "GET",
+            vec![("Range", range)].into_iter());
+        match inp.probe_file("./public/index.html").unwrap() {
+            Output::File(wrapper) => assert!(!wrapper.head().is_partial()),
+            _ => unreachable!(),
+        }
+        let cfg = Config::new().max_ranges(2)
+            .reject_excess_ranges(true).done();
+        let inp = Input::from_headers(&cfg, "GET",
+            vec![("Range", range)].into_iter());
+        match inp.probe_file("./public/index.html").unwrap() {
+            Output::InvalidRange => {}
+            _ => unreachable!(),
+        }
+        let cfg = Config::new().max_ranges(3).done();
+        let inp = Input::from_headers(&cfg, "GET",
+            vec![("Range", range)].into_iter());
+        match inp.probe_file("./public/index.html").unwrap() {
+            Output::File(wrapper) => {
+                assert_eq!(wrapper.head().byte_range(), Some((0, 5, 126)));
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn if_unmodified_since() {
+        let cfg = Config::new().done();
+        let inp = Input::from_headers(&cfg, "GET", vec![
+            ("If-Unmodified-Since", &b"Tue, 22 Aug 2017 20:47:13 GMT"[..]),
+        ].into_iter());
+        match inp.probe_file("./public/index.html").unwrap() {
+            Output::PreconditionFailed(head) => {
+                assert!(head.last_modified().is_some());
+            }
+            _ => unreachable!(),
+        }
+        let inp = Input::from_headers(&cfg, "GET", vec![
+            ("If-Unmodified-Since", &b"Fri, 01 Jan 2100 00:00:00 GMT"[..]),
+        ].into_iter());
+        match inp.probe_file("./public/index.html").unwrap() {
+            Output::File(_) => {}
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn if_unmodified_since_round_trip() {
+        use std::time::{Duration, UNIX_EPOCH};
+        use httpdate::HttpDate;
+        use embedded::EmbeddedFs;
+
+        let mut fs = EmbeddedFs::new(&[("/a.txt", b"hello")]);
+        fs.modified(UNIX_EPOCH + Duration::new(1_500_000_000, 500_000_000));
+        let cfg = Config::new().done();
+        let inp = Input::from_headers(&cfg, "HEAD", vec![].into_iter());
+        let modified = match inp.probe_file_in(&fs, "/a.txt").unwrap() {
+            Output::FileHead(head) => head.last_modified().unwrap(),
+            _ => unreachable!(),
+        };
+        let since = HttpDate::from(modified).to_string();
+        let inp = Input::from_headers(&cfg, "GET", vec![
+            ("If-Unmodified-Since", since.as_bytes()),
+        ].into_iter());
+        match inp.probe_file_in(&fs, "/a.txt").unwrap() {
+            Output::File(_) | Output::FileInMemory(..) => {}
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn overlong_precondition() {
+        let cfg = Config::new().strong_etag(10).done();
+        let long = vec![b'a'; MAX_HEADER_LENGTH + 1];
+        for name in &["If-Match", "If-Unmodified-Since"] {
+            let inp = Input::from_headers(&cfg, "GET",
+                vec![(*name, &long[..])].into_iter());
+            match inp.probe_file("./public/index.html").unwrap() {
+                Output::PreconditionFailed(_) => {}
+                _ => unreachable!(),
+            }
+        }
+        // other headers are still skipped
+        let inp = Input::from_headers(&cfg, "GET",
+            vec![("If-None-Match", &long[..])].into_iter());
+        match inp.probe_file("./public/index.html").unwrap() {
+            Output::File(_) => {}
+            _ => unreachable!(),
+        }
+        // `If-Match` is ignored with etags disabled
+        let mut inp = Input::from_headers(&cfg, "GET",
+            vec![("If-Match", &long[..])].into_iter());
+        inp.disable_etag();
+        match inp.probe_file("./public/index.html").unwrap() {
+            Output::File(_) => {}
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn etag_encoding_suffix() {
+        let cfg = Config::new().etag_encoding_suffix(true).done();
+        let inp = Input::from_headers(&cfg, "HEAD",
+            vec![("Accept-Encoding", &b"gzip"[..])].into_iter());
+        let etag = match inp.probe_file("./public/index.html").unwrap() {
+            Output::FileHead(head) => {
+                let (_, etag) = head.headers()
+                    .find(|&(name, _)| name == "ETag").unwrap();
+                etag.to_string()
+            }
+            _ => unreachable!(),
+        };
+        assert!(etag.ends_with(r#"-gz""#));
+        let inp = Input::from_headers(&cfg, "GET", vec![
+            ("Accept-Encoding", &b"gzip"[..]),
+            ("If-None-Match", etag.as_bytes()),
+        ].into_iter());
+        match inp.probe_file("./public/index.html").unwrap() {
+            Output::NotModified(_) => {}
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn delegate() {
+        let cfg = Config::new().delegate("X-Accel-Redirect").done();
+        for _ in 0..2 {
+            let inp = Input::from_headers(&cfg, "GET",
+                vec![("Accept-Encoding", &b"gzip"[..])].into_iter());
+            match inp.probe_file("public/index.html").unwrap() {
+                Output::Delegate(head, path) => {
+                    assert_eq!(path, Path::new("public/index.html.gz"));
+                    assert_eq!(head.content_length(), 109);
+                }
+                _ => unreachable!(),
+            }
+            let inp = Input::from_headers(&cfg, "HEAD", vec![].into_iter());
+            match inp.probe_file("public/index.html").unwrap() {
+                Output::FileHead(_) => {}
+                _ => unreachable!(),
+            }
+        }
+        let cfg = Config::new().delegate("X-Sendfile")
+            .metadata_cache(::std::time::Duration::new(60, 0), 10).done();
+        let inp = Input::from_headers(&cfg, "GET", vec![].into_iter());
+        match inp.probe_file("public/index.html").unwrap() {
+            Output::Delegate(_, path) => {
+                assert_eq!(path, Path::new("public/index.html"));
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn path_limits() {
+        let cfg = Config::new()
+            .max_path_length(20).max_path_components(3).done();
+        let inp = Input::from_headers(&cfg, "HEAD", vec![].into_iter());
+        match inp.probe_file("./public/index.html").unwrap() {
+            Output::FileHead(_) => {}
+            _ => unreachable!(),
+        }
+        match inp.probe_file("./public/a/index.html").unwrap() {
+            Output::NotFound => {}
+            _ => unreachable!(),
+        }
+        let long = format!("./public/{}", "x".repeat(10000));
+        match inp.probe_file(&long).unwrap() {
+            Output::NotFound => {}
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn windows_names() {
+        for name in &["CON", "nul.txt", "com1", "LPT9.tar.gz", "aux .js",
+                      "file.txt::$DATA", "file.txt.", "file.txt "]
+        {
+            assert!(is_unsafe_windows_name(name), "{}", name);
+        }
+        for name in &["CONSOLE", "com10", "index.html", "nul-x", "com", "aé"]
+        {
+            assert!(!is_unsafe_windows_name(name), "{}", name);
+        }
+        assert!(is_unsafe_on_windows(Path::new("public/con/x.txt")));
+        assert!(!is_unsafe_on_windows(Path::new("./public/index.html")));
+    }
+
+    #[test]
+    fn negotiate() {
+        let cfg = Config::new()
+            .negotiate("application/json", "json")
+            .negotiate("text/html", ".html")
+            .not_found_cache(::std::time::Duration::new(60, 0), 10)
+            .done();
+        for _ in 0..2 {
+            let inp = Input::from_headers(&cfg, "HEAD",
+                vec![("Accept", &b"application/json, text/*;q=0.5"[..])]
+                .into_iter());
+            match inp.probe_file("public/index").unwrap() {
+                Output::FileHead(head) => {
+                    assert_eq!(head.content_location(), Some("index.html"));
+                    assert_eq!(head.mime(), "text/html");
+                    let headers = head.headers()
+                        .map(|(k, v)| (k, v.to_string()))
+                        .collect::<Vec<_>>();
+                    assert!(headers.contains(
+                        &("Vary", String::from("Accept"))));
+                    assert!(headers.contains(
+                        &("Content-Location", String::from("index.html"))));
+                }
+                _ => unreachable!(),
+            }
+        }
+        let inp = Input::from_headers(&cfg, "HEAD",
+            vec![("Accept", &b"application/json"[..])].into_iter());
+        match inp.probe_file("public/index").unwrap() {
+            Output::NotFound => {}
+            _ => unreachable!(),
+        }
+        // existing paths are not negotiated
+        let inp = Input::from_headers(&cfg, "HEAD", vec![].into_iter());
+        match inp.probe_file("public/index.html").unwrap() {
+            Output::FileHead(head) => {
+                assert_eq!(head.content_location(), None);
+                assert!(head.headers().all(|(k, _)| k != "Vary"));
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn negotiate_index() {
+        use test_util::TempDir;
+        let root = TempDir::new("negotiate-index");
+        root.write("index.html", "<p>hello</p>");
+        root.write("index.json", "{}");
+        let cfg = Config::new().add_index_file("index.html")
+            .add_index_file("index.json").negotiate_index(true).done();
+        let cached = Config::new().add_index_file("index.html")
+            .add_index_file("index.json").negotiate_index(true)
+            .metadata_cache(::std::time::Duration::new(60, 0), 10).done();
+        for cfg in &[cfg, cached] {
+            for &(accept, mime) in &[
+                (&b"application/json, text/html;q=0.5"[..],
+                 "application/json"),
+                (&b"text/html, */*;q=0.1"[..], "text/html"),
+                (&b"image/png"[..], "text/html"),
+            ] {
+                let inp = Input::from_headers(cfg, "HEAD",
+                    vec![("Accept", accept)].into_iter());
+                match inp.probe_file(root.path()).unwrap() {
+                    Output::FileHead(head) => {
+                        assert_eq!(head.mime(), mime);
+                        assert!(head.headers().any(|(k, v)| {
+                            k == "Vary" && v.to_string() == "Accept"
+                        }));
+                    }
+                    _ => unreachable!(),
+                }
+            }
+        }
+        // definition order is used by default
+        let cfg = Config::new().add_index_file("index.html")
+            .add_index_file("index.json").done();
+        let inp = Input::from_headers(&cfg, "HEAD",
+            vec![("Accept", &b"application/json"[..])].into_iter());
+        match inp.probe_file(root.path()).unwrap() {
+            Output::FileHead(head) => {
+                assert_eq!(head.mime(), "text/html");
+                assert!(head.headers().all(|(k, _)| k != "Vary"));
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn type_patterns() {
+        assert!(type_matches("text/*", "text/css"));
+        assert!(type_matches("text/*", "TEXT/plain"));
+        assert!(!type_matches("text/*", "text/"));
+        assert!(!type_matches("text/*", "texts/css"));
+        assert!(type_matches("*+xml", "image/svg+xml"));
+        assert!(!type_matches("*+xml", "application/xml"));
+        assert!(type_matches("application/xml", "application/xml"));
+        assert!(type_matches("*/*", "image/png"));
+    }
+
+    #[test]
+    fn compressible_types() {
+        let cfg = Config::new().done();
+        let inp = Input::from_headers(&cfg, "HEAD", vec![].into_iter());
+        assert!(inp.encodings_enabled("text/html"));
+        assert!(inp.encodings_enabled("application/javascript"));
+        assert!(inp.encodings_enabled("image/svg+xml"));
+        assert!(inp.encodings_enabled("application/wasm"));
+        assert!(!inp.encodings_enabled("image/png"));
+        let cfg = Config::new().extended_text_types(false).done();
+        let inp = Input::from_headers(&cfg, "HEAD", vec![].into_iter());
+        assert!(inp.encodings_enabled("text/html"));
+        assert!(!inp.encodings_enabled("image/svg+xml"));
+        assert!(!inp.encodings_enabled("application/wasm"));
+        let cfg = Config::new()
+            .extended_text_types(false)
+            .add_compressible_type("*+xml")
+            .add_compressible_type("application/wasm")
+            .done();
+        let inp = Input::from_headers(&cfg, "HEAD", vec![].into_iter());
+        assert!(inp.encodings_enabled("text/html"));
+        assert!(inp.encodings_enabled("image/svg+xml"));
+        assert!(inp.encodings_enabled("application/wasm"));
+        assert!(!inp.encodings_enabled("image/png"));
+        let cfg = Config::new()
+            .extended_text_types(false)
+            .encodings_on_types(&["application/json"])
+            .done();
+        let inp = Input::from_headers(&cfg, "HEAD",
+            vec![("Accept-Encoding", &b"br"[..])].into_iter());
+        assert!(inp.encodings_enabled("application/json"));
+        assert!(!inp.encodings_enabled("text/html"));
+        match inp.probe_file("public/index.html").unwrap() {
+            Output::FileHead(head) => {
+                assert_eq!(head.encoding(), Encoding::Identity);
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn mime_override() {
+        let cfg = Config::new().done();
+        let inp = Input::from_headers(&cfg, "HEAD", vec![].into_iter());
+        assert_eq!(&*inp.content_type(Path::new("x.blend")),
+                   "application/octed-stream");
+        // e.g. read from a config file
+        let custom = format!("text/x-{}", "custom");
+        let cfg = Config::new()
+            .mime_override("blend", "application/x-blender")
+            .mime_override(".HTML", "application/xhtml+xml")
+            .mime_override("html", &custom)
+            .done();
+        let inp = Input::from_headers(&cfg, "HEAD", vec![].into_iter());
+        assert_eq!(&*inp.content_type(Path::new("x.BLEND")),
+                   "application/x-blender");
+        assert_eq!(&*inp.content_type(Path::new("x.css")), "text/css");
+        match inp.probe_file("public/index.html").unwrap() {
+            Output::FileHead(head) => assert_eq!(head.mime(), "text/x-custom"),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn compound_extensions() {
+        let cfg = Config::new()
+            .mime_override("min.js", "text/x-minified")
+            .mime_override("json.gz", "application/x-json-archive")
+            .done();
+        let inp = Input::from_headers(&cfg, "HEAD", vec![].into_iter());
+        let ctype = |x| inp.content_type(Path::new(x)).to_string();
+        assert_eq!(ctype("dist/app.js.map"), "application/json");
+        assert_eq!(ctype("app.min.css.map"), "application/json");
+        assert_eq!(ctype("/srv/archive.TAR.gz"), "application/x-gtar");
+        assert_eq!(ctype("app.min.js"), "text/x-minified");
+        assert_eq!(ctype("app.js"), "application/javascript");
+        assert_eq!(ctype("data.json.gz"), "application/x-json-archive");
+        assert_eq!(ctype("x.gz"), "application/gzip");
+        assert_eq!(ctype(".js"), "application/octed-stream");
+        assert_eq!(ctype("dir.js/file"), "application/octed-stream");
+    }
+
+    #[test]
+    fn encoded_paths() {
+        use test_util::TempDir;
+        #[derive(Debug)]
+        struct Sibling;
+        impl EncodedPath for Sibling {
+            fn encoded_path(&self, path: &Path, enc: Encoding) -> PathBuf {
+                let dir = if enc == Encoding::Gzip { "gz" } else { "br" };
+                path.parent().unwrap().join(dir).join(path.file_name().unwrap())
+            }
+        }
+        let root = TempDir::new("encoded-path");
+        root.write("x.html", "hello");
+        root.write("x.html.gzip", "abc");
+        root.write("gz/x.html", "ab");
+        let suffix = Config::new()
+            .encoding_suffix(Encoding::Gzip, ".gzip").done();
+        let sibling = Config::new().encoded_path(Sibling).done();
+        let cached = Config::new().encoded_path(Sibling)
+            .metadata_cache(::std::time::Duration::new(60, 0), 10).done();
+        for &(ref cfg, len) in &[(suffix, 3), (sibling, 2), (cached, 2)] {
+            let inp = Input::from_headers(cfg, "HEAD",
+                vec![("Accept-Encoding", &b"gzip, br"[..])].into_iter());
+            match inp.probe_file(root.join("x.html")).unwrap() {
+                Output::FileHead(head) => {
+                    assert_eq!(head.encoding(), Encoding::Gzip);
+                    assert_eq!(head.content_length(), len);
+                }
+                _ => unreachable!(),
+            }
+        }
+    }
+
+    #[test]
+    fn direct_encoded() {
+        use test_util::TempDir;
+        let root = TempDir::new("direct-encoded");
+        root.write("a.txt.gz", "gzipped");
+        root.write("a.txt.gz.br", "double");
+        root.write("a.tar.gz", "archive");
+        root.write("a.bin.gz", "binary");
+        let cfg = Config::new().encodings_on_all_files().done();
+        let cached = Config::new().encodings_on_all_files()
+            .metadata_cache(::std::time::Duration::new(60, 0), 10).done();
+        for cfg in &[cfg, cached] {
+            let inp = Input::from_headers(cfg, "HEAD",
+                vec![("Accept-Encoding", &b"br, gzip"[..])].into_iter());
+            match inp.probe_file(root.join("a.txt.gz")).unwrap() {
+                Output::FileHead(head) => {
+                    assert_eq!(head.encoding(), Encoding::Identity);
+                    assert_eq!(head.mime(), "application/gzip");
+                    assert_eq!(head.content_length(), 7);
+                }
+                _ => unreachable!(),
+            }
+        }
+        let cfg = Config::new().encoded_inner_type(true)
+            .metadata_cache(::std::time::Duration::new(60, 0), 10).done();
+        for _ in 0..2 {
+            let inp = Input::from_headers(&cfg, "HEAD",
+                vec![("Accept-Encoding", &b"gzip"[..])].into_iter());
+            match inp.probe_file("public/index.html.gz").unwrap() {
+                Output::FileHead(head) => {
+                    assert_eq!(head.encoding(), Encoding::Gzip);
+                    assert_eq!(head.mime(), "text/html");
+                    assert_eq!(head.content_length(), 109);
+                }
+                _ => unreachable!(),
+            }
+            let inp = Input::from_headers(&cfg, "HEAD", vec![].into_iter());
+            match inp.probe_file("public/index.html.gz").unwrap() {
+                Output::FileHead(head) => {
+                    assert_eq!(head.encoding(), Encoding::Identity);
+                    assert_eq!(head.mime(), "application/gzip");
+                }
+                _ => unreachable!(),
+            }
+            // not compressible, so downloaded as is
+            let inp = Input::from_headers(&cfg, "HEAD",
+                vec![("Accept-Encoding", &b"gzip"[..])].into_iter());
+            for &(name, mime) in &[("a.tar.gz", "application/x-gtar"),
+                                   ("a.bin.gz", "application/gzip")]
+            {
+                match inp.probe_file(root.join(name)).unwrap() {
+                    Output::FileHead(head) => {
+                        assert_eq!(head.encoding(), Encoding::Identity);
+                        assert_eq!(head.mime(), mime);
+                    }
+                    _ => unreachable!(),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn brotli_min_size() {
+        use test_util::TempDir;
+        let root = TempDir::new("brotli-min-size");
+        for name in &["small.txt", "large.txt"] {
+            root.write(name, "hello");
+            root.write(format!("{}.gz", name), "gz");
+            root.write(format!("{}.br", name), "br");
+        }
+        root.write("large.txt", "hello world");
+        let cfg = Config::new().brotli_min_size(10).done();
+        let cached = Config::new().brotli_min_size(10)
+            .metadata_cache(::std::time::Duration::new(60, 0), 10).done();
+        for cfg in &[cfg, cached] {
+            let enc = |name, header: &[u8]| {
+                let inp = Input::from_headers(cfg, "HEAD",
+                    vec![("Accept-Encoding", header)].into_iter());
+                match inp.probe_file(root.join(name)).unwrap() {
+                    Output::FileHead(head) => head.encoding(),
+                    _ => unreachable!(),
+                }
+            };
+            assert_eq!(enc("small.txt", b"br, gzip"), Encoding::Gzip);
+            assert_eq!(enc("small.txt", b"br"), Encoding::Identity);
+            assert_eq!(enc("large.txt", b"br, gzip"), Encoding::Brotli);
+        }
+    }
+
+    #[test]
+    fn no_encodings_for() {
+        use test_util::TempDir;
+        let root = TempDir::new("no-encodings-for");
+        for name in &["a.txt", "a.woff2", "media/b.txt"] {
+            root.write(name, "hello");
+            root.write(format!("{}.gz", name), "gz");
+        }
+        let cfg = Config::new().encodings_on_all_files()
+            .no_encodings_for_extension(".WOFF2")
+            .no_encodings_for_path("*/media/*")
+            .done();
+        let cached = Config::new().encodings_on_all_files()
+            .no_encodings_for_extension("woff2")
+            .no_encodings_for_path("*/media/*")
+            .metadata_cache(::std::time::Duration::new(60, 0), 10).done();
+        for cfg in &[cfg, cached] {
+            let inp = Input::from_headers(cfg, "HEAD",
+                vec![("Accept-Encoding", &b"gzip"[..])].into_iter());
+            let enc = |name| match inp.probe_file(root.join(name)).unwrap() {
+                Output::FileHead(head) => head.encoding(),
+                _ => unreachable!(),
+            };
+            assert_eq!(enc("a.txt"), Encoding::Gzip);
+            assert_eq!(enc("a.woff2"), Encoding::Identity);
+            assert_eq!(enc("media/b.txt"), Encoding::Identity);
+        }
+    }
+
+    #[test]
+    fn denied_extension() {
+        let cfg = Config::new()
+            .add_index_file("index.html")
+            .deny_extension(".HTML")
+            .done();
+        let inp = Input::from_headers(&cfg, "HEAD", vec![].into_iter());
+        match inp.probe_file("public").unwrap() {
+            Output::Forbidden => {}
+            _ => unreachable!(),
+        }
+        // encoded variants are denied too
+        match inp.probe_file("public/index.html.gz").unwrap() {
+            Output::Forbidden => {}
+            _ => unreachable!(),
+        }
+        let cfg = Config::new().deny_extension("sql").done();
+        let inp = Input::from_headers(&cfg, "HEAD", vec![].into_iter());
+        match inp.probe_file("public/index.html.gz").unwrap() {
+            Output::FileHead(_) => {}
+            _ => unreachable!(),
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlink_escape() {
+        use std::fs::canonicalize;
+        use std::os::unix::fs::symlink;
+        use test_util::TempDir;
+        let root = TempDir::new("symlink");
+        root.write("inside.txt", "hello");
+        symlink(canonicalize("public/index.html").unwrap(),
+                root.join("escape.html")).unwrap();
+        let cfg = Config::new().restrict_to_root(root.path()).done();
+        let inp = Input::from_headers(&cfg, "GET", vec![].into_iter());
+        match inp.probe_file(root.join("inside.txt")).unwrap() {
+            Output::File(_) => {}
+            _ => unreachable!(),
+        }
+        match inp.probe_file(root.join("escape.html")).unwrap() {
+            Output::Forbidden => {}
+            _ => unreachable!(),
+        }
+        let cfg = Config::new().done();
+        let inp = Input::from_headers(&cfg, "GET", vec![].into_iter());
+        match inp.probe_file(root.join("escape.html")).unwrap() {
+            Output::File(_) => {}
+            _ => unreachable!(),
+        }
+        // files are never served if the root can't be resolved
+        let cfg = Config::new().restrict_to_root(root.join("none")).done();
+        let inp = Input::from_headers(&cfg, "GET", vec![].into_iter());
+        assert!(inp.probe_file(root.join("inside.txt")).is_err());
+    }
+
+    #[test]
+    fn metrics() {
+        use std::sync::Mutex;
+
+        type Probe = (&'static str, u16, Encoding);
+        #[derive(Debug, Default)]
+        struct Counter(Mutex<(Vec<Probe>, u64)>);
+        impl Metrics for Arc<Counter> {
+            fn probed(&self, variant: &'static str, status: u16,
+                encoding: Encoding)
+            {
+                self.0.lock().unwrap().0.push((variant, status, encoding));
+            }
+            fn bytes_sent(&self, bytes: u64) {
+                self.0.lock().unwrap().1 += bytes;
+            }
+        }
+
+        let counter = Arc::new(Counter::default());
+        let cfg = Config::new().metrics(counter.clone()).done();
+        let inp = Input::from_headers(&cfg, "GET", vec![].into_iter());
+        let etag = match inp.probe_file("public/index.html").unwrap() {
+            Output::File(mut wrapper) => {
+                assert_eq!(wrapper.read_all(1000).unwrap().len(), 126);
+                let (_, etag) = wrapper.headers()
+                    .find(|&(name, _)| name == "ETag").unwrap();
+                etag.to_string()
+            }
+            _ => unreachable!(),
+        };
+        let inp = Input::from_headers(&cfg, "GET",
+            vec![("If-None-Match", etag.as_bytes())].into_iter());
+        match inp.probe_file("public/index.html").unwrap() {
+            Output::NotModified(_) => {}
+            _ => unreachable!(),
+        }
+        match inp.probe_file_multi(&["public/a", "public/b"]).unwrap() {
+            Output::NotFound => {}
+            _ => unreachable!(),
+        }
+        assert_eq!(*counter.0.lock().unwrap(), (vec![
+            ("File", 200, Encoding::Identity),
+            ("NotModified", 304, Encoding::Identity),
+            ("NotFound", 404, Encoding::Identity),
+        ], 126));
+    }
+
+    #[test]
+    fn explanation() {
+        let cfg = Config::new().explain(true).done();
+        let inp = Input::from_headers(&cfg, "GET",
+            vec![("Accept-Encoding", &b"gzip"[..])].into_iter());
+        let etag = match inp.probe_file("public/index.html").unwrap() {
+            Output::File(wrapper) => {
+                let expl = wrapper.head().explanation().unwrap();
+                assert_eq!(expl.paths(),
+                           &[PathBuf::from("public/index.html.gz")]);
+                assert_eq!(expl.encoding(), Some(Encoding::Gzip));
+                assert_eq!(expl.condition(), None);
+                let (_, etag) = wrapper.headers()
+                    .find(|&(name, _)| name == "ETag").unwrap();
+                etag.to_string()
+            }
+            _ => unreachable!(),
+        };
+        let inp = Input::from_headers(&cfg, "GET", vec![
+            ("Accept-Encoding", &b"gzip"[..]),
+            ("If-None-Match", etag.as_bytes()),
+        ].into_iter());
+        let output = inp.probe_file("public/index.html").unwrap();
+        assert_eq!(output.suggested_status(), 304);
+        assert_eq!(output.explanation().unwrap().condition(),
+                   Some("If-None-Match"));
+        let output = inp.probe_file("public/missing.html").unwrap();
+        assert!(output.explanation().is_none());
+        assert_eq!(inp.explanation().unwrap().to_string(),
+                   "probed [public/missing.html]");
+
+        let cfg = Config::new().done();
+        let inp = Input::from_headers(&cfg, "GET", vec![].into_iter());
+        let output = inp.probe_file("public/index.html").unwrap();
+        assert!(output.explanation().is_none());
+        assert!(inp.explanation().is_none());
+    }
+
+    #[test]
+    fn overrides() {
+        use super::content_disposition;
+
+        let cfg = Config::new().done();
+        let mut inp = Input::from_headers(&cfg, "HEAD",
+            vec![("If-None-Match", &b"*"[..])].into_iter());
+        inp.override_mime("text/plain")
+            .disable_etag()
+            .force_download("index.html");
+        let head = match inp.probe_file("public/index.html").unwrap() {
+            Output::FileHead(head) => head,
+            _ => unreachable!(),
+        };
+        let headers = head.headers()
+            .map(|(name, value)| format!("{}: {}", name, value))
+            .collect::<Vec<_>>();
+        assert!(headers.contains(
+            &"Content-Type: text/plain; charset=utf-8".to_string()));
+        assert!(headers.contains(&"Content-Disposition: attachment; \
+            filename=\"index.html\"".to_string()));
+        assert!(head.etag().is_none());
+
+        assert_eq!(content_disposition("a \"b\".csv"),
+                   "attachment; filename=\"a _b_.csv\"; \
+                    filename*=UTF-8''a%20%22b%22.csv");
+        assert_eq!(content_disposition("файл.txt"),
+                   "attachment; filename=\"____.txt\"; \
+                    filename*=UTF-8''%D1%84%D0%B0%D0%B9%D0%BB.txt");
+    }
+
+    #[test]
+    fn long_header() {
+        let cfg = Config::new().done();
+        let range = format!("bytes=0-0{}", ",0-0".repeat(3000));
+        let inp = Input::from_headers(&cfg, "GET",
+            vec![("Range", range.as_bytes())].into_iter());
+        assert_eq!(inp.mode, Mode::Get);
+        assert!(inp.range.is_none());
+    }
+
+    #[test]
+    fn forced_encoding() {
+        let cfg = Config::new().done();
+        let headers = || vec![("Accept-Encoding", &b"gzip, br"[..])];
+        let encoding = |inp: &Input| {
+            match inp.probe_file("public/index.html").unwrap() {
+                Output::File(wrapper) => wrapper.head().encoding(),
+                _ => unreachable!(),
+            }
+        };
+        let mut inp = Input::from_headers(&cfg, "GET", headers().into_iter());
+        assert_eq!(encoding(&inp), Encoding::Brotli);
+        inp.force_encoding(Encoding::Gzip);
+        assert_eq!(encoding(&inp), Encoding::Gzip);
+        inp.identity_only();
+        assert_eq!(encoding(&inp), Encoding::Identity);
+
+        let mut inp = Input::from_headers(&cfg, "GET", vec![].into_iter());
+        inp.force_encoding(Encoding::Brotli);
+        assert_eq!(encoding(&inp), Encoding::Brotli);
+
+        let cfg = Config::new().no_encodings().done();
+        let mut inp = Input::from_headers(&cfg, "GET", vec![].into_iter());
+        inp.force_encoding(Encoding::Brotli);
+        assert_eq!(encoding(&inp), Encoding::Identity);
+        assert_eq!(inp.encodings().collect::<Vec<_>>(),
+                   vec![Encoding::Identity]);
+    }
+
     #[cfg(all(target_arch="x86_64", target_os="linux"))]
     #[test]
     fn size() {
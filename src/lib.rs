@@ -17,16 +17,66 @@ extern crate generic_array;
 extern crate httpdate;
 extern crate mime_guess;
 extern crate typenum;
+#[cfg(feature="http")] extern crate http;
+#[cfg(feature="hyper")] extern crate hyper;
+#[cfg(feature="tokio")] extern crate tokio;
+#[cfg(feature="bytes")] extern crate bytes;
+#[cfg(feature="futures-core")] extern crate futures_core;
+#[cfg(feature="tower-service")] extern crate tower_service;
+#[cfg(feature="memmap2")] extern crate memmap2;
+#[cfg(feature="miniz_oxide")] extern crate miniz_oxide;
+#[cfg(feature="xattr")] extern crate xattr;
+#[cfg(feature="tracing")] extern crate tracing;
+#[cfg(feature="serde")] extern crate serde;
+#[cfg(feature="toml")] extern crate toml;
 
+#[macro_use] mod trace;
+
+mod accept;
+mod archive;
+mod builder;
+mod charset;
 mod conditionals;
 mod config;
+mod disk_pool;
+mod embedded;
 mod etag;
+mod explain;
+mod fd_cache;
+mod head_cache;
 mod input;
+mod metrics;
 mod output;
+mod pack_fs;
+pub mod path;
+pub mod rules;
 mod range;
+mod throttle;
 mod accept_encoding;
+pub mod vfs;
+#[cfg(feature="http")] mod http_interop;
+#[cfg(feature="hyper")] pub mod hyper_compat;
+#[cfg(feature="tower")] pub mod tower_compat;
+#[cfg(feature="tokio")] mod tokio_compat;
+#[cfg(feature="stream")] mod stream;
+#[cfg(feature="bytes")] mod bytes_compat;
+#[cfg(feature="zip")] mod zip_fs;
+#[cfg(feature="serde")] mod settings;
+#[cfg(feature="serde")] mod dir_config;
+#[cfg(test)] mod test_util;
 
 pub use input::Input;
 pub use config::Config;
-pub use output::{Output, Head, FileWrapper};
-pub use accept_encoding::{Encoding, Iter as EncodingIter};
+pub use builder::{ConfigBuilder, ConfigError, InputBuilder, Method};
+pub use output::{Output, Head, DirInfo, FileWrapper, TypedValue};
+pub use archive::TarArchive;
+pub use accept_encoding::{Encoding, EncodedPath, Iter as EncodingIter};
+pub use accept_encoding::{AcceptEncoding, AcceptEncodingParser};
+pub use etag::{Etag, EtagProvider, DefaultEtag, ContentOnlyEtag};
+pub use explain::Explanation;
+pub use metrics::Metrics;
+pub use range::RangeError;
+pub use disk_pool::{DiskPool, DiskFuture};
+#[cfg(feature="tokio")] pub use tokio_compat::{ProbeFuture, AsyncFileWrapper};
+#[cfg(feature="stream")] pub use stream::FileStream;
+#[cfg(feature="serde")] pub use settings::Settings;
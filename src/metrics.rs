@@ -0,0 +1,28 @@
+use std::fmt;
+
+use accept_encoding::Encoding;
+
+
+/// Hooks for collecting metrics of served files
+///
+/// Set it using `Config::metrics`. All methods do nothing by default, so
+/// implement only the ones needed. Methods are called in the disk thread,
+/// so they should be fast (e.g. increment atomic counters).
+pub trait Metrics: fmt::Debug + Send + Sync {
+    /// Called for each result of `Input::probe_file` (and other `probe_*`
+    /// methods)
+    ///
+    /// `variant` is the name of the `Output` variant, like `"File"` or
+    /// `"NotModified"` (so the ratio of 304 responses can be tracked),
+    /// `status` is `Output::suggested_status`. `encoding` is the encoding
+    /// of the chosen file (identity if there is no file). Errors are not
+    /// reported.
+    fn probed(&self, _variant: &'static str, _status: u16,
+        _encoding: Encoding)
+    {
+    }
+    /// Called when bytes of the body are read (or marked as sent) by
+    /// `FileWrapper`
+    fn bytes_sent(&self, _bytes: u64) {
+    }
+}
@@ -1,17 +1,29 @@
 use std::cmp::min;
+use std::ffi::OsStr;
 use std::fmt::{self, Display};
-use std::fs::{Metadata, File};
-use std::io::{self, Read, Write, Seek, SeekFrom};
-use std::time::{UNIX_EPOCH, Duration};
+use std::fs::File;
+use std::io::{self, Read, Seek, Write};
+use std::ops;
+use std::path::{Path, PathBuf};
+use std::slice;
+use std::thread;
+use std::time::{UNIX_EPOCH, Duration, Instant, SystemTime};
 use std::sync::Arc;
+#[cfg(unix)] use std::os::unix::io::{AsRawFd, RawFd};
+#[cfg(windows)] use std::os::windows::io::{AsRawHandle, RawHandle};
 
 use httpdate::HttpDate;
+#[cfg(feature="memmap")] use memmap2::{Mmap, MmapOptions};
 
 use accept_encoding::Encoding;
-use config::Config;
-use input::{Input, is_text_file};
+use config::{Config, CharsetPolicy};
+use input::{Input, Mode};
 use range::{Range, Slice};
 use etag::Etag;
+use explain::Explanation;
+use rules::ExtraHeader;
+use throttle::Throttle;
+use vfs::{Metadata, ReadAt, SeekReader};
 
 /// This is a heuristic that there are no valid dates before 1990-01-01
 /// Lower timestamps like 1970-01-01 00:00:01 are used by nixos and some
@@ -22,12 +34,26 @@ use etag::Etag;
 /// as we don't think anybody serves files with lower date set genuinely.
 const MIN_DATE: u64 = 631152000;
 
+/// Maximum size of the read buffer allocated on stack
+const STACK_BUFFER: usize = 65536;
+
 const BYTES: &str = "bytes";
 const BYTES_PTR: &&str = &BYTES;
+const NONE: &str = "none";
+const NONE_PTR: &&str = &NONE;
+const ACCEPT: &str = "Accept";
+const ACCEPT_PTR: &&str = &ACCEPT;
 
 
 #[derive(Debug)]
-struct ContentType(&'static str, Arc<Config>);
+struct ContentType {
+    mime: Mime,
+    config: Arc<Config>,
+    /// False if charset is not acceptable by `Accept-Charset`
+    charset_acceptable: bool,
+    /// Charset detected by `Config::sniff_charset`
+    sniffed: Option<&'static str>,
+}
 
 /// This enum represents all the information needed to form response for
 /// the HTTP request
@@ -35,10 +61,13 @@ struct ContentType(&'static str, Arc<Config>);
 /// Variants of this structure represent different modes of responding on
 /// request.
 #[derive(Debug)]
-pub enum Output {
+pub enum Output<F=File> {
     /// File not found
     NotFound,
     /// File was requested using `HEAD` method
+    ///
+    /// If there is a `Range` header, the head is partial (206) and has the
+    /// same `Content-Range` and `Content-Length` as for `GET`.
     FileHead(Head),
     /// File is not modified, should return 304
     ///
@@ -46,29 +75,130 @@ pub enum Output {
     /// or `If-Modified-Since`
     NotModified(Head),
     /// Normal file was requested using `GET` method
-    File(FileWrapper),
+    File(FileWrapper<F>),
     /// The `GET` file request includes `Range` field, and range is
     /// contiguous
-    FileRange(FileWrapper),
-    /// The matching path is a directory
+    FileRange(FileWrapper<F>),
+    /// Small file that is already read into memory
+    ///
+    /// This is returned instead of `File` for files (or ranges) smaller
+    /// than `Config::inline_threshold`.
+    FileInMemory(Head, Vec<u8>),
+    /// The file should be sent by the frontend server
+    ///
+    /// This is returned instead of `File` for `GET` requests if
+    /// `Config::delegate` is enabled. Send the headers of the `Head` along
+    /// with the header set in config having the path of the file (incl.
+    /// index file name and encoding suffix) as the value.
+    Delegate(Head, PathBuf),
+    /// The matching path is a directory (and no index file is found)
+    ///
+    /// Path and metadata of the directory are returned by
+    /// `Input::probe_file_dir`, so that listing or redirect can be made
+    /// without probing it again. Use `DirInfo::listing_etag` and
+    /// `DirInfo::modified` with `Head::synthetic` to answer conditional
+    /// requests for the listing.
     Directory,
+    /// The file is outside of the root directory (e.g. symlink points
+    /// outside), should return 403
+    ///
+    /// See `Config::restrict_to_root`.
+    Forbidden,
     /// Invalid method was requested
     InvalidMethod,
     /// Invalid `Range` header in request, should return 416
     InvalidRange,
+    /// Range is outside of the file, should return 416
+    ///
+    /// The head contains `Content-Range: bytes */<size>` and validators
+    /// (`ETag`, `Last-Modified`). Content length is zero.
+    RangeNotSatisfiable(Head),
+    /// Precondition of the request failed, should return 412
+    ///
+    /// This is returned if `If-Match` or `If-Unmodified-Since` doesn't
+    /// match the file. The head contains validators (`ETag`,
+    /// `Last-Modified`) only, so client can revalidate. Content length is
+    /// zero.
+    PreconditionFailed(Head),
+    /// Charset of the text file is not acceptable, should return 406
+    ///
+    /// Only returned if `Config::reject_unacceptable_charset` is enabled.
+    NotAcceptable,
+    /// Request is redirected by `Config::redirect_rules`
+    ///
+    /// Contains status code (301, 302, 303, 307 or 308) and the value of
+    /// the `Location` header.
+    Redirect(u16, String),
+}
+
+/// Mime type of the file
+///
+/// Types guessed by extension are static, the ones set at runtime (e.g.
+/// `Config::mime_override`) are shared to avoid copying them for every
+/// request.
+#[derive(Debug, Clone)]
+pub(crate) enum Mime {
+    Static(&'static str),
+    Shared(Arc<str>),
+}
+
+/// Directory found instead of the file (see `Input::probe_file_dir`)
+#[derive(Debug, Clone)]
+pub struct DirInfo {
+    path: PathBuf,
+    // boxed to keep `Output` small
+    metadata: Box<Metadata>,
 }
 
 /// All the metadata of for the response headers
 #[derive(Debug)]
 pub struct Head {
-    config: Arc<Config>,
+    // boxed to keep `Output` (and futures containing it) small
+    pub(crate) data: Box<HeadData>,
+}
+
+#[derive(Debug)]
+pub(crate) struct HeadData {
+    pub(crate) config: Arc<Config>,
     encoding: Encoding,
     content_length: u64,
-    content_type: Option<ContentType>,
+    content_type: ContentType,
     last_modified: Option<HttpDate>,
+    date: Option<HttpDate>,
     etag: Option<Etag>,
     range: Option<ContentRange>,
-    not_modified: bool,
+    pub(crate) location: Option<String>,
+    vary_accept: bool,
+    status: Status,
+    /// State of the rate limiter (kept here so `FileWrapper` is small)
+    throttle: Option<Throttle>,
+    pub(crate) explanation: Option<Explanation>,
+    pub(crate) extra_headers: Vec<ExtraHeader>,
+}
+
+/// Kind of the response the head is made for
+#[derive(Debug)]
+enum Status {
+    /// Normal response (200 or 206)
+    Ok,
+    NotModified,
+    PreconditionFailed,
+    RangeNotSatisfiable(UnsatisfiedRange),
+}
+
+/// Value of `Content-Range` for 416 response: `bytes */<file size>`
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct UnsatisfiedRange(u64);
+
+/// File metadata needed to make response headers
+///
+/// Fields disabled in config are empty.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct FileInfo {
+    pub size: u64,
+    pub modified: Option<SystemTime>,
+    pub etag: Option<Etag>,
+    pub charset: Option<&'static str>,
 }
 
 #[derive(Debug, PartialEq, Eq)]
@@ -81,24 +211,43 @@ pub struct ContentRange {
 /// Structure that contains all the metadata for response headers and
 /// the file which will be sent in response body.
 #[derive(Debug)]
-pub struct FileWrapper {
+pub struct FileWrapper<F=File> {
     head: Head,
-    file: File,
+    file: F,
     bytes_left: u64,
+    /// Offset of the next byte to send (file position is kept in sync)
+    offset: u64,
+    #[cfg(feature="memmap")]
+    mmap: Option<Mapped>,
+}
+
+/// Memory-mapped part of the file
+#[cfg(feature="memmap")]
+#[derive(Debug)]
+struct Mapped {
+    /// Offset in the file of the first byte of the map
+    offset: u64,
+    map: Mmap,
 }
 
 #[derive(Clone, Copy, Debug)]
 enum HeaderIterState {
+    Date,
     LastModified,
     Etag,
+    ContentLocation,
+    Vary,
 
     // these not needed if NotModified
     Encoding,
+    ContentLength,
     AcceptRanges,
     ContentRange,
     ContentType,
 
-    Done,
+    /// Headers from `Config::header_rules` (index in the list), the
+    /// iteration ends after the last one
+    Extra(usize),
 }
 
 #[derive(Debug)]
@@ -107,6 +256,77 @@ pub struct HeaderIter<'a> {
     state: HeaderIterState,
 }
 
+/// Iterator over response headers with typed values
+///
+/// Created by `Head::typed_headers`.
+#[derive(Debug)]
+pub struct TypedHeaderIter<'a> {
+    head: &'a Head,
+    state: HeaderIterState,
+}
+
+/// Iterator over headers added by `Config::header_rules` and
+/// `Input::force_download`
+///
+/// Created by `Head::extra_headers`.
+#[derive(Debug)]
+pub struct ExtraHeaderIter<'a> {
+    iter: slice::Iter<'a, ExtraHeader>,
+}
+
+/// Value of the response header
+///
+/// All of them implement `Display`, which formats the value exactly as
+/// `Head::headers` does.
+#[derive(Debug, Clone, Copy)]
+pub enum TypedValue<'a> {
+    /// Date (`Date` and `Last-Modified`)
+    Date(SystemTime),
+    /// Entity tag (`ETag`)
+    Etag(&'a Etag),
+    /// Encoding of the body (`Content-Encoding`)
+    Encoding(Encoding),
+    /// Length of the body (`Content-Length`)
+    Length(u64),
+    /// Range as start, end (inclusive) and file size (`Content-Range`)
+    Range(u64, u64, u64),
+    /// File size for unsatisfiable range (`Content-Range: bytes */size`)
+    UnsatisfiedRange(u64),
+    /// Mime type and charset (`Content-Type`)
+    ContentType(&'a str, Option<&'a str>),
+    /// Value that doesn't need formatting (e.g. `Accept-Ranges`)
+    Static(&'static str),
+    /// Value that is stored as a string (e.g. `Content-Location`)
+    Text(&'a str),
+}
+
+
+impl HeaderIterState {
+    fn next(self, head: &Head) -> HeaderIterState {
+        use self::HeaderIterState as H;
+        match self {
+            H::Date => H::LastModified,
+            H::LastModified => H::Etag,
+            H::Etag => H::ContentLocation,
+            H::ContentLocation => H::Vary,
+            H::Vary => match head.data.status {
+                Status::Ok => H::Encoding,
+                Status::NotModified => H::Extra(0),
+                Status::PreconditionFailed => H::Extra(0),
+                Status::RangeNotSatisfiable(_) => H::ContentRange,
+            },
+            H::Encoding => H::ContentLength,
+            H::ContentLength => H::AcceptRanges,
+            H::AcceptRanges => H::ContentRange,
+            H::ContentRange => match head.data.status {
+                Status::RangeNotSatisfiable(_) => H::Extra(0),
+                _ => H::ContentType,
+            },
+            H::ContentType => H::Extra(0),
+            H::Extra(idx) => H::Extra(idx + 1),
+        }
+    }
+}
 
 impl<'a> Iterator for HeaderIter<'a> {
     type Item=(&'a str, &'a Display);
@@ -114,45 +334,158 @@ impl<'a> Iterator for HeaderIter<'a> {
         use self::HeaderIterState as H;
         loop {
             let value = match self.state {
+                H::Date => {
+                    self.head.data.date.as_ref()
+                        .map(|x| ("Date", x as &dyn Display))
+                }
                 H::LastModified => {
-                    self.head.last_modified.as_ref()
+                    self.head.data.last_modified.as_ref()
                         .map(|x| ("Last-Modified", x as &Display))
                 }
                 H::Etag => {
-                    self.head.etag.as_ref()
+                    self.head.data.etag.as_ref()
                         .map(|x| ("ETag", x as &Display))
                 }
+                H::ContentLocation => {
+                    self.head.data.location.as_ref()
+                        .map(|x| ("Content-Location", x as &dyn Display))
+                }
+                H::Vary => {
+                    if self.head.data.vary_accept {
+                        Some(("Vary", ACCEPT_PTR as &dyn Display))
+                    } else {
+                        None
+                    }
+                }
                 H::Encoding => {
-                    if self.head.encoding != Encoding::Identity {
+                    if self.head.data.encoding != Encoding::Identity {
                         Some(("Content-Encoding",
-                              &self.head.encoding as &Display))
+                              &self.head.data.encoding as &dyn Display))
                     } else {
                         None
                     }
                 }
-                H::ContentRange => {
-                    self.head.range.as_ref()
-                        .map(|x| ("Content-Range", x as &Display))
-                }
+                H::ContentRange => match self.head.data.status {
+                    Status::RangeNotSatisfiable(ref x) => {
+                        Some(("Content-Range", x as &dyn Display))
+                    }
+                    _ => {
+                        self.head.data.range.as_ref()
+                            .map(|x| ("Content-Range", x as &dyn Display))
+                    }
+                },
                 H::ContentType => {
-                    self.head.content_type.as_ref()
-                        .map(|x| ("Content-Type", x as &Display))
+                    if self.head.data.config.content_type {
+                        Some(("Content-Type",
+                              &self.head.data.content_type as &dyn Display))
+                    } else {
+                        None
+                    }
                 }
-                H::AcceptRanges => {
+                H::ContentLength => {
+                    if self.head.data.config.content_length_header {
+                        Some(("Content-Length",
+                              &self.head.data.content_length as &dyn Display))
+                    } else {
+                        None
+                    }
+                }
+                H::AcceptRanges if self.head.data.config.ranges => {
                     Some(("Accept-Ranges", BYTES_PTR as &Display))
                 }
-                H::Done => None,
+                H::AcceptRanges => {
+                    Some(("Accept-Ranges", NONE_PTR as &dyn Display))
+                }
+                H::Extra(idx) => match self.head.data.extra_headers.get(idx) {
+                    Some((name, value)) => {
+                        Some((&name[..], value as &dyn Display))
+                    }
+                    None => return None,
+                },
             };
-            self.state = match self.state {
-                H::LastModified => H::Etag,
-                H::Etag if self.head.not_modified => H::Done,
-                H::Etag => H::Encoding,
-                H::Encoding => H::AcceptRanges,
-                H::AcceptRanges => H::ContentRange,
-                H::ContentRange => H::ContentType,
-                H::ContentType => H::Done,
-                H::Done => return None,
+            self.state = self.state.next(self.head);
+            match value {
+                Some(x) => return Some(x),
+                None => continue,
+            }
+        }
+    }
+}
+
+impl<'a> Iterator for TypedHeaderIter<'a> {
+    type Item=(&'static str, TypedValue<'a>);
+    fn next(&mut self) -> Option<(&'static str, TypedValue<'a>)> {
+        use self::HeaderIterState as H;
+        let data = &self.head.data;
+        loop {
+            let value = match self.state {
+                H::Date => {
+                    data.date.map(|x| ("Date", TypedValue::Date(x.into())))
+                }
+                H::LastModified => {
+                    data.last_modified
+                        .map(|x| ("Last-Modified", TypedValue::Date(x.into())))
+                }
+                H::Etag => {
+                    data.etag.as_ref()
+                        .map(|x| ("ETag", TypedValue::Etag(x)))
+                }
+                H::ContentLocation => {
+                    data.location.as_ref()
+                        .map(|x| ("Content-Location", TypedValue::Text(x)))
+                }
+                H::Vary => {
+                    if data.vary_accept {
+                        Some(("Vary", TypedValue::Static(ACCEPT)))
+                    } else {
+                        None
+                    }
+                }
+                H::Encoding => {
+                    if data.encoding != Encoding::Identity {
+                        Some(("Content-Encoding",
+                              TypedValue::Encoding(data.encoding)))
+                    } else {
+                        None
+                    }
+                }
+                H::ContentRange => match data.status {
+                    Status::RangeNotSatisfiable(UnsatisfiedRange(size)) => {
+                        Some(("Content-Range",
+                              TypedValue::UnsatisfiedRange(size)))
+                    }
+                    _ => {
+                        data.range.as_ref().map(|x| ("Content-Range",
+                            TypedValue::Range(x.start, x.end, x.file_size)))
+                    }
+                },
+                H::ContentType => {
+                    if data.config.content_type {
+                        Some(("Content-Type", TypedValue::ContentType(
+                            &data.content_type.mime,
+                            data.content_type.charset())))
+                    } else {
+                        None
+                    }
+                }
+                H::ContentLength => {
+                    if data.config.content_length_header {
+                        Some(("Content-Length",
+                              TypedValue::Length(data.content_length)))
+                    } else {
+                        None
+                    }
+                }
+                H::AcceptRanges if data.config.ranges => {
+                    Some(("Accept-Ranges", TypedValue::Static(BYTES)))
+                }
+                H::AcceptRanges => {
+                    Some(("Accept-Ranges", TypedValue::Static(NONE)))
+                }
+                // yielded by `ExtraHeaderIter`
+                H::Extra(_) => return None,
             };
+            self.state = self.state.next(self.head);
             match value {
                 Some(x) => return Some(x),
                 None => continue,
@@ -161,193 +494,1117 @@ impl<'a> Iterator for HeaderIter<'a> {
     }
 }
 
+impl<'a> Iterator for ExtraHeaderIter<'a> {
+    type Item=(&'a str, &'a str);
+    fn next(&mut self) -> Option<(&'a str, &'a str)> {
+        self.iter.next().map(|(name, value)| (&name[..], &value[..]))
+    }
+}
+
+impl<'a> TypedValue<'a> {
+    /// Returns the value if it's a static string (doesn't need formatting)
+    pub fn as_static(&self) -> Option<&'static str> {
+        match *self {
+            TypedValue::Static(x) => Some(x),
+            TypedValue::Encoding(enc) => Some(enc.token()),
+            _ => None,
+        }
+    }
+}
+
+impl<'a> fmt::Display for TypedValue<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TypedValue::Date(x) => write!(f, "{}", HttpDate::from(x)),
+            TypedValue::Etag(x) => write!(f, "{}", x),
+            TypedValue::Encoding(x) => write!(f, "{}", x),
+            TypedValue::Length(x) => write!(f, "{}", x),
+            TypedValue::Range(start, end, file_size) => {
+                write!(f, "{}", ContentRange {
+                    start,
+                    end,
+                    file_size,
+                })
+            }
+            TypedValue::UnsatisfiedRange(size) => {
+                write!(f, "{}", UnsatisfiedRange(size))
+            }
+            TypedValue::ContentType(mime, Some(charset)) => {
+                write!(f, "{}; charset={}", mime, charset)
+            }
+            TypedValue::ContentType(mime, None) => f.write_str(mime),
+            TypedValue::Static(x) => f.write_str(x),
+            TypedValue::Text(x) => f.write_str(x),
+        }
+    }
+}
+
+pub(crate) fn sensible_date(time: SystemTime) -> Option<SystemTime> {
+    if time < UNIX_EPOCH + Duration::new(MIN_DATE, 0) {
+        None
+    } else {
+        Some(time)
+    }
+}
+
+impl FileInfo {
+    pub(crate) fn new(config: &Config, metadata: &Metadata) -> FileInfo {
+        FileInfo {
+            size: metadata.len(),
+            modified: if config.last_modified {
+                metadata.modified().and_then(sensible_date)
+            } else {
+                None
+            },
+            etag: if config.etag {
+                Some(Etag::weak(config.etag_provider.etag(metadata))
+                     .salted(&config.etag_salt))
+            } else {
+                None
+            },
+            charset: None,
+        }
+    }
+    /// Returns true if metadata matches (detected charset is not compared)
+    pub(crate) fn same_file(&self, other: &FileInfo) -> bool {
+        self.size == other.size &&
+            self.modified == other.modified &&
+            self.etag == other.etag
+    }
+    /// Same as `new`, but makes strong etag if enabled in config
+    ///
+    /// **Must be run in disk thread**
+    pub(crate) fn with_content<F: ReadAt>(config: &Config,
+        metadata: &Metadata, file: &F)
+        -> io::Result<FileInfo>
+    {
+        let mut info = FileInfo::new(config, metadata);
+        #[cfg(feature="xattr")]
+        let persist = config.etag_xattr;
+        #[cfg(not(feature="xattr"))]
+        let persist = false;
+        if let Some(ref hashes) = config.strong_etag {
+            if info.etag.is_some() {
+                info.etag = Some(hashes.get(file, metadata, persist)?
+                                 .salted(&config.etag_salt));
+            }
+        }
+        Ok(info)
+    }
+}
+
+impl DirInfo {
+    pub(crate) fn new(path: &Path, metadata: Metadata) -> DirInfo {
+        DirInfo {
+            path: path.to_path_buf(),
+            metadata: Box::new(metadata),
+        }
+    }
+    /// Path of the directory (as passed to `probe_file`)
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+    /// Metadata of the directory
+    pub fn metadata(&self) -> &Metadata {
+        &self.metadata
+    }
+    /// Modification time of the directory, usable as `Last-Modified` of
+    /// the listing
+    pub fn modified(&self) -> Option<SystemTime> {
+        self.metadata.modified()
+    }
+    /// Weak etag of the listing made of `entries` of this directory
+    ///
+    /// The etag changes when the directory is modified or the set of
+    /// entries changes (the order doesn't matter). Pass the names that
+    /// are actually shown (e.g. after filtering hidden files), as
+    /// returned by `FileSystem::read_dir`.
+    pub fn listing_etag<S: AsRef<OsStr>>(&self, entries: &[S]) -> Etag {
+        let names = entries.iter().map(|x| x.as_ref()).collect::<Vec<_>>();
+        Etag::from_listing(&self.metadata, &names)
+    }
+    /// Returns the path of the directory, consuming the info
+    pub fn into_path(self) -> PathBuf {
+        self.path
+    }
+}
+
 impl Head {
+    fn new(data: HeadData) -> Head {
+        Head { data: Box::new(data) }
+    }
     /// Returns true if response contains partial content (206)
     pub fn is_partial(&self) -> bool {
-        self.range.is_some()
+        self.data.range.is_some()
     }
     /// Returns true if response is skipped because cache is fresh (304)
     pub fn is_not_modified(&self) -> bool {
-        self.not_modified
+        matches!(self.data.status, Status::NotModified)
+    }
+    /// Returns HTTP status code that should be sent (200, 206, 304, 412
+    /// or 416)
+    pub fn suggested_status(&self) -> u16 {
+        match self.data.status {
+            Status::NotModified => 304,
+            Status::PreconditionFailed => 412,
+            Status::RangeNotSatisfiable(_) => 416,
+            Status::Ok if self.data.range.is_some() => 206,
+            Status::Ok => 200,
+        }
     }
-    pub(crate) fn from_meta(inp: &Input, encoding: Encoding,
-        metadata: &Metadata, ctype: &'static str)
+    /// Make head for generated content (e.g. directory listing, error
+    /// page or a blob in memory)
+    ///
+    /// Conditional and range headers of the request are checked the same
+    /// way as for files. Returns `Err(output)` if there is no body to send
+    /// (i.e. `NotModified`, `PreconditionFailed`, `InvalidMethod`,
+    /// `InvalidRange` or `RangeNotSatisfiable`). Otherwise
+    /// send `content_length()` bytes of the data, starting at the offset
+    /// from `byte_range()` if any. Use `Etag::from_data` to make etag.
+    ///
+    /// Etag and last modified time are dropped if disabled in config.
+    pub fn synthetic(inp: &Input, len: u64, mime: &'static str,
+        modified: Option<SystemTime>, etag: Option<Etag>)
         -> Result<Head, Output>
     {
-        let mod_time = if inp.config.last_modified {
-            metadata.modified().ok()
-            .and_then(|x| if x < UNIX_EPOCH + Duration::new(MIN_DATE, 0) {
-                None
+        match inp.mode {
+            Mode::Head | Mode::Get => {}
+            Mode::InvalidMethod => return Err(Output::InvalidMethod),
+            Mode::InvalidRange => return Err(Output::InvalidRange),
+        }
+        let info = FileInfo {
+            size: len,
+            modified: if inp.config.last_modified {
+                modified.and_then(sensible_date)
             } else {
-                Some(x)
-            })
+                None
+            },
+            etag: if inp.config.etag { etag } else { None },
+            charset: None,
+        };
+        Head::from_meta(inp, Encoding::Identity, &info, &Mime::Static(mime))
+    }
+    pub(crate) fn from_meta<F>(inp: &Input, encoding: Encoding,
+        info: &FileInfo, ctype: &Mime)
+        -> Result<Head, Output<F>>
+    {
+        let overrides = inp.overrides.as_deref();
+        let ctype = match overrides.and_then(|x| x.mime) {
+            Some(mime) => Mime::Static(mime),
+            None => ctype.clone(),
+        };
+        if !inp.charset_acceptable && inp.config.has_charset(&ctype) &&
+            inp.config.charset_policy == CharsetPolicy::NotAcceptable
+        {
+            debug!(content_type = &*ctype, "charset is not acceptable");
+            return Err(Output::NotAcceptable);
+        }
+        let content_type = ContentType {
+            mime: ctype,
+            config: inp.config.clone(),
+            charset_acceptable: inp.charset_acceptable,
+            sniffed: info.charset,
+        };
+        let mod_time = info.modified;
+        let size = info.size;
+        let date = if inp.config.date_header {
+            Some(SystemTime::now().into())
         } else {
             None
         };
-        let size = metadata.len();
-        let etag = if inp.config.etag {
-            Some(Etag::from_metadata(metadata))
+        let etag = match info.etag.clone() {
+            Some(_) if overrides.map(|x| x.no_etag).unwrap_or(false) => None,
+            Some(etag) if inp.config.etag_encoding_suffix => {
+                Some(etag.with_encoding(encoding))
+            }
+            etag => etag,
+        };
+        let extra_headers = overrides.and_then(|x| x.disposition.clone())
+            .into_iter().collect::<Vec<_>>();
+        let oversized = overrides.and_then(|x| x.oversized);
+        let precondition_failed = if oversized.is_some() {
+            true
+        } else if let Some(ref tags) = inp.if_match {
+            !tags.iter()
+                .any(|x| etag.as_ref().map(|e| e.strong_eq(x)).unwrap_or(false))
+        } else if let Some(ref since) = inp.if_unmodified {
+            // compare with the precision of `Last-Modified` we send
+            mod_time
+                .map(|x| HttpDate::from(x) > HttpDate::from(*since))
+                .unwrap_or(false)
         } else {
-            None
+            false
         };
+        if precondition_failed {
+            debug!(etag = ?etag.as_ref().map(|x| x.to_string()),
+                   "If-Match or If-Unmodified-Since failed");
+            inp.explain.condition(if let Some(name) = oversized {
+                name
+            } else if inp.if_match.is_some() {
+                "If-Match"
+            } else {
+                "If-Unmodified-Since"
+            });
+            return Err(Output::PreconditionFailed(Head::new(HeadData {
+                config: inp.config.clone(),
+                encoding,
+                content_length: 0, // no body
+                content_type,
+                last_modified: mod_time.map(Into::into),
+                date,
+                etag,
+                range: None,
+                location: None,
+                vary_accept: false,
+                status: Status::PreconditionFailed,
+                throttle: None,
+                explanation: None,
+                extra_headers,
+            })))
+        }
         if inp.if_none.len() > 0 {
-            if inp.if_none.iter().any(|x| Some(x) == etag.as_ref()) {
-                return Err(Output::NotModified(Head {
+            if inp.if_none.iter()
+                .any(|x| etag.as_ref().map(|e| e.weak_eq(x)).unwrap_or(false)) {
+                debug!(etag = ?etag.as_ref().map(|x| x.to_string()),
+                       "If-None-Match matches, not modified");
+                inp.explain.condition("If-None-Match");
+                return Err(Output::NotModified(Head::new(HeadData {
                     config: inp.config.clone(),
                     encoding: encoding,
                     content_length: 0, // don't need to send
-                    content_type: None, // don't need to send
+                    content_type,
                     last_modified: mod_time.map(Into::into),
+                    date,
                     etag: etag,
                     range: None,
-                    not_modified: true,
-                }))
+                    location: None,
+                    vary_accept: false,
+                    status: Status::NotModified,
+                    throttle: None,
+                    explanation: None,
+                    extra_headers,
+                })))
             }
         } else if let Some(ref last_mod) = inp.if_modified {
             if mod_time.as_ref().map(|x| last_mod <= x).unwrap_or(false) {
-                return Err(Output::NotModified(Head {
+                debug!("If-Modified-Since matches, not modified");
+                inp.explain.condition("If-Modified-Since");
+                return Err(Output::NotModified(Head::new(HeadData {
                     config: inp.config.clone(),
                     encoding: encoding,
                     content_length: 0, // don't need to send
-                    content_type: None, // don't need to send
+                    content_type,
                     last_modified: mod_time.map(Into::into),
+                    date,
                     etag: etag,
                     range: None,
-                    not_modified: true,
-                }))
+                    location: None,
+                    vary_accept: false,
+                    status: Status::NotModified,
+                    throttle: None,
+                    explanation: None,
+                    extra_headers,
+                })))
+            }
+        }
+        // if validator doesn't match, the whole file is sent
+        let range_valid = match overrides.and_then(|x| x.if_range.as_ref()) {
+            None => true,
+            Some(Ok(ref date)) => mod_time
+                .map(|x| HttpDate::from(x) == HttpDate::from(*date))
+                .unwrap_or(false),
+            Some(Err(ref tag)) => etag.as_ref().map(|e| {
+                if inp.config.strong_if_range {
+                    e.strong_eq(tag)
+                } else {
+                    e.weak_eq(tag)
+                }
+            }).unwrap_or(false),
+        };
+        if !range_valid {
+            debug!("If-Range doesn't match, sending the whole file");
+            inp.explain.condition("If-Range");
+        }
+        let inp_range = if range_valid { &inp.range } else { &None };
+        let (range, clen) = match resolve_range(inp_range, size) {
+            Ok(x) => x,
+            Err(unsatisfied) => {
+                debug!(size = size, "range is not satisfiable");
+                return Err(Output::RangeNotSatisfiable(Head::new(HeadData {
+                    config: inp.config.clone(),
+                    encoding,
+                    content_length: 0, // no body
+                    content_type,
+                    last_modified: mod_time.map(Into::into),
+                    date,
+                    etag,
+                    range: None,
+                    location: None,
+                    vary_accept: false,
+                    status: Status::RangeNotSatisfiable(unsatisfied),
+                    throttle: None,
+                    explanation: None,
+                    extra_headers,
+                })))
             }
+        };
+        let range = match range {
+            Some(ref r) if inp.config.full_range_as_ok &&
+                r.start == 0 && r.end.saturating_add(1) >= r.file_size
+            => None,
+            range => range,
+        };
+        if let Some(ref r) = range {
+            inp.explain.range(r.start, r.end);
         }
-        let (range, clen) = resolve_range(&inp.range, size)?;
-        Ok(Head {
+        Ok(Head::new(HeadData {
             config: inp.config.clone(),
             encoding: encoding,
             content_length: clen,
-            content_type: if inp.config.content_type {
-                Some(ContentType(ctype, inp.config.clone()))
-            } else {
-                None
-            },
+            content_type,
             last_modified: mod_time.map(Into::into),
+            date,
             etag: etag,
             range: range,
-            not_modified: false,
-        })
+            location: None,
+            vary_accept: false,
+            status: Status::Ok,
+            throttle: inp.config.rate_limit.map(|rate| {
+                Throttle::new(rate, inp.config.read_buffer_size as u64)
+            }),
+            explanation: None,
+            extra_headers,
+        }))
     }
     /// Returns the value of `Content-Length` header that should be sent
     pub fn content_length(&self) -> u64 {
-        self.content_length
+        self.data.content_length
+    }
+    /// Returns explanation of why this output is chosen
+    ///
+    /// Only available if `Config::explain` is enabled (and the head is
+    /// made by one of the `Input::probe_*` methods).
+    pub fn explanation(&self) -> Option<&Explanation> {
+        self.data.explanation.as_ref()
+    }
+    /// Returns `(start, end, total)` of the partial content
+    ///
+    /// The `end` is inclusive, as in `Content-Range` header. Returns `None`
+    /// if the whole file is sent (this includes a suffix range like
+    /// `bytes=-5` on an empty file).
+    pub fn byte_range(&self) -> Option<(u64, u64, u64)> {
+        self.data.range.as_ref().map(|r| (r.start, r.end, r.file_size))
+    }
+    /// Returns etag of the file (if enabled in config)
+    ///
+    /// Use `to_string()` to get the value of the `ETag` header.
+    pub fn etag(&self) -> Option<&Etag> {
+        self.data.etag.as_ref()
+    }
+    /// Returns modification time of the file as sent in `Last-Modified`
+    ///
+    /// Note: it's truncated to seconds, and is `None` if disabled in config
+    /// or if the file has no sensible modification time.
+    pub fn last_modified(&self) -> Option<SystemTime> {
+        self.data.last_modified.map(SystemTime::from)
+    }
+    /// Returns mime type of the file (without charset)
+    ///
+    /// This is returned even if `Content-Type` header is disabled in
+    /// config.
+    pub fn mime(&self) -> &str {
+        &self.data.content_type.mime
+    }
+    /// Returns encoding of the response body
+    pub fn encoding(&self) -> Encoding {
+        self.data.encoding
+    }
+    /// Returns the value of `Content-Location` header
+    ///
+    /// It's only set if the file is chosen by content negotiation (see
+    /// `Config::negotiate`) or if `Config::content_location` is enabled.
+    /// The value is a name of the file relative to the requested path.
+    pub fn content_location(&self) -> Option<&str> {
+        self.data.location.as_ref().map(|x| &x[..])
     }
     /// Returns the iterator over headers to send in response
     ///
-    /// Note: this does not include `Content-Length` header,
-    /// use `content_length()` method explicitly.
-    pub fn headers(&self) -> HeaderIter {
+    /// Headers added by `Config::header_rules` are yielded last. Note: this
+    /// does not include `Content-Length` header (unless
+    /// `Config::content_length_header` is enabled), use `content_length()`
+    /// method explicitly.
+    pub fn headers(&self) -> HeaderIter<'_> {
         HeaderIter {
             head: self,
-            state: HeaderIterState::LastModified,
+            state: HeaderIterState::Date,
+        }
+    }
+    /// Returns the iterator over headers with typed values
+    ///
+    /// This yields the same headers as `headers()`, but allows to avoid
+    /// formatting values that are static strings or that are stored in
+    /// typed form anyway. Headers added by `Config::header_rules` (or
+    /// `Input::force_download`) are not yielded, use `extra_headers()`.
+    pub fn typed_headers(&self) -> TypedHeaderIter<'_> {
+        TypedHeaderIter {
+            head: self,
+            state: HeaderIterState::Date,
         }
     }
+    /// Returns the iterator over headers added by `Config::header_rules`
+    /// and `Input::force_download`
+    ///
+    /// These are the last ones yielded by `headers()`.
+    pub fn extra_headers(&self) -> ExtraHeaderIter<'_> {
+        ExtraHeaderIter { iter: self.data.extra_headers.iter() }
+    }
 }
 
-impl FileWrapper {
-    pub(crate) fn new(head: Head, mut file: File)
-        -> Result<FileWrapper, io::Error>
+impl<F: ReadAt> FileWrapper<F> {
+    pub(crate) fn new(head: Head, file: F)
+        -> Result<FileWrapper<F>, io::Error>
     {
-        let nbytes = match head.range {
-            Some(ContentRange { start, end, .. }) => {
-                if start != 0 {
-                    file.seek(SeekFrom::Start(start))?;
-                }
-                end - start + 1
-            }
-            _ => head.content_length,
-        };
+        let offset = head.data.range.as_ref().map(|r| r.start).unwrap_or(0);
+        let nbytes = head.data.content_length;
+        #[cfg(feature="memmap")]
+        let mmap = map_file(&head.data.config, &file, offset, nbytes)?;
         Ok(FileWrapper {
             head: head,
             file: file,
             bytes_left: nbytes,
+            offset,
+            #[cfg(feature="memmap")]
+            mmap,
         })
     }
+    pub(crate) fn into_head(self) -> Head {
+        self.head
+    }
+    /// Split the wrapper into the head, the file and the `offset()`
+    ///
+    /// Number of bytes left is determined by the head and the offset, so
+    /// the wrapper can be reconstructed using `from_parts`. This is useful
+    /// to take ownership of the file for `sendfile(2)` or to move it
+    /// between threads in custom containers.
+    pub fn into_parts(self) -> (Head, F, u64) {
+        (self.head, self.file, self.offset)
+    }
+    /// Make the wrapper back from the parts returned by `into_parts`
+    ///
+    /// Reading is continued at `offset`. Returns error of kind
+    /// `InvalidInput` if offset is outside of the range (or the file) that
+    /// the head refers to.
+    pub fn from_parts(head: Head, file: F, offset: u64)
+        -> Result<FileWrapper<F>, io::Error>
+    {
+        let mut wrapper = FileWrapper::new(head, file)?;
+        let skip = offset.checked_sub(wrapper.offset)
+            .filter(|&skip| skip <= wrapper.bytes_left)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput,
+                "offset is outside of the response body"))?;
+        // bytes were sent by the previous wrapper, so don't report them
+        wrapper.bytes_left -= skip;
+        wrapper.offset += skip;
+        Ok(wrapper)
+    }
+    /// Returns metadata of the response
+    pub fn head(&self) -> &Head {
+        &self.head
+    }
     /// Returns true if response contains partial content (206)
     pub fn is_partial(&self) -> bool {
-        self.head.range.is_some()
+        self.head.data.range.is_some()
+    }
+    /// Returns HTTP status code that should be sent (200 or 206)
+    pub fn suggested_status(&self) -> u16 {
+        self.head.suggested_status()
     }
     /// Returns the value of `Content-Length` header that should be sent
     pub fn content_length(&self) -> u64 {
-        self.head.content_length
+        self.head.data.content_length
     }
     /// Returns the iterator over headers to send in response
     ///
-    /// Note: this does not include `Content-Length` header,
-    /// use `content_length()` method explicitly.
-    pub fn headers(&self) -> HeaderIter {
+    /// Headers added by `Config::header_rules` are yielded last. Note: this
+    /// does not include `Content-Length` header (unless
+    /// `Config::content_length_header` is enabled), use `content_length()`
+    /// method explicitly.
+    pub fn headers(&self) -> HeaderIter<'_> {
         self.head.headers()
     }
+    /// Returns the iterator over headers with typed values
+    ///
+    /// See `Head::typed_headers`.
+    pub fn typed_headers(&self) -> TypedHeaderIter<'_> {
+        self.head.typed_headers()
+    }
+    /// Returns the iterator over headers added by `Config::header_rules`
+    ///
+    /// See `Head::extra_headers`.
+    pub fn extra_headers(&self) -> ExtraHeaderIter<'_> {
+        self.head.extra_headers()
+    }
     /// Read chunk from file into an output file
     ///
+    /// At most `Config::read_buffer_size` bytes are read at once. Returns
+    /// error of kind `UnexpectedEof` if the file ends before the whole
+    /// body is sent, i.e. it's truncated since the head was made (see also
+    /// `Config::tail_mode`).
+    ///
     /// **Must be run in disk thread**
-    pub fn read_chunk<O>(&mut self, mut output: O) -> io::Result<usize>
+    pub fn read_chunk<O>(&mut self, output: O) -> io::Result<usize>
         where O: Write
     {
         if self.bytes_left == 0 {
             return Ok(0)
         }
-        let mut buf = [0u8; 65536];
-        let max = min(buf.len() as u64, self.bytes_left) as usize;
-        let bytes = self.file.read(&mut buf[..max])?;
-        let wbytes = match output.write(&buf[..bytes]) {
-            Ok(wbytes) if wbytes != bytes => {
-                assert!(wbytes < bytes);
-                self.file.seek(SeekFrom::Current(
-                    - ((bytes - wbytes) as i64)))?;
-                wbytes
+        let size = min(self.head.data.config.read_buffer_size as u64,
+                       self.allowance()?) as usize;
+        #[cfg(feature="memmap")]
+        {
+            if self.mmap.is_some() {
+                return self.write_mapped(output, size);
             }
-            Ok(wbytes) => wbytes,
-            Err(e) => {
-                // Probaby it's WouldBlock, but let's rewind on anything
-                self.file.seek(SeekFrom::Current(- (bytes as i64)))?;
-                return Err(e);
-            }
-        };
-        self.bytes_left -= wbytes as u64;
-        Ok(wbytes)
-    }
-}
-
-impl Output {
-}
-
-impl fmt::Display for ContentRange {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        if self.file_size == 0 {
-            write!(f, "bytes */0")
-        } else {
-            write!(f, "bytes {}-{}/{}", self.start, self.end, self.file_size)
         }
-    }
-}
-
-impl fmt::Display for ContentType {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        if is_text_file(self.0) {
-            if let Some(ref charset) = self.1.text_charset {
-                write!(f, "{}; charset={}", self.0, charset)
-            } else {
-                f.write_str(self.0)
-            }
+        if size > STACK_BUFFER {
+            let mut buf = vec![0u8; size];
+            self.read_with_buffer(&mut buf, output)
         } else {
-            f.write_str(self.0)
+            let mut buf = [0u8; STACK_BUFFER];
+            self.read_with_buffer(&mut buf[..size], output)
         }
     }
-}
-
-fn resolve_range(inp_range: &Option<Range>, size: u64)
-    -> Result<(Option<ContentRange>, u64), Output>
+    /// Read next chunk of the body into a buffer
+    ///
+    /// Returns number of bytes read, zero means the whole body is read.
+    /// Unlike `read_chunk` this doesn't use intermediate buffer, so buffer
+    /// management is up to the caller. Premature end of file is an error,
+    /// the same as for `read_chunk`.
+    ///
+    /// **Must be run in disk thread**
+    pub fn read_into(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let max = min(buf.len() as u64, self.bytes_left) as usize;
+        if max == 0 {
+            return Ok(0)
+        }
+        let max = min(max as u64, self.allowance()?) as usize;
+        #[cfg(feature="memmap")]
+        {
+            if self.mmap.is_some() {
+                let max = {
+                    let chunk = self.mapped_chunk().expect("file is mapped");
+                    let max = min(chunk.len(), max);
+                    buf[..max].copy_from_slice(&chunk[..max]);
+                    max
+                };
+                if max == 0 {
+                    return self.eof();
+                }
+                self.mark_sent(max as u64);
+                return Ok(max);
+            }
+        }
+        let bytes = self.file.read_at(&mut buf[..max], self.offset)?;
+        if bytes == 0 {
+            return self.eof();
+        }
+        self.mark_sent(bytes as u64);
+        Ok(bytes)
+    }
+    /// Error when file ends before the body is complete
+    ///
+    /// Unless in tail mode, the file is truncated since the head was made,
+    /// so the response can't be completed and the connection should be
+    /// closed (so client doesn't cache the short body).
+    fn eof(&self) -> io::Result<usize> {
+        debug!(offset = self.offset, bytes_left = self.bytes_left,
+               "premature end of file");
+        if self.head.data.config.tail_mode {
+            Err(io::Error::new(io::ErrorKind::WouldBlock,
+                "file is not written up to the response size yet"))
+        } else {
+            Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                "file is truncated while sending"))
+        }
+    }
+    /// Read the rest of the body into a vector
+    ///
+    /// Returns error of kind `InvalidInput` without reading anything if
+    /// more than `limit` bytes are left. This is useful to send small
+    /// files in one go.
+    ///
+    /// If read would block (because of the rate limit or in tail mode)
+    /// after some bytes are read, these bytes are returned, so check
+    /// `bytes_left()` to find out whether the body is complete.
+    ///
+    /// **Must be run in disk thread**
+    pub fn read_all(&mut self, limit: u64) -> io::Result<Vec<u8>> {
+        if self.bytes_left > limit {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                "response body is larger than the limit"));
+        }
+        let mut buf = vec![0u8; self.bytes_left as usize];
+        let mut pos = 0;
+        while pos < buf.len() {
+            match self.read_into(&mut buf[pos..]) {
+                Ok(0) => break,
+                Ok(bytes) => pos += bytes,
+                // bytes read are already marked as sent, so keep them
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock &&
+                    pos > 0 => break,
+                Err(e) => return Err(e),
+            }
+        }
+        buf.truncate(pos);
+        Ok(buf)
+    }
+    #[cfg(feature="memmap")]
+    fn write_mapped<O>(&mut self, mut output: O, size: usize)
+        -> io::Result<usize>
+        where O: Write
+    {
+        let wbytes = {
+            let chunk = self.mapped_chunk().expect("file is mapped");
+            if chunk.is_empty() {
+                return self.eof();
+            }
+            let max = min(chunk.len(), size);
+            output.write(&chunk[..max])?
+        };
+        self.mark_sent(wbytes as u64);
+        Ok(wbytes)
+    }
+    fn read_with_buffer<O>(&mut self, buf: &mut [u8], mut output: O)
+        -> io::Result<usize>
+        where O: Write
+    {
+        let max = min(buf.len() as u64, self.bytes_left) as usize;
+        let bytes = self.file.read_at(&mut buf[..max], self.offset)?;
+        if bytes == 0 {
+            return self.eof();
+        }
+        // File position is not used, so there is nothing to rewind if
+        // write is partial or fails (probably with WouldBlock)
+        let wbytes = output.write(&buf[..bytes])?;
+        assert!(wbytes <= bytes);
+        self.mark_sent(wbytes as u64);
+        Ok(wbytes)
+    }
+    /// Check that the file is not changed since the head was made
+    ///
+    /// Compares size, modification time and (metadata-based) etag of the
+    /// opened file with the ones in the head, and returns `true` if they
+    /// are the same, or if the file can't change (see `ReadAt::metadata`).
+    ///
+    /// If the file is changed and nothing is read yet, content length,
+    /// etag and last modified time are regenerated and `false` is
+    /// returned, so headers should be taken from `head()` again. Partial
+    /// responses can't be regenerated (the range refers to the old
+    /// version of the file), so error of kind `InvalidData` is returned
+    /// for them and if some bytes are read already.
+    ///
+    /// This is useful if wrapper is kept for some time (e.g. in a queue)
+    /// before the headers are sent.
+    ///
+    /// **Must be run in disk thread**
+    pub fn revalidate(&mut self) -> io::Result<bool> {
+        let meta = match self.file.metadata()? {
+            Some(meta) => meta,
+            None => return Ok(true),
+        };
+        let config = self.head.data.config.clone();
+        let info = FileInfo::new(&config, &meta);
+        let size = match self.head.data.range {
+            Some(ref range) => range.file_size,
+            None => self.head.data.content_length,
+        };
+        let etag_changed = match (&self.head.data.etag, &info.etag) {
+            // strong etags are hashes of the contents, so are not compared
+            (Some(old), Some(new)) => {
+                old.is_weak() && old.value != new.value
+            }
+            _ => false,
+        };
+        if info.size == size && !etag_changed &&
+            info.modified.map(HttpDate::from) == self.head.data.last_modified
+        {
+            return Ok(true);
+        }
+        if self.head.data.range.is_some() || self.offset != 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                "file is changed since the response head was made"));
+        }
+        let info = FileInfo::with_content(&config, &meta, &self.file)?;
+        #[cfg(feature="memmap")]
+        {
+            self.mmap = map_file(&config, &self.file, 0, info.size)?;
+        }
+        let data = &mut self.head.data;
+        data.content_length = info.size;
+        data.last_modified = info.modified.map(Into::into);
+        data.etag = match info.etag {
+            Some(etag) if config.etag_encoding_suffix => {
+                Some(etag.with_encoding(data.encoding))
+            }
+            etag => etag,
+        };
+        self.bytes_left = info.size;
+        Ok(false)
+    }
+    /// Returns offset in the file of the next byte to send
+    ///
+    /// Along with `bytes_left()` and `as_raw_fd()` this allows to send
+    /// file using `sendfile(2)` or similar system calls. Use `mark_sent`
+    /// to update the state afterwards.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+    /// Returns number of bytes that are still to be sent
+    pub fn bytes_left(&self) -> u64 {
+        self.bytes_left
+    }
+    /// Mark `n` bytes as sent by external means (e.g. by `sendfile(2)`)
+    ///
+    /// After that `read_chunk` can be used to send the rest of the file.
+    /// Note: file position is not used by the wrapper (reads are done
+    /// at `offset()`), so it's never updated.
+    ///
+    /// # Panics
+    ///
+    /// When `n` is larger than `bytes_left()`
+    pub fn mark_sent(&mut self, n: u64) {
+        assert!(n <= self.bytes_left);
+        trace!(offset = self.offset, bytes = n,
+               bytes_left = self.bytes_left - n, "chunk sent");
+        self.bytes_left -= n;
+        self.offset += n;
+        if let Some(ref mut throttle) = self.head.data.throttle {
+            throttle.consume(n, Instant::now());
+        }
+        if let Some(ref metrics) = self.head.data.config.metrics {
+            metrics.bytes_sent(n);
+        }
+    }
+    /// Limit bandwidth of this response to `bytes_per_second`
+    ///
+    /// Overrides `Config::rate_limit` (`None` disables the limit). See
+    /// `throttle_delay` for details.
+    ///
+    /// # Panics
+    ///
+    /// When limit is zero
+    pub fn set_rate_limit(&mut self, bytes_per_second: Option<u64>) {
+        assert!(bytes_per_second != Some(0), "rate limit must be non-zero");
+        let burst = self.head.data.config.read_buffer_size as u64;
+        self.head.data.throttle = bytes_per_second
+            .map(|rate| Throttle::new(rate, burst));
+    }
+    /// Returns time to wait until the next chunk can be read
+    ///
+    /// When rate limit is exceeded, `read_chunk` and `read_into` return
+    /// error of kind `WouldBlock`, the read should be retried after this
+    /// delay. Returns `None` if chunk of `Config::read_buffer_size` (or the
+    /// rest of the body) can be read immediately.
+    pub fn throttle_delay(&self) -> Option<Duration> {
+        let chunk = min(self.head.data.config.read_buffer_size as u64,
+                        self.bytes_left);
+        self.head.data.throttle.as_ref()
+            .and_then(|t| t.delay(chunk, Instant::now()))
+    }
+    /// Number of bytes that can be read now (with rate limit)
+    fn allowance(&self) -> io::Result<u64> {
+        match self.head.data.throttle {
+            Some(ref throttle) => match throttle.allowance(Instant::now()) {
+                0 => {
+                    trace!(offset = self.offset, "rate limit exceeded");
+                    Err(io::Error::new(io::ErrorKind::WouldBlock,
+                        "rate limit exceeded, see `throttle_delay()`"))
+                }
+                bytes => Ok(bytes),
+            },
+            None => Ok(u64::MAX),
+        }
+    }
+    /// Returns the rest of the file body if file is memory-mapped
+    ///
+    /// Use `mark_sent` to mark part of the chunk as sent. Returns `None`
+    /// if the file is not mapped (see `Config::mmap_threshold`). The chunk
+    /// is shorter than `bytes_left()` if file was truncated before it was
+    /// mapped.
+    ///
+    /// Only available with the `memmap` feature.
+    #[cfg(feature="memmap")]
+    pub fn mapped_chunk(&self) -> Option<&[u8]> {
+        self.mmap.as_ref().map(|m| {
+            let len = m.map.len() as u64;
+            let start = self.offset - m.offset;
+            let end = min(start + self.bytes_left, len);
+            let start = min(start, end);
+            &m.map[start as usize..end as usize]
+        })
+    }
+}
+
+/// Reads the rest of the body (same as `read_into`)
+///
+/// Unlike `read_into` it sleeps for `throttle_delay()` when rate limit is
+/// exceeded, so `io::copy` and similar work with `Config::rate_limit`.
+/// In tail mode `WouldBlock` is still returned for a file not written yet.
+///
+/// **Must be run in disk thread**
+impl<F: ReadAt> Read for FileWrapper<F> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let result = self.read_into(buf);
+            match result {
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    match self.throttle_delay() {
+                        Some(delay) => thread::sleep(delay),
+                        None => return result,
+                    }
+                }
+                _ => return result,
+            }
+        }
+    }
+}
+
+impl<R: Read + Seek> FileWrapper<SeekReader<R>> {
+    /// Make a wrapper that reads body from any seekable reader
+    ///
+    /// This is useful along with `Head::synthetic` to serve archive
+    /// entries, in-memory buffers and other non-file bodies. Range of the
+    /// head is honored, i.e. reading starts at the start of the range.
+    pub fn from_reader(head: Head, reader: R)
+        -> io::Result<FileWrapper<SeekReader<R>>>
+    {
+        FileWrapper::new(head, SeekReader::new(reader))
+    }
+}
+
+#[cfg(unix)]
+impl<F: AsRawFd> AsRawFd for FileWrapper<F> {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
+#[cfg(windows)]
+impl<F: AsRawHandle> AsRawHandle for FileWrapper<F> {
+    fn as_raw_handle(&self) -> RawHandle {
+        self.file.as_raw_handle()
+    }
+}
+
+impl<F: ReadAt> Output<F> {
+    /// Returns HTTP status code that should be used for the response
+    ///
+    /// Note: `Directory` is reported as 404, because serving directory
+    /// listings (or redirecting to the index) is up to the application.
+    pub fn suggested_status(&self) -> u16 {
+        match *self {
+            Output::NotFound => 404,
+            Output::FileHead(ref head) => head.suggested_status(),
+            Output::NotModified(ref head) => head.suggested_status(),
+            Output::File(ref wrapper) => wrapper.suggested_status(),
+            Output::FileRange(ref wrapper) => wrapper.suggested_status(),
+            Output::FileInMemory(ref head, _) => head.suggested_status(),
+            Output::Delegate(ref head, _) => head.suggested_status(),
+            Output::Directory => 404,
+            Output::Forbidden => 403,
+            Output::InvalidMethod => 405,
+            Output::InvalidRange => 416,
+            Output::RangeNotSatisfiable(ref head) => head.suggested_status(),
+            Output::PreconditionFailed(ref head) => head.suggested_status(),
+            Output::NotAcceptable => 406,
+            Output::Redirect(status, _) => status,
+        }
+    }
+    /// Returns explanation of why this output is chosen
+    ///
+    /// It's `None` unless `Config::explain` is enabled and for outputs
+    /// without a head (use `Input::explanation` for them).
+    pub fn explanation(&self) -> Option<&Explanation> {
+        match *self {
+            Output::FileHead(ref head) |
+            Output::NotModified(ref head) |
+            Output::FileInMemory(ref head, _) |
+            Output::Delegate(ref head, _) |
+            Output::RangeNotSatisfiable(ref head) |
+            Output::PreconditionFailed(ref head) => head.explanation(),
+            Output::File(ref wrapper) |
+            Output::FileRange(ref wrapper) => wrapper.head.explanation(),
+            Output::NotFound | Output::Directory | Output::Forbidden |
+            Output::InvalidMethod | Output::InvalidRange |
+            Output::NotAcceptable | Output::Redirect(..) => None,
+        }
+    }
+    /// Name of the variant (used for metrics)
+    pub(crate) fn variant_name(&self) -> &'static str {
+        match *self {
+            Output::NotFound => "NotFound",
+            Output::FileHead(..) => "FileHead",
+            Output::NotModified(..) => "NotModified",
+            Output::File(..) => "File",
+            Output::FileRange(..) => "FileRange",
+            Output::FileInMemory(..) => "FileInMemory",
+            Output::Delegate(..) => "Delegate",
+            Output::Directory => "Directory",
+            Output::Forbidden => "Forbidden",
+            Output::InvalidMethod => "InvalidMethod",
+            Output::InvalidRange => "InvalidRange",
+            Output::RangeNotSatisfiable(..) => "RangeNotSatisfiable",
+            Output::PreconditionFailed(..) => "PreconditionFailed",
+            Output::NotAcceptable => "NotAcceptable",
+            Output::Redirect(..) => "Redirect",
+        }
+    }
+    /// Encoding of the response body (identity if there is no body)
+    pub(crate) fn encoding(&self) -> Encoding {
+        match *self {
+            Output::FileHead(ref head) |
+            Output::NotModified(ref head) |
+            Output::FileInMemory(ref head, _) |
+            Output::Delegate(ref head, _) |
+            Output::RangeNotSatisfiable(ref head) |
+            Output::PreconditionFailed(ref head) => head.encoding(),
+            Output::File(ref wrapper) |
+            Output::FileRange(ref wrapper) => wrapper.head.encoding(),
+            Output::NotFound | Output::Directory | Output::Forbidden |
+            Output::InvalidMethod | Output::InvalidRange |
+            Output::NotAcceptable | Output::Redirect(..) => Encoding::Identity,
+        }
+    }
+    pub(crate) fn head_mut(&mut self) -> Option<&mut Head> {
+        match *self {
+            Output::FileHead(ref mut head) |
+            Output::NotModified(ref mut head) |
+            Output::FileInMemory(ref mut head, _) |
+            Output::Delegate(ref mut head, _) |
+            Output::RangeNotSatisfiable(ref mut head) |
+            Output::PreconditionFailed(ref mut head) => Some(head),
+            Output::File(ref mut wrapper) |
+            Output::FileRange(ref mut wrapper) => Some(&mut wrapper.head),
+            Output::NotFound | Output::Directory | Output::Forbidden |
+            Output::InvalidMethod | Output::InvalidRange |
+            Output::NotAcceptable | Output::Redirect(..) => None,
+        }
+    }
+    /// Set `Content-Location` of the response (if `location` is `Some`)
+    pub(crate) fn with_location(mut self, location: Option<String>)
+        -> Output<F>
+    {
+        if let Some(head) = self.head_mut() {
+            if location.is_some() {
+                head.data.location = location;
+            }
+        }
+        self
+    }
+    /// Mark response as chosen by `Accept` header
+    ///
+    /// Adds `Vary: Accept` and `Content-Location` (unless the latter is
+    /// already set to a more specific variant).
+    pub(crate) fn negotiated(mut self, location: String) -> Output<F> {
+        if let Some(head) = self.head_mut() {
+            head.data.vary_accept = true;
+            if head.data.location.is_none() {
+                head.data.location = Some(location);
+            }
+        }
+        self
+    }
+    /// Add `Vary: Accept` (if there is a head)
+    pub(crate) fn vary_accept(mut self) -> Output<F> {
+        if let Some(head) = self.head_mut() {
+            head.data.vary_accept = true;
+        }
+        self
+    }
+}
+
+impl fmt::Display for ContentRange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.file_size == 0 {
+            write!(f, "bytes */0")
+        } else {
+            write!(f, "bytes {}-{}/{}", self.start, self.end, self.file_size)
+        }
+    }
+}
+
+impl fmt::Display for UnsatisfiedRange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "bytes */{}", self.0)
+    }
+}
+
+impl ops::Deref for Mime {
+    type Target = str;
+    fn deref(&self) -> &str {
+        match *self {
+            Mime::Static(x) => x,
+            Mime::Shared(ref x) => x,
+        }
+    }
+}
+
+impl ContentType {
+    fn charset(&self) -> Option<&str> {
+        if self.charset_acceptable && self.config.has_charset(&self.mime) {
+            let sniffed = self.sniffed;
+            self.config.text_charset.as_ref()
+                .map(|x| sniffed.unwrap_or(&x[..]))
+        } else {
+            None
+        }
+    }
+}
+
+impl fmt::Display for ContentType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(charset) = self.charset() {
+            write!(f, "{}; charset={}", &*self.mime, charset)
+        } else {
+            f.write_str(&self.mime)
+        }
+    }
+}
+
+/// Map `nbytes` of the file starting at `offset` (only the part that
+/// exists now, so the map is shorter if the file is already truncated)
+#[cfg(feature="memmap")]
+fn map_file<F: ReadAt>(config: &Config, file: &F, offset: u64, nbytes: u64)
+    -> io::Result<Option<Mapped>>
+{
+    match (config.mmap_threshold, file.as_file()) {
+        (Some(threshold), Some(file)) if nbytes > 0 && nbytes >= threshold
+        => {
+            let file_len = file.metadata()?.len();
+            let len = min(nbytes, file_len.saturating_sub(offset));
+            if len == 0 {
+                // nothing to map, reading reports the truncation
+                return Ok(None);
+            }
+            // Unsound if the file is truncated while mapped (the process
+            // gets `SIGBUS`), this is the contract of
+            // `Config::mmap_threshold` which the user has opted into
+            let map = unsafe {
+                MmapOptions::new().offset(offset).len(len as usize).map(file)?
+            };
+            Ok(Some(Mapped { offset, map }))
+        }
+        _ => Ok(None),
+    }
+}
+
+fn resolve_range(inp_range: &Option<Range>, size: u64)
+    -> Result<(Option<ContentRange>, u64), UnsatisfiedRange>
 {
     let range = match *inp_range {
         Some(Range::SingleRangeOfBytes(Slice::FromTo(s, e))) => {
             if s >= size {
-                return Err(Output::InvalidRange);
+                return Err(UnsatisfiedRange(size));
             } else {
                 let nbytes = min(size - s, (e - s).saturating_add(1));
                 Some(ContentRange {
@@ -357,6 +1614,9 @@ fn resolve_range(inp_range: &Option<Range>, size: u64)
                 })
             }
         }
+        // there is no way to express an empty part in `Content-Range`,
+        // so the whole (empty) file is sent
+        Some(Range::SingleRangeOfBytes(Slice::Last(_))) if size == 0 => None,
         Some(Range::SingleRangeOfBytes(Slice::Last(mut nbytes))) => {
             let start = if nbytes > size {
                 nbytes = size;
@@ -372,7 +1632,7 @@ fn resolve_range(inp_range: &Option<Range>, size: u64)
         }
         Some(Range::SingleRangeOfBytes(Slice::AllFrom(start))) => {
             if start >= size {
-                return Err(Output::InvalidRange);
+                return Err(UnsatisfiedRange(size));
             } else {
                 Some(ContentRange {
                     start: start,
@@ -384,7 +1644,6 @@ fn resolve_range(inp_range: &Option<Range>, size: u64)
         None => None,
     };
     let clen = match range {
-        Some(_) if size == 0 => 0,
         Some(ref rng) => rng.end - rng.start + 1,
         None => size,
     };
@@ -393,24 +1652,53 @@ fn resolve_range(inp_range: &Option<Range>, size: u64)
 
 #[cfg(test)]
 mod test {
-    use std::mem::size_of;
     use super::*;
 
     fn send<T: Send>(_: &T) {}
     fn self_contained<T: 'static>(_: &T) {}
 
+    /// Reports size 11 for any file, as if it was truncated after `stat`
+    /// (or is not fully written yet)
+    struct Stale(PathBuf);
+
+    impl ::vfs::FileSystem for Stale {
+        type File = File;
+        fn metadata(&self, _: &Path) -> io::Result<Metadata> {
+            Ok(Metadata::file(11, None))
+        }
+        fn open(&self, _: &Path) -> io::Result<(File, Metadata)> {
+            Ok((File::open(&self.0)?, Metadata::file(11, None)))
+        }
+        fn read_dir(&self, _: &Path)
+            -> io::Result<Vec<::std::ffi::OsString>>
+        {
+            Ok(Vec::new())
+        }
+    }
+
     #[test]
     #[cfg(unix)]
     fn traits() {
-        let v = Output::NotFound;
+        let v: Output = Output::NotFound;
         send(&v);
         self_contained(&v);
     }
 
-    #[cfg(all(target_arch="x86_64", target_os="linux"))]
+    #[cfg(all(target_arch="x86_64", target_os="linux",
+              not(feature="memmap")))]
     #[test]
     fn size() {
-        assert_eq!(size_of::<Output>(), 128);
+        use std::mem::size_of;
+        assert_eq!(size_of::<Output>(), 40);
+    }
+
+    #[test]
+    fn suggested_status() {
+        assert_eq!(Output::<File>::NotFound.suggested_status(), 404);
+        assert_eq!(Output::<File>::Directory.suggested_status(), 404);
+        assert_eq!(Output::<File>::Forbidden.suggested_status(), 403);
+        assert_eq!(Output::<File>::InvalidMethod.suggested_status(), 405);
+        assert_eq!(Output::<File>::InvalidRange.suggested_status(), 416);
     }
 
     #[test]
@@ -459,8 +1747,7 @@ mod test {
 
     #[test]
     fn range_on_zero_length() {
-        assert_eq!(resolve(last(100), 0), res(0, 0, 0));
-        assert_eq!(resolve_clen(last(100), 0), 0);
+        assert_eq!(resolve_range(&Some(last(100)), 0).unwrap(), (None, 0));
         resolve_range(&Some(from(100)), 0).unwrap_err();
         resolve_range(&Some(range(0, 100)), 0).unwrap_err();
     }
@@ -469,7 +1756,8 @@ mod test {
     fn range_on_short() {
         assert_eq!(resolve(last(1000), 100), res(0, 99, 100));
         assert_eq!(resolve_clen(last(1000), 100), 100);
-        resolve_range(&Some(range(1000, 2000)), 100).unwrap_err();
+        assert_eq!(resolve_range(&Some(range(1000, 2000)), 100).unwrap_err(),
+                   UnsatisfiedRange(100));
         assert_eq!(resolve(range(10, 1000), 100), res(10, 99, 100));
         assert_eq!(resolve_clen(range(10, 1000), 100), 90);
     }
@@ -480,4 +1768,722 @@ mod test {
         assert_eq!(resolve(range(100, 1000), 10000), res(100, 1000, 10000));
         assert_eq!(resolve(from(777), 10000), res(777, 9999, 10000));
     }
+
+    #[cfg(feature="memmap")]
+    #[test]
+    fn mmap() {
+        let cfg = Config::new().mmap_threshold(0).done();
+        let inp = Input::from_headers(&cfg, "GET",
+            vec![("Range", &b"bytes=2-8"[..])].into_iter());
+        let mut wrapper = match inp.probe_file("public/index.html").unwrap() {
+            Output::File(wrapper) => wrapper,
+            _ => unreachable!(),
+        };
+        assert_eq!(wrapper.mapped_chunk(), Some(&b"DOCTYPE"[..]));
+        // only the range is mapped
+        assert_eq!(wrapper.mmap.as_ref().map(|m| m.map.len()), Some(7));
+        wrapper.mark_sent(2);
+        let mut buf = Vec::new();
+        assert_eq!(wrapper.read_chunk(&mut buf).unwrap(), 5);
+        assert_eq!(&buf[..], b"CTYPE");
+        assert_eq!(wrapper.read_chunk(&mut buf).unwrap(), 0);
+    }
+
+    #[test]
+    fn suffix_range_on_empty_file() {
+        use test_util::TempDir;
+
+        let dir = TempDir::new("empty-range");
+        let path = dir.write("empty.txt", "");
+        let cfg = Config::new().done();
+        let inp = Input::from_headers(&cfg, "GET",
+            vec![("Range", &b"bytes=-5"[..])].into_iter());
+        let mut wrapper = match inp.probe_file(&path).unwrap() {
+            Output::File(wrapper) => wrapper,
+            _ => unreachable!(),
+        };
+        assert_eq!(wrapper.suggested_status(), 200);
+        assert_eq!(wrapper.head().byte_range(), None);
+        assert_eq!((wrapper.content_length(), wrapper.bytes_left()), (0, 0));
+        let mut buf = Vec::new();
+        assert_eq!(wrapper.read_chunk(&mut buf).unwrap(), 0);
+        assert!(buf.is_empty());
+    }
+
+    #[cfg(feature="memmap")]
+    #[test]
+    fn mmap_truncated() {
+        use test_util::TempDir;
+
+        let dir = TempDir::new("mmap");
+        let fs = Stale(dir.write("a.txt", "hello"));
+        let cfg = Config::new().mmap_threshold(0).done();
+        let inp = Input::from_headers(&cfg, "GET", vec![].into_iter());
+        let mut wrapper = match inp.probe_file_in(&fs, "/a.txt").unwrap() {
+            Output::File(wrapper) => wrapper,
+            _ => unreachable!(),
+        };
+        assert_eq!(wrapper.mapped_chunk(), Some(&b"hello"[..]));
+        let mut buf = Vec::new();
+        assert_eq!(wrapper.read_chunk(&mut buf).unwrap(), 5);
+        let err = wrapper.read_chunk(&mut buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn mark_sent() {
+        let cfg = Config::new().done();
+        let inp = Input::from_headers(&cfg, "GET",
+            vec![("Range", &b"bytes=2-8"[..])].into_iter());
+        let mut wrapper = match inp.probe_file("public/index.html").unwrap() {
+            Output::File(wrapper) => wrapper,
+            _ => unreachable!(),
+        };
+        assert_eq!((wrapper.offset(), wrapper.bytes_left()), (2, 7));
+        assert_eq!(wrapper.head().byte_range(), Some((2, 8, 126)));
+        wrapper.mark_sent(2);
+        assert_eq!((wrapper.offset(), wrapper.bytes_left()), (4, 5));
+        let mut buf = Vec::new();
+        assert_eq!(wrapper.read_chunk(&mut buf).unwrap(), 5);
+        assert_eq!(&buf[..], b"CTYPE");
+        assert_eq!((wrapper.offset(), wrapper.bytes_left()), (9, 0));
+    }
+
+    #[test]
+    fn read_buffer_size() {
+        let cfg = Config::new().read_buffer_size(100).done();
+        let inp = Input::from_headers(&cfg, "GET", vec![].into_iter());
+        let mut wrapper = match inp.probe_file("public/index.html").unwrap() {
+            Output::File(wrapper) => wrapper,
+            _ => unreachable!(),
+        };
+        let mut buf = Vec::new();
+        assert_eq!(wrapper.read_chunk(&mut buf).unwrap(), 100);
+        assert_eq!(wrapper.read_chunk(&mut buf).unwrap(), 26);
+        assert_eq!(wrapper.read_chunk(&mut buf).unwrap(), 0);
+    }
+
+    #[test]
+    fn read_into() {
+        let cfg = Config::new().done();
+        let inp = Input::from_headers(&cfg, "GET",
+            vec![("Range", &b"bytes=2-8"[..])].into_iter());
+        let mut wrapper = match inp.probe_file("public/index.html").unwrap() {
+            Output::File(wrapper) => wrapper,
+            _ => unreachable!(),
+        };
+        let mut buf = [0u8; 4];
+        assert_eq!(wrapper.read_into(&mut buf).unwrap(), 4);
+        assert_eq!(&buf, b"DOCT");
+        assert_eq!(wrapper.read_into(&mut buf).unwrap(), 3);
+        assert_eq!(&buf[..3], b"YPE");
+        assert_eq!(wrapper.read_into(&mut buf).unwrap(), 0);
+    }
+
+    #[test]
+    fn read_all() {
+        let cfg = Config::new().done();
+        let inp = Input::from_headers(&cfg, "GET",
+            vec![("Range", &b"bytes=2-8"[..])].into_iter());
+        let mut wrapper = match inp.probe_file("public/index.html").unwrap() {
+            Output::File(wrapper) => wrapper,
+            _ => unreachable!(),
+        };
+        assert_eq!(wrapper.read_all(6).unwrap_err().kind(),
+                   io::ErrorKind::InvalidInput);
+        assert_eq!(&wrapper.read_all(7).unwrap()[..], b"DOCTYPE");
+        assert_eq!(wrapper.read_all(7).unwrap().len(), 0);
+    }
+
+    #[test]
+    fn accessors() {
+        let cfg = Config::new().content_type(false).done();
+        let inp = Input::from_headers(&cfg, "HEAD",
+            vec![("Accept-Encoding", &b"gzip"[..])].into_iter());
+        let head = match inp.probe_file("public/index.html").unwrap() {
+            Output::FileHead(head) => head,
+            _ => unreachable!(),
+        };
+        assert_eq!(head.mime(), "text/html");
+        assert_eq!(head.encoding(), Encoding::Gzip);
+        assert!(head.etag().unwrap().is_weak());
+        assert_eq!(head.headers().find(|&(name, _)| name == "ETag")
+                   .unwrap().1.to_string(),
+                   head.etag().unwrap().to_string());
+        assert!(head.last_modified().is_some());
+        assert!(!head.headers().any(|(name, _)| name == "Content-Type"));
+        assert_eq!(head.byte_range(), None);
+    }
+
+    #[test]
+    fn typed_headers() {
+        let cfg = Config::new().done();
+        let inp = Input::from_headers(&cfg, "GET", vec![
+            ("Accept-Encoding", &b"gzip"[..]),
+            ("Range", &b"bytes=2-8"[..]),
+        ].into_iter());
+        let wrapper = match inp.probe_file("public/index.html").unwrap() {
+            Output::File(wrapper) => wrapper,
+            _ => unreachable!(),
+        };
+        let head = wrapper.head();
+        let plain = head.headers()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect::<Vec<_>>();
+        let typed = head.typed_headers()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect::<Vec<_>>();
+        assert_eq!(plain, typed);
+        let statics = head.typed_headers()
+            .filter_map(|(name, value)| value.as_static().map(|v| (name, v)))
+            .collect::<Vec<_>>();
+        assert_eq!(statics, vec![
+            ("Content-Encoding", "gzip"),
+            ("Accept-Ranges", "bytes"),
+        ]);
+    }
+
+    #[test]
+    fn full_range_as_ok() {
+        let cfg = Config::new().done();
+        let inp = Input::from_headers(&cfg, "HEAD",
+            vec![("Range", &b"bytes=0-"[..])].into_iter());
+        assert_eq!(inp.probe_file("public/index.html").unwrap()
+                   .suggested_status(), 206);
+        let cfg = Config::new().full_range_as_ok(true).done();
+        for range in &["bytes=0-", "bytes=0-125", "bytes=-1000"] {
+            let inp = Input::from_headers(&cfg, "HEAD",
+                vec![("Range", range.as_bytes())].into_iter());
+            let head = match inp.probe_file("public/index.html").unwrap() {
+                Output::FileHead(head) => head,
+                _ => unreachable!(),
+            };
+            assert_eq!(head.suggested_status(), 200);
+            assert_eq!(head.byte_range(), None);
+            assert_eq!(head.content_length(), 126);
+            assert!(!head.headers().any(|(name, _)| name == "Content-Range"));
+        }
+        let inp = Input::from_headers(&cfg, "HEAD",
+            vec![("Range", &b"bytes=0-124"[..])].into_iter());
+        assert_eq!(inp.probe_file("public/index.html").unwrap()
+                   .suggested_status(), 206);
+    }
+
+    #[test]
+    fn no_ranges() {
+        let cfg = Config::new().ranges(false).done();
+        let inp = Input::from_headers(&cfg, "HEAD", vec![
+            ("Range", &b"bytes=2-8"[..]),
+            ("If-Range", &b"Tue, 22 Aug 2017 20:47:13 GMT"[..]),
+        ].into_iter());
+        let head = match inp.probe_file("public/index.html").unwrap() {
+            Output::FileHead(head) => head,
+            _ => unreachable!(),
+        };
+        assert_eq!(head.suggested_status(), 200);
+        assert_eq!(head.content_length(), 126);
+        assert!(head.headers().any(|(name, value)| {
+            name == "Accept-Ranges" && value.to_string() == "none"
+        }));
+        assert!(head.typed_headers().any(|(name, value)| {
+            name == "Accept-Ranges" && value.as_static() == Some("none")
+        }));
+        let inp = Input::from_headers(&cfg, "GET",
+            vec![("Range", &b"bytes=garbage"[..])].into_iter());
+        assert_eq!(inp.probe_file("public/index.html").unwrap()
+                   .suggested_status(), 200);
+    }
+
+    #[test]
+    fn range_not_satisfiable() {
+        let cfg = Config::new().content_length_header(true).done();
+        let inp = Input::from_headers(&cfg, "GET",
+            vec![("Range", &b"bytes=1000-"[..])].into_iter());
+        let outp = inp.probe_file("public/index.html").unwrap();
+        assert_eq!(outp.suggested_status(), 416);
+        let head = match outp {
+            Output::RangeNotSatisfiable(head) => head,
+            _ => unreachable!(),
+        };
+        let names = head.headers().map(|(name, _)| name)
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec!["Last-Modified", "ETag", "Content-Range"]);
+        assert!(head.headers().any(|(name, value)| {
+            name == "Content-Range" && value.to_string() == "bytes */126"
+        }));
+        assert!(head.typed_headers().any(|(name, value)| {
+            name == "Content-Range" && value.to_string() == "bytes */126"
+        }));
+    }
+
+    #[test]
+    fn content_length_header() {
+        let cfg = Config::new().content_length_header(true).done();
+        let inp = Input::from_headers(&cfg, "HEAD", vec![].into_iter());
+        let head = match inp.probe_file("public/index.html").unwrap() {
+            Output::FileHead(head) => head,
+            _ => unreachable!(),
+        };
+        assert!(head.headers().any(|(name, value)| {
+            name == "Content-Length" && value.to_string() == "126"
+        }));
+        assert!(head.typed_headers().any(|(name, value)| {
+            name == "Content-Length" && value.to_string() == "126"
+        }));
+    }
+
+    #[test]
+    fn date_header() {
+        let cfg = Config::new().date_header(true).done();
+        let inp = Input::from_headers(&cfg, "HEAD", vec![].into_iter());
+        let head = match inp.probe_file("public/index.html").unwrap() {
+            Output::FileHead(head) => head,
+            _ => unreachable!(),
+        };
+        let (name, value) = head.headers().next().unwrap();
+        assert_eq!(name, "Date");
+        assert!(value.to_string().ends_with(" GMT"));
+        let cfg = Config::new().done();
+        let inp = Input::from_headers(&cfg, "HEAD", vec![].into_iter());
+        match inp.probe_file("public/index.html").unwrap() {
+            Output::FileHead(head) => {
+                assert!(!head.headers().any(|(name, _)| name == "Date"));
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn content_location() {
+        let cfg = Config::new()
+            .add_index_file("index.html")
+            .content_location(true)
+            .metadata_cache(Duration::new(60, 0), 10)
+            .done();
+        for _ in 0..2 {
+            let inp = Input::from_headers(&cfg, "GET",
+                vec![("Accept-Encoding", &b"br"[..])].into_iter());
+            match inp.probe_file("public").unwrap() {
+                Output::File(wrapper) => {
+                    assert_eq!(wrapper.head().content_location(),
+                               Some("index.html.br"));
+                    assert!(wrapper.headers().any(|(name, value)| {
+                        name == "Content-Location" &&
+                            value.to_string() == "index.html.br"
+                    }));
+                    assert!(!wrapper.headers().any(|(name, _)| name == "Vary"));
+                }
+                _ => unreachable!(),
+            }
+        }
+        let cfg = Config::new().content_location(true).done();
+        let inp = Input::from_headers(&cfg, "HEAD", vec![].into_iter());
+        let tag = match inp.probe_file("public/index.html").unwrap() {
+            Output::FileHead(head) => {
+                assert_eq!(head.content_location(), Some("index.html"));
+                head.etag().unwrap().to_string()
+            }
+            _ => unreachable!(),
+        };
+        let inp = Input::from_headers(&cfg, "HEAD",
+            vec![("If-None-Match", tag.as_bytes())].into_iter());
+        match inp.probe_file("public/index.html").unwrap() {
+            Output::NotModified(head) => {
+                assert!(head.headers().any(|(name, _)| {
+                    name == "Content-Location"
+                }));
+            }
+            _ => unreachable!(),
+        }
+        let cfg = Config::new().done();
+        let inp = Input::from_headers(&cfg, "HEAD", vec![].into_iter());
+        match inp.probe_file("public/index.html").unwrap() {
+            Output::FileHead(head) => assert_eq!(head.content_location(), None),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn accept_charset() {
+        let headers = vec![("Accept-Charset", &b"iso-8859-1"[..])];
+        let cfg = Config::new().done();
+        let inp = Input::from_headers(&cfg, "HEAD",
+                                      headers.clone().into_iter());
+        match inp.probe_file("public/index.html").unwrap() {
+            Output::FileHead(head) => {
+                assert!(head.headers().any(|(name, value)| {
+                    name == "Content-Type" &&
+                        value.to_string() == "text/html; charset=utf-8"
+                }));
+            }
+            _ => unreachable!(),
+        }
+        let cfg = Config::new().drop_unacceptable_charset().done();
+        let inp = Input::from_headers(&cfg, "HEAD",
+                                      headers.clone().into_iter());
+        match inp.probe_file("public/index.html").unwrap() {
+            Output::FileHead(head) => {
+                assert!(head.headers().any(|(name, value)| {
+                    name == "Content-Type" && value.to_string() == "text/html"
+                }));
+            }
+            _ => unreachable!(),
+        }
+        let cfg = Config::new().reject_unacceptable_charset().done();
+        let inp = Input::from_headers(&cfg, "GET", headers.into_iter());
+        match inp.probe_file("public/index.html").unwrap() {
+            Output::NotAcceptable => {}
+            _ => unreachable!(),
+        }
+        let inp = Input::from_headers(&cfg, "GET",
+            vec![("Accept-Charset", &b"utf-8"[..])].into_iter());
+        match inp.probe_file("public/index.html").unwrap() {
+            Output::File(_) => {}
+            _ => unreachable!(),
+        }
+    }
+
+    fn ctype(cfg: &Arc<Config>, mime: &'static str) -> String {
+        let inp = Input::from_headers(cfg, "HEAD", vec![].into_iter());
+        let head = Head::synthetic(&inp, 1, mime, None, None).unwrap();
+        head.headers().find(|&(name, _)| name == "Content-Type")
+            .unwrap().1.to_string()
+    }
+
+    #[test]
+    fn extended_text_types() {
+        let cfg = Config::new().done();
+        assert_eq!(ctype(&cfg, "application/json"),
+                   "application/json; charset=utf-8");
+        assert_eq!(ctype(&cfg, "image/svg+xml"),
+                   "image/svg+xml; charset=utf-8");
+        assert_eq!(ctype(&cfg, "application/wasm"), "application/wasm");
+        let cfg = Config::new().extended_text_types(false).done();
+        assert_eq!(ctype(&cfg, "application/json"), "application/json");
+        assert_eq!(ctype(&cfg, "text/css"), "text/css; charset=utf-8");
+    }
+
+    #[test]
+    fn charset_types() {
+        let cfg = Config::new().done();
+        assert_eq!(ctype(&cfg, "text/csv"), "text/csv; charset=utf-8");
+        let cfg = Config::new()
+            .charset_on_types(&["text/html", "text/css"])
+            .add_charset_type("*+yaml")
+            .done();
+        assert_eq!(ctype(&cfg, "text/csv"), "text/csv");
+        assert_eq!(ctype(&cfg, "text/html"), "text/html; charset=utf-8");
+        assert_eq!(ctype(&cfg, "application/x+yaml"),
+                   "application/x+yaml; charset=utf-8");
+        assert_eq!(ctype(&cfg, "application/javascript"),
+                   "application/javascript");
+        assert_eq!(ctype(&cfg, "application/json"),
+                   "application/json; charset=utf-8");
+    }
+
+    #[test]
+    fn revalidate() {
+        use std::fs::OpenOptions;
+        use test_util::TempDir;
+        let dir = TempDir::new("revalidate");
+        let path = dir.write("a.txt", b"0123456789");
+        let cfg = Config::new().done();
+        let probe = |range: &str| {
+            let headers = if range.is_empty() {
+                vec![]
+            } else {
+                vec![("Range", range.as_bytes())]
+            };
+            let inp = Input::from_headers(&cfg, "GET", headers.into_iter());
+            match inp.probe_file(&path).unwrap() {
+                Output::File(wrapper) => wrapper,
+                _ => unreachable!(),
+            }
+        };
+        let mut wrapper = probe("");
+        let mut partial = probe("bytes=2-5");
+        assert!(wrapper.revalidate().unwrap());
+        let etag = wrapper.head().etag().unwrap().to_string();
+        OpenOptions::new().append(true).open(&path).unwrap()
+            .write_all(b"abcde").unwrap();
+        assert!(!wrapper.revalidate().unwrap());
+        assert_eq!(wrapper.content_length(), 15);
+        assert_ne!(wrapper.head().etag().unwrap().to_string(), etag);
+        assert!(wrapper.revalidate().unwrap());
+        assert_eq!(wrapper.read_all(100).unwrap(), b"0123456789abcde");
+        assert_eq!(partial.revalidate().unwrap_err().kind(),
+                   io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn sniff_charset() {
+        use test_util::TempDir;
+        let root = TempDir::new("charset");
+        root.write("latin1.txt", b"caf\xE9");
+        root.write("utf16.txt", b"\xFF\xFEh\x00i\x00");
+        root.write("ascii.txt", b"hello");
+        root.write("ascii.txt.gz", b"\x1f\x8b\x08\xff");
+        let cfg = Config::new().sniff_charset(true).done();
+        let cached = Config::new().sniff_charset(true)
+            .metadata_cache(Duration::new(60, 0), 10).done();
+        for cfg in &[cfg, cached] {
+            let ctype = |name: &str| {
+                let inp = Input::from_headers(cfg, "HEAD",
+                    vec![("Accept-Encoding", &b"gzip"[..])].into_iter());
+                match inp.probe_file(root.join(name)).unwrap() {
+                    Output::FileHead(head) => {
+                        head.headers()
+                            .find(|&(name, _)| name == "Content-Type")
+                            .unwrap().1.to_string()
+                    }
+                    _ => unreachable!(),
+                }
+            };
+            assert_eq!(ctype("latin1.txt"), "text/plain; charset=iso-8859-1");
+            assert_eq!(ctype("utf16.txt"), "text/plain; charset=utf-16le");
+            assert_eq!(ctype("ascii.txt"), "text/plain; charset=utf-8");
+        }
+        let cfg = Config::new().done();
+        let inp = Input::from_headers(&cfg, "HEAD", vec![].into_iter());
+        match inp.probe_file(root.join("latin1.txt")).unwrap() {
+            Output::FileHead(head) => {
+                assert!(head.headers().any(|(name, value)| {
+                    name == "Content-Type" &&
+                        value.to_string() == "text/plain; charset=utf-8"
+                }));
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn synthetic() {
+        let data = b"<h1>Not Found</h1>";
+        let etag = Etag::from_data(data);
+        let cfg = Config::new().done();
+        let inp = Input::from_headers(&cfg, "GET",
+            vec![("Range", &b"bytes=4-"[..])].into_iter());
+        let head = Head::synthetic(&inp, data.len() as u64, "text/html",
+                                   None, Some(etag.clone())).unwrap();
+        assert_eq!(head.byte_range(), Some((4, 17, 18)));
+        assert_eq!(head.content_length(), 14);
+        assert!(head.headers().any(|(name, value)| {
+            name == "Content-Type" &&
+                value.to_string() == "text/html; charset=utf-8"
+        }));
+        let tag = etag.to_string();
+        let inp = Input::from_headers(&cfg, "GET",
+            vec![("If-None-Match", tag.as_bytes())].into_iter());
+        match Head::synthetic(&inp, 18, "text/html", None, Some(etag)) {
+            Err(Output::NotModified(_)) => {}
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn listing() {
+        let dir = DirInfo::new(Path::new("public"), Metadata::directory());
+        let etag = dir.listing_etag(&["b.txt", "a.txt"]);
+        assert!(etag.is_weak());
+        assert_eq!(etag, dir.listing_etag(&["a.txt", "b.txt"]));
+        assert!(etag != dir.listing_etag(&["a.txt"]));
+        let cfg = Config::new().done();
+        let tag = etag.to_string();
+        let inp = Input::from_headers(&cfg, "GET",
+            vec![("If-None-Match", tag.as_bytes())].into_iter());
+        match Head::synthetic(&inp, 10, "text/html", dir.modified(),
+                              Some(etag.clone()))
+        {
+            Err(Output::NotModified(_)) => {}
+            _ => unreachable!(),
+        }
+        let inp = Input::from_headers(&cfg, "GET", vec![].into_iter());
+        let head = Head::synthetic(&inp, 10, "text/html", dir.modified(),
+                                   Some(etag)).unwrap();
+        assert_eq!(head.suggested_status(), 200);
+    }
+
+    #[test]
+    fn from_reader() {
+        use std::io::Cursor;
+        let data = b"<h1>Not Found</h1>".to_vec();
+        let cfg = Config::new().done();
+        let inp = Input::from_headers(&cfg, "GET",
+            vec![("Range", &b"bytes=4-"[..])].into_iter());
+        let head = Head::synthetic(&inp, data.len() as u64, "text/html",
+                                   None, None).unwrap();
+        let mut wrapper = FileWrapper::from_reader(head, Cursor::new(data))
+            .unwrap();
+        assert!(wrapper.is_partial());
+        assert_eq!(&wrapper.read_all(100).unwrap()[..], b"Not Found</h1>");
+    }
+
+    #[test]
+    fn rate_limit() {
+        let cfg = Config::new().read_buffer_size(10).rate_limit(100).done();
+        let inp = Input::from_headers(&cfg, "GET", vec![].into_iter());
+        let mut wrapper = match inp.probe_file("public/index.html").unwrap() {
+            Output::File(wrapper) => wrapper,
+            _ => unreachable!(),
+        };
+        assert_eq!(wrapper.throttle_delay(), None);
+        let mut buf = Vec::new();
+        assert_eq!(wrapper.read_chunk(&mut buf).unwrap(), 10);
+        assert!(wrapper.throttle_delay().unwrap() > Duration::from_millis(50));
+        assert_eq!(wrapper.read_chunk(&mut buf).unwrap_err().kind(),
+                   io::ErrorKind::WouldBlock);
+        assert_eq!(wrapper.read_into(&mut [0u8; 10]).unwrap_err().kind(),
+                   io::ErrorKind::WouldBlock);
+        assert_eq!(wrapper.bytes_left(), 116);
+        wrapper.set_rate_limit(None);
+        assert_eq!(wrapper.throttle_delay(), None);
+        assert_eq!(wrapper.read_all(1000).unwrap().len(), 116);
+
+        // partially read body is kept
+        let inp = Input::from_headers(&cfg, "GET", vec![].into_iter());
+        let mut wrapper = match inp.probe_file("public/index.html").unwrap() {
+            Output::File(wrapper) => wrapper,
+            _ => unreachable!(),
+        };
+        assert_eq!(wrapper.read_all(1000).unwrap().len(), 10);
+        assert_eq!(wrapper.bytes_left(), 116);
+        assert_eq!(wrapper.read_all(1000).unwrap_err().kind(),
+                   io::ErrorKind::WouldBlock);
+
+        // inlined files are not throttled
+        let cfg = Config::new().read_buffer_size(10).rate_limit(100)
+            .inline_threshold(1000).done();
+        let inp = Input::from_headers(&cfg, "GET", vec![].into_iter());
+        match inp.probe_file("public/index.html").unwrap() {
+            Output::FileInMemory(_, data) => assert_eq!(data.len(), 126),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn truncated() {
+        use std::io::Cursor;
+        let cfg = Config::new().done();
+        let inp = Input::from_headers(&cfg, "GET", vec![].into_iter());
+        let head = Head::synthetic(&inp, 20, "text/plain", None, None)
+            .unwrap();
+        let mut wrapper = FileWrapper::from_reader(head,
+            Cursor::new(b"0123456789".to_vec())).unwrap();
+        let mut buf = Vec::new();
+        assert_eq!(wrapper.read_chunk(&mut buf).unwrap(), 10);
+        assert_eq!(wrapper.read_chunk(&mut buf).unwrap_err().kind(),
+                   io::ErrorKind::UnexpectedEof);
+        assert_eq!(wrapper.read_into(&mut [0u8; 10]).unwrap_err().kind(),
+                   io::ErrorKind::UnexpectedEof);
+        assert_eq!(wrapper.bytes_left(), 10);
+    }
+
+    #[test]
+    fn tail_mode() {
+        use std::io::Cursor;
+        let cfg = Config::new().tail_mode(true).done();
+        let inp = Input::from_headers(&cfg, "GET", vec![].into_iter());
+        let head = Head::synthetic(&inp, 20, "text/plain", None, None)
+            .unwrap();
+        let mut wrapper = FileWrapper::from_reader(head,
+            Cursor::new(b"0123456789".to_vec())).unwrap();
+        let mut buf = Vec::new();
+        assert_eq!(wrapper.read_chunk(&mut buf).unwrap(), 10);
+        assert_eq!(wrapper.read_chunk(&mut buf).unwrap_err().kind(),
+                   io::ErrorKind::WouldBlock);
+        assert_eq!(wrapper.read_into(&mut [0u8; 10]).unwrap_err().kind(),
+                   io::ErrorKind::WouldBlock);
+        assert_eq!(wrapper.offset(), 10);
+        assert_eq!(wrapper.bytes_left(), 10);
+    }
+
+    #[test]
+    fn tail_mode_skips_inline() {
+        use test_util::TempDir;
+
+        let dir = TempDir::new("tail-inline");
+        let fs = Stale(dir.write("a.txt", "hello"));
+        let cfg = Config::new().tail_mode(true).inline_threshold(100).done();
+        let inp = Input::from_headers(&cfg, "GET", vec![].into_iter());
+        let mut wrapper = match inp.probe_file_in(&fs, "/a.txt").unwrap() {
+            Output::File(wrapper) => wrapper,
+            _ => unreachable!(),
+        };
+        let mut buf = Vec::new();
+        assert_eq!(wrapper.read_chunk(&mut buf).unwrap(), 5);
+        assert_eq!(wrapper.read_chunk(&mut buf).unwrap_err().kind(),
+                   io::ErrorKind::WouldBlock);
+        assert_eq!(&buf[..], b"hello");
+    }
+
+    #[test]
+    fn read() {
+        let cfg = Config::new().done();
+        let inp = Input::from_headers(&cfg, "GET",
+            vec![("Range", &b"bytes=2-8"[..])].into_iter());
+        let mut wrapper = match inp.probe_file("public/index.html").unwrap() {
+            Output::File(wrapper) => wrapper,
+            _ => unreachable!(),
+        };
+        let mut buf = Vec::new();
+        assert_eq!(io::copy(&mut wrapper, &mut buf).unwrap(), 7);
+        assert_eq!(&buf[..], b"DOCTYPE");
+        assert_eq!(wrapper.bytes_left(), 0);
+    }
+
+    #[test]
+    fn read_with_rate_limit() {
+        let cfg = Config::new()
+            .read_buffer_size(16)
+            .rate_limit(10_000)
+            .done();
+        let inp = Input::from_headers(&cfg, "GET", vec![].into_iter());
+        let mut wrapper = match inp.probe_file("public/index.html").unwrap() {
+            Output::File(wrapper) => wrapper,
+            _ => unreachable!(),
+        };
+        let mut buf = Vec::new();
+        assert_eq!(io::copy(&mut wrapper, &mut buf).unwrap(), 126);
+        assert_eq!(wrapper.bytes_left(), 0);
+    }
+
+    #[test]
+    fn parts() {
+        let cfg = Config::new().done();
+        let inp = Input::from_headers(&cfg, "GET",
+            vec![("Range", &b"bytes=2-8"[..])].into_iter());
+        let mut wrapper = match inp.probe_file("public/index.html").unwrap() {
+            Output::File(wrapper) => wrapper,
+            _ => unreachable!(),
+        };
+        wrapper.mark_sent(2);
+        let (head, file, offset) = wrapper.into_parts();
+        assert_eq!(offset, 4);
+        let mut wrapper = FileWrapper::from_parts(head, file, offset)
+            .unwrap();
+        assert_eq!(wrapper.bytes_left(), 5);
+        assert_eq!(&wrapper.read_all(5).unwrap()[..], b"CTYPE");
+        let (head, file, _) = wrapper.into_parts();
+        assert_eq!(FileWrapper::from_parts(head, file, 1).unwrap_err().kind(),
+                   io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn inline() {
+        let cfg = Config::new().inline_threshold(100).done();
+        let inp = Input::from_headers(&cfg, "GET", vec![].into_iter());
+        match inp.probe_file("public/index.html").unwrap() {
+            Output::File(_) => {}
+            _ => unreachable!(),
+        }
+        let inp = Input::from_headers(&cfg, "GET",
+            vec![("Range", &b"bytes=2-8"[..])].into_iter());
+        match inp.probe_file("public/index.html").unwrap() {
+            Output::FileInMemory(ref head, ref data) => {
+                assert_eq!(head.content_length(), 7);
+                assert_eq!(&data[..], b"DOCTYPE");
+            }
+            _ => unreachable!(),
+        }
+    }
 }
@@ -0,0 +1,310 @@
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsString;
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use vfs::{FileSystem, Metadata, ReadAt, normalize, list_dir, read_exact_at};
+
+const TAR_BLOCK: u64 = 512;
+
+
+/// Filesystem that serves files out of a single large pack file
+///
+/// Every file is a `(offset, length)` slice of the pack. The index of
+/// slices is supplied by the caller (see `PackFs::new`) or read from the
+/// tar headers (see `PackFs::from_tar`). Byte ranges are served within
+/// an entry, the same as for normal files.
+///
+/// All files report modification time of the pack file itself.
+#[derive(Debug)]
+pub struct PackFs {
+    pack: Arc<File>,
+    entries: HashMap<PathBuf, (u64, u64)>,
+    dirs: HashSet<PathBuf>,
+    modified: Option<SystemTime>,
+}
+
+/// A file opened from `PackFs`
+#[derive(Debug)]
+pub struct PackFile {
+    pack: Arc<File>,
+    offset: u64,
+    len: u64,
+}
+
+fn invalid(msg: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Parses the octal number (as used in tar headers)
+fn octal(field: &[u8]) -> io::Result<u64> {
+    let mut result = 0u64;
+    for &c in field {
+        match c {
+            b'0'..=b'7' => {
+                result = result.checked_mul(8)
+                    .ok_or_else(|| invalid("bad number in tar header"))?
+                    + (c - b'0') as u64;
+            }
+            b' ' | 0 => {}
+            _ => return Err(invalid("bad number in tar header")),
+        }
+    }
+    Ok(result)
+}
+
+/// Returns the part of the field up to the first zero byte
+fn text(field: &[u8]) -> &[u8] {
+    let end = field.iter().position(|&c| c == 0).unwrap_or(field.len());
+    &field[..end]
+}
+
+impl PackFs {
+    /// Create filesystem from a pack file and an index of
+    /// `(path, offset, length)` entries
+    ///
+    /// Returns error of kind `InvalidInput` if any entry is out of bounds
+    /// of the pack file.
+    pub fn new<I, P>(pack: File, index: I) -> io::Result<PackFs>
+        where I: IntoIterator<Item=(P, u64, u64)>,
+              P: AsRef<Path>,
+    {
+        let meta = pack.metadata()?;
+        let mut fs = PackFs {
+            pack: Arc::new(pack),
+            entries: HashMap::new(),
+            dirs: HashSet::new(),
+            modified: meta.modified().ok(),
+        };
+        fs.dirs.insert(PathBuf::new());
+        for (path, offset, len) in index {
+            if offset.checked_add(len).map(|end| end > meta.len())
+                .unwrap_or(true)
+            {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                    "pack entry is out of bounds of the file"));
+            }
+            fs.add_entry(path.as_ref(), offset, len);
+        }
+        Ok(fs)
+    }
+    /// Create filesystem from an uncompressed tar archive
+    ///
+    /// Only regular files are served, GNU long names are supported, other
+    /// extensions (e.g. pax headers) are ignored. Archive ends with a zero
+    /// block or at the end of file (if it's at the block boundary).
+    pub fn from_tar(pack: File) -> io::Result<PackFs> {
+        let file_len = pack.metadata()?.len();
+        let mut index = Vec::new();
+        let mut header = [0u8; TAR_BLOCK as usize];
+        let mut offset = 0;
+        let mut long_name = None;
+        while offset != file_len {
+            read_exact_at(&pack, &mut header, offset)?;
+            if header.iter().all(|&c| c == 0) {
+                break;
+            }
+            let size = octal(&header[124..136])?;
+            let data = offset + TAR_BLOCK;
+            offset = data + size.div_ceil(TAR_BLOCK) * TAR_BLOCK;
+            match header[156] {
+                b'0' | 0 => {
+                    let name = match long_name.take() {
+                        Some(name) => name,
+                        None if &header[257..262] == b"ustar" => {
+                            let prefix = text(&header[345..500]);
+                            let name = text(&header[..100]);
+                            if prefix.is_empty() {
+                                name.to_vec()
+                            } else {
+                                let mut buf = prefix.to_vec();
+                                buf.push(b'/');
+                                buf.extend_from_slice(name);
+                                buf
+                            }
+                        }
+                        None => text(&header[..100]).to_vec(),
+                    };
+                    let name = String::from_utf8(name)
+                        .map_err(|_| invalid("non-utf8 name in tar"))?;
+                    index.push((name, data, size));
+                }
+                b'L' => {
+                    let mut name = vec![0u8; size as usize];
+                    read_exact_at(&pack, &mut name, data)?;
+                    long_name = Some(text(&name).to_vec());
+                }
+                _ => {}
+            }
+        }
+        PackFs::new(pack, index)
+    }
+    fn add_entry(&mut self, path: &Path, offset: u64, len: u64) {
+        let path = match normalize(path) {
+            Some(ref path) if path.as_os_str().is_empty() => return,
+            Some(path) => path,
+            None => return,
+        };
+        let mut dir = path.clone();
+        while dir.pop() {
+            self.dirs.insert(dir.clone());
+        }
+        self.entries.insert(path, (offset, len));
+    }
+    fn entry(&self, path: &Path) -> io::Result<Option<(u64, u64)>> {
+        let path = normalize(path)
+            .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+        match self.entries.get(&path) {
+            Some(&entry) => Ok(Some(entry)),
+            None if self.dirs.contains(&path) => Ok(None),
+            None => Err(io::ErrorKind::NotFound.into()),
+        }
+    }
+    fn file_meta(&self, offset: u64, len: u64) -> Metadata {
+        // offset makes etag different for entries of the same size
+        Metadata::file(len, self.modified).with_version(offset)
+    }
+}
+
+impl FileSystem for PackFs {
+    type File = PackFile;
+    fn metadata(&self, path: &Path) -> io::Result<Metadata> {
+        match self.entry(path)? {
+            Some((offset, len)) => Ok(self.file_meta(offset, len)),
+            None => Ok(Metadata::directory()),
+        }
+    }
+    fn open(&self, path: &Path) -> io::Result<(PackFile, Metadata)> {
+        match self.entry(path)? {
+            Some((offset, len)) => {
+                let file = PackFile {
+                    pack: self.pack.clone(),
+                    offset,
+                    len,
+                };
+                Ok((file, self.file_meta(offset, len)))
+            }
+            None => Err(io::ErrorKind::PermissionDenied.into()),
+        }
+    }
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<OsString>> {
+        if self.entry(path)?.is_some() {
+            return Err(io::Error::other("not a directory"));
+        }
+        let path = normalize(path).expect("path is valid");
+        Ok(list_dir(&path, self.entries.keys().chain(self.dirs.iter())))
+    }
+}
+
+impl PackFile {
+    /// Returns offset of the file within the pack
+    ///
+    /// Note: `FileWrapper::offset()` is relative to the start of the
+    /// entry, add this value to get offset in the pack file.
+    pub fn pack_offset(&self) -> u64 {
+        self.offset
+    }
+}
+
+impl ReadAt for PackFile {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        if offset >= self.len {
+            return Ok(0);
+        }
+        let max = ::std::cmp::min(buf.len() as u64, self.len - offset);
+        self.pack.read_at(&mut buf[..max as usize], self.offset + offset)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use config::Config;
+    use input::Input;
+    use output::Output;
+    use test_util::TempDir;
+    use super::*;
+
+    fn get(fs: &PackFs, path: &str, range: &str) -> Vec<u8> {
+        let cfg = Arc::new(Config::new());
+        let inp = Input::from_headers(&cfg, "GET",
+            vec![("Range", range.as_bytes())].into_iter());
+        match inp.probe_file_in(fs, path).unwrap() {
+            Output::File(mut wrapper) => wrapper.read_all(1000).unwrap(),
+            _ => unreachable!(),
+        }
+    }
+
+    fn tar_header(name: &str, size: usize, kind: u8) -> Vec<u8> {
+        let mut header = vec![0u8; TAR_BLOCK as usize];
+        header[..name.len()].copy_from_slice(name.as_bytes());
+        let size = format!("{:011o}", size);
+        header[124..135].copy_from_slice(size.as_bytes());
+        header[156] = kind;
+        header[257..263].copy_from_slice(b"ustar\0");
+        header
+    }
+
+    fn pad(buf: &mut Vec<u8>) {
+        while buf.len() % TAR_BLOCK as usize != 0 {
+            buf.push(0);
+        }
+    }
+
+    #[test]
+    fn index() {
+        let dir = TempDir::new("pack");
+        let path = dir.write("test.pack", "0123456789abcdef");
+        let fs = PackFs::new(File::open(&path).unwrap(), vec![
+            ("/tiles/1.bin", 0, 10),
+            ("/tiles/2.bin", 10, 6),
+        ]).unwrap();
+        assert_eq!(get(&fs, "/tiles/2.bin", "bytes=0-"), b"abcdef");
+        assert_eq!(get(&fs, "/tiles/2.bin", "bytes=-2"), b"ef");
+        assert_eq!(get(&fs, "tiles/1.bin", "bytes=2-4"), b"234");
+        assert_eq!(fs.read_dir(Path::new("tiles")).unwrap(),
+                   vec!["1.bin", "2.bin"]);
+        assert_eq!(PackFs::new(File::open(&path).unwrap(),
+                               vec![("x", 10, 7)]).unwrap_err().kind(),
+                   io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn tar() {
+        let long_name = format!("{}/file.txt", "d".repeat(120));
+        let mut tar = tar_header("maps/a.txt", 5, b'0');
+        tar.extend_from_slice(b"hello");
+        pad(&mut tar);
+        tar.extend(tar_header("././@LongLink", long_name.len() + 1, b'L'));
+        tar.extend_from_slice(long_name.as_bytes());
+        tar.push(0);
+        pad(&mut tar);
+        tar.extend(tar_header("short-name", 5, b'0'));
+        tar.extend_from_slice(b"world");
+        pad(&mut tar);
+        tar.extend(vec![0u8; 2 * TAR_BLOCK as usize]);
+        let dir = TempDir::new("pack-tar");
+        let path = dir.write("test.tar", tar);
+        let fs = PackFs::from_tar(File::open(&path).unwrap()).unwrap();
+        assert_eq!(get(&fs, "/maps/a.txt", "bytes=1-"), b"ello");
+        assert_eq!(get(&fs, &long_name, "bytes=0-"), b"world");
+        assert!(fs.metadata(Path::new("short-name")).is_err());
+    }
+
+    #[test]
+    fn tar_without_trailer() {
+        let mut tar = tar_header("a.txt", 5, b'0');
+        tar.extend_from_slice(b"hello");
+        pad(&mut tar);
+        let dir = TempDir::new("pack-tar");
+        let path = dir.write("test.tar", &tar);
+        let fs = PackFs::from_tar(File::open(&path).unwrap()).unwrap();
+        assert_eq!(get(&fs, "/a.txt", "bytes=0-"), b"hello");
+        // but the data of the last entry must be complete
+        let path = dir.write("short.tar", &tar[..tar.len() - 1]);
+        assert!(PackFs::from_tar(File::open(&path).unwrap()).is_err());
+    }
+}
@@ -0,0 +1,149 @@
+//! Sanitization of request paths
+//!
+//! Use `resolve` to convert the path from the request line into a path
+//! within the root directory, before passing it to `Input::probe_file`.
+use std::error::Error;
+use std::fmt;
+use std::path::{Path, PathBuf, Component};
+use std::str::from_utf8;
+
+
+/// Error returned by `resolve` for paths that must not be served
+///
+/// All of them should usually be reported as `404 Not Found` (or
+/// `400 Bad Request`) to the client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PathError {
+    /// Percent sign is not followed by two hex digits
+    InvalidEscape,
+    /// Decoded path is not valid UTF-8
+    InvalidUtf8,
+    /// Path contains `..` component
+    ParentDir,
+    /// Path contains NUL byte
+    NulByte,
+    /// Path contains backslash (only on windows)
+    Backslash,
+    /// Path component is absolute or has a prefix (e.g. `C:` on windows)
+    AbsoluteComponent,
+}
+
+fn hex(c: u8) -> Option<u8> {
+    match c {
+        b'0'..=b'9' => Some(c - b'0'),
+        b'a'..=b'f' => Some(c - b'a' + 10),
+        b'A'..=b'F' => Some(c - b'A' + 10),
+        _ => None,
+    }
+}
+
+fn percent_decode(raw: &str) -> Result<Vec<u8>, PathError> {
+    let bytes = raw.as_bytes();
+    let mut result = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hi = bytes.get(i+1).and_then(|&c| hex(c));
+            let lo = bytes.get(i+2).and_then(|&c| hex(c));
+            match (hi, lo) {
+                (Some(hi), Some(lo)) => result.push((hi << 4) | lo),
+                _ => return Err(PathError::InvalidEscape),
+            }
+            i += 3;
+        } else {
+            result.push(bytes[i]);
+            i += 1;
+        }
+    }
+    Ok(result)
+}
+
+/// Joins request path to the `base` directory
+///
+/// The `raw_path` is the path from the request line, query string (if
+/// any) is stripped. The path is percent-decoded, empty and `.` components
+/// are skipped. Paths having `..`, NUL bytes or absolute components
+/// (and backslashes on windows) are rejected, even if they are encoded.
+pub fn resolve<P: AsRef<Path>>(base: P, raw_path: &str)
+    -> Result<PathBuf, PathError>
+{
+    let raw_path = match raw_path.find(['?', '#']) {
+        Some(end) => &raw_path[..end],
+        None => raw_path,
+    };
+    let decoded = percent_decode(raw_path)?;
+    let decoded = from_utf8(&decoded).map_err(|_| PathError::InvalidUtf8)?;
+    let mut result = base.as_ref().to_path_buf();
+    for component in decoded.split('/') {
+        match component {
+            "" | "." => continue,
+            ".." => return Err(PathError::ParentDir),
+            _ if component.contains('\0') => return Err(PathError::NulByte),
+            _ if cfg!(windows) && component.contains('\\') => {
+                return Err(PathError::Backslash);
+            }
+            _ => {}
+        }
+        let mut parsed = Path::new(component).components();
+        match (parsed.next(), parsed.next()) {
+            (Some(Component::Normal(_)), None) => result.push(component),
+            _ => return Err(PathError::AbsoluteComponent),
+        }
+    }
+    Ok(result)
+}
+
+impl fmt::Display for PathError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::PathError::*;
+        f.write_str(match *self {
+            InvalidEscape => "invalid percent-encoding in path",
+            InvalidUtf8 => "path is not valid utf-8",
+            ParentDir => "path contains parent directory component",
+            NulByte => "path contains NUL byte",
+            Backslash => "path contains backslash",
+            AbsoluteComponent => "path contains absolute component",
+        })
+    }
+}
+
+impl Error for PathError {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn ok(raw: &str) -> PathBuf {
+        resolve("/www", raw).unwrap()
+    }
+
+    #[test]
+    fn normal() {
+        assert_eq!(ok("/a/./b//c"), PathBuf::from("/www/a/b/c"));
+        assert_eq!(ok("/"), PathBuf::from("/www"));
+        assert_eq!(ok("/hello%20world.txt?x=1"),
+                   PathBuf::from("/www/hello world.txt"));
+        assert_eq!(ok("/%D1%84.txt"), PathBuf::from("/www/ф.txt"));
+        assert_eq!(ok("/a%2Fb"), PathBuf::from("/www/a/b"));
+    }
+
+    #[test]
+    fn errors() {
+        use super::PathError::*;
+        assert_eq!(resolve("/www", "/a/../b"), Err(ParentDir));
+        assert_eq!(resolve("/www", "/%2e%2E/etc/passwd"), Err(ParentDir));
+        assert_eq!(resolve("/www", "/a%2F..%2Fb"), Err(ParentDir));
+        assert_eq!(resolve("/www", "/a%00.txt"), Err(NulByte));
+        assert_eq!(resolve("/www", "/a%2"), Err(InvalidEscape));
+        assert_eq!(resolve("/www", "/a%zz"), Err(InvalidEscape));
+        assert_eq!(resolve("/www", "/%ff"), Err(InvalidUtf8));
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn windows() {
+        use super::PathError::*;
+        assert_eq!(resolve("C:\\www", "/a%5C..%5Cb"), Err(Backslash));
+        assert_eq!(resolve("C:\\www", "/C:/b"), Err(AbsoluteComponent));
+    }
+}
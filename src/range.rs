@@ -1,3 +1,6 @@
+use std::cmp::{min, max};
+use std::error::Error;
+use std::fmt;
 use std::u64;
 use std::str::from_utf8;
 
@@ -18,122 +21,178 @@ pub enum Range {
     // TODO(tailhook) maybe support other range units
 }
 
+/// Reason why the `Range` header is invalid
+///
+/// See `Input::range_error`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RangeError {
+    /// Header is not valid utf-8
+    InvalidUtf8,
+    /// Unit is not `bytes`
+    BadUnit,
+    /// Slice is not `start-end`, `start-` or `-length`
+    BadSlice,
+    /// Number can't be parsed (or is too large)
+    BadNumber,
+    /// Start of the range is larger than the end
+    Reversed,
+    /// Multiple ranges can't be merged into a single one
+    Unmergeable,
+    /// More ranges than `Config::max_ranges`
+    TooManyRanges,
+    /// Multiple `Range` headers
+    Duplicate,
+}
+
 pub struct RangeParser {
-    // TODO(tailhook) maybe have better error
-    result: Result<Option<Range>, ()>,
+    result: Result<Option<Range>, RangeError>,
+    gap: u64,
+    max_ranges: Option<usize>,
 }
 
 
-fn parse_slice(slc: &str) -> Result<Slice, ()> {
+fn parse_num(x: &str) -> Result<u64, RangeError> {
+    x.parse().map_err(|_| RangeError::BadNumber)
+}
+
+fn parse_slice(slc: &str) -> Result<Slice, RangeError> {
     let mut pair = slc.splitn(2, "-");
     match (pair.next().map(|x| x.trim()), pair.next().map(|x| x.trim())) {
-        (Some(""), Some("")) => Err(()),
-        (None, _) => Err(()),
-        (_, None) => Err(()),
+        (Some(""), Some("")) => Err(RangeError::BadSlice),
+        (None, _) => Err(RangeError::BadSlice),
+        (_, None) => Err(RangeError::BadSlice),
         (Some(""), Some(x)) => {
-            Ok(Slice::Last(x.parse().map_err(|_| ())?))
+            Ok(Slice::Last(parse_num(x)?))
         }
         (Some(x), Some("")) => {
-            Ok(Slice::AllFrom(x.parse().map_err(|_| ())?))
+            Ok(Slice::AllFrom(parse_num(x)?))
         }
         (Some(x), Some(y)) => {
-            let x = x.parse().map_err(|_| ())?;
-            let y = y.parse().map_err(|_| ())?;
+            let x = parse_num(x)?;
+            let y = parse_num(y)?;
             if x > y {
-                return Err(());
+                return Err(RangeError::Reversed);
             }
             Ok(Slice::FromTo(x, y))
         }
     }
 }
 
+/// Returns true if the range ending at `end` (inclusive) and the range
+/// starting at `start` overlap or have at most `gap` bytes between them
+fn reaches(end: u64, start: u64, gap: u64) -> bool {
+    start <= end || start - end - 1 <= gap
+}
+
 impl Slice {
-    fn merge(&mut self, other: Slice) -> bool {
+    /// Merge `other` into this slice if they overlap or the gap between
+    /// them is at most `gap` bytes (zero means only adjacent are merged)
+    ///
+    /// File size is unknown at this point, so suffix ranges (`-500`) are
+    /// merged with others only if the result doesn't depend on it, i.e.
+    /// with other suffix ranges or with ranges covering the whole file.
+    fn merge(&mut self, other: Slice, gap: u64) -> bool {
         use self::Slice::*;
 
-        match (self, other) {
-
-            // contained range
-            (&mut FromTo(x1, y1), FromTo(x2, y2))
-            if x1 >= x2 && y1 <= y2
-            => true,
-
-            // reverse contained range
-            (&mut FromTo(ref mut x1, ref mut y1), FromTo(x2, y2))
-            if x2 >= *x1 && y2 <= *y1
-            => {
-                *x1 = x2;
-                *y1 = y2;
-                true
+        let merged = match (*self, other) {
+            (FromTo(x1, y1), FromTo(x2, y2)) => {
+                if reaches(y1, x2, gap) && reaches(y2, x1, gap) {
+                    Some(FromTo(min(x1, x2), max(y1, y2)))
+                } else {
+                    None
+                }
             }
-
-            // adjancent range
-            (&mut FromTo(x1, ref mut y1), FromTo(x2, y2))
-            if x2 >= x1 && x2 <= *y1+1
+            (FromTo(x1, y1), AllFrom(x2)) | (AllFrom(x2), FromTo(x1, y1))
             => {
-                *y1 = y2;
-                true
+                if reaches(y1, x2, gap) {
+                    Some(AllFrom(min(x1, x2)))
+                } else {
+                    None
+                }
             }
-
-            // reverse adjacent range
-            (&mut FromTo(ref mut x1, _y1), FromTo(x2, y2))
-            if y2+1 >= *x1 && x2 < *x1
-            => {
-                *x1 = x2;
+            (AllFrom(x1), AllFrom(x2)) => Some(AllFrom(min(x1, x2))),
+            (Last(n1), Last(n2)) => Some(Last(max(n1, n2))),
+            // whole file
+            (AllFrom(0), Last(_)) | (Last(_), AllFrom(0)) => Some(AllFrom(0)),
+            _ => None,
+        };
+        match merged {
+            Some(slice) => {
+                *self = slice;
                 true
             }
-
-            // TODO(tailhook) cover other cases
-            _ => false,
+            None => false,
         }
     }
 }
 
-fn parse_header(header: &[u8]) -> Result<Range, ()> {
-    let header = from_utf8(header).map_err(|_| {
-        // Invalid utf-8 in range header
-    })?;
+fn num_ranges(header: &[u8]) -> usize {
+    header.iter().filter(|&&x| x == b',').count() + 1
+}
+
+fn parse_header(header: &[u8], gap: u64) -> Result<Range, RangeError> {
+    let header = from_utf8(header).map_err(|_| RangeError::InvalidUtf8)?;
     if !header.starts_with("bytes=") {
-        // Invalid unit in range header
-        return Err(());
+        return Err(RangeError::BadUnit);
     }
     let mut slices = header[6..].split(",");
-    let slice = slices.next()
-        .ok_or_else(|| {
-            // Empty range header
-        })?;
+    let slice = slices.next().ok_or(RangeError::BadSlice)?;
     let mut slice = parse_slice(slice)?;
     for item in slices {
-        if !slice.merge(parse_slice(item)?) {
-            // Can't merge two ranges
-            return Err(());
+        if !slice.merge(parse_slice(item)?, gap) {
+            return Err(RangeError::Unmergeable);
         }
     }
     Ok(Range::SingleRangeOfBytes(slice))
 }
 
+impl fmt::Display for RangeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::RangeError::*;
+        f.write_str(match *self {
+            InvalidUtf8 => "invalid utf-8 in range header",
+            BadUnit => "invalid unit in range header",
+            BadSlice => "invalid range syntax",
+            BadNumber => "invalid number in range",
+            Reversed => "range start is larger than its end",
+            Unmergeable => "can't merge multiple ranges",
+            TooManyRanges => "too many ranges",
+            Duplicate => "duplicate range header",
+        })
+    }
+}
+
+impl Error for RangeError {}
+
 impl RangeParser {
-    pub fn new() -> RangeParser {
+    /// Parser that merges ranges having up to `gap` bytes between them
+    ///
+    /// Header having more than `max_ranges` ranges is invalid.
+    pub fn new(gap: u64, max_ranges: Option<usize>) -> RangeParser {
         RangeParser {
             result: Ok(None),
+            gap,
+            max_ranges,
         }
     }
     pub fn add_header(&mut self, header: &[u8]) {
         match self.result {
-            Err(()) => {}
+            Err(_) => {}
             ref mut r @ Ok(Some(_)) => {
-                // Duplicate range header
-                *r = Err(());
+                *r = Err(RangeError::Duplicate);
             }
             ref mut r @ Ok(None) => {
-                match parse_header(header) {
-                    Ok(x) => *r = Ok(Some(x)),
-                    Err(()) => *r = Err(()),
+                if self.max_ranges.map(|m| num_ranges(header) > m)
+                    .unwrap_or(false)
+                {
+                    *r = Err(RangeError::TooManyRanges);
+                    return;
                 }
+                *r = parse_header(header, self.gap).map(Some);
             }
         }
     }
-    pub fn done(self) -> Result<Option<Range>, ()> {
+    pub fn done(self) -> Result<Option<Range>, RangeError> {
         self.result
     }
 }
@@ -162,8 +221,8 @@ mod test {
         self_contained(&v);
     }
 
-    fn parse(x: &str) -> Result<Option<Range>, ()> {
-        let mut parser = RangeParser::new();
+    fn parse(x: &str) -> Result<Option<Range>, RangeError> {
+        let mut parser = RangeParser::new(0, None);
         parser.add_header(x.as_bytes());
         parser.done()
     }
@@ -181,7 +240,21 @@ mod test {
 
     #[test]
     fn bad_ranges() {
-        assert_eq!(parse("bytes=1000-100"), Err(()));
+        assert_eq!(parse("bytes=1000-100"), Err(RangeError::Reversed));
+        assert_eq!(parse("bytes=a-100"), Err(RangeError::BadNumber));
+        assert_eq!(parse("bytes=-"), Err(RangeError::BadSlice));
+        assert_eq!(parse("bytes=100"), Err(RangeError::BadSlice));
+        assert_eq!(parse("items=0-100"), Err(RangeError::BadUnit));
+        assert_eq!(parse("bytes=99999999999999999999-"),
+                   Err(RangeError::BadNumber));
+    }
+
+    #[test]
+    fn duplicate() {
+        let mut parser = RangeParser::new(0, None);
+        parser.add_header(b"bytes=0-100");
+        parser.add_header(b"bytes=0-100");
+        assert_eq!(parser.done(), Err(RangeError::Duplicate));
     }
 
     #[test]
@@ -204,9 +277,70 @@ mod test {
             Ok(Some(Range::SingleRangeOfBytes(Slice::FromTo(0, 2000)))));
     }
 
+    #[test]
+    fn merge_contained() {
+        assert_eq!(parse("bytes=0-100, 0-1000"),
+            Ok(Some(Range::SingleRangeOfBytes(Slice::FromTo(0, 1000)))));
+        assert_eq!(parse("bytes=0-1000, 10-100"),
+            Ok(Some(Range::SingleRangeOfBytes(Slice::FromTo(0, 1000)))));
+    }
+
+    #[test]
+    fn merge_open_ended() {
+        assert_eq!(parse("bytes=0-499,500-"),
+            Ok(Some(Range::SingleRangeOfBytes(Slice::AllFrom(0)))));
+        assert_eq!(parse("bytes=500-,0-1000"),
+            Ok(Some(Range::SingleRangeOfBytes(Slice::AllFrom(0)))));
+        assert_eq!(parse("bytes=1000-,500-"),
+            Ok(Some(Range::SingleRangeOfBytes(Slice::AllFrom(500)))));
+        assert_eq!(parse("bytes=0-,-500"),
+            Ok(Some(Range::SingleRangeOfBytes(Slice::AllFrom(0)))));
+        assert_eq!(parse("bytes=-500,-100"),
+            Ok(Some(Range::SingleRangeOfBytes(Slice::Last(500)))));
+        assert_eq!(parse("bytes=0-100,1000-"),
+                   Err(RangeError::Unmergeable));
+        assert_eq!(parse_gap("bytes=0-100,1000-", 899),
+            Ok(Some(Range::SingleRangeOfBytes(Slice::AllFrom(0)))));
+        // depends on the file size
+        assert_eq!(parse("bytes=0-100,-500"),
+                   Err(RangeError::Unmergeable));
+        assert_eq!(parse("bytes=100-,-500"),
+                   Err(RangeError::Unmergeable));
+    }
+
     #[test]
     fn no_merge() {
-        assert_eq!(parse("bytes=0-500,1000-2000"), Err(()));
+        assert_eq!(parse("bytes=0-500,1000-2000"),
+                   Err(RangeError::Unmergeable));
+    }
+
+    fn parse_gap(x: &str, gap: u64) -> Result<Option<Range>, RangeError> {
+        let mut parser = RangeParser::new(gap, None);
+        parser.add_header(x.as_bytes());
+        parser.done()
+    }
+
+    #[test]
+    fn merge_gap() {
+        assert_eq!(parse_gap("bytes=0-500,1000-2000", 499),
+            Ok(Some(Range::SingleRangeOfBytes(Slice::FromTo(0, 2000)))));
+        assert_eq!(parse_gap("bytes=1000-2000,0-500", 499),
+            Ok(Some(Range::SingleRangeOfBytes(Slice::FromTo(0, 2000)))));
+        assert_eq!(parse_gap("bytes=0-500,1000-2000", 498),
+                   Err(RangeError::Unmergeable));
+        assert_eq!(parse_gap("bytes=0-1,2-10", 100),
+            Ok(Some(Range::SingleRangeOfBytes(Slice::FromTo(0, 10)))));
+    }
+
+    #[test]
+    fn max_ranges() {
+        let mut parser = RangeParser::new(0, Some(2));
+        parser.add_header(b"bytes=0-1,2-3");
+        assert_eq!(parser.done(),
+            Ok(Some(Range::SingleRangeOfBytes(Slice::FromTo(0, 3)))));
+        let mut parser = RangeParser::new(0, Some(2));
+        parser.add_header(b"bytes=0-1,2-3,4-5");
+        assert_eq!(parser.done(), Err(RangeError::TooManyRanges));
     }
 
     #[test]
@@ -0,0 +1,631 @@
+//! Rule files of static hosting services
+//!
+//! `HeaderRules` parses the `_headers` file (as used by Netlify and
+//! Cloudflare Pages), which adds response headers to files matching
+//! path patterns. Enable it with `Config::header_rules`.
+//!
+//! `RedirectRules` parses the `_redirects` file, which makes redirects
+//! and rewrites (e.g. fallback to `index.html` for single page apps).
+//! Enable it with `Config::redirect_rules`.
+//!
+//! Patterns are matched against the path of the file relative to the root
+//! directory, with a leading slash (e.g. `/blog/index.html`; directory
+//! requests are matched by the path of the directory, e.g. `/blog`).
+//! A star matches any sequence of characters (including slashes),
+//! `:name` matches a single non-empty path segment, and a trailing `/*`
+//! matches the directory itself too. In redirect targets `:splat` is
+//! replaced by the part matched by the star and `:name` by the segment.
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, Component};
+use std::sync::Arc;
+
+
+/// Error parsing a rule file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RulesError {
+    line: usize,
+    reason: &'static str,
+}
+
+/// Parsed `_headers` file
+///
+/// The format is a path pattern on its own line, followed by indented
+/// `Name: value` lines. Empty lines and lines starting with `#` are
+/// ignored:
+///
+/// ```text
+/// /assets/*
+///   Cache-Control: public, max-age=31536000, immutable
+/// /*
+///   X-Frame-Options: DENY
+/// ```
+///
+/// Headers of all matching rules are added in the order of the file.
+#[derive(Debug, Clone, Default)]
+pub struct HeaderRules {
+    rules: Vec<HeaderRule>,
+}
+
+#[derive(Debug, Clone)]
+struct HeaderRule {
+    pattern: String,
+    headers: Vec<ExtraHeader>,
+}
+
+/// Parsed `_redirects` file
+///
+/// Each line is a source pattern, a target and an optional status code
+/// separated by whitespace. Empty lines and lines starting with `#` are
+/// ignored. The first matching rule is used:
+///
+/// ```text
+/// /old/*      /new/:splat
+/// /docs/:page https://docs.example.com/:page 302
+/// /*          /index.html 200
+/// ```
+///
+/// Redirects (status 301, 302, 303, 307, 308, or `Config::redirect_status`
+/// if omitted) are checked before the filesystem and make
+/// `Output::Redirect`. Rewrites
+/// (status 200) serve the target file from the root directory instead
+/// of the missing file. With `200!` the rewrite is applied even if the
+/// file exists.
+#[derive(Debug, Clone, Default)]
+pub struct RedirectRules {
+    rules: Vec<RedirectRule>,
+}
+
+#[derive(Debug, Clone)]
+struct RedirectRule {
+    from: String,
+    to: String,
+    /// `None` means default status
+    status: Option<u16>,
+    force: bool,
+}
+
+/// Result of `RedirectRules::find`
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Redirect {
+    /// Status code and the value of `Location`
+    Redirect(u16, String),
+    /// Path of the target (relative to the root) and whether it's forced
+    Rewrite(String, bool),
+}
+
+/// A response header added by rules (name and value)
+pub(crate) type ExtraHeader = (Arc<str>, Arc<str>);
+
+impl RulesError {
+    /// Line number (starting from 1) where error is found
+    pub fn line(&self) -> usize {
+        self.line
+    }
+}
+
+impl fmt::Display for RulesError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.reason)
+    }
+}
+
+impl Error for RulesError {}
+
+fn is_token(name: &str) -> bool {
+    !name.is_empty() && name.bytes().all(|c| matches!(c,
+        b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' |
+        b'!' | b'#' | b'$' | b'%' | b'&' | b'\'' | b'*' | b'+' | b'-' |
+        b'.' | b'^' | b'_' | b'`' | b'|' | b'~'))
+}
+
+/// Iterate over significant lines with their (1-based) numbers
+fn lines(text: &str) -> impl Iterator<Item=(usize, &str)> {
+    text.lines().enumerate()
+        .map(|(idx, line)| (idx + 1, line.trim_end()))
+        .filter(|&(_, line)| {
+            let trimmed = line.trim_start();
+            !trimmed.is_empty() && !trimmed.starts_with('#')
+        })
+}
+
+impl HeaderRules {
+    /// Parse the text of a `_headers` file
+    pub fn parse(text: &str) -> Result<HeaderRules, RulesError> {
+        let mut rules = Vec::<HeaderRule>::new();
+        for (line_no, line) in lines(text) {
+            let error = |reason| RulesError { line: line_no, reason };
+            if !line.starts_with(|c: char| c.is_whitespace()) {
+                if !line.starts_with('/') {
+                    return Err(error("path pattern must start with a slash"));
+                }
+                rules.push(HeaderRule {
+                    pattern: line.to_string(),
+                    headers: Vec::new(),
+                });
+                continue;
+            }
+            let rule = match rules.last_mut() {
+                Some(rule) => rule,
+                None => return Err(error("header without a path pattern")),
+            };
+            let mut pair = line.trim().splitn(2, ':');
+            let name = pair.next().unwrap_or("").trim();
+            let value = match pair.next() {
+                Some(value) => value.trim(),
+                None => return Err(error("colon expected")),
+            };
+            if !is_token(name) {
+                return Err(error("invalid header name"));
+            }
+            if value.bytes().any(|c| c < 0x20 && c != b'\t' || c == 0x7f) {
+                return Err(error("invalid header value"));
+            }
+            rule.headers.push((name.into(), value.into()));
+        }
+        Ok(HeaderRules { rules })
+    }
+    /// Read and parse the `_headers` file
+    ///
+    /// Parse errors are returned as errors of kind `InvalidData`.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> io::Result<HeaderRules> {
+        let text = fs::read_to_string(path)?;
+        HeaderRules::parse(&text)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+    /// Returns true if there are no rules
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+    /// Headers of all rules matching the path (relative to the root)
+    pub(crate) fn matching(&self, url_path: &str) -> Vec<ExtraHeader> {
+        let mut result = Vec::new();
+        for rule in &self.rules {
+            if matches(&rule.pattern, url_path) {
+                result.extend(rule.headers.iter().cloned());
+            }
+        }
+        result
+    }
+}
+
+impl RedirectRules {
+    /// Parse the text of a `_redirects` file
+    pub fn parse(text: &str) -> Result<RedirectRules, RulesError> {
+        let mut rules = Vec::new();
+        for (line_no, line) in lines(text) {
+            let error = |reason| RulesError { line: line_no, reason };
+            let mut words = line.split_whitespace();
+            let (from, to) = match (words.next(), words.next()) {
+                (Some(from), Some(to)) => (from, to),
+                _ => return Err(error("source and target expected")),
+            };
+            if !from.starts_with('/') {
+                return Err(error("source must start with a slash"));
+            }
+            let (status, force) = match words.next() {
+                None => (None, false),
+                Some(code) => {
+                    let (code, force) = match code.strip_suffix('!') {
+                        Some(code) => (code, true),
+                        None => (code, false),
+                    };
+                    match code.parse() {
+                        Ok(x @ 200) | Ok(x @ 301) | Ok(x @ 302) |
+                        Ok(x @ 303) | Ok(x @ 307) | Ok(x @ 308) => {
+                            (Some(x), force)
+                        }
+                        _ => return Err(error("unsupported status code")),
+                    }
+                }
+            };
+            if words.next().is_some() {
+                return Err(error("unexpected text after status code"));
+            }
+            if status == Some(200) && !to.starts_with('/') {
+                return Err(error("rewrite target must start with a slash"));
+            }
+            rules.push(RedirectRule {
+                from: from.to_string(),
+                to: to.to_string(),
+                status,
+                force,
+            });
+        }
+        Ok(RedirectRules { rules })
+    }
+    /// Read and parse the `_redirects` file
+    ///
+    /// Parse errors are returned as errors of kind `InvalidData`.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> io::Result<RedirectRules> {
+        let text = fs::read_to_string(path)?;
+        RedirectRules::parse(&text)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+    /// Returns true if there are no rules
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+    /// Find the first rule matching the path (relative to the root)
+    ///
+    /// Redirects without status in the rule get `default_status`.
+    pub(crate) fn find(&self, url_path: &str, default_status: u16)
+        -> Option<Redirect>
+    {
+        for rule in &self.rules {
+            if let Some(captures) = capture(&rule.from, url_path) {
+                let target = expand(&rule.to, &captures);
+                return Some(match rule.status.unwrap_or(default_status) {
+                    200 => Redirect::Rewrite(target, rule.force),
+                    status => Redirect::Redirect(status, target),
+                });
+            }
+        }
+        None
+    }
+}
+
+/// Convert path of the file into a path for matching rules
+///
+/// Returns `None` if the path is outside of the `root`.
+pub(crate) fn url_path(root: &Path, path: &Path) -> Option<String> {
+    let rel = path.strip_prefix(root).ok()?;
+    let mut result = String::with_capacity(rel.as_os_str().len() + 1);
+    for cmp in rel.components() {
+        match cmp {
+            Component::Normal(name) => {
+                result.push('/');
+                result.push_str(name.to_str()?);
+            }
+            Component::CurDir => {}
+            _ => return None,
+        }
+    }
+    if result.is_empty() {
+        result.push('/');
+    }
+    Some(result)
+}
+
+/// Join path components with `/` regardless of platform separator
+///
+/// Leading `/` is kept for absolute paths, `.` components are dropped and
+/// a Windows prefix (drive letter) is skipped. Returns `None` if the path
+/// is not valid UTF-8.
+pub(crate) fn slash_path(path: &Path) -> Option<String> {
+    let mut result = String::with_capacity(path.as_os_str().len());
+    for cmp in path.components() {
+        let name = match cmp {
+            Component::Prefix(..) | Component::CurDir => continue,
+            Component::RootDir => {
+                result.push('/');
+                continue;
+            }
+            Component::ParentDir => "..",
+            Component::Normal(name) => name.to_str()?,
+        };
+        if !result.is_empty() && !result.ends_with('/') {
+            result.push('/');
+        }
+        result.push_str(name);
+    }
+    Some(result)
+}
+
+/// Match path against a pattern with `*` and `:placeholder`s
+pub(crate) fn matches(pattern: &str, path: &str) -> bool {
+    capture(pattern, path).is_some()
+}
+
+/// Placeholder names (`splat` for the star) and matched parts of the path
+type Captures<'a, 'b> = Vec<(&'a str, &'b str)>;
+
+/// Length of the placeholder name at the start of `text` (if any)
+fn placeholder(text: &str) -> Option<usize> {
+    let bytes = text.as_bytes();
+    if !bytes.is_empty() && bytes[0].is_ascii_alphabetic() {
+        Some(bytes.iter()
+            .take_while(|c| c.is_ascii_alphanumeric() || **c == b'_')
+            .count())
+    } else {
+        None
+    }
+}
+
+/// Match path against a pattern, returning the captured parts
+fn capture<'a, 'b>(pattern: &'a str, path: &'b str)
+    -> Option<Captures<'a, 'b>>
+{
+    if pattern.ends_with("/*") && path == &pattern[..pattern.len()-2] {
+        return Some(vec![("splat", "")]);
+    }
+    let mut captures = Vec::new();
+    if match_from(pattern, path, &mut captures) {
+        Some(captures)
+    } else {
+        None
+    }
+}
+
+fn match_from<'a, 'b>(pattern: &'a str, path: &'b str,
+    captures: &mut Captures<'a, 'b>)
+    -> bool
+{
+    if let Some(rest) = pattern.strip_prefix('*') {
+        for i in (0..path.len()+1).filter(|&i| path.is_char_boundary(i)) {
+            captures.push(("splat", &path[..i]));
+            if match_from(rest, &path[i..], captures) {
+                return true;
+            }
+            captures.pop();
+        }
+        return false;
+    }
+    if let Some(rest) = pattern.strip_prefix(':') {
+        if let Some(len) = placeholder(rest) {
+            let seg = path.find('/').unwrap_or(path.len());
+            if seg == 0 {
+                return false;
+            }
+            captures.push((&rest[..len], &path[..seg]));
+            if match_from(&rest[len..], &path[seg..], captures) {
+                return true;
+            }
+            captures.pop();
+            return false;
+        }
+    }
+    match (pattern.chars().next(), path.chars().next()) {
+        (None, None) => true,
+        (Some(a), Some(b)) if a == b => {
+            let n = a.len_utf8();
+            match_from(&pattern[n..], &path[n..], captures)
+        }
+        _ => false,
+    }
+}
+
+/// Replace `:name` placeholders in the target with captured parts
+///
+/// Captures are decoded paths, so they are percent-encoded back (except
+/// slashes of the splat) to make a valid `Location` and rewrite target.
+fn expand(target: &str, captures: &Captures) -> String {
+    let mut result = String::with_capacity(target.len());
+    let mut rest = target;
+    while let Some(pos) = rest.find(':') {
+        result.push_str(&rest[..pos]);
+        rest = &rest[pos+1..];
+        let found = placeholder(rest).and_then(|len| {
+            captures.iter().find(|&&(name, _)| name == &rest[..len])
+                .map(|&(_, value)| (len, value))
+        });
+        match found {
+            Some((len, value)) => {
+                encode(value, &mut result);
+                rest = &rest[len..];
+            }
+            None => result.push(':'),
+        }
+    }
+    result.push_str(rest);
+    result
+}
+
+fn encode(value: &str, result: &mut String) {
+    for &b in value.as_bytes() {
+        match b {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' |
+            b'-' | b'.' | b'_' | b'~' | b'/' => result.push(b as char),
+            _ => result.push_str(&format!("%{:02X}", b)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+    use super::*;
+
+    #[test]
+    fn patterns() {
+        assert!(matches("/a.html", "/a.html"));
+        assert!(!matches("/a.html", "/b.html"));
+        assert!(matches("/assets/*", "/assets/x/y.js"));
+        assert!(matches("/assets/*", "/assets/"));
+        assert!(matches("/assets/*", "/assets"));
+        assert!(!matches("/assets/*", "/assets2"));
+        assert!(matches("/*.css", "/x/y.css"));
+        assert!(matches("/blog/:year/*", "/blog/2020/post.html"));
+        assert!(!matches("/blog/:year/*", "/blog//post.html"));
+        assert!(!matches("/blog/:year", "/blog/2020/post.html"));
+    }
+
+    #[test]
+    fn redirects() {
+        let rules = RedirectRules::parse("\
+            # comment\n\
+            /old/*         /new/:splat\n\
+            /docs/:page    https://docs.example.com/:page.html 302\n\
+            /app/*         /app/index.html 200\n\
+            /index.html    /other.html 200!\n\
+        ").unwrap();
+        assert_eq!(rules.find("/old/a/b.html", 301),
+                   Some(Redirect::Redirect(301, "/new/a/b.html".into())));
+        assert_eq!(rules.find("/old", 301),
+                   Some(Redirect::Redirect(301, "/new/".into())));
+        assert_eq!(rules.find("/docs/intro", 301),
+                   Some(Redirect::Redirect(302,
+                        "https://docs.example.com/intro.html".into())));
+        assert_eq!(rules.find("/docs/intro/x", 301), None);
+        assert_eq!(rules.find("/app/users/1", 301),
+                   Some(Redirect::Rewrite("/app/index.html".into(), false)));
+        assert_eq!(rules.find("/index.html", 301),
+                   Some(Redirect::Rewrite("/other.html".into(), true)));
+        assert_eq!(rules.find("/a.html", 301), None);
+        assert_eq!(rules.find("/old/my file?.html", 301),
+                   Some(Redirect::Redirect(301,
+                        "/new/my%20file%3F.html".into())));
+        assert_eq!(rules.find("/docs/файл", 301),
+                   Some(Redirect::Redirect(302,
+                        "https://docs.example.com/%D1%84%D0%B0%D0%B9%D0%BB.html"
+                        .into())));
+    }
+
+    #[test]
+    fn probe_redirects() {
+        use config::Config;
+        use input::Input;
+        use output::Output;
+
+        let rules = RedirectRules::parse("\
+            /old/*   /:splat 308\n\
+            /app/*   /index.html 200\n\
+        ").unwrap();
+        let cfg = Config::new().redirect_rules("public", rules).done();
+        let inp = Input::from_headers(&cfg, "GET", vec![].into_iter());
+        match inp.probe_file("public/old/index.html").unwrap() {
+            Output::Redirect(status, location) => {
+                assert_eq!(status, 308);
+                assert_eq!(location, "/index.html");
+            }
+            _ => unreachable!(),
+        }
+        let inp = Input::from_headers(&cfg, "HEAD", vec![].into_iter());
+        match inp.probe_file("public/app/users/1").unwrap() {
+            Output::FileHead(head) => {
+                assert_eq!(head.content_length(), 126);
+            }
+            _ => unreachable!(),
+        }
+        let inp = Input::from_headers(&cfg, "POST", vec![].into_iter());
+        assert_eq!(inp.probe_file("public/old/index.html").unwrap()
+                   .suggested_status(), 405);
+    }
+
+    #[test]
+    fn redirect_status() {
+        use config::Config;
+        use input::Input;
+        use output::Output;
+
+        let rules = RedirectRules::parse("\
+            /old/*   /:splat\n\
+            /q/*     /index.html?from=q 308\n\
+        ").unwrap();
+        let cfg = Config::new().redirect_rules("public", rules)
+            .redirect_status(307).redirect_query(true).done();
+        let redirect = |path, query| {
+            let mut inp = Input::from_headers(&cfg, "GET", vec![].into_iter());
+            inp.query(query);
+            match inp.probe_file(path).unwrap() {
+                Output::Redirect(status, location) => (status, location),
+                _ => unreachable!(),
+            }
+        };
+        assert_eq!(redirect("public/old/index.html", "a=1"),
+                   (307, String::from("/index.html?a=1")));
+        assert_eq!(redirect("public/q/x", "a=1"),
+                   (308, String::from("/index.html?from=q&a=1")));
+        assert_eq!(redirect("public/old/x", ""),
+                   (307, String::from("/x")));
+    }
+
+    #[test]
+    fn redirect_errors() {
+        let err = |text| RedirectRules::parse(text).unwrap_err().to_string();
+        assert_eq!(err("/a"), "line 1: source and target expected");
+        assert_eq!(err("/a /b 404"), "line 1: unsupported status code");
+        assert_eq!(err("\n/a /b 301 x"),
+                   "line 2: unexpected text after status code");
+        assert_eq!(err("/a http://x/ 200"),
+                   "line 1: rewrite target must start with a slash");
+    }
+
+    #[test]
+    fn url_paths() {
+        let root = Path::new("public");
+        assert_eq!(url_path(root, Path::new("public/a/b.html")).unwrap(),
+                   "/a/b.html");
+        assert_eq!(url_path(root, Path::new("public")).unwrap(), "/");
+        assert_eq!(url_path(root, Path::new("other/a")), None);
+    }
+
+    #[test]
+    fn slash_paths() {
+        let path = Path::new("public").join("media").join("a.js");
+        assert_eq!(slash_path(&path).unwrap(), "public/media/a.js");
+        assert_eq!(slash_path(Path::new("/srv/./a//b")).unwrap(), "/srv/a/b");
+        assert_eq!(slash_path(Path::new("../a")).unwrap(), "../a");
+        assert!(matches("*/media/*", &slash_path(&path).unwrap()));
+    }
+
+    #[test]
+    fn parse() {
+        let rules = HeaderRules::parse("\
+            # comment\n\
+            /assets/*\n  \
+              Cache-Control: max-age=3600\n\n\
+            /*\n  \
+              X-Frame-Options: DENY\n  \
+              Link: </style.css>; rel=preload\n\
+        ").unwrap();
+        let names = |path| rules.matching(path).iter()
+            .map(|(name, value)| format!("{}: {}", name, value))
+            .collect::<Vec<_>>();
+        assert_eq!(names("/assets/a.js"), [
+            "Cache-Control: max-age=3600",
+            "X-Frame-Options: DENY",
+            "Link: </style.css>; rel=preload",
+        ]);
+        assert_eq!(names("/a.html").len(), 2);
+    }
+
+    #[test]
+    fn probe() {
+        use config::Config;
+        use input::Input;
+        use output::Output;
+
+        let rules = HeaderRules::parse("/*.html\n  X-Frame-Options: DENY\n")
+            .unwrap();
+        let cfg = Config::new()
+            .add_index_file("index.html")
+            .header_rules("public", rules)
+            .done();
+        let inp = Input::from_headers(&cfg, "HEAD", vec![].into_iter());
+        let head = match inp.probe_file("public/index.html").unwrap() {
+            Output::FileHead(head) => head,
+            _ => unreachable!(),
+        };
+        let (name, value) = head.headers().last().unwrap();
+        assert_eq!((name, value.to_string().as_str()),
+                   ("X-Frame-Options", "DENY"));
+        assert!(head.typed_headers().all(|(k, _)| k != "X-Frame-Options"));
+        assert_eq!(head.extra_headers().collect::<Vec<_>>(),
+                   [("X-Frame-Options", "DENY")]);
+        // directory is matched by its own path
+        match inp.probe_file("public").unwrap() {
+            Output::FileHead(head) => {
+                assert!(head.headers().all(|(k, _)| k != "X-Frame-Options"));
+            }
+            _ => unreachable!(),
+        }
+        match inp.probe_file("public/index.html.gz").unwrap() {
+            Output::FileHead(head) => {
+                assert!(head.headers().all(|(k, _)| k != "X-Frame-Options"));
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn errors() {
+        let err = |text| HeaderRules::parse(text).unwrap_err().to_string();
+        assert_eq!(err("  X-A: b"), "line 1: header without a path pattern");
+        assert_eq!(err("/a\n  X-A"), "line 2: colon expected");
+        assert_eq!(err("/a\n  X A: b"), "line 2: invalid header name");
+        assert_eq!(err("a"), "line 1: path pattern must start with a slash");
+    }
+}
@@ -0,0 +1,379 @@
+use std::path::PathBuf;
+use std::time::Duration;
+
+use serde::Deserialize;
+use toml;
+
+use accept_encoding::Encoding;
+use config::Config;
+use etag::DefaultEtag;
+
+
+/// Serializable subset of the `Config`, e.g. for a section of the config
+/// file of the application
+///
+/// All fields are optional, the ones that are absent are left as in
+/// `Config::new()`. Lists replace the defaults. Unknown fields are errors.
+/// Example (TOML):
+///
+/// ```toml
+/// index-files = ["index.html"]
+/// encodings = "all"
+/// deny-extensions = ["bak", "swp"]
+/// metadata-cache = { ttl = 60, capacity = 1000 }
+/// ```
+///
+/// Note that `mmap-threshold` makes the process crash if a served file is
+/// truncated, see `Config::mmap_threshold`.
+///
+/// Only available with the `serde` feature.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all="kebab-case")]
+pub struct Settings {
+    /// Empty string means no charset, see `Config::no_text_charset`
+    text_charset: Option<String>,
+    sniff_charset: Option<bool>,
+    charset_types: Option<Vec<String>>,
+    unacceptable_charset: Option<CharsetPolicy>,
+    extended_text_types: Option<bool>,
+    index_files: Option<Vec<String>>,
+    negotiate_index: Option<bool>,
+    /// Pairs of media type and extension, see `Config::negotiate`
+    negotiate: Option<Vec<(String, String)>>,
+    allow_methods: Option<Vec<String>>,
+    encodings: Option<EncodingSupport>,
+    /// Implies `encodings = "types"`
+    compressible_types: Option<Vec<String>>,
+    brotli_suffix: Option<String>,
+    gzip_suffix: Option<String>,
+    brotli_min_size: Option<u64>,
+    no_encoding_extensions: Option<Vec<String>>,
+    /// Patterns, see `Config::no_encodings_for_path`
+    no_encoding_paths: Option<Vec<String>>,
+    encoded_inner_type: Option<bool>,
+    content_type: Option<bool>,
+    content_length: Option<bool>,
+    content_location: Option<bool>,
+    date: Option<bool>,
+    etag: Option<bool>,
+    /// `false` switches back to `DefaultEtag`
+    etag_content_only: Option<bool>,
+    etag_salt: Option<String>,
+    etag_encoding_suffix: Option<bool>,
+    /// Capacity of the cache of content hashes
+    strong_etag: Option<usize>,
+    strong_if_range: Option<bool>,
+    #[cfg(feature="xattr")]
+    etag_xattr: Option<bool>,
+    last_modified: Option<bool>,
+    deny_hidden_files: Option<bool>,
+    deny_extensions: Option<Vec<String>>,
+    max_path_length: Option<usize>,
+    max_path_components: Option<usize>,
+    archive_limits: Option<ArchiveLimits>,
+    read_buffer_size: Option<usize>,
+    rate_limit: Option<u64>,
+    explain: Option<bool>,
+    tail_mode: Option<bool>,
+    delegate: Option<String>,
+    inline_threshold: Option<u64>,
+    ranges: Option<bool>,
+    range_merge_gap: Option<u64>,
+    max_ranges: Option<usize>,
+    reject_excess_ranges: Option<bool>,
+    ignore_invalid_range: Option<bool>,
+    full_range_as_ok: Option<bool>,
+    fd_cache: Option<usize>,
+    metadata_cache: Option<CacheSettings>,
+    not_found_cache: Option<CacheSettings>,
+    #[cfg(feature="memmap")]
+    mmap_threshold: Option<u64>,
+    restrict_to_root: Option<PathBuf>,
+}
+
+/// What to do if charset is unacceptable by `Accept-Charset`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all="kebab-case")]
+enum CharsetPolicy {
+    /// Send charset anyway (default)
+    Ignore,
+    /// See `Config::drop_unacceptable_charset`
+    Drop,
+    /// See `Config::reject_unacceptable_charset`
+    Reject,
+}
+
+/// Files for which encoded variants are searched
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all="kebab-case")]
+enum EncodingSupport {
+    /// See `Config::no_encodings`
+    Never,
+    /// Compressible types only (default)
+    Types,
+    /// See `Config::encodings_on_all_files`
+    All,
+}
+
+/// Settings of `metadata_cache` and `not_found_cache`
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CacheSettings {
+    /// Time to live in seconds
+    ttl: u64,
+    /// Maximum number of entries
+    capacity: usize,
+}
+
+/// Settings of `archive_limits`
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ArchiveLimits {
+    /// Number of nested directories
+    depth: usize,
+    /// Number of files and directories
+    entries: usize,
+    /// Size of the archive
+    bytes: u64,
+}
+
+impl Settings {
+    /// Apply settings on top of the config
+    pub fn apply(&self, cfg: &mut Config) {
+        fn strs(list: &[String]) -> Vec<&str> {
+            list.iter().map(|x| &x[..]).collect()
+        }
+        match self.text_charset {
+            Some(ref x) if x.is_empty() => { cfg.no_text_charset(); }
+            Some(ref x) => { cfg.text_charset(x); }
+            None => {}
+        }
+        if let Some(x) = self.sniff_charset { cfg.sniff_charset(x); }
+        if let Some(ref x) = self.charset_types {
+            cfg.charset_on_types(&strs(x));
+        }
+        match self.unacceptable_charset {
+            Some(CharsetPolicy::Ignore) => {
+                cfg.charset_policy = ::config::CharsetPolicy::Ignore;
+            }
+            Some(CharsetPolicy::Drop) => { cfg.drop_unacceptable_charset(); }
+            Some(CharsetPolicy::Reject) => {
+                cfg.reject_unacceptable_charset();
+            }
+            None => {}
+        }
+        if let Some(x) = self.extended_text_types {
+            cfg.extended_text_types(x);
+        }
+        if let Some(ref x) = self.index_files {
+            cfg.index_files.clear();
+            for name in x {
+                cfg.add_index_file(name);
+            }
+        }
+        if let Some(x) = self.negotiate_index { cfg.negotiate_index(x); }
+        if let Some(ref x) = self.negotiate {
+            cfg.negotiate_types.clear();
+            for (mime, ext) in x {
+                cfg.negotiate(mime, ext);
+            }
+        }
+        if let Some(ref x) = self.allow_methods {
+            cfg.extra_methods.clear();
+            for method in x {
+                cfg.allow_method(method);
+            }
+        }
+        match self.encodings {
+            Some(EncodingSupport::Never) => { cfg.no_encodings(); }
+            Some(EncodingSupport::Types) => { cfg.encodings_on_text_files(); }
+            Some(EncodingSupport::All) => { cfg.encodings_on_all_files(); }
+            None => {}
+        }
+        if let Some(ref x) = self.compressible_types {
+            cfg.encodings_on_types(&strs(x));
+        }
+        if let Some(ref x) = self.brotli_suffix {
+            cfg.encoding_suffix(Encoding::Brotli, x);
+        }
+        if let Some(ref x) = self.gzip_suffix {
+            cfg.encoding_suffix(Encoding::Gzip, x);
+        }
+        if let Some(x) = self.brotli_min_size { cfg.brotli_min_size(x); }
+        if let Some(ref x) = self.no_encoding_extensions {
+            cfg.plain_extensions.clear();
+            for ext in x {
+                cfg.no_encodings_for_extension(ext);
+            }
+        }
+        if let Some(ref x) = self.no_encoding_paths {
+            cfg.plain_paths.clear();
+            for pattern in x {
+                cfg.no_encodings_for_path(pattern);
+            }
+        }
+        if let Some(x) = self.encoded_inner_type {
+            cfg.encoded_inner_type(x);
+        }
+        if let Some(x) = self.content_type { cfg.content_type(x); }
+        if let Some(x) = self.content_length {
+            cfg.content_length_header(x);
+        }
+        if let Some(x) = self.content_location { cfg.content_location(x); }
+        if let Some(x) = self.date { cfg.date_header(x); }
+        if let Some(x) = self.etag { cfg.etag(x); }
+        match self.etag_content_only {
+            Some(true) => { cfg.etag_content_only(); }
+            Some(false) => { cfg.etag_provider(DefaultEtag); }
+            None => {}
+        }
+        if let Some(ref x) = self.etag_salt { cfg.etag_salt(x.as_bytes()); }
+        if let Some(x) = self.etag_encoding_suffix {
+            cfg.etag_encoding_suffix(x);
+        }
+        if let Some(x) = self.strong_etag { cfg.strong_etag(x); }
+        if let Some(x) = self.strong_if_range { cfg.strong_if_range(x); }
+        #[cfg(feature="xattr")]
+        {
+            if let Some(x) = self.etag_xattr { cfg.etag_xattr(x); }
+        }
+        if let Some(x) = self.last_modified { cfg.last_modified(x); }
+        if let Some(x) = self.deny_hidden_files { cfg.deny_hidden_files(x); }
+        if let Some(ref x) = self.deny_extensions {
+            cfg.denied_extensions.clear();
+            for ext in x {
+                cfg.deny_extension(ext);
+            }
+        }
+        if let Some(x) = self.max_path_length { cfg.max_path_length(x); }
+        if let Some(x) = self.max_path_components {
+            cfg.max_path_components(x);
+        }
+        if let Some(x) = self.archive_limits {
+            cfg.archive_limits(x.depth, x.entries, x.bytes);
+        }
+        if let Some(x) = self.read_buffer_size { cfg.read_buffer_size(x); }
+        if let Some(x) = self.rate_limit { cfg.rate_limit(x); }
+        if let Some(x) = self.explain { cfg.explain(x); }
+        if let Some(x) = self.tail_mode { cfg.tail_mode(x); }
+        if let Some(ref x) = self.delegate { cfg.delegate(x); }
+        if let Some(x) = self.inline_threshold { cfg.inline_threshold(x); }
+        if let Some(x) = self.ranges { cfg.ranges(x); }
+        if let Some(x) = self.range_merge_gap { cfg.range_merge_gap(x); }
+        if let Some(x) = self.max_ranges { cfg.max_ranges(x); }
+        if let Some(x) = self.reject_excess_ranges {
+            cfg.reject_excess_ranges(x);
+        }
+        if let Some(x) = self.ignore_invalid_range {
+            cfg.ignore_invalid_range(x);
+        }
+        if let Some(x) = self.full_range_as_ok { cfg.full_range_as_ok(x); }
+        if let Some(x) = self.fd_cache { cfg.fd_cache(x); }
+        if let Some(x) = self.metadata_cache {
+            cfg.metadata_cache(Duration::from_secs(x.ttl), x.capacity);
+        }
+        if let Some(x) = self.not_found_cache {
+            cfg.not_found_cache(Duration::from_secs(x.ttl), x.capacity);
+        }
+        #[cfg(feature="memmap")]
+        {
+            if let Some(x) = self.mmap_threshold { cfg.mmap_threshold(x); }
+        }
+        if let Some(ref x) = self.restrict_to_root {
+            cfg.restrict_to_root(x);
+        }
+    }
+}
+
+impl Config {
+    /// Make config from a TOML document (see `Settings` for the schema)
+    ///
+    /// Only available with the `serde` feature.
+    pub fn from_toml_str(text: &str) -> Result<Config, toml::de::Error> {
+        Config::from_value(toml::from_str(text)?)
+    }
+    /// Make config from an already parsed TOML value
+    ///
+    /// This is useful when static files are configured in a section of
+    /// the application's config. Only available with the `serde` feature.
+    ///
+    /// Fails if `restrict-to-root` directory can't be resolved, or if
+    /// `read-buffer-size` or `rate-limit` is zero.
+    pub fn from_value(value: toml::Value) -> Result<Config, toml::de::Error>
+    {
+        use serde::de::Error;
+        let settings = Settings::deserialize(value)?;
+        if settings.read_buffer_size == Some(0) {
+            return Err(toml::de::Error::custom(
+                "read-buffer-size: must be non-zero"));
+        }
+        if settings.rate_limit == Some(0) {
+            return Err(toml::de::Error::custom(
+                "rate-limit: must be non-zero"));
+        }
+        let mut cfg = Config::new();
+        settings.apply(&mut cfg);
+        if let Some(Err(kind)) = cfg.root {
+            return Err(toml::de::Error::custom(format!(
+                "restrict-to-root: can't resolve directory: {:?}", kind)));
+        }
+        Ok(cfg)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use config::{Config, EncodingSupport, CharsetPolicy};
+
+    #[test]
+    fn from_toml() {
+        let cfg = Config::from_toml_str(r#"
+            text-charset = ""
+            unacceptable-charset = "drop"
+            index-files = ["index.html", "index.htm"]
+            negotiate = [["text/html", "html"]]
+            encodings = "all"
+            gzip-suffix = ".gzip"
+            deny-extensions = ["bak"]
+            max-ranges = 2
+            metadata-cache = { ttl = 60, capacity = 100 }
+        "#).unwrap();
+        assert_eq!(cfg.text_charset, None);
+        assert_eq!(cfg.charset_policy, CharsetPolicy::Drop);
+        assert_eq!(cfg.index_files, ["index.html", "index.htm"]);
+        assert_eq!(cfg.negotiate_types,
+                   [(String::from("text/html"), String::from("html"))]);
+        assert_eq!(cfg.encoding_support, EncodingSupport::AllFiles);
+        assert_eq!(cfg.gzip_suffix, ".gzip");
+        assert_eq!(cfg.brotli_suffix, ".br");
+        assert_eq!(cfg.denied_extensions, ["bak"]);
+        assert_eq!(cfg.max_ranges, Some(2));
+        assert!(cfg.head_cache.is_some());
+        assert!(cfg.not_found_cache.is_none());
+        // defaults are kept
+        assert!(cfg.etag);
+        assert_eq!(cfg.read_buffer_size, 65536);
+    }
+
+    #[test]
+    fn etag_content_only() {
+        let provider = |text| format!("{:?}",
+            Config::from_toml_str(text).unwrap().etag_provider);
+        assert_eq!(provider("etag-content-only = true"), "ContentOnlyEtag");
+        assert_eq!(provider("etag-content-only = false"), "DefaultEtag");
+        assert_eq!(provider(""), "DefaultEtag");
+    }
+
+    #[test]
+    fn unknown_field() {
+        let err = Config::from_toml_str("index_files = []").unwrap_err();
+        assert!(err.to_string().contains("index_files"));
+        assert!(Config::from_toml_str("encodings = \"some\"").is_err());
+        assert!(Config::from_toml_str("restrict-to-root = \"public/none\"")
+                .is_err());
+        let err = Config::from_toml_str("read-buffer-size = 0").unwrap_err();
+        assert!(err.to_string().contains("read-buffer-size"));
+        let err = Config::from_toml_str("rate-limit = 0").unwrap_err();
+        assert!(err.to_string().contains("rate-limit"));
+    }
+}
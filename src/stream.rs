@@ -0,0 +1,138 @@
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::thread;
+use std::time::Duration;
+
+use bytes::Bytes;
+use futures_core::Stream;
+use tokio::runtime::Handle;
+use tokio::sync::mpsc;
+
+use output::FileWrapper;
+use vfs::ReadAt;
+
+/// Number of chunks buffered between disk thread and the consumer
+const CHANNEL_SIZE: usize = 4;
+/// How often to check whether the file has grown (in tail mode)
+const TAIL_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+
+/// A stream of file chunks read in the disk thread
+///
+/// Create one with `FileWrapper::into_stream`.
+///
+/// Only available with the `stream` feature.
+#[derive(Debug)]
+pub struct FileStream {
+    rx: mpsc::Receiver<io::Result<Bytes>>,
+}
+
+impl<F: ReadAt + Send + 'static> FileWrapper<F> {
+    /// Convert file into a stream of chunks
+    ///
+    /// Chunks are read using `spawn_blocking` on the supplied runtime.
+    /// Reading stops as soon as the stream is dropped. When rate limit is
+    /// exceeded the task sleeps for `throttle_delay()`, and in tail mode
+    /// it waits for the file to grow, instead of failing the stream.
+    ///
+    /// Only available with the `stream` feature.
+    pub fn into_stream(mut self, pool: &Handle) -> FileStream {
+        let (tx, rx) = mpsc::channel(CHANNEL_SIZE);
+        pool.spawn_blocking(move || {
+            let size = self.head().data.config.read_buffer_size;
+            loop {
+                match self.read_bytes(size) {
+                    Ok(ref chunk) if chunk.is_empty() => break,
+                    Ok(chunk) => {
+                        if tx.blocking_send(Ok(chunk)).is_err() {
+                            // stream is dropped, i.e. connection is closed
+                            break;
+                        }
+                    }
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                        if tx.is_closed() {
+                            break;
+                        }
+                        // rate limit is exceeded, or the file isn't
+                        // written yet in tail mode
+                        thread::sleep(self.throttle_delay()
+                            .unwrap_or(TAIL_POLL_INTERVAL));
+                    }
+                    Err(e) => {
+                        tx.blocking_send(Err(e)).ok();
+                        break;
+                    }
+                }
+            }
+        });
+        FileStream { rx }
+    }
+}
+
+impl Stream for FileStream {
+    type Item = io::Result<Bytes>;
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context)
+        -> Poll<Option<io::Result<Bytes>>>
+    {
+        self.rx.poll_recv(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::future::poll_fn;
+    use std::sync::Arc;
+    use tokio::runtime::Builder;
+    use config::Config;
+    use input::Input;
+    use output::Output;
+    use super::*;
+
+    #[test]
+    fn read_range() {
+        let rt = Builder::new_current_thread().build().unwrap();
+        let cfg = Arc::new(Config::new());
+        let inp = Input::from_headers(&cfg, "GET",
+            vec![("Range", &b"bytes=2-8"[..])].into_iter());
+        let wrapper = match inp.probe_file("public/index.html").unwrap() {
+            Output::File(wrapper) => wrapper,
+            _ => unreachable!(),
+        };
+        let mut stream = wrapper.into_stream(rt.handle());
+        let mut body = Vec::new();
+        while let Some(chunk) = rt.block_on(
+            poll_fn(|cx| Pin::new(&mut stream).poll_next(cx)))
+        {
+            body.extend_from_slice(&chunk.unwrap());
+        }
+        assert_eq!(&body[..], b"DOCTYPE");
+    }
+
+    #[test]
+    fn rate_limit() {
+        use test_util::TempDir;
+
+        let dir = TempDir::new("stream-rate-limit");
+        let data = vec![b'x'; 3000];
+        let path = dir.write("a.txt", &data[..]);
+        let rt = Builder::new_current_thread().build().unwrap();
+        let cfg = Config::new()
+            .read_buffer_size(1024)
+            .rate_limit(100_000)
+            .done();
+        let inp = Input::from_headers(&cfg, "GET", vec![].into_iter());
+        let wrapper = match inp.probe_file(&path).unwrap() {
+            Output::File(wrapper) => wrapper,
+            _ => unreachable!(),
+        };
+        let mut stream = wrapper.into_stream(rt.handle());
+        let mut body = Vec::new();
+        while let Some(chunk) = rt.block_on(
+            poll_fn(|cx| Pin::new(&mut stream).poll_next(cx)))
+        {
+            body.extend_from_slice(&chunk.unwrap());
+        }
+        assert_eq!(body, data);
+    }
+}
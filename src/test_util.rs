@@ -0,0 +1,48 @@
+use std::env::temp_dir;
+use std::fs::{create_dir_all, remove_dir_all, write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+
+/// Temporary directory for test fixtures, removed when dropped
+///
+/// Names are unique per process and per call, so tests running in
+/// parallel (or overlapping runs) never see each other's files.
+#[derive(Debug)]
+pub(crate) struct TempDir {
+    path: PathBuf,
+}
+
+impl TempDir {
+    pub fn new(name: &str) -> TempDir {
+        let path = temp_dir().join(format!("http-file-headers-{}-{}-{}",
+            name, process::id(), COUNTER.fetch_add(1, Ordering::SeqCst)));
+        remove_dir_all(&path).ok();
+        create_dir_all(&path).unwrap();
+        TempDir { path }
+    }
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+    pub fn join<P: AsRef<Path>>(&self, name: P) -> PathBuf {
+        self.path.join(name)
+    }
+    /// Write the file (creating parent directories), returns its path
+    pub fn write<P: AsRef<Path>, D: AsRef<[u8]>>(&self, name: P, data: D)
+        -> PathBuf
+    {
+        let path = self.path.join(name);
+        create_dir_all(path.parent().unwrap()).unwrap();
+        write(&path, data).unwrap();
+        path
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        remove_dir_all(&self.path).ok();
+    }
+}
@@ -0,0 +1,88 @@
+use std::cmp::min;
+use std::time::{Duration, Instant};
+
+
+/// Token bucket limiting the bandwidth of a single response
+///
+/// The bucket is full initially, so the first `burst` bytes are sent
+/// without waiting.
+#[derive(Debug, Clone)]
+pub(crate) struct Throttle {
+    /// Bytes per second
+    rate: u64,
+    /// Size of the bucket
+    burst: u64,
+    tokens: u64,
+    updated: Option<Instant>,
+}
+
+impl Throttle {
+    pub fn new(rate: u64, burst: u64) -> Throttle {
+        Throttle {
+            rate,
+            burst,
+            tokens: burst,
+            updated: None,
+        }
+    }
+    /// Number of bytes that can be sent now
+    pub fn allowance(&self, now: Instant) -> u64 {
+        let elapsed = match self.updated {
+            Some(updated) if now > updated => now - updated,
+            _ => return self.tokens,
+        };
+        let earned = self.rate as u128 * elapsed.as_nanos() / 1_000_000_000;
+        min(self.tokens as u128 + earned, self.burst as u128) as u64
+    }
+    /// Account bytes sent
+    pub fn consume(&mut self, bytes: u64, now: Instant) {
+        self.tokens = self.allowance(now).saturating_sub(bytes);
+        self.updated = Some(now);
+    }
+    /// Time to wait until `bytes` (at most burst) can be sent
+    pub fn delay(&self, bytes: u64, now: Instant) -> Option<Duration> {
+        let bytes = min(bytes, self.burst);
+        let deficit = bytes.saturating_sub(self.allowance(now));
+        if deficit == 0 {
+            return None;
+        }
+        let rate = self.rate as u128;
+        let nanos = (deficit as u128 * 1_000_000_000).div_ceil(rate);
+        Some(Duration::new((nanos / 1_000_000_000) as u64,
+                           (nanos % 1_000_000_000) as u32))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+    use super::Throttle;
+
+    #[test]
+    fn burst() {
+        let now = Instant::now();
+        let mut t = Throttle::new(100, 10);
+        assert_eq!(t.allowance(now), 10);
+        assert_eq!(t.delay(10, now), None);
+        t.consume(10, now);
+        assert_eq!(t.allowance(now), 0);
+        assert_eq!(t.delay(10, now), Some(Duration::from_millis(100)));
+        assert_eq!(t.delay(5, now), Some(Duration::from_millis(50)));
+        assert_eq!(t.delay(1000, now), Some(Duration::from_millis(100)));
+        let later = now + Duration::from_millis(50);
+        assert_eq!(t.allowance(later), 5);
+        assert_eq!(t.delay(5, later), None);
+    }
+
+    #[test]
+    fn idle() {
+        let now = Instant::now();
+        let mut t = Throttle::new(100, 10);
+        t.consume(10, now);
+        // idle time doesn't allow to send more than burst
+        let later = now + Duration::new(60, 0);
+        assert_eq!(t.allowance(later), 10);
+        t.consume(10, later);
+        assert_eq!(t.allowance(later), 0);
+    }
+}
@@ -0,0 +1,269 @@
+use std::cmp::min;
+use std::fs::File;
+use std::future::Future;
+use std::io;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use tokio::io::{AsyncRead, ReadBuf};
+use tokio::task::{spawn_blocking, JoinHandle};
+
+use input::Input;
+use output::{Output, Head, FileWrapper, HeaderIter};
+use vfs::ReadAt;
+
+
+/// Future returned by `Input::probe_file_async`
+///
+/// Only available with the `tokio` feature.
+#[derive(Debug)]
+pub struct ProbeFuture {
+    join: JoinHandle<io::Result<Output>>,
+}
+
+/// Asynchronous counterpart of `FileWrapper`
+///
+/// Create one with `FileWrapper::into_async`. Implements `AsyncRead`
+/// which reads only the requested range of the file.
+///
+/// Only available with the `tokio` feature.
+#[derive(Debug)]
+pub struct AsyncFileWrapper {
+    head: Head,
+    file: Arc<File>,
+    /// Offset of the next chunk to read from the file
+    offset: u64,
+    /// Bytes left to read from the file (not counting `chunk`)
+    bytes_left: u64,
+    chunk: Vec<u8>,
+    /// Number of bytes of the `chunk` already returned to the reader
+    chunk_pos: usize,
+    read: Option<JoinHandle<io::Result<Vec<u8>>>>,
+}
+
+impl Input {
+    /// Open files from filesystem without blocking the event loop
+    ///
+    /// This runs `probe_file` on the tokio blocking thread pool (the same
+    /// one used by `tokio::fs`), but does all the system calls in a single
+    /// task rather than a task per call.
+    ///
+    /// **Must be called within tokio runtime**
+    ///
+    /// Only available with the `tokio` feature.
+    pub fn probe_file_async<P: AsRef<Path>>(&self, base_path: P)
+        -> ProbeFuture
+    {
+        let inp = self.clone();
+        let path = base_path.as_ref().to_path_buf();
+        ProbeFuture {
+            join: spawn_blocking(move || inp.probe_file(path)),
+        }
+    }
+}
+
+impl Future for ProbeFuture {
+    type Output = io::Result<Output>;
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context)
+        -> Poll<io::Result<Output>>
+    {
+        match Pin::new(&mut self.join).poll(cx) {
+            Poll::Pending => Poll::Pending,
+            Poll::Ready(Ok(result)) => Poll::Ready(result),
+            Poll::Ready(Err(e)) => {
+                Poll::Ready(Err(io::Error::other(e)))
+            }
+        }
+    }
+}
+
+impl FileWrapper {
+    /// Convert into a wrapper that reads file asynchronously
+    ///
+    /// Chunks of `Config::read_buffer_size` are read at explicit offsets
+    /// on the tokio blocking thread pool, so file position is never used
+    /// (descriptors from `Config::fd_cache` share it). Rate limit is not
+    /// applied.
+    ///
+    /// Only available with the `tokio` feature.
+    pub fn into_async(self) -> io::Result<AsyncFileWrapper> {
+        let bytes_left = self.bytes_left();
+        let (head, file, offset) = self.into_parts();
+        Ok(AsyncFileWrapper {
+            head,
+            file: Arc::new(file),
+            offset,
+            bytes_left,
+            chunk: Vec::new(),
+            chunk_pos: 0,
+            read: None,
+        })
+    }
+}
+
+impl AsyncFileWrapper {
+    /// Returns metadata of the response
+    pub fn head(&self) -> &Head {
+        &self.head
+    }
+    /// Returns true if response contains partial content (206)
+    pub fn is_partial(&self) -> bool {
+        self.head.is_partial()
+    }
+    /// Returns HTTP status code that should be sent (200 or 206)
+    pub fn suggested_status(&self) -> u16 {
+        self.head.suggested_status()
+    }
+    /// Returns the value of `Content-Length` header that should be sent
+    pub fn content_length(&self) -> u64 {
+        self.head.content_length()
+    }
+    /// Returns the iterator over headers to send in response
+    ///
+    /// Note: this does not include `Content-Length` header (unless
+    /// `Config::content_length_header` is enabled), use `content_length()`
+    /// method explicitly.
+    pub fn headers(&self) -> HeaderIter<'_> {
+        self.head.headers()
+    }
+    /// Returns number of bytes that are still to be read
+    pub fn bytes_left(&self) -> u64 {
+        self.bytes_left + (self.chunk.len() - self.chunk_pos) as u64
+    }
+}
+
+impl AsyncRead for AsyncFileWrapper {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context,
+        buf: &mut ReadBuf)
+        -> Poll<io::Result<()>>
+    {
+        let this = &mut *self;
+        loop {
+            if this.chunk_pos < this.chunk.len() {
+                let end = min(this.chunk.len(),
+                              this.chunk_pos + buf.remaining());
+                buf.put_slice(&this.chunk[this.chunk_pos..end]);
+                this.chunk_pos = end;
+                return Poll::Ready(Ok(()));
+            }
+            if let Some(ref mut read) = this.read {
+                let chunk = match Pin::new(read).poll(cx) {
+                    Poll::Pending => return Poll::Pending,
+                    Poll::Ready(Ok(result)) => result,
+                    Poll::Ready(Err(e)) => Err(io::Error::other(e)),
+                };
+                this.read = None;
+                let chunk = chunk?;
+                this.offset += chunk.len() as u64;
+                this.bytes_left -= chunk.len() as u64;
+                this.chunk = chunk;
+                this.chunk_pos = 0;
+                continue;
+            }
+            if this.bytes_left == 0 || buf.remaining() == 0 {
+                return Poll::Ready(Ok(()));
+            }
+            let file = this.file.clone();
+            let offset = this.offset;
+            let size = min(this.bytes_left,
+                this.head.data.config.read_buffer_size as u64) as usize;
+            this.read = Some(spawn_blocking(move || {
+                read_exact_at(&file, offset, size)
+            }));
+        }
+    }
+}
+
+fn read_exact_at(file: &File, mut offset: u64, size: usize)
+    -> io::Result<Vec<u8>>
+{
+    let mut buf = vec![0u8; size];
+    let mut pos = 0;
+    while pos < size {
+        match file.read_at(&mut buf[pos..], offset) {
+            Ok(0) => {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                    "file is truncated while sending"));
+            }
+            Ok(bytes) => {
+                pos += bytes;
+                offset += bytes as u64;
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use tokio::io::AsyncReadExt;
+    use tokio::runtime::Builder;
+    use config::Config;
+    use super::*;
+
+    #[test]
+    fn probe() {
+        let rt = Builder::new_current_thread().build().unwrap();
+        let _guard = rt.enter();
+        let cfg = Arc::new(Config::new());
+        let inp = Input::from_headers(&cfg, "HEAD", vec![].into_iter());
+        match rt.block_on(inp.probe_file_async("public/index.html")) {
+            Ok(Output::FileHead(head)) => {
+                assert_eq!(head.content_length(), 126);
+            }
+            _ => unreachable!(),
+        }
+        match rt.block_on(inp.probe_file_async("public/nonexistent")) {
+            Ok(Output::NotFound) => {}
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn async_read() {
+        let rt = Builder::new_current_thread().build().unwrap();
+        let cfg = Arc::new(Config::new());
+        let inp = Input::from_headers(&cfg, "GET",
+            vec![("Range", &b"bytes=2-8"[..])].into_iter());
+        let mut wrapper = match inp.probe_file("public/index.html").unwrap() {
+            Output::File(wrapper) => wrapper.into_async().unwrap(),
+            _ => unreachable!(),
+        };
+        assert_eq!(wrapper.bytes_left(), 7);
+        let mut body = Vec::new();
+        rt.block_on(wrapper.read_to_end(&mut body)).unwrap();
+        assert_eq!(&body[..], b"DOCTYPE");
+        assert_eq!(wrapper.bytes_left(), 0);
+    }
+
+    #[test]
+    fn async_read_fd_cache() {
+        use std::fs;
+        let rt = Builder::new_current_thread().build().unwrap();
+        let _guard = rt.enter();
+        let cfg = Config::new().fd_cache(16).read_buffer_size(16).done();
+        let open = |range: &'static [u8]| {
+            let inp = Input::from_headers(&cfg, "GET",
+                vec![("Range", range)].into_iter());
+            match inp.probe_file("public/index.html").unwrap() {
+                Output::File(wrapper) => wrapper.into_async().unwrap(),
+                _ => unreachable!(),
+            }
+        };
+        // both descriptors are clones of the cached one
+        let mut full = open(b"bytes=0-");
+        let mut tail = open(b"bytes=100-");
+        let data = fs::read("public/index.html").unwrap();
+        let mut body = Vec::new();
+        rt.block_on(tail.read_to_end(&mut body)).unwrap();
+        assert_eq!(&body[..], &data[100..]);
+        let mut body = Vec::new();
+        rt.block_on(full.read_to_end(&mut body)).unwrap();
+        assert_eq!(body, data);
+    }
+}
@@ -0,0 +1,155 @@
+//! A [tower](https://github.com/tower-rs/tower) service serving a directory
+//!
+//! Only available with the `tower` feature.
+//!
+//! ```rust,ignore
+//! let config = Config::new().add_index_file("index.html").done();
+//! let app = Router::new()
+//!     .nest_service("/static", ServeDir::new("./public", &config));
+//! ```
+use std::convert::Infallible;
+use std::future::Future;
+use std::io;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use http::{Request, Response};
+use hyper::Body;
+use tokio::runtime::Handle;
+use tokio::task::JoinHandle;
+use tower_service::Service;
+
+use config::Config;
+use hyper_compat::{into_response, error_response};
+use input::Input;
+use output::Output;
+use path::resolve;
+
+
+/// A service that serves files from a directory
+///
+/// Files are probed and read using `spawn_blocking`. Filesystem errors
+/// are reported as `500 Internal Server Error` so the service never fails.
+#[derive(Debug, Clone)]
+pub struct ServeDir {
+    root: PathBuf,
+    config: Arc<Config>,
+    handle: Option<Handle>,
+}
+
+/// Future returned by the `ServeDir` service
+#[derive(Debug)]
+pub struct ResponseFuture {
+    state: Option<(JoinHandle<io::Result<Output>>, Handle)>,
+}
+
+impl ServeDir {
+    /// Create a service serving files from `root` directory
+    ///
+    /// Blocking tasks are spawned on the current tokio runtime.
+    pub fn new<P: Into<PathBuf>>(root: P, config: &Arc<Config>) -> ServeDir {
+        ServeDir {
+            root: root.into(),
+            config: config.clone(),
+            handle: None,
+        }
+    }
+    /// Use specified runtime for blocking tasks
+    pub fn with_handle(mut self, handle: Handle) -> ServeDir {
+        self.handle = Some(handle);
+        self
+    }
+}
+
+impl<B> Service<Request<B>> for ServeDir {
+    type Response = Response<Body>;
+    type Error = Infallible;
+    type Future = ResponseFuture;
+    fn poll_ready(&mut self, _cx: &mut Context)
+        -> Poll<Result<(), Infallible>>
+    {
+        Poll::Ready(Ok(()))
+    }
+    fn call(&mut self, req: Request<B>) -> ResponseFuture {
+        let (parts, _) = req.into_parts();
+        let path = match resolve(&self.root, parts.uri.path()) {
+            Ok(path) => path,
+            Err(_) => return ResponseFuture { state: None },
+        };
+        let inp = Input::from_request_parts(&self.config, &parts);
+        let handle = self.handle.clone().unwrap_or_else(Handle::current);
+        let join = handle.spawn_blocking(move || inp.probe_file(&path));
+        ResponseFuture { state: Some((join, handle)) }
+    }
+}
+
+impl Future for ResponseFuture {
+    type Output = Result<Response<Body>, Infallible>;
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context)
+        -> Poll<Result<Response<Body>, Infallible>>
+    {
+        let response = match self.state {
+            None => error_response(404),
+            Some((ref mut join, ref handle)) => {
+                match Pin::new(join).poll(cx) {
+                    Poll::Pending => return Poll::Pending,
+                    Poll::Ready(Ok(Ok(output))) => {
+                        into_response(output, handle)
+                    }
+                    Poll::Ready(Ok(Err(_err))) => {
+                        warn!(error = %_err, "can't probe file");
+                        error_response(500)
+                    }
+                    Poll::Ready(Err(_err)) => {
+                        warn!(error = %_err, "disk task failed");
+                        error_response(500)
+                    }
+                }
+            }
+        };
+        Poll::Ready(Ok(response))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use hyper::body::to_bytes;
+    use tokio::runtime::{Builder, Runtime};
+    use super::*;
+
+    fn request(rt: &Runtime, svc: &ServeDir, path: &str) -> Response<Body> {
+        let mut svc = svc.clone().with_handle(rt.handle().clone());
+        let req = Request::get(path).body(()).unwrap();
+        rt.block_on(svc.call(req)).unwrap()
+    }
+
+    #[test]
+    fn serve() {
+        let rt = Builder::new_current_thread().build().unwrap();
+        let cfg = Config::new().add_index_file("index.html").done();
+        let svc = ServeDir::new("public", &cfg);
+        assert_eq!(request(&rt, &svc, "/").status(), 200);
+        let resp = request(&rt, &svc, "/index.html");
+        assert_eq!(resp.status(), 200);
+        let body = rt.block_on(to_bytes(resp.into_body())).unwrap();
+        assert!(body.starts_with(b"<!DOCTYPE html>"));
+    }
+
+    #[test]
+    fn not_found() {
+        let rt = Builder::new_current_thread().build().unwrap();
+        let svc = ServeDir::new("public", &Config::new().done());
+        assert_eq!(request(&rt, &svc, "/nonexistent").status(), 404);
+        assert_eq!(request(&rt, &svc, "/../Cargo.toml").status(), 404);
+    }
+
+    #[test]
+    fn encoded() {
+        let rt = Builder::new_current_thread().build().unwrap();
+        let svc = ServeDir::new("public", &Config::new().done());
+        assert_eq!(request(&rt, &svc, "/index%2Ehtml").status(), 200);
+        assert_eq!(request(&rt, &svc, "/%2E%2E/Cargo.toml").status(), 404);
+    }
+}
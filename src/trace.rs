@@ -0,0 +1,52 @@
+//! Macros for the optional `tracing` instrumentation
+//!
+//! Without the `tracing` feature they expand to nothing, so arguments are
+//! neither evaluated nor required to implement `Debug`.
+
+/// Enter a debug-level span until the end of the current block
+#[cfg(feature="tracing")]
+macro_rules! enter_span {
+    ($($arg:tt)*) => {
+        let _span = ::tracing::debug_span!($($arg)*).entered();
+    };
+}
+
+/// Emit a debug-level event (decisions made while probing a file)
+#[cfg(feature="tracing")]
+macro_rules! debug {
+    ($($arg:tt)*) => { ::tracing::debug!($($arg)*) };
+}
+
+/// Emit a trace-level event (reading chunks of the body)
+#[cfg(feature="tracing")]
+macro_rules! trace {
+    ($($arg:tt)*) => { ::tracing::trace!($($arg)*) };
+}
+
+/// Emit a warn-level event (errors turned into a 500 response)
+#[cfg(feature="tracing")]
+#[allow(unused_macros)]  // only used with the `tower` feature
+macro_rules! warn {
+    ($($arg:tt)*) => { ::tracing::warn!($($arg)*) };
+}
+
+#[cfg(not(feature="tracing"))]
+macro_rules! enter_span {
+    ($($arg:tt)*) => {};
+}
+
+#[cfg(not(feature="tracing"))]
+macro_rules! debug {
+    ($($arg:tt)*) => {};
+}
+
+#[cfg(not(feature="tracing"))]
+macro_rules! trace {
+    ($($arg:tt)*) => {};
+}
+
+#[cfg(not(feature="tracing"))]
+#[allow(unused_macros)]
+macro_rules! warn {
+    ($($arg:tt)*) => {};
+}
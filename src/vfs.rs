@@ -0,0 +1,405 @@
+//! Filesystem abstraction used for probing files
+//!
+//! By default files are served from the OS filesystem (see `StdFs`), but
+//! `Input::probe_file_in` accepts any implementation of `FileSystem`, so
+//! files can be served from archives, embedded assets or test fakes.
+use std::collections::HashSet;
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf, Component};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use fd_cache::FdCache;
+
+pub use embedded::{EmbeddedFs, EmbeddedFile};
+pub use pack_fs::{PackFs, PackFile};
+#[cfg(feature="zip")] pub use zip_fs::{ZipFs, ZipFile};
+
+
+/// A virtual filesystem to serve files from
+///
+/// Paths passed to the methods are the ones passed to
+/// `Input::probe_file_in`, with index file names or encoding suffixes
+/// (`.gz`, `.br`) appended.
+pub trait FileSystem {
+    /// Opened file
+    type File: ReadAt;
+    /// Returns metadata of the path (following symlinks if applicable)
+    ///
+    /// Should return error of kind `NotFound` if path does not exist.
+    fn metadata(&self, path: &Path) -> io::Result<Metadata>;
+    /// Opens a file and returns metadata of the opened file
+    ///
+    /// Should return error of kind `NotFound` if path does not exist.
+    fn open(&self, path: &Path) -> io::Result<(Self::File, Metadata)>;
+    /// Returns names of the entries in the directory
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<OsString>>;
+    /// Returns absolute path with all symlinks resolved
+    ///
+    /// This is used to check `Config::restrict_to_root`. Default
+    /// implementation returns `None`, which means there are no symlinks.
+    fn canonicalize(&self, _path: &Path) -> io::Result<Option<PathBuf>> {
+        Ok(None)
+    }
+}
+
+/// A file that supports reading at arbitrary offset
+///
+/// Reads must not depend on (or change) any kind of file position, as
+/// the same file may be shared by several responses.
+pub trait ReadAt {
+    /// Read bytes starting at `offset` into `buf`
+    ///
+    /// Returns number of bytes read, zero means end of file.
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize>;
+    /// Returns underlying OS file, if there is one
+    ///
+    /// This is used to enable memory mapping (see `Config::mmap_threshold`).
+    fn as_file(&self) -> Option<&File> {
+        None
+    }
+    /// Returns current metadata of the opened file, if it can change
+    ///
+    /// This is used by `FileWrapper::revalidate`. Default implementation
+    /// returns `None`, which means the file never changes once opened.
+    fn metadata(&self) -> io::Result<Option<Metadata>> {
+        Ok(None)
+    }
+}
+
+/// Adapter that allows to serve any `Read + Seek` object (e.g. archive
+/// entry, in-memory cursor, or a network-backed blob)
+///
+/// Every read seeks to the requested offset first, reads are serialized
+/// by the mutex. See `FileWrapper::from_reader`.
+#[derive(Debug)]
+pub struct SeekReader<R> {
+    reader: Mutex<R>,
+}
+
+/// Metadata of the file in a `FileSystem`
+#[derive(Debug, Clone)]
+pub struct Metadata {
+    is_dir: bool,
+    len: u64,
+    modified: Option<SystemTime>,
+    created: Option<SystemTime>,
+    extra: Option<[u64; 4]>,
+}
+
+/// The filesystem of the operating system
+///
+/// This is what `Input::probe_file` uses (along with `Config::fd_cache`
+/// if enabled).
+#[derive(Debug, Clone, Default)]
+pub struct StdFs {
+    fd_cache: Option<Arc<FdCache>>,
+}
+
+impl Metadata {
+    /// Metadata of a regular file of `len` bytes
+    pub fn file(len: u64, modified: Option<SystemTime>) -> Metadata {
+        Metadata {
+            is_dir: false,
+            len,
+            modified,
+            created: None,
+            extra: None,
+        }
+    }
+    /// Metadata of a directory
+    pub fn directory() -> Metadata {
+        Metadata {
+            is_dir: true,
+            len: 0,
+            modified: None,
+            created: None,
+            extra: None,
+        }
+    }
+    /// Set creation time of the file (it's used for etag)
+    pub fn with_created(mut self, created: SystemTime) -> Metadata {
+        self.created = Some(created);
+        self
+    }
+    /// Set a version of the file (e.g. checksum or generation number)
+    ///
+    /// This value is mixed into etag, so it's useful when file can be
+    /// changed without changing size and modification time.
+    pub fn with_version(mut self, version: u64) -> Metadata {
+        self.extra = Some([version, 0, 0, 0]);
+        self
+    }
+    /// Returns true if this is a regular file
+    pub fn is_file(&self) -> bool {
+        !self.is_dir
+    }
+    /// Returns true if this is a directory
+    pub fn is_dir(&self) -> bool {
+        self.is_dir
+    }
+    /// Size of the file in bytes
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+    /// Returns true if the file is empty
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+    /// Modification time of the file
+    pub fn modified(&self) -> Option<SystemTime> {
+        self.modified
+    }
+    /// Creation time of the file
+    pub fn created(&self) -> Option<SystemTime> {
+        self.created
+    }
+    pub(crate) fn extra(&self) -> Option<&[u64; 4]> {
+        self.extra.as_ref()
+    }
+}
+
+impl From<&fs::Metadata> for Metadata {
+    fn from(meta: &fs::Metadata) -> Metadata {
+        Metadata {
+            // anything that is not a directory is treated as a file, and
+            // rejected when opening if it's not a regular file
+            is_dir: meta.is_dir(),
+            len: meta.len(),
+            modified: meta.modified().ok(),
+            created: meta.created().ok(),
+            extra: extra(meta),
+        }
+    }
+}
+
+#[cfg(unix)]
+fn extra(meta: &fs::Metadata) -> Option<[u64; 4]> {
+    use std::os::unix::fs::MetadataExt;
+    // sometimes last_modified date is not reliable
+    // so we use inode number and `ctime` date on unix systems too
+    Some([meta.dev(), meta.ino(),
+          meta.ctime() as u64, meta.ctime_nsec() as u64])
+}
+
+#[cfg(not(unix))]
+fn extra(_: &fs::Metadata) -> Option<[u64; 4]> {
+    None
+}
+
+impl StdFs {
+    /// Create a filesystem object that opens files without caching
+    pub fn new() -> StdFs {
+        StdFs { fd_cache: None }
+    }
+    pub(crate) fn with_cache(cache: Option<Arc<FdCache>>) -> StdFs {
+        StdFs { fd_cache: cache }
+    }
+}
+
+impl FileSystem for StdFs {
+    type File = File;
+    fn metadata(&self, path: &Path) -> io::Result<Metadata> {
+        fs::metadata(path).map(|m| Metadata::from(&m))
+    }
+    fn open(&self, path: &Path) -> io::Result<(File, Metadata)> {
+        let (file, meta) = match self.fd_cache {
+            Some(ref cache) => cache.open(path)?,
+            None => {
+                let f = File::open(path)?;
+                let meta = f.metadata()?;
+                (f, meta)
+            }
+        };
+        if !meta.is_file() {
+            return Err(io::ErrorKind::PermissionDenied.into());
+        }
+        Ok((file, Metadata::from(&meta)))
+    }
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<OsString>> {
+        fs::read_dir(path)?
+            .map(|entry| entry.map(|e| e.file_name()))
+            .collect()
+    }
+    fn canonicalize(&self, path: &Path) -> io::Result<Option<PathBuf>> {
+        fs::canonicalize(path).map(Some)
+    }
+}
+
+/// Strips root and `.` components, returns `None` for `..`
+pub(crate) fn normalize(path: &Path) -> Option<PathBuf> {
+    let mut result = PathBuf::new();
+    for cmp in path.components() {
+        match cmp {
+            Component::Normal(name) => result.push(name),
+            Component::RootDir | Component::CurDir => {}
+            Component::Prefix(_) | Component::ParentDir => return None,
+        }
+    }
+    Some(result)
+}
+
+/// Returns sorted names of the direct children of `dir` among `paths`
+pub(crate) fn list_dir<'a, I>(dir: &Path, paths: I) -> Vec<OsString>
+    where I: Iterator<Item=&'a PathBuf>
+{
+    let mut names = HashSet::new();
+    for path in paths {
+        if path.parent() == Some(dir) {
+            if let Some(name) = path.file_name() {
+                names.insert(name.to_os_string());
+            }
+        }
+    }
+    let mut names = names.into_iter().collect::<Vec<_>>();
+    names.sort();
+    names
+}
+
+/// Reads exactly `buf.len()` bytes at `offset`
+pub(crate) fn read_exact_at<F: ReadAt>(file: &F, mut buf: &mut [u8],
+    mut offset: u64)
+    -> io::Result<()>
+{
+    while !buf.is_empty() {
+        match file.read_at(buf, offset)? {
+            0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+            n => {
+                let tmp = buf;
+                buf = &mut tmp[n..];
+                offset += n as u64;
+            }
+        }
+    }
+    Ok(())
+}
+
+impl<R: Read + Seek> SeekReader<R> {
+    /// Wrap a reader
+    pub fn new(reader: R) -> SeekReader<R> {
+        SeekReader { reader: Mutex::new(reader) }
+    }
+    /// Returns the original reader back
+    pub fn into_inner(self) -> R {
+        self.reader.into_inner().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl<R: Read + Seek> ReadAt for SeekReader<R> {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        let mut reader = self.reader.lock()
+            .map_err(|_| io::Error::other("reader lock is poisoned"))?;
+        reader.seek(SeekFrom::Start(offset))?;
+        reader.read(buf)
+    }
+}
+
+impl ReadAt for File {
+    #[cfg(unix)]
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        use std::os::unix::fs::FileExt;
+        FileExt::read_at(self, buf, offset)
+    }
+    #[cfg(windows)]
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        use std::os::windows::fs::FileExt;
+        self.seek_read(buf, offset)
+    }
+    #[cfg(not(any(unix, windows)))]
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        use std::io::{Read, Seek, SeekFrom};
+        let mut file = self;
+        file.seek(SeekFrom::Start(offset))?;
+        file.read(buf)
+    }
+    fn as_file(&self) -> Option<&File> {
+        Some(self)
+    }
+    fn metadata(&self) -> io::Result<Option<Metadata>> {
+        Ok(Some(Metadata::from(&File::metadata(self)?)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use std::path::PathBuf;
+    use std::sync::Arc;
+    use config::Config;
+    use input::Input;
+    use output::Output;
+    use super::*;
+
+    #[derive(Debug)]
+    struct MemFile(&'static [u8]);
+
+    impl ReadAt for MemFile {
+        fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+            let start = ::std::cmp::min(offset as usize, self.0.len());
+            let data = &self.0[start..];
+            let n = ::std::cmp::min(data.len(), buf.len());
+            buf[..n].copy_from_slice(&data[..n]);
+            Ok(n)
+        }
+    }
+
+    struct MemFs(HashMap<PathBuf, &'static [u8]>);
+
+    impl FileSystem for MemFs {
+        type File = MemFile;
+        fn metadata(&self, path: &Path) -> io::Result<Metadata> {
+            if path == Path::new("/") {
+                return Ok(Metadata::directory());
+            }
+            self.0.get(path)
+                .map(|data| Metadata::file(data.len() as u64, None))
+                .ok_or(io::ErrorKind::NotFound.into())
+        }
+        fn open(&self, path: &Path) -> io::Result<(MemFile, Metadata)> {
+            let meta = self.metadata(path)?;
+            Ok((MemFile(self.0[path]), meta))
+        }
+        fn read_dir(&self, _: &Path) -> io::Result<Vec<OsString>> {
+            Ok(self.0.keys()
+                .filter_map(|p| p.file_name().map(|x| x.to_os_string()))
+                .collect())
+        }
+    }
+
+    #[test]
+    fn memory() {
+        let mut files = HashMap::new();
+        files.insert(PathBuf::from("/index.txt"), &b"hello"[..]);
+        let fs = MemFs(files);
+        let cfg = Config::new().add_index_file("index.txt").done();
+        let inp = Input::from_headers(&cfg, "GET",
+            vec![("Range", &b"bytes=1-"[..])].into_iter());
+        let mut wrapper = match inp.probe_file_in(&fs, "/").unwrap() {
+            Output::File(wrapper) => wrapper,
+            _ => unreachable!(),
+        };
+        assert_eq!(wrapper.read_all(100).unwrap(), b"ello");
+        match inp.probe_file_in(&fs, "/missing.txt").unwrap() {
+            Output::NotFound => {}
+            _ => unreachable!(),
+        }
+        let inp = Input::from_headers(&Arc::new(Config::new()), "GET",
+            vec![].into_iter());
+        match inp.probe_file_in(&fs, "/").unwrap() {
+            Output::Directory => {}
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn std_fs() {
+        let fs = StdFs::new();
+        assert!(fs.metadata(Path::new("public")).unwrap().is_dir());
+        let (_, meta) = fs.open(Path::new("public/index.html")).unwrap();
+        assert_eq!(meta.len(), 126);
+        assert!(fs.read_dir(Path::new("public")).unwrap()
+            .iter().any(|x| x == "index.html"));
+    }
+}
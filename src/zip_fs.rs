@@ -0,0 +1,487 @@
+use std::cmp::min;
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsString;
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use miniz_oxide::inflate::decompress_to_vec_with_limit;
+
+use vfs::{FileSystem, Metadata, ReadAt, normalize, list_dir, read_exact_at};
+
+const STORED: u16 = 0;
+const DEFLATED: u16 = 8;
+const LOCAL_HEADER: u32 = 0x04034b50;
+const CENTRAL_HEADER: u32 = 0x02014b50;
+const END_OF_DIRECTORY: u32 = 0x06054b50;
+const END_SIZE: usize = 22;
+const MAX_COMMENT: usize = 65535;
+/// Default for `ZipFs::max_inflate`
+const MAX_INFLATE: u64 = 64 << 20;
+/// Header of the gzip member: no flags, no mtime, unknown OS
+const GZIP_HEADER: [u8; 10] = [0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 0xff];
+
+
+/// Filesystem that serves files out of a zip archive
+///
+/// Only stored and deflated entries are supported, other entries are
+/// skipped. Every deflated entry is also visible with the `.gz` suffix,
+/// so if client accepts gzip (and encodings are enabled for the file)
+/// compressed data is sent as is, wrapped into a gzip header. Otherwise,
+/// deflated entry is decompressed into memory on the first read, entries
+/// larger than `max_inflate` can't be opened.
+///
+/// Modification times of the entries are treated as UTC. Zip64 archives
+/// are not supported.
+///
+/// Only available with the `zip` feature.
+#[derive(Debug)]
+pub struct ZipFs {
+    archive: Arc<File>,
+    entries: HashMap<PathBuf, Entry>,
+    dirs: HashSet<PathBuf>,
+    max_inflate: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Entry {
+    method: u16,
+    /// Virtual `.gz` file for the deflated entry
+    gzip: bool,
+    crc32: u32,
+    compressed_size: u64,
+    size: u64,
+    header_offset: u64,
+    modified: Option<SystemTime>,
+}
+
+/// A file opened from `ZipFs`
+#[derive(Debug)]
+pub struct ZipFile {
+    kind: Kind,
+}
+
+#[derive(Debug)]
+enum Kind {
+    Stored { archive: Arc<File>, start: u64 },
+    Gzip { archive: Arc<File>, start: u64, len: u64, trailer: [u8; 8] },
+    Deflated {
+        archive: Arc<File>,
+        start: u64,
+        compressed_size: u64,
+        size: u64,
+        data: Mutex<Option<Vec<u8>>>,
+    },
+}
+
+fn le16(buf: &[u8], off: usize) -> u16 {
+    (buf[off] as u16) | ((buf[off+1] as u16) << 8)
+}
+
+fn le32(buf: &[u8], off: usize) -> u32 {
+    (le16(buf, off) as u32) | ((le16(buf, off+2) as u32) << 16)
+}
+
+fn invalid(msg: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Converts MS-DOS date and time into a timestamp (as if it were UTC)
+fn dos_time(date: u16, time: u16) -> Option<SystemTime> {
+    let year = 1980 + (date >> 9) as i64;
+    let month = ((date >> 5) & 15) as i64;
+    let day = (date & 31) as i64;
+    if !(1..=12).contains(&month) || day < 1 {
+        return None;
+    }
+    // days from civil, see http://howardhinnant.github.io/date_algorithms
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y / 400;
+    let yoe = y - era * 400;
+    let doy = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146097 + doe - 719468;
+    let secs = days * 86400
+        + (time >> 11) as i64 * 3600
+        + ((time >> 5) & 63) as i64 * 60
+        + (time & 31) as i64 * 2;
+    Some(UNIX_EPOCH + Duration::from_secs(secs as u64))
+}
+
+impl ZipFs {
+    /// Read central directory of the zip archive
+    pub fn new(archive: File) -> io::Result<ZipFs> {
+        let file_len = archive.metadata()?.len();
+        let tail_len = min(file_len, (END_SIZE + MAX_COMMENT) as u64);
+        let mut tail = vec![0u8; tail_len as usize];
+        read_exact_at(&archive, &mut tail, file_len - tail_len)?;
+        let end = (0..(tail.len() + 1).saturating_sub(END_SIZE)).rev()
+            .find(|&i| le32(&tail, i) == END_OF_DIRECTORY)
+            .ok_or_else(|| invalid("zip end of directory not found"))?;
+        let dir_size = le32(&tail, end + 12);
+        let dir_offset = le32(&tail, end + 16);
+        if dir_offset == 0xFFFFFFFF || le16(&tail, end + 10) == 0xFFFF {
+            return Err(invalid("zip64 archives are not supported"));
+        }
+        let mut dir = vec![0u8; dir_size as usize];
+        read_exact_at(&archive, &mut dir, dir_offset as u64)?;
+
+        let mut fs = ZipFs {
+            archive: Arc::new(archive),
+            entries: HashMap::new(),
+            dirs: HashSet::new(),
+            max_inflate: MAX_INFLATE,
+        };
+        fs.dirs.insert(PathBuf::new());
+        let mut pos = 0;
+        while pos + 46 <= dir.len() {
+            if le32(&dir, pos) != CENTRAL_HEADER {
+                return Err(invalid("bad zip central directory entry"));
+            }
+            let name_len = le16(&dir, pos + 28) as usize;
+            let extra_len = le16(&dir, pos + 30) as usize;
+            let comment_len = le16(&dir, pos + 32) as usize;
+            let name_end = pos + 46 + name_len;
+            if name_end > dir.len() {
+                return Err(invalid("bad zip central directory entry"));
+            }
+            let entry = Entry {
+                method: le16(&dir, pos + 10),
+                gzip: false,
+                crc32: le32(&dir, pos + 16),
+                compressed_size: le32(&dir, pos + 20) as u64,
+                size: le32(&dir, pos + 24) as u64,
+                header_offset: le32(&dir, pos + 42) as u64,
+                modified: dos_time(le16(&dir, pos + 14),
+                                   le16(&dir, pos + 12)),
+            };
+            let name = String::from_utf8_lossy(&dir[pos + 46..name_end])
+                .into_owned();
+            pos = name_end + extra_len + comment_len;
+            fs.add_entry(&name, entry);
+        }
+        Ok(fs)
+    }
+    /// Maximum size of the deflated entry to decompress (default 64 MiB)
+    ///
+    /// Opening larger entries fails, unless they are served compressed via
+    /// the virtual `.gz` file. Decompressed entries are kept in memory
+    /// while the file is open.
+    pub fn max_inflate(&mut self, bytes: u64) -> &mut Self {
+        self.max_inflate = bytes;
+        self
+    }
+    fn add_entry(&mut self, name: &str, entry: Entry) {
+        let path = match normalize(Path::new(name)) {
+            Some(ref path) if path.as_os_str().is_empty() => return,
+            Some(path) => path,
+            None => return,
+        };
+        let mut dir = path.clone();
+        while dir.pop() {
+            self.dirs.insert(dir.clone());
+        }
+        if name.ends_with("/") {
+            self.dirs.insert(path);
+            return;
+        }
+        match entry.method {
+            STORED => {}
+            DEFLATED => {
+                let mut gz = path.clone().into_os_string();
+                gz.push(".gz");
+                let gz = PathBuf::from(gz);
+                // real `.gz` entries take precedence
+                let real = self.entries.get(&gz).map(|e| !e.gzip);
+                if !real.unwrap_or(false) {
+                    self.entries.insert(gz, Entry { gzip: true, .. entry });
+                }
+            }
+            _ => return,
+        }
+        self.entries.insert(path, entry);
+    }
+    /// Returns `None` for directories
+    fn entry(&self, path: &Path) -> io::Result<Option<&Entry>> {
+        let path = normalize(path)
+            .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+        match self.entries.get(&path) {
+            Some(entry) => Ok(Some(entry)),
+            None if self.dirs.contains(&path) => Ok(None),
+            None => Err(io::ErrorKind::NotFound.into()),
+        }
+    }
+}
+
+impl Entry {
+    fn len(&self) -> u64 {
+        if self.gzip {
+            self.compressed_size + (GZIP_HEADER.len() + 8) as u64
+        } else {
+            self.size
+        }
+    }
+    fn metadata(&self) -> Metadata {
+        Metadata::file(self.len(), self.modified)
+            .with_version(self.crc32 as u64)
+    }
+}
+
+impl FileSystem for ZipFs {
+    type File = ZipFile;
+    fn metadata(&self, path: &Path) -> io::Result<Metadata> {
+        match self.entry(path)? {
+            Some(entry) => Ok(entry.metadata()),
+            None => Ok(Metadata::directory()),
+        }
+    }
+    fn open(&self, path: &Path) -> io::Result<(ZipFile, Metadata)> {
+        let entry = match self.entry(path)? {
+            Some(entry) => entry,
+            None => return Err(io::ErrorKind::PermissionDenied.into()),
+        };
+        let mut header = [0u8; 30];
+        read_exact_at(&*self.archive, &mut header, entry.header_offset)?;
+        if le32(&header, 0) != LOCAL_HEADER {
+            return Err(invalid("bad zip local file header"));
+        }
+        let start = entry.header_offset + 30
+            + le16(&header, 26) as u64 + le16(&header, 28) as u64;
+        let kind = if entry.method == STORED {
+            Kind::Stored { archive: self.archive.clone(), start }
+        } else if entry.gzip {
+            let mut trailer = [0u8; 8];
+            for i in 0..4 {
+                trailer[i] = (entry.crc32 >> (i * 8)) as u8;
+                trailer[i+4] = (entry.size >> (i * 8)) as u8;
+            }
+            Kind::Gzip {
+                archive: self.archive.clone(),
+                start,
+                len: entry.compressed_size,
+                trailer,
+            }
+        } else if entry.size > self.max_inflate {
+            return Err(io::Error::other(
+                "zip entry is too large to decompress"));
+        } else {
+            Kind::Deflated {
+                archive: self.archive.clone(),
+                start,
+                compressed_size: entry.compressed_size,
+                size: entry.size,
+                data: Mutex::new(None),
+            }
+        };
+        Ok((ZipFile { kind }, entry.metadata()))
+    }
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<OsString>> {
+        if self.entry(path)?.is_some() {
+            return Err(io::Error::other("not a directory"));
+        }
+        let path = normalize(path).expect("path is valid");
+        let files = self.entries.iter()
+            .filter(|&(_, e)| !e.gzip)
+            .map(|(p, _)| p);
+        Ok(list_dir(&path, files.chain(self.dirs.iter())))
+    }
+}
+
+impl ReadAt for ZipFile {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        match self.kind {
+            // reads are limited by the content length, so we don't need
+            // to check for the end of the entry here
+            Kind::Stored { ref archive, start } => {
+                archive.read_at(buf, start + offset)
+            }
+            Kind::Gzip { ref archive, start, len, ref trailer } => {
+                let hlen = GZIP_HEADER.len() as u64;
+                if offset < hlen {
+                    copy_from(&GZIP_HEADER, buf, offset)
+                } else if offset < hlen + len {
+                    let max = min(buf.len() as u64, hlen + len - offset);
+                    archive.read_at(&mut buf[..max as usize],
+                                    start + offset - hlen)
+                } else {
+                    copy_from(trailer, buf, offset - hlen - len)
+                }
+            }
+            Kind::Deflated { ref archive, start, compressed_size, size,
+                             ref data } => {
+                let mut data = data.lock().expect("zip file is not poisoned");
+                if data.is_none() {
+                    *data = Some(inflate(archive, start,
+                                         compressed_size, size)?);
+                }
+                copy_from(data.as_ref().unwrap(), buf, offset)
+            }
+        }
+    }
+}
+
+fn inflate(archive: &File, start: u64, compressed_size: u64, size: u64)
+    -> io::Result<Vec<u8>>
+{
+    let mut packed = vec![0u8; compressed_size as usize];
+    read_exact_at(archive, &mut packed, start)?;
+    // limit protects from the entry that lies about its size
+    let data = decompress_to_vec_with_limit(&packed, size as usize)
+        .map_err(|_| invalid("bad deflate stream in zip"))?;
+    if data.len() as u64 != size {
+        return Err(invalid("bad size of zip entry"));
+    }
+    Ok(data)
+}
+
+fn copy_from(data: &[u8], buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    let start = min(offset, data.len() as u64) as usize;
+    let n = min(data.len() - start, buf.len());
+    buf[..n].copy_from_slice(&data[start..start+n]);
+    Ok(n)
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use miniz_oxide::deflate::compress_to_vec;
+    use miniz_oxide::inflate::decompress_to_vec;
+    use config::Config;
+    use input::Input;
+    use output::Output;
+    use test_util::TempDir;
+    use super::*;
+
+    const CSS: &[u8] = b"body { color: black; color: black; color: black }";
+
+    fn crc32(data: &[u8]) -> u32 {
+        let mut crc = !0u32;
+        for &b in data {
+            crc ^= b as u32;
+            for _ in 0..8 {
+                crc = (crc >> 1) ^ (0xEDB88320 & (!(crc & 1)).wrapping_add(1));
+            }
+        }
+        !crc
+    }
+
+    fn push16(buf: &mut Vec<u8>, x: u16) {
+        buf.extend_from_slice(&[x as u8, (x >> 8) as u8]);
+    }
+
+    fn push32(buf: &mut Vec<u8>, x: u32) {
+        push16(buf, x as u16);
+        push16(buf, (x >> 16) as u16);
+    }
+
+    fn make_zip(files: &[(&str, u16, &[u8])]) -> Vec<u8> {
+        let mut data = Vec::new();
+        let mut dir = Vec::new();
+        for &(name, method, content) in files {
+            let packed = if method == DEFLATED {
+                compress_to_vec(content, 6)
+            } else {
+                content.to_vec()
+            };
+            let offset = data.len() as u32;
+            for &central in &[false, true] {
+                let buf = if central { &mut dir } else { &mut data };
+                let sig = if central { CENTRAL_HEADER } else { LOCAL_HEADER };
+                push32(buf, sig);
+                if central {
+                    push16(buf, 20);  // version made by
+                }
+                push16(buf, 20);  // version needed
+                push16(buf, 0);  // flags
+                push16(buf, method);
+                push16(buf, 0);  // time
+                push16(buf, (20 << 9) | (1 << 5) | 1);  // 2000-01-01
+                push32(buf, crc32(content));
+                push32(buf, packed.len() as u32);
+                push32(buf, content.len() as u32);
+                push16(buf, name.len() as u16);
+                push16(buf, 0);  // extra
+                if central {
+                    push16(buf, 0);  // comment
+                    push16(buf, 0);  // disk
+                    push16(buf, 0);  // internal attributes
+                    push32(buf, 0);  // external attributes
+                    push32(buf, offset);
+                }
+                buf.extend_from_slice(name.as_bytes());
+            }
+            data.extend_from_slice(&packed);
+        }
+        let dir_offset = data.len() as u32;
+        data.extend_from_slice(&dir);
+        push32(&mut data, END_OF_DIRECTORY);
+        push32(&mut data, 0);  // disk numbers
+        push16(&mut data, files.len() as u16);
+        push16(&mut data, files.len() as u16);
+        push32(&mut data, dir.len() as u32);
+        push32(&mut data, dir_offset);
+        push16(&mut data, 0);  // comment
+        data
+    }
+
+    fn get(fs: &ZipFs, path: &str, headers: Vec<(&str, &[u8])>) -> Vec<u8> {
+        let cfg = Arc::new(Config::new());
+        let inp = Input::from_headers(&cfg, "GET", headers.into_iter());
+        match inp.probe_file_in(fs, path).unwrap() {
+            Output::File(mut wrapper) => wrapper.read_all(1000).unwrap(),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn serve() {
+        let dir = TempDir::new("zip");
+        let path = dir.write("test.zip", make_zip(&[
+            ("index.html", STORED, b"hello world"),
+            ("css/main.css", DEFLATED, CSS),
+        ]));
+        let fs = ZipFs::new(File::open(&path).unwrap()).unwrap();
+        assert_eq!(get(&fs, "/index.html", vec![]), b"hello world");
+        assert_eq!(get(&fs, "/index.html",
+                       vec![("Range", b"bytes=6-")]), b"world");
+        assert_eq!(get(&fs, "/css/main.css", vec![]), CSS);
+        let gz = get(&fs, "/css/main.css", vec![("Accept-Encoding", b"gzip")]);
+        assert_eq!(&gz[..10], &GZIP_HEADER[..]);
+        assert_eq!(decompress_to_vec(&gz[10..gz.len()-8]).unwrap(), CSS);
+        assert_eq!(le32(&gz, gz.len() - 8), crc32(CSS));
+        assert_eq!(le32(&gz, gz.len() - 4), CSS.len() as u32);
+        assert!(fs.metadata(Path::new("css")).unwrap().is_dir());
+        assert_eq!(fs.read_dir(Path::new("/")).unwrap(),
+                   vec!["css", "index.html"]);
+        assert_eq!(fs.metadata(Path::new("index.html")).unwrap().modified(),
+                   Some(UNIX_EPOCH + Duration::from_secs(946684800)));
+    }
+
+    #[test]
+    fn max_inflate() {
+        let dir = TempDir::new("zip");
+        let path = dir.write("test.zip", make_zip(&[
+            ("css/main.css", DEFLATED, CSS),
+        ]));
+        let mut fs = ZipFs::new(File::open(&path).unwrap()).unwrap();
+        // opening returns metadata, data is inflated on the first read
+        let (file, meta) = fs.open(Path::new("css/main.css")).unwrap();
+        assert_eq!(meta.len(), CSS.len() as u64);
+        match file.kind {
+            Kind::Deflated { ref data, .. } => {
+                assert!(data.lock().unwrap().is_none());
+            }
+            _ => unreachable!(),
+        }
+        let mut buf = [0u8; 4];
+        assert_eq!(file.read_at(&mut buf, 0).unwrap(), 4);
+        assert_eq!(&buf, b"body");
+
+        fs.max_inflate(CSS.len() as u64 - 1);
+        assert!(fs.open(Path::new("css/main.css")).is_err());
+        // compressed data is still served as is
+        assert!(fs.open(Path::new("css/main.css.gz")).is_ok());
+    }
+}